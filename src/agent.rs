@@ -0,0 +1,407 @@
+//! A local aggregator which receives records from sibling processes over a
+//! unix socket and forwards them upstream through one shared `Client`.
+//!
+//! Processes on the host connect to the agent with `Client::new_unix`, as if
+//! it were fluentd. The agent relays their records without decoding them, so
+//! buffering, batching, spooling and failover are configured once, on the
+//! client given to the agent. A chunk is acked to the sender once its records
+//! are queued by that client.
+//!
+//! Message, Forward and PackedForward modes are accepted. The shared key
+//! handshake and compressed PackedForward are not supported, and EventTime
+//! timestamps are truncated to seconds.
+//!
+//! ## Example
+//!
+//! ```no_run
+//! use tokio_fluent::agent::Agent;
+//! use tokio_fluent::{Client, Config};
+//!
+//! #[tokio::main]
+//! async fn main() {
+//!     let config = Config {
+//!         spool_dir: Some("/var/lib/app/spool".into()),
+//!         ..Default::default()
+//!     };
+//!     let upstream = Client::new_tcp("10.0.0.1:24224".parse().unwrap(), &config)
+//!         .await
+//!         .unwrap();
+//!
+//!     let agent = Agent::bind("/run/app/fluent.sock", upstream).unwrap();
+//!     agent.run().await.unwrap();
+//! }
+//! ```
+
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+use bytes::{Bytes, BytesMut};
+use rmp::Marker;
+use serde::de::{Deserialize, IgnoredAny};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{UnixListener, UnixStream};
+
+use crate::client::Client;
+use crate::diag::diag;
+
+/// The size beyond which a message which is still incomplete is rejected.
+const MAX_MESSAGE_SIZE: usize = 64 * 1024 * 1024;
+
+/// Listens on a unix socket and relays the records received to a client.
+#[derive(Debug)]
+pub struct Agent {
+    listener: UnixListener,
+    client: Arc<Client>,
+}
+
+impl Agent {
+    /// Listen on the unix socket at `path`, relaying the records received to
+    /// `client`. A socket file left by a previous process is not removed.
+    pub fn bind<P: AsRef<Path>>(path: P, client: Client) -> io::Result<Self> {
+        Ok(Self {
+            listener: UnixListener::bind(path)?,
+            client: Arc::new(client),
+        })
+    }
+
+    /// Accept connections until the listener fails, serving each of them on
+    /// a task of its own.
+    pub async fn run(self) -> io::Result<()> {
+        loop {
+            let (stream, _) = self.listener.accept().await?;
+            let client = self.client.clone();
+            tokio::spawn(async move {
+                if let Err(e) = serve(stream, &client).await {
+                    diag!(warn; "dropping an agent connection: {}", e);
+                }
+            });
+        }
+    }
+}
+
+/// Relay the messages of a connection until it is closed.
+async fn serve(mut stream: UnixStream, client: &Client) -> io::Result<()> {
+    let mut buf = BytesMut::with_capacity(64 * 1024);
+    loop {
+        while let Some(len) = value_len(&buf)? {
+            let message = Message::decode(buf.split_to(len).freeze())?;
+            for (timestamp, record) in message.entries {
+                client
+                    .send_raw(&message.tag, timestamp, record)
+                    .map_err(io::Error::other)?;
+            }
+            if let Some(chunk) = message.chunk {
+                let mut ack = Vec::with_capacity(chunk.len() + 8);
+                write(rmp::encode::write_map_len(&mut ack, 1));
+                write(rmp::encode::write_str(&mut ack, "ack"));
+                write(rmp::encode::write_str(&mut ack, &chunk));
+                stream.write_all(&ack).await?;
+            }
+        }
+        if buf.len() > MAX_MESSAGE_SIZE {
+            return Err(invalid("the message is too large"));
+        }
+        if stream.read_buf(&mut buf).await? == 0 {
+            return Ok(());
+        }
+    }
+}
+
+/// A forward protocol message, with its records left encoded.
+#[derive(Debug, PartialEq)]
+struct Message {
+    tag: String,
+    entries: Vec<(i64, Bytes)>,
+    chunk: Option<String>,
+}
+
+impl Message {
+    /// Decode a complete message in any of the forward modes.
+    fn decode(message: Bytes) -> io::Result<Self> {
+        let mut cursor = Cursor::new(message);
+        let len = cursor.array_len()?;
+        if !(2..=4).contains(&len) {
+            return Err(invalid("the message is not a forward protocol array"));
+        }
+        let tag = cursor.str()?;
+
+        let mut entries = Vec::new();
+        let options = match cursor.marker()? {
+            // Forward mode: [tag, [[time, record], ...], option]
+            Marker::FixArray(_) | Marker::Array16 | Marker::Array32 => {
+                let count = cursor.array_len()?;
+                for _ in 0..count {
+                    entries.push(cursor.entry()?);
+                }
+                len - 2
+            }
+            // PackedForward mode: [tag, msgpack stream of [time, record], option]
+            Marker::Bin8
+            | Marker::Bin16
+            | Marker::Bin32
+            | Marker::FixStr(_)
+            | Marker::Str8
+            | Marker::Str16
+            | Marker::Str32 => {
+                let mut stream = Cursor::new(cursor.bytes()?);
+                while !stream.is_empty() {
+                    entries.push(stream.entry()?);
+                }
+                len - 2
+            }
+            // Message mode: [tag, time, record, option]
+            _ => {
+                let timestamp = time(&cursor.value()?)?;
+                entries.push((timestamp, cursor.value()?));
+                len - 3
+            }
+        };
+
+        let mut chunk = None;
+        if options > 0 {
+            let count = cursor.map_len()?;
+            for _ in 0..count {
+                match cursor.str()?.as_str() {
+                    "chunk" => chunk = Some(cursor.str()?),
+                    "compressed" => {
+                        if cursor.str()? != "text" {
+                            return Err(invalid("compressed messages are not supported"));
+                        }
+                    }
+                    _ => {
+                        cursor.value()?;
+                    }
+                }
+            }
+        }
+        Ok(Self {
+            tag,
+            entries,
+            chunk,
+        })
+    }
+}
+
+/// Reads the values of a complete message, sharing the bytes of the records.
+struct Cursor {
+    bytes: Bytes,
+    pos: usize,
+}
+
+impl Cursor {
+    fn new(bytes: Bytes) -> Self {
+        Self { bytes, pos: 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos == self.bytes.len()
+    }
+
+    /// Read the head of the next array, advancing past it.
+    fn array_len(&mut self) -> io::Result<u32> {
+        let mut rd = &self.bytes[self.pos..];
+        let len = rmp::decode::read_array_len(&mut rd).map_err(invalid)?;
+        self.pos = self.bytes.len() - rd.len();
+        Ok(len)
+    }
+
+    /// Read the head of the next map, advancing past it.
+    fn map_len(&mut self) -> io::Result<u32> {
+        let mut rd = &self.bytes[self.pos..];
+        let len = rmp::decode::read_map_len(&mut rd).map_err(invalid)?;
+        self.pos = self.bytes.len() - rd.len();
+        Ok(len)
+    }
+
+    fn marker(&self) -> io::Result<Marker> {
+        self.bytes
+            .get(self.pos)
+            .map(|&b| Marker::from_u8(b))
+            .ok_or_else(|| invalid("the message ended early"))
+    }
+
+    /// Return the next value as is.
+    fn value(&mut self) -> io::Result<Bytes> {
+        let len = value_len(&self.bytes[self.pos..])?
+            .ok_or_else(|| invalid("the message ended early"))?;
+        let value = self.bytes.slice(self.pos..self.pos + len);
+        self.pos += len;
+        Ok(value)
+    }
+
+    /// Return the content of the next str or bin value.
+    fn bytes(&mut self) -> io::Result<Bytes> {
+        let value = self.value()?;
+        let len = match Marker::from_u8(value[0]) {
+            Marker::Bin8 | Marker::Bin16 | Marker::Bin32 => {
+                rmp::decode::read_bin_len(&mut &value[..]).map_err(invalid)?
+            }
+            _ => rmp::decode::read_str_len(&mut &value[..]).map_err(invalid)?,
+        };
+        Ok(value.slice(value.len() - len as usize..))
+    }
+
+    fn str(&mut self) -> io::Result<String> {
+        String::from_utf8(self.bytes()?.to_vec()).map_err(invalid)
+    }
+
+    /// Read a `[time, record]` entry.
+    fn entry(&mut self) -> io::Result<(i64, Bytes)> {
+        if self.array_len()? != 2 {
+            return Err(invalid("the entry is not a [time, record] array"));
+        }
+        let timestamp = time(&self.value()?)?;
+        Ok((timestamp, self.value()?))
+    }
+}
+
+/// Decode a time, either an integer of seconds or an EventTime.
+fn time(value: &[u8]) -> io::Result<i64> {
+    if let [0xd7, 0x00, seconds @ ..] = value {
+        let seconds: [u8; 4] = seconds[..4].try_into().map_err(invalid)?;
+        return Ok(u32::from_be_bytes(seconds) as i64);
+    }
+    rmp::decode::read_int(&mut &value[..]).map_err(invalid)
+}
+
+/// Return the length of the msgpack value at the head of `buf`, or None if
+/// it is incomplete.
+fn value_len(buf: &[u8]) -> io::Result<Option<usize>> {
+    use rmp_serde::decode::Error as DecodeError;
+
+    let mut rd = buf;
+    match IgnoredAny::deserialize(&mut rmp_serde::Deserializer::new(&mut rd)) {
+        Ok(_) => Ok(Some(buf.len() - rd.len())),
+        Err(DecodeError::InvalidMarkerRead(ref e) | DecodeError::InvalidDataRead(ref e))
+            if e.kind() == io::ErrorKind::UnexpectedEof =>
+        {
+            Ok(None)
+        }
+        Err(e) => Err(invalid(e)),
+    }
+}
+
+fn invalid<E: Into<Box<dyn std::error::Error + Send + Sync>>>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+/// Writing to a Vec can not fail.
+fn write<T, E: std::fmt::Debug>(result: Result<T, E>) {
+    result.expect("failed to write to memory");
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::protocol::Frame;
+    use crate::record::{Map, Value};
+    use crate::record_map;
+    use crate::Config;
+
+    fn encode<T: serde::Serialize>(value: &T) -> Bytes {
+        rmp_serde::to_vec(value).unwrap().into()
+    }
+
+    #[test]
+    fn test_decode() {
+        let record = record_map!("age".to_string() => 22.into());
+        let encoded = encode(&record);
+
+        let frame = Frame::message("test", 1, encoded.clone(), Some("chunk-id"));
+        let message = Message::decode(frame.to_bytes()).unwrap();
+        assert_eq!(
+            message,
+            Message {
+                tag: "test".to_string(),
+                entries: vec![(1, encoded.clone())],
+                chunk: Some("chunk-id".to_string()),
+            }
+        );
+
+        let entries = encode(&vec![(1, &record), (2, &record)]);
+        let frame = Frame::forward("test", entries, None);
+        let message = Message::decode(frame.to_bytes()).unwrap();
+        assert_eq!(
+            message.entries,
+            vec![(1, encoded.clone()), (2, encoded.clone())]
+        );
+        assert_eq!(message.chunk, None);
+
+        let mut entries = encode(&(1, &record)).to_vec();
+        entries.extend_from_slice(&encode(&(2, &record)));
+        let frame = Frame::packed_forward("test", entries.into(), 2, Some("chunk-id"));
+        let message = Message::decode(frame.to_bytes()).unwrap();
+        assert_eq!(message.entries, vec![(1, encoded.clone()), (2, encoded)]);
+
+        // EventTime of 1 second and 2 nanoseconds, with an empty record
+        let event_time =
+            Bytes::from_static(b"\x93\xa4test\xd7\x00\x00\x00\x00\x01\x00\x00\x00\x02\x80");
+        let message = Message::decode(event_time).unwrap();
+        assert_eq!(message.entries, vec![(1, Bytes::from_static(b"\x80"))]);
+
+        let gzip = Bytes::from_static(b"\x93\xa4test\xc4\x00\x81\xaacompressed\xa4gzip");
+        assert!(Message::decode(gzip).is_err());
+    }
+
+    #[test]
+    fn test_value_len() {
+        let message = Frame::message("test", 1, encode(&Map::new()), Some("chunk-id")).to_bytes();
+        for len in 0..message.len() {
+            assert_eq!(value_len(&message[..len]).unwrap(), None);
+        }
+        let mut buf = message.to_vec();
+        buf.extend_from_slice(b"\x93");
+        assert_eq!(value_len(&buf).unwrap(), Some(message.len()));
+        assert!(value_len(b"\xc1").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_relay() {
+        let dir = std::env::temp_dir().join(format!("tokio-fluent-agent-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let upstream_path = dir.join("upstream.sock");
+        let agent_path = dir.join("agent.sock");
+
+        let upstream = UnixListener::bind(&upstream_path).unwrap();
+        let received = tokio::spawn(async move {
+            let (mut stream, _) = upstream.accept().await.unwrap();
+            let mut buf = BytesMut::new();
+            loop {
+                if let Some(len) = value_len(&buf).unwrap() {
+                    let message = Message::decode(buf.split_to(len).freeze()).unwrap();
+                    let chunk = message.chunk.clone().unwrap();
+                    stream
+                        .write_all(&encode(&HashMap::from([("ack", chunk)])))
+                        .await
+                        .unwrap();
+                    return message;
+                }
+                assert!(stream.read_buf(&mut buf).await.unwrap() > 0);
+            }
+        });
+
+        let client = Client::new_unix(&upstream_path, &Config::default())
+            .await
+            .unwrap();
+        let agent = Agent::bind(&agent_path, client).unwrap();
+        tokio::spawn(agent.run());
+
+        let sibling = Client::new_unix(&agent_path, &Config::default())
+            .await
+            .unwrap();
+        let record = record_map!("age".to_string() => 22.into());
+        sibling.send_raw("app.access", 1, encode(&record)).unwrap();
+
+        let message = tokio::time::timeout(Duration::from_secs(5), received)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(message.tag, "app.access");
+        assert_eq!(message.entries, vec![(1, encode(&record))]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -2,7 +2,7 @@
 //!
 //! ## Example
 //!
-//! ```
+//! ```no_run
 //! use tokio_fluent::{Client, Config, FluentClient};
 //! use tokio_fluent::record::{Map, Value};
 //!
@@ -21,40 +21,429 @@
 //! }
 //! ```
 
+#[cfg(feature = "tcp")]
+use std::collections::HashMap;
+#[cfg(feature = "tcp")]
 use std::net::SocketAddr;
+#[cfg(all(unix, feature = "unix"))]
 use std::path::Path;
-use std::sync::Arc;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
 use anyhow::Result as AnyhowResult;
 use base64::{engine::general_purpose, Engine};
-use tokio::sync::broadcast::{channel, Sender};
+use bytes::Bytes;
+use serde::Serialize;
+use tokio::runtime::Handle;
+use tokio::sync::broadcast::{channel, Receiver, Sender};
+use tokio::sync::mpsc;
+use tokio::sync::watch;
+use tokio::task::{JoinError, JoinHandle};
 use uuid::Uuid;
 
+use crate::clock::CoarseClock;
+use crate::dead_letter::DeadLetter;
+use crate::event::Event;
+use crate::filter::{TagDenied, TagFilter};
+use crate::handshake::HandshakeConfig;
+#[cfg(feature = "tcp")]
+use crate::heartbeat::Heartbeat;
+use crate::hooks::ChunkHooks;
+use crate::queue;
 use crate::record::Map;
+use crate::rewrite::TagRewriteRule;
+use crate::secondary::Secondary;
+use crate::selector::{RoundRobin, ServerStats, SharedSelector};
+use crate::spool::Limits;
+use crate::stats::{Recorder, Stats};
+use crate::tag::TagCache;
+use crate::transport::Connectable;
+#[cfg(all(windows, feature = "named-pipe"))]
+use crate::transport::NamedPipeConfig;
+#[cfg(any(feature = "rustls", feature = "native-tls"))]
+use crate::transport::TLSConnectionConfig;
+#[cfg(all(unix, feature = "unix"))]
+use crate::transport::UnixSocketConfig;
+#[cfg(feature = "tcp")]
+use crate::transport::{FailoverConfig, Monitored};
+#[cfg(feature = "tcp")]
+use crate::transport::{SocketOptions, TCPConnectionConfig};
+pub use crate::worker::Error as WorkerError;
 use crate::worker::{
-    Message, Options, Record, RetryConfig, TCPConnectionConfig, UnixSocketConfig, Worker,
+    Ack, AckReceiver, AdaptiveConfig, BatchConfig, Forward, Message, Options, RawRecord, Record,
+    RetryBudget, RetryConfig, SlowAckConfig, Worker, WorkerConfig,
 };
 
+const EVENT_CHANNEL_CAPACITY: usize = 128;
+
 #[derive(Debug, Clone)]
-pub struct SendError {
-    source: String,
+/// The error of the sending and flushing methods of `Client`.
+pub enum ClientError {
+    /// The worker has exited, so nothing can be queued anymore.
+    QueueClosed,
+    /// The buffer of the worker is full and the overflow policy is
+    /// `Overflow::Error`.
+    QueueFull,
+    /// The tag is not allowed by `Config::allowed_tags` or `Config::denied_tags`.
+    Denied(String),
+    /// The tag or the record is empty in `Config::strict` mode.
+    Invalid(&'static str),
+    /// The record could not be converted to a msgpack map.
+    Encode(String),
+    /// The JSON record passed to `Client::send_json` is not an object, but
+    /// the JSON type named here.
+    #[cfg(feature = "json")]
+    NotAnObject(&'static str),
+    /// The worker exited before it was done with the records.
+    Terminated,
+    /// The worker was not done with the records before the timeout.
+    Timeout,
+    /// The worker gave up on the records.
+    Worker(WorkerError),
+}
+
+/// The former name of `ClientError`.
+pub type SendError = ClientError;
+
+impl std::error::Error for ClientError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ClientError::Worker(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for ClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientError::QueueClosed => write!(f, "the worker has exited"),
+            ClientError::QueueFull => write!(f, "the buffer is full"),
+            ClientError::Denied(tag) => write!(f, "the tag is not allowed: {}", tag),
+            ClientError::Invalid(reason) => write!(f, "{}", reason),
+            ClientError::Encode(e) => write!(f, "failed to encode the record: {}", e),
+            #[cfg(feature = "json")]
+            ClientError::NotAnObject(found) => {
+                write!(f, "the record must be a JSON object, not {}", found)
+            }
+            ClientError::Terminated => write!(f, "the worker exited before it was done"),
+            ClientError::Timeout => write!(f, "timed out waiting for the worker"),
+            ClientError::Worker(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<queue::SendError> for ClientError {
+    fn from(e: queue::SendError) -> Self {
+        match e {
+            queue::SendError::Full => ClientError::QueueFull,
+            queue::SendError::Closed => ClientError::QueueClosed,
+        }
+    }
+}
+
+impl From<WorkerError> for ClientError {
+    fn from(e: WorkerError) -> Self {
+        ClientError::Worker(e)
+    }
+}
+
+/// Why a record was refused before it was queued.
+#[derive(Debug)]
+enum Rejected {
+    /// The tag is not allowed by the tag filter.
+    Denied(TagDenied),
+    /// The tag or the record is empty in `Config::strict` mode.
+    Invalid(&'static str),
+}
+
+impl From<TagDenied> for Rejected {
+    fn from(e: TagDenied) -> Self {
+        Rejected::Denied(e)
+    }
+}
+
+impl From<Rejected> for ClientError {
+    fn from(e: Rejected) -> Self {
+        match e {
+            Rejected::Denied(TagDenied(tag)) => ClientError::Denied(tag),
+            Rejected::Invalid(reason) => ClientError::Invalid(reason),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// The error of `Client::try_send`.
+pub enum TrySendError {
+    /// The buffer of the worker is at `Config::buffer_capacity`.
+    QueueFull,
+    /// The worker has exited.
+    Closed,
+    /// The tag is not allowed by `Config::allowed_tags` or `Config::denied_tags`.
+    Denied,
+    /// The tag or the record is empty in `Config::strict` mode.
+    Invalid,
+}
+
+impl std::error::Error for TrySendError {}
+
+impl std::fmt::Display for TrySendError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TrySendError::QueueFull => write!(f, "the buffer is full"),
+            TrySendError::Closed => write!(f, "the worker has exited"),
+            TrySendError::Denied => write!(f, "the tag is not allowed"),
+            TrySendError::Invalid => write!(f, "the tag or the record is empty"),
+        }
+    }
+}
+
+impl From<Rejected> for TrySendError {
+    fn from(e: Rejected) -> Self {
+        match e {
+            Rejected::Denied(_) => TrySendError::Denied,
+            Rejected::Invalid(_) => TrySendError::Invalid,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// The error of `Client::send_timeout`.
+pub enum SendTimeoutError {
+    /// The buffer of the worker stayed full until the timeout.
+    Timeout,
+    /// The worker has exited.
+    Closed,
+    /// The tag is not allowed by `Config::allowed_tags` or `Config::denied_tags`.
+    Denied,
+    /// The tag or the record is empty in `Config::strict` mode.
+    Invalid,
 }
 
-impl std::error::Error for SendError {}
+impl std::error::Error for SendTimeoutError {}
 
-impl std::fmt::Display for SendError {
+impl std::fmt::Display for SendTimeoutError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.source)
+        match self {
+            SendTimeoutError::Timeout => write!(f, "timed out waiting for room in the buffer"),
+            SendTimeoutError::Closed => write!(f, "the worker has exited"),
+            SendTimeoutError::Denied => write!(f, "the tag is not allowed"),
+            SendTimeoutError::Invalid => write!(f, "the tag or the record is empty"),
+        }
+    }
+}
+
+impl From<Rejected> for SendTimeoutError {
+    fn from(e: Rejected) -> Self {
+        match e {
+            Rejected::Denied(_) => SendTimeoutError::Denied,
+            Rejected::Invalid(_) => SendTimeoutError::Invalid,
+        }
+    }
+}
+
+#[derive(Debug)]
+/// The delivery result of the records of `Client::send_batch_acked`.
+pub struct Delivery {
+    receiver: AckReceiver,
+}
+
+impl Delivery {
+    /// Wait until fluentd acknowledged the records, or return the reason the
+    /// worker gave up on them.
+    pub async fn wait(self) -> Result<(), SendError> {
+        match self.receiver.await {
+            Ok(result) => result.map_err(ClientError::Worker),
+            Err(_) => Err(ClientError::Terminated),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+/// The records handled while draining the workers, see `Client::stop_graceful`.
+pub struct Drained {
+    /// The number of records written to the server.
+    pub flushed: u64,
+    /// The number of records dropped, because the buffer was full or they
+    /// could not be delivered after `Config::max_retry` attempts.
+    pub dropped: u64,
+    /// The number of records appended to `Config::spool_dir` to be replayed
+    /// by the next client.
+    pub spooled: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Per-record overrides of the delivery behavior, see `Client::send_with_options`.
+pub struct SendOptions {
+    /// Wait for fluentd to acknowledge the record, retrying until it does.
+    /// Without an ack a record is written once and lost if the connection
+    /// drops, which saves a round trip for records that are cheap to lose.
+    /// The default is true.
+    pub ack: bool,
+    /// Overrides `Config::max_retry` for this record, e.g. to retry audit
+    /// events longer than the rest. The default is None.
+    pub max_retry: Option<u32>,
+}
+
+impl Default for SendOptions {
+    fn default() -> Self {
+        Self {
+            ack: true,
+            max_retry: None,
+        }
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// The forward protocol mode used to send records.
+pub enum Mode {
+    /// Send every record as its own request.
+    Message,
+    /// Accumulate records per tag and send them together as msgpack-packed
+    /// chunks. This is how the official loggers achieve high throughput.
+    PackedForward,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// How the wait between retries is randomized, so that many clients do not
+/// retry in lockstep after an outage of the server.
+pub enum Jitter {
+    /// Wait exactly the backoff. This is the default.
+    None,
+    /// Wait a random duration between zero and the backoff.
+    Full,
+    /// Wait half the backoff plus a random duration up to the other half.
+    Equal,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// What the worker does when the server acks a chunk with another chunk id,
+/// i.e. `WorkerError::AckUnmatched`.
+pub enum AckMismatch {
+    /// Drop the connection, which is out of sync, and resend the chunk on a
+    /// new one within `Config::max_retry`. This is the default.
+    Reconnect,
+    /// Resend the chunk on the same connection up to this many times within
+    /// `Config::max_retry`, then drop the connection and give the chunk up.
+    Resend(u32),
+    /// Drop the connection and give the chunk up at once, handing its records
+    /// to `Config::dead_letter` and `Config::secondary_path`.
+    DeadLetter,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// What happens to a record sent while the buffer of the client is full.
+pub enum Overflow {
+    /// Wait until the worker makes room. This blocks the calling thread, so
+    /// the worker must run on another thread, e.g. on `Config::runtime`.
+    /// Inside a multi-threaded runtime the wait runs in `block_in_place`, and
+    /// inside a current-thread runtime, where waiting would stall every task,
+    /// a send to a full buffer returns `ClientError::QueueFull` instead.
+    Block,
+    /// Drop the record being sent.
+    DropNewest,
+    /// Drop the oldest buffered record to make room.
+    DropOldest,
+    /// Return an error from the send call.
+    Error,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// How `Value::Bytes` is encoded in msgpack.
+pub enum BytesEncoding {
+    /// Encode as msgpack `str`, replacing invalid UTF-8 sequences with U+FFFD.
+    /// Opt in for byte strings which are text, since some fluentd parsers
+    /// mis-handle bin-encoded text. Binary payloads are corrupted.
+    Str,
+    /// Encode as raw msgpack `bin`, so that binary payloads such as protobuf
+    /// messages are shipped intact. This is the default.
+    Bin,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// How `Value::DateTime` is encoded in msgpack.
+pub enum DateTimeEncoding {
+    /// Encode as an RFC 3339 `str` in UTC, such as `2024-01-02T03:04:05.678Z`,
+    /// which every fluentd output understands. This is the default.
+    Rfc3339,
+    /// Encode as the EventTime ext type of the forward protocol, keeping
+    /// nanoseconds.
+    EventTime,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// The format of `Config::secondary_path`.
+pub enum SecondaryFormat {
+    /// Append chunks as they would have been sent. This is the default.
+    Msgpack,
+    /// Append every record as a line of JSON with its tag, time and record.
+    #[cfg(feature = "json")]
+    Json,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// A fluentd server of `Config::servers`.
+pub struct Server {
+    /// The address of the server as "hostname:port".
+    /// The hostname is resolved again on every connection.
+    pub host: String,
+    /// Whether the server is a standby, only used when no other server is reachable.
+    pub standby: bool,
+    /// The weight of the server for weighted selectors, see `Config::selector`.
+    /// The default is 1.
+    pub weight: u32,
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Self {
+            host: String::new(),
+            standby: false,
+            weight: 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// The protocol of a `Proxy`.
+pub enum ProxyKind {
+    /// SOCKS5, with optional username/password authentication.
+    Socks5,
+    /// An HTTP proxy tunneling with the CONNECT method, with optional Basic authentication.
+    Http,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// A proxy of `Config::proxy`.
+pub struct Proxy {
+    /// The protocol of the proxy.
+    pub kind: ProxyKind,
+    /// The address of the proxy as "hostname:port".
+    pub addr: String,
+    /// The username to authenticate with, if the proxy requires it.
+    pub username: Option<String>,
+    /// The password of `username`.
+    pub password: Option<String>,
+}
+
 #[derive(Debug, Clone)]
 /// Config for a client.
 pub struct Config {
     /// The timeout value to connect to the fluentd server.
     /// The default is 3 seconds.
     pub timeout: Duration,
+    /// Disable Nagle's algorithm on TCP and TLS connections, so that small
+    /// chunks are sent at once instead of being held back until the previous
+    /// segments are acknowledged by TCP. The default is true.
+    pub tcp_nodelay: bool,
+    /// The size of the kernel send buffer (SO_SNDBUF) of TCP and TLS
+    /// connections, set before connecting. Raise it for high-throughput
+    /// links with a high latency. The default is 0 (the system default).
+    pub tcp_send_buffer_size: u32,
+    /// The size of the kernel receive buffer (SO_RCVBUF) of TCP and TLS
+    /// connections, set before connecting. The default is 0 (the system default).
+    pub tcp_recv_buffer_size: u32,
     /// The duration of the initial wait for the first retry, in milliseconds.
     /// The default is 500.
     pub retry_wait: u64,
@@ -65,226 +454,2589 @@ pub struct Config {
     /// If calculated retry wait is larger than this value, operation will fail.
     /// The default is 60,000 (60 seconds).
     pub max_retry_wait: u64,
+    /// How the wait between retries is randomized within the backoff, so that
+    /// clients do not reconnect in lockstep after an aggregator restart.
+    /// The default is `Jitter::None`.
+    pub retry_jitter: Jitter,
+    /// Retry with the backoff capped at `max_retry_wait` until the chunk is
+    /// delivered, ignoring `max_retry`, for agents which must never drop
+    /// records. Records of `SendOptions::max_retry` keep their limit. While
+    /// the worker retries, records pile up in the buffer, so combine this with
+    /// `Overflow::Block` to never drop any. The default is false.
+    pub retry_forever: bool,
+    /// The number of retries per second shared by the workers, refilling a
+    /// bucket of as many retries, so that a flood of failing writes can not
+    /// keep the workers sleeping between retries. Once the budget is spent,
+    /// chunks fail without being retried, as if `max_retry` was exceeded, and
+    /// an `Event::Backoff` is emitted. The default is 0 (no limit).
+    pub retry_budget: u32,
     /// The maximum lifetime of a connection before reconnection is attempted.
     /// Note that reconnection is only triggered when new log lines are sent.
     /// If no new log lines are received within this timeframe, the connection
     /// will remain open, even if it's older than `max_connection_lifetime`.
     /// The default is 0 (no reconnection).
     pub max_connection_lifetime: Duration,
+    /// How long to wait for the ack of a chunk before the write is treated as
+    /// failed, and retried on a new connection. Otherwise a server which
+    /// accepts writes but never acks would block the worker forever.
+    /// The default is 190 seconds, as the `ack_response_timeout` of fluentd.
+    /// Zero waits forever.
+    pub ack_timeout: Duration,
+    /// Request an ack for every chunk, which fluentd sends only if
+    /// `require_ack_response` is enabled on its forward input. Disable this
+    /// against servers without it, which would never ack: the `chunk` option
+    /// is not sent and acks are not read, regardless of `SendOptions::ack`.
+    /// The default is true.
+    pub require_ack_response: bool,
+    /// What to do when the server acks a chunk with another chunk id.
+    /// Chunks given up by the policy are not spooled.
+    /// The default is `AckMismatch::Reconnect`.
+    pub ack_mismatch: AckMismatch,
+    /// The ack latency above which a chunk is considered slow.
+    /// When `slow_ack_window` consecutive chunks are slow, an `Event::SlowAck`
+    /// is emitted. The default is 0 (disabled).
+    pub slow_ack_threshold: Duration,
+    /// The number of consecutive slow chunks required to emit an `Event::SlowAck`.
+    /// The default is 10.
+    pub slow_ack_window: u32,
+    /// Use a clock cached by a background task for the timestamp of `send`,
+    /// instead of reading the system clock on every call.
+    /// This is useful at very high event rates. The default is false.
+    pub coarse_clock: bool,
+    /// Send the timestamps of records as the EventTime ext type of the forward
+    /// protocol, with nanoseconds, so that fluentd keeps the order of events
+    /// within a second. The system clock is read even if `coarse_clock` is
+    /// enabled. Records of `send_batch` keep whole seconds.
+    /// The default is false.
+    pub event_time: bool,
+    /// The key of a unique event id (UUID v4) added to every record at send time,
+    /// enabling downstream deduplication. Records which already have the key are
+    /// left untouched. The default is None (disabled).
+    pub event_id_key: Option<String>,
+    /// Track the type of every top-level field per tag and emit an
+    /// `Event::SchemaDrift` when a field changes its type or a new field appears.
+    /// The default is false.
+    pub detect_schema_drift: bool,
+    /// The forward protocol mode. The default is `Mode::Message`.
+    pub mode: Mode,
+    /// The interval at which batched records are flushed in `Mode::PackedForward`.
+    /// The default is 1 second.
+    pub flush_interval: Duration,
+    /// The size in bytes of the batched records of a tag above which they are
+    /// flushed without waiting for `flush_interval`, in `Mode::PackedForward`.
+    /// The default is 1 MiB.
+    pub batch_size: usize,
+    /// The number of bytes flushed per `flush_interval` in `Mode::PackedForward`.
+    /// Batches are flushed in turn, the oldest first, so that a hot tag can
+    /// not monopolize the connection and batches left over are flushed first
+    /// on the next interval. Batches reaching `batch_size` are still flushed
+    /// right away. The default is 0 (flush every batch each interval).
+    pub flush_budget: usize,
+    /// Adapt the batch size and the flush interval to the ack latency in
+    /// `Mode::PackedForward`. Starting from `min_batch_size` and
+    /// `min_flush_interval`, both are doubled while acks take less than half
+    /// of `target_ack_latency` and halved while they take longer, up to
+    /// `batch_size` and `flush_interval`. The default is false.
+    pub adaptive_batching: bool,
+    /// The lower bound of the batch size with `adaptive_batching`.
+    /// The default is 64 KiB.
+    pub min_batch_size: usize,
+    /// The lower bound of the flush interval with `adaptive_batching`.
+    /// The default is 100 milliseconds.
+    pub min_flush_interval: Duration,
+    /// The ack latency which `adaptive_batching` aims to stay under.
+    /// The default is 100 milliseconds.
+    pub target_ack_latency: Duration,
+    /// The shared key used to authenticate with a fluentd forward input which
+    /// has `<security>` enabled. The HELO/PING/PONG handshake is performed on
+    /// every connection and the connection fails if it is rejected.
+    /// The default is None (no authentication).
+    pub shared_key: Option<String>,
+    /// The hostname of this client sent in the handshake.
+    /// The default is "localhost".
+    pub self_hostname: String,
+    /// The username sent in the handshake when the server requires user
+    /// authentication. Only used with `shared_key`.
+    /// The default is None.
+    pub username: Option<String>,
+    /// The password of `username`.
+    /// The default is None.
+    pub password: Option<String>,
+    /// How byte strings (`Value::Bytes`) are encoded.
+    /// The default is `BytesEncoding::Bin`.
+    pub bytes_encoding: BytesEncoding,
+    /// How points in time (`Value::DateTime`) in records are encoded.
+    /// The default is `DateTimeEncoding::Rfc3339`.
+    pub datetime_encoding: DateTimeEncoding,
+    /// Rules rewriting the tags of records in the worker, like fluentd's
+    /// `rewrite_tag_filter`. The first matching rule is applied.
+    /// The default is empty (no rewriting).
+    pub tag_rewrite_rules: Vec<TagRewriteRule>,
+    /// Patterns of the tags records may be sent to, in fluentd's `<match>`
+    /// syntax (`*` matches a tag part, `**` zero or more parts). Checked when
+    /// a record is sent, before `tag_rewrite_rules`.
+    /// The default is empty (every tag is allowed).
+    pub allowed_tags: Vec<String>,
+    /// Patterns of the tags records may not be sent to, taking precedence
+    /// over `allowed_tags`. The default is empty.
+    pub denied_tags: Vec<String>,
+    /// Drop the records of tags which are not allowed, counting them in
+    /// `Stats::denied_records`, instead of returning an error from the send.
+    /// The default is false.
+    pub drop_denied_tags: bool,
+    /// Reject records with an empty tag or an empty map with an error from
+    /// the send instead of shipping them. Checked before `allowed_tags`.
+    /// The default is false.
+    pub strict: bool,
+    /// The interval at which the hostname of `Client::new_tcp_host` is resolved
+    /// again, reconnecting when its addresses changed. The hostname is always
+    /// resolved again on reconnection. The default is 0 (disabled).
+    pub dns_refresh_interval: Duration,
+    /// The servers used by `Client::new_tcp_servers`.
+    /// The default is empty.
+    pub servers: Vec<Server>,
+    /// The number of consecutive failed connections to the active server after
+    /// which `Client::new_tcp_servers` fails over to the next server.
+    /// The default is 3.
+    pub failover_threshold: u32,
+    /// The interval at which `Client::new_tcp_servers` probes the first server
+    /// after failing over, to fail back once it has recovered.
+    /// The default is 30 seconds.
+    pub failback_interval: Duration,
+    /// Distribute records among the non-standby servers of `servers` in turn,
+    /// with a worker and a connection for each server, instead of sending
+    /// every record to a single server. Servers which failed
+    /// `failover_threshold` times in a row are skipped while another one is
+    /// available. The default is false.
+    pub load_balance: bool,
+    /// Choose the server used by `Client::new_tcp_servers`, see the `selector`
+    /// module. With `load_balance`, the selector chooses the worker of every
+    /// record among the non-standby servers, and each worker still fails over
+    /// to the standby servers. Otherwise it chooses the server to connect to
+    /// on every connection and every `failback_interval`, among the servers
+    /// which have not failed `failover_threshold` times in a row.
+    /// The default is None: the servers are used in order, and records are
+    /// distributed among them by `selector::RoundRobin` with `load_balance`.
+    pub selector: Option<SharedSelector>,
+    /// The interval at which `Client::new_tcp_servers` sends UDP heartbeats to
+    /// every server, like the `heartbeat_type udp` of fluentd's out_forward.
+    /// A server is considered unavailable when it does not answer for
+    /// `heartbeat_timeout`, and is avoided while another server is available.
+    /// The default is 0 (disabled).
+    pub heartbeat_interval: Duration,
+    /// The time without an answer to heartbeats after which a server is
+    /// considered unavailable. The default is 10 seconds.
+    pub heartbeat_timeout: Duration,
+    /// The SOCKS5 or HTTP proxy through which TCP connections to fluentd are tunneled.
+    /// Hostnames of `Client::new_tcp_host` and `servers` are resolved by the proxy.
+    /// The default is None (direct connections).
+    pub proxy: Option<Proxy>,
+    /// The number of records buffered for each worker before `overflow` applies.
+    /// The records of a `Client::send_batch` take a single slot together, but
+    /// are counted one by one when the overflow policy drops them.
+    /// The default is 1024.
+    pub buffer_capacity: usize,
+    /// What happens to a record sent while the buffer is full.
+    /// The default is `Overflow::DropOldest`.
+    pub overflow: Overflow,
+    /// The tag of the records written when the worker switches to another
+    /// server of `servers`, documenting the transition with the `from`, `to`,
+    /// `reason` and `duration` (in seconds) fields. An `Event::Failover` is
+    /// emitted regardless. The default is None (no records).
+    pub failover_tag: Option<String>,
+    /// A directory where chunks are spooled when the server can not be reached,
+    /// instead of being dropped once `max_retry` is exceeded. While chunks are
+    /// spooled, new chunks are appended to the spool to keep their order, and
+    /// the spool is replayed every `max_retry_wait` until the server recovers.
+    /// Chunks left by a previous process are replayed on start. Every chunk is
+    /// checksummed, and chunks found corrupt on replay are renamed with a
+    /// `.corrupt` extension and skipped.
+    /// Each worker uses its own subdirectory. The default is None (no spooling).
+    pub spool_dir: Option<PathBuf>,
+    /// The size in bytes beyond which the oldest chunks spooled by a worker
+    /// are removed. The default is 0 (no limit).
+    pub spool_max_bytes: u64,
+    /// The age beyond which spooled chunks are removed. Chunks are grouped by
+    /// the hour they were spooled in, and removed once the end of their hour
+    /// is older than this. The default is zero (no limit).
+    pub spool_max_age: Duration,
+    /// The number of spooled chunks each worker replays per second, so that a
+    /// large backlog does not overwhelm fluentd once it recovers. The progress
+    /// of a replay is reported by `Event::ReplayProgress`. The default is 0
+    /// (no limit).
+    pub spool_replay_rate: u32,
+    /// Append every chunk to the spool in `spool_dir` before writing it, and
+    /// remove it once fluentd acked it, so that chunks in flight when the
+    /// process exits are replayed by the next client using the same
+    /// directory. This gives at-least-once delivery across restarts, at the
+    /// cost of a file write per chunk. Records still in the buffer or in a
+    /// batch of `Mode::PackedForward` are not logged yet.
+    /// Requires `spool_dir`. The default is false.
+    pub durable: bool,
+    /// Callbacks on the chunks created, acked and abandoned by the workers,
+    /// e.g. to commit the offsets of a source only once fluentd acked its
+    /// records. See the `hooks` module. The default is None.
+    pub chunk_hooks: Option<Arc<dyn ChunkHooks>>,
+    /// The channel receiving the records of the chunks dropped after
+    /// `max_retry` attempts, so that they can be persisted elsewhere. Chunks
+    /// which are spooled are not dropped. Records are discarded if the
+    /// channel is full or closed, since the worker never waits for it.
+    /// See the `dead_letter` module. The default is None.
+    pub dead_letter: Option<mpsc::Sender<DeadLetter>>,
+    /// A file where the chunks dropped after `max_retry` attempts are
+    /// appended, like the `<secondary>` output of fluentd. Chunks which are
+    /// spooled are not dropped. Every worker appends to the same file.
+    /// The default is None.
+    pub secondary_path: Option<PathBuf>,
+    /// The format of `secondary_path`. The default is `SecondaryFormat::Msgpack`.
+    pub secondary_format: SecondaryFormat,
+    /// The size in bytes beyond which `secondary_path` is rotated to
+    /// `<secondary_path>.1`, replacing the previous one.
+    /// The default is 0 (no rotation).
+    pub secondary_max_bytes: u64,
+    /// The runtime on which the worker runs, so that log IO does not compete
+    /// with latency-critical tasks of the application.
+    /// See `spawn_logging_runtime` for a dedicated single-threaded runtime.
+    /// The default is None (the runtime creating the client).
+    pub runtime: Option<Handle>,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
             timeout: Duration::new(3, 0),
+            tcp_nodelay: true,
+            tcp_send_buffer_size: 0,
+            tcp_recv_buffer_size: 0,
             retry_wait: 500,
             max_retry: 10,
             max_retry_wait: 60000,
+            retry_jitter: Jitter::None,
+            retry_forever: false,
+            retry_budget: 0,
             max_connection_lifetime: Duration::from_secs(0),
+            ack_timeout: Duration::from_secs(190),
+            require_ack_response: true,
+            ack_mismatch: AckMismatch::Reconnect,
+            slow_ack_threshold: Duration::from_secs(0),
+            slow_ack_window: 10,
+            coarse_clock: false,
+            event_time: false,
+            event_id_key: None,
+            detect_schema_drift: false,
+            mode: Mode::Message,
+            flush_interval: Duration::from_secs(1),
+            batch_size: 1024 * 1024,
+            adaptive_batching: false,
+            min_batch_size: 64 * 1024,
+            min_flush_interval: Duration::from_millis(100),
+            target_ack_latency: Duration::from_millis(100),
+            flush_budget: 0,
+            shared_key: None,
+            self_hostname: "localhost".to_string(),
+            username: None,
+            password: None,
+            bytes_encoding: BytesEncoding::Bin,
+            datetime_encoding: DateTimeEncoding::Rfc3339,
+            tag_rewrite_rules: Vec::new(),
+            allowed_tags: Vec::new(),
+            denied_tags: Vec::new(),
+            drop_denied_tags: false,
+            strict: false,
+            dns_refresh_interval: Duration::from_secs(0),
+            servers: Vec::new(),
+            failover_threshold: 3,
+            failback_interval: Duration::from_secs(30),
+            load_balance: false,
+            selector: None,
+            heartbeat_interval: Duration::from_secs(0),
+            heartbeat_timeout: Duration::from_secs(10),
+            proxy: None,
+            buffer_capacity: 1024,
+            overflow: Overflow::DropOldest,
+            failover_tag: None,
+            spool_dir: None,
+            spool_max_bytes: 0,
+            spool_max_age: Duration::ZERO,
+            spool_replay_rate: 0,
+            durable: false,
+            chunk_hooks: None,
+            dead_letter: None,
+            secondary_path: None,
+            secondary_format: SecondaryFormat::Msgpack,
+            secondary_max_bytes: 0,
+            runtime: None,
         }
     }
 }
 
-pub trait FluentClient: Send + Sync {
-    fn send(&self, tag: &str, record: Map) -> Result<(), SendError>;
-    fn stop(self) -> Result<(), SendError>;
+impl Config {
+    /// A config sending every record as soon as it is sent, and giving up
+    /// quickly when the server is unreachable rather than delaying later records.
+    pub fn low_latency() -> Self {
+        Self {
+            timeout: Duration::from_secs(1),
+            retry_wait: 50,
+            max_retry: 3,
+            max_retry_wait: 1000,
+            mode: Mode::Message,
+            ..Default::default()
+        }
+    }
+
+    /// A config batching records into packed forward chunks and reading the
+    /// clock from a cache, for very high event rates.
+    pub fn high_throughput() -> Self {
+        Self {
+            mode: Mode::PackedForward,
+            flush_interval: Duration::from_secs(1),
+            batch_size: 8 * 1024 * 1024,
+            coarse_clock: true,
+            ..Default::default()
+        }
+    }
+
+    /// A config never dropping a record: chunks are retried until they are
+    /// delivered, and a send to a full buffer returns `ClientError::QueueFull`
+    /// instead of dropping a record, so use `Client::send_async` to wait for
+    /// room. An event id is added to every record so that duplicates caused
+    /// by retries can be removed downstream. Set `spool_dir` as well to keep
+    /// the chunks across restarts.
+    pub fn lossless() -> Self {
+        Self {
+            retry_forever: true,
+            max_retry_wait: 60000,
+            overflow: Overflow::Error,
+            mode: Mode::PackedForward,
+            flush_interval: Duration::from_millis(200),
+            event_id_key: Some("event_id".to_string()),
+            ..Default::default()
+        }
+    }
 }
 
-#[derive(Debug, Clone)]
-/// A fluentd client.
-pub struct Client {
-    sender: Sender<Message>,
+/// The name of the thread of `spawn_logging_runtime`.
+const LOGGING_THREAD_NAME: &str = "tokio-fluent";
+
+/// Spawn a single-threaded runtime on a dedicated thread, for `Config::runtime`.
+///
+/// The runtime lives until the process exits.
+///
+/// ## Example
+///
+/// ```no_run
+/// use tokio_fluent::client::spawn_logging_runtime;
+/// use tokio_fluent::{Client, Config};
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let config = Config {
+///         runtime: Some(spawn_logging_runtime().unwrap()),
+///         ..Default::default()
+///     };
+///     let client = Client::new_tcp("127.0.0.1:24224".parse().unwrap(), &config)
+///         .await
+///         .unwrap();
+/// }
+/// ```
+pub fn spawn_logging_runtime() -> std::io::Result<Handle> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    let handle = runtime.handle().clone();
+    std::thread::Builder::new()
+        .name(LOGGING_THREAD_NAME.to_string())
+        .spawn(move || runtime.block_on(std::future::pending::<()>()))?;
+    Ok(handle)
 }
 
-impl Client {
-    /// Connect to the fluentd server using TCP and create a worker with tokio::spawn.
-    pub async fn new_tcp(addr: SocketAddr, config: &Config) -> AnyhowResult<Client> {
-        let (sender, receiver) = channel(1024);
+/// The key of the severity added by the level-aware methods of `FluentClient`.
+pub const LEVEL_KEY: &str = "level";
 
-        let config = config.clone();
-        let stream_config = Arc::new(TCPConnectionConfig {
-            addr: addr.to_owned(),
-            timeout: config.timeout,
-        });
-        // create the worker --
-        // new() will try to establish an connection, so it returns error if connection,
-        // so it returns error upon connection error
-        let mut worker = Worker::new(
-            stream_config,
-            config.max_connection_lifetime,
-            receiver,
-            RetryConfig {
-                initial_wait: config.retry_wait,
-                max: config.max_retry,
-                max_wait: config.max_retry_wait,
-            },
-        )
-        .await?;
-        let _ = tokio::spawn(async move { worker.run().await });
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+/// Severity of a record.
+pub enum Level {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
 
-        Ok(Self { sender })
+impl Level {
+    /// Return the lowercase name of the level, e.g. "info".
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Level::Trace => "trace",
+            Level::Debug => "debug",
+            Level::Info => "info",
+            Level::Warn => "warn",
+            Level::Error => "error",
+        }
     }
+}
 
-    /// Connect to the fluentd server using unix domain socket and create a worker with tokio::spawn.
-    pub async fn new_unix<P: AsRef<Path> + std::marker::Send>(
-        path: P,
-        config: &Config,
-    ) -> AnyhowResult<Client> {
-        let (sender, receiver) = channel(1024);
+impl std::fmt::Display for Level {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
 
-        let config = config.clone();
-        let stream_config = Arc::new(UnixSocketConfig {
-            path: path.as_ref().to_path_buf(),
-            timeout: config.timeout,
-        });
-        // create the worker --
-        // new() will try to establish an connection, so it returns error if connection,
-        // so it returns error upon connection error
-        let mut worker = Worker::new(
-            stream_config,
-            config.max_connection_lifetime,
-            receiver,
-            RetryConfig {
-                initial_wait: config.retry_wait,
-                max: config.max_retry,
-                max_wait: config.max_retry_wait,
-            },
-        )
-        .await?;
-        let _ = tokio::spawn(async move {
-            worker.run().await;
-        });
+pub trait FluentClient: Send + Sync {
+    fn send(&self, tag: &str, record: Map) -> Result<(), SendError>;
+    fn stop(self) -> Result<(), SendError>;
 
-        Ok(Self { sender })
+    /// Send a fluent record with its severity set in the `level` field.
+    fn log(&self, level: Level, tag: &str, mut record: Map) -> Result<(), SendError> {
+        record.insert(LEVEL_KEY.to_string(), level.as_str().into());
+        self.send(tag, record)
     }
 
-    fn send_with_time(&self, tag: &str, record: Map, timestamp: i64) -> Result<(), SendError> {
-        let record = Record {
-            tag: tag.into(),
-            record,
-            timestamp,
-            options: Options {
-                chunk: general_purpose::STANDARD.encode(Uuid::new_v4()),
-            },
-        };
-        self.sender
-            .send(Message::Record(record))
-            .map_err(|e| SendError {
-                source: e.to_string(),
-            })?;
-        Ok(())
+    /// Send a fluent record with `level` set to "debug".
+    fn debug(&self, tag: &str, record: Map) -> Result<(), SendError> {
+        self.log(Level::Debug, tag, record)
     }
-}
 
-impl FluentClient for Client {
-    /// Send a fluent record to the fluentd server.
-    ///
-    /// ## Params:
-    /// `tag` - Event category of a record to send.
-    ///
-    /// `record` - Map object to send as a fluent record.
-    fn send(&self, tag: &str, record: Map) -> Result<(), SendError> {
-        self.send_with_time(tag, record, chrono::Local::now().timestamp())
+    /// Send a fluent record with `level` set to "info".
+    fn info(&self, tag: &str, record: Map) -> Result<(), SendError> {
+        self.log(Level::Info, tag, record)
     }
 
-    /// Stop the worker.
-    fn stop(self) -> Result<(), SendError> {
-        self.sender
-            .send(Message::Terminate)
-            .map_err(|e| SendError {
-                source: e.to_string(),
-            })?;
-        Ok(())
+    /// Send a fluent record with `level` set to "warn".
+    fn warn(&self, tag: &str, record: Map) -> Result<(), SendError> {
+        self.log(Level::Warn, tag, record)
     }
-}
 
-/// The worker is terminated when client is dropped.
-impl Drop for Client {
-    fn drop(&mut self) {
-        let _ = self.sender.send(Message::Terminate);
+    /// Send a fluent record with `level` set to "error".
+    fn error(&self, tag: &str, record: Map) -> Result<(), SendError> {
+        self.log(Level::Error, tag, record)
     }
 }
 
 #[derive(Debug, Clone)]
-/// NopClient does nothing.
-pub struct NopClient;
+/// The channels to the workers of a client. Records are distributed among
+/// them by the selector of `balancer`.
+struct Workers {
+    senders: Arc<[queue::Sender]>,
+    /// None with a single worker.
+    balancer: Option<Balancer>,
+}
 
-impl FluentClient for NopClient {
-    fn send(&self, _tag: &str, _record: Map) -> Result<(), SendError> {
-        Ok(())
-    }
+#[derive(Debug, Clone)]
+/// Chooses the worker of every record with `Config::selector`.
+struct Balancer {
+    selector: SharedSelector,
+    /// The stats of the server of each worker.
+    servers: Arc<[Arc<Mutex<ServerStats>>]>,
+}
 
-    fn stop(self) -> Result<(), SendError> {
-        Ok(())
+impl Balancer {
+    fn select(&self) -> Option<usize> {
+        let servers: Vec<ServerStats> = self
+            .servers
+            .iter()
+            .map(|stats| stats.lock().unwrap().clone())
+            .collect();
+        self.selector.lock().unwrap().select(&servers)
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn test_send_with_time() {
-        use std::collections::HashMap;
+impl Workers {
+    /// Return the sender of the next worker.
+    fn next(&self) -> &queue::Sender {
+        let index = self.balancer.as_ref().and_then(Balancer::select);
+        index
+            .and_then(|index| self.senders.get(index))
+            .unwrap_or(&self.senders[0])
+    }
 
-        use chrono::TimeZone;
+    /// Send a message to the next worker.
+    fn send(&self, message: Message) -> Result<(), SendError> {
+        self.next().send(message)?;
+        Ok(())
+    }
 
-        use crate::record::Value;
-        use crate::record_map;
+    /// Send a message to the next worker if its buffer has room.
+    fn try_send(&self, message: Message) -> Result<(), TrySendError> {
+        self.next().try_send(message).map_err(|e| match e {
+            queue::SendError::Full => TrySendError::QueueFull,
+            queue::SendError::Closed => TrySendError::Closed,
+        })
+    }
 
-        let (sender, mut receiver) = channel(1024);
-        let client = Client { sender };
+    /// Send a message to the next worker, waiting for room in its buffer.
+    async fn send_async(&self, message: Message) -> Result<(), SendError> {
+        self.next()
+            .send_async(message)
+            .await
+            .map_err(ClientError::from)
+    }
 
-        let timestamp = chrono::Utc.timestamp_opt(1234567, 0).unwrap().timestamp();
-        let record = record_map!("age".to_string() => 20.into());
-        assert!(
-            client.send_with_time("test", record, timestamp).is_ok(),
-            "failed to send with time"
-        );
+    /// Ask every worker to flush, returning the receivers notified once
+    /// each of them has written out the messages enqueued before.
+    fn flush(&self) -> Result<Vec<AckReceiver>, SendError> {
+        self.senders
+            .iter()
+            .map(|sender| {
+                let (ack, receiver) = Ack::new();
+                sender.send_control(Message::Flush(ack))?;
+                Ok(receiver)
+            })
+            .collect()
+    }
 
-        let got = receiver.try_recv().expect("failed to receive");
-        match got {
-            Message::Record(r) => {
-                assert_eq!(r.tag, "test");
-                assert_eq!(r.record, record_map!("age".to_string() => 20.into()));
-                assert_eq!(r.timestamp, 1234567);
+    /// Send a control message to every worker.
+    fn broadcast(&self, message: Message) -> Result<(), SendError> {
+        let mut result = Ok(());
+        for sender in self.senders.iter() {
+            if let Err(e) = sender.send_control(message.clone()) {
+                result = Err(e.into());
             }
-            Message::Terminate => unreachable!("got terminate message"),
         }
+        result
     }
+}
 
-    #[test]
-    fn test_stop() {
-        let (sender, mut receiver) = channel(1024);
-        let client = Client { sender };
-        assert!(client.stop().is_ok(), "faled to stop");
+impl Workers {
+    /// Return the number of records dropped by the overflow policy.
+    fn dropped(&self) -> u64 {
+        self.senders.iter().map(queue::Sender::dropped).sum()
+    }
+}
 
-        let got = receiver.try_recv().expect("failed to receive");
-        match got {
-            Message::Record(_) => unreachable!("got record message"),
-            Message::Terminate => {}
+/// Several workers are used in turn.
+impl From<Vec<queue::Sender>> for Workers {
+    fn from(senders: Vec<queue::Sender>) -> Self {
+        let balancer = (senders.len() > 1).then(|| Balancer {
+            selector: Arc::new(Mutex::new(RoundRobin::new())),
+            servers: senders.iter().map(|_| Default::default()).collect(),
+        });
+        Self {
+            senders: senders.into(),
+            balancer,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+/// A fluentd client.
+pub struct Client {
+    workers: Workers,
+    events: Sender<Event>,
+    stats: Arc<Recorder>,
+    clock: Option<CoarseClock>,
+    tags: Arc<TagCache>,
+    filter: Arc<TagFilter>,
+    strict: bool,
+    event_time: bool,
+    event_id_key: Option<String>,
+    bytes_encoding: BytesEncoding,
+    datetime_encoding: DateTimeEncoding,
+    done: watch::Receiver<()>,
+    /// The tasks of the workers, taken by the first call to `join`.
+    handles: Arc<Mutex<Vec<JoinHandle<()>>>>,
+    terminate_on_drop: bool,
+}
+
+impl Client {
+    /// Connect to the fluentd server using TCP and create a worker with tokio::spawn.
+    #[cfg(feature = "tcp")]
+    pub async fn new_tcp(addr: SocketAddr, config: &Config) -> AnyhowResult<Client> {
+        let stream_config = TCPConnectionConfig {
+            host: None,
+            addrs: std::sync::Mutex::new(vec![addr]),
+            timeout: config.timeout,
+            proxy: config.proxy.clone(),
+            socket: Self::socket_options(config),
         };
+        Self::spawn(stream_config, config, config.dns_refresh_interval).await
     }
 
-    #[test]
-    fn test_client_drop_sends_terminate() {
-        let (sender, mut receiver) = channel(1024);
-        {
-            Client { sender };
+    /// Connect to the fluentd server at `host`, given as "hostname:port", using TCP
+    /// and create a worker with tokio::spawn.
+    ///
+    /// The hostname is resolved with the resolver of the system, and the resolved
+    /// addresses are tried in order until a connection is established.
+    /// It is resolved again on every reconnection, and every `dns_refresh_interval`
+    /// if set, so that the client follows address changes of the server.
+    ///
+    /// With `proxy`, the hostname is resolved by the proxy instead.
+    #[cfg(feature = "tcp")]
+    pub async fn new_tcp_host(host: &str, config: &Config) -> AnyhowResult<Client> {
+        let addrs = match config.proxy {
+            Some(_) => vec![],
+            None => tokio::time::timeout(config.timeout, tokio::net::lookup_host(host))
+                .await??
+                .collect::<Vec<_>>(),
+        };
+        if addrs.is_empty() && config.proxy.is_none() {
+            return Err(anyhow::anyhow!("{} did not resolve to any address", host));
         }
-        let got = receiver.try_recv().expect("failed to receive");
-        match got {
-            Message::Record(_) => unreachable!("got record message"),
-            Message::Terminate => {}
+        let stream_config = TCPConnectionConfig {
+            host: Some(host.to_string()),
+            addrs: std::sync::Mutex::new(addrs),
+            timeout: config.timeout,
+            proxy: config.proxy.clone(),
+            socket: Self::socket_options(config),
         };
+        Self::spawn(stream_config, config, config.dns_refresh_interval).await
     }
 
-    #[test]
-    fn test_default_config() {
-        let config: Config = Default::default();
-        assert_eq!(config.timeout, Duration::new(3, 0));
-        assert_eq!(config.retry_wait, 500);
-        assert_eq!(config.max_retry, 10);
-        assert_eq!(config.max_retry_wait, 60000);
+    /// Connect to one of the fluentd servers of `config.servers` using TCP and
+    /// create a worker with tokio::spawn.
+    ///
+    /// Non-standby servers are used first. When the active server can not be
+    /// reached `failover_threshold` times in a row, the next server is used,
+    /// and the first server is probed every `failback_interval` to fail back
+    /// once it has recovered.
+    ///
+    /// With `load_balance`, a worker is created for each non-standby server and
+    /// records are distributed among them in turn. Each worker fails over to the
+    /// standby servers independently.
+    ///
+    /// With `heartbeat_interval`, servers which stop answering UDP heartbeats
+    /// are avoided before a write to them fails.
+    ///
+    /// With `selector`, the server, or the worker with `load_balance`, is
+    /// chosen by the selector instead.
+    #[cfg(feature = "tcp")]
+    pub async fn new_tcp_servers(config: &Config) -> AnyhowResult<Client> {
+        if config.servers.is_empty() {
+            return Err(anyhow::anyhow!("no servers are configured"));
+        }
+        // a server shared by several workers has a single heartbeat
+        let heartbeats: HashMap<&str, Arc<Heartbeat>> = if config.heartbeat_interval.is_zero() {
+            HashMap::new()
+        } else {
+            config
+                .servers
+                .iter()
+                .map(|server| {
+                    let heartbeat = Heartbeat::spawn(
+                        server.host.clone(),
+                        config.heartbeat_interval,
+                        config.heartbeat_timeout,
+                        config.runtime.as_ref(),
+                    );
+                    (server.host.as_str(), heartbeat)
+                })
+                .collect()
+        };
+        // and a single entry of stats
+        let stats: HashMap<&str, Arc<Mutex<ServerStats>>> = config
+            .servers
+            .iter()
+            .map(|server| {
+                let stats = ServerStats {
+                    weight: server.weight,
+                    ..Default::default()
+                };
+                (server.host.as_str(), Arc::new(Mutex::new(stats)))
+            })
+            .collect();
+        let tcp = |server: &Server| {
+            let stream_config = Monitored {
+                server: TCPConnectionConfig {
+                    host: Some(server.host.clone()),
+                    addrs: std::sync::Mutex::new(vec![]),
+                    timeout: config.timeout,
+                    proxy: config.proxy.clone(),
+                    socket: Self::socket_options(config),
+                },
+                heartbeat: heartbeats.get(server.host.as_str()).cloned(),
+                stats: stats[server.host.as_str()].clone(),
+            };
+            (stream_config, server.standby)
+        };
+        let (primaries, standbys): (Vec<&Server>, Vec<&Server>) =
+            config.servers.iter().partition(|server| !server.standby);
+        let load_balance = config.load_balance && primaries.len() > 1;
+        let balancer = load_balance.then(|| Balancer {
+            selector: config
+                .selector
+                .clone()
+                .unwrap_or_else(|| Arc::new(Mutex::new(RoundRobin::new()))),
+            servers: primaries
+                .iter()
+                .map(|primary| stats[primary.host.as_str()].clone())
+                .collect(),
+        });
+        let groups = if load_balance {
+            primaries
+                .into_iter()
+                .map(|primary| {
+                    std::iter::once(primary)
+                        .chain(standbys.iter().copied())
+                        .map(tcp)
+                        .collect()
+                })
+                .collect()
+        } else {
+            vec![config.servers.iter().map(tcp).collect()]
+        };
+        let stream_configs = groups
+            .into_iter()
+            .map(|servers| {
+                let failover = FailoverConfig::new(servers, config.failover_threshold);
+                match config.selector {
+                    Some(ref selector) if !load_balance => failover.with_selector(selector.clone()),
+                    _ => failover,
+                }
+            })
+            .collect();
+        let refresh_interval = [
+            config.dns_refresh_interval,
+            config.failback_interval,
+            config.heartbeat_interval,
+        ]
+        .into_iter()
+        .filter(|interval| !interval.is_zero())
+        .min()
+        .unwrap_or_default();
+        let mut client = Self::spawn_workers(stream_configs, config, refresh_interval).await?;
+        client.workers.balancer = balancer;
+        Ok(client)
+    }
+
+    /// Connect to the fluentd server using TLS and create a worker with tokio::spawn.
+    #[cfg(any(feature = "rustls", feature = "native-tls"))]
+    pub async fn new_tls(
+        addr: SocketAddr,
+        tls_config: crate::tls::TlsConfig,
+        config: &Config,
+    ) -> AnyhowResult<Client> {
+        let stream_config = TLSConnectionConfig {
+            addr: addr.to_owned(),
+            timeout: config.timeout,
+            tls: tls_config,
+            socket: Self::socket_options(config),
+        };
+        Self::spawn(stream_config, config, config.dns_refresh_interval).await
+    }
+
+    /// Connect to the fluentd server using unix domain socket and create a worker with tokio::spawn.
+    ///
+    /// On Linux, a path starting with '@' or a NUL byte, such as "@fluent-bit",
+    /// names a socket in the abstract namespace.
+    #[cfg(all(unix, feature = "unix"))]
+    pub async fn new_unix<P: AsRef<Path> + std::marker::Send>(
+        path: P,
+        config: &Config,
+    ) -> AnyhowResult<Client> {
+        let stream_config = UnixSocketConfig {
+            path: path.as_ref().to_path_buf(),
+            timeout: config.timeout,
+        };
+        Self::spawn(stream_config, config, config.dns_refresh_interval).await
+    }
+
+    /// Connect to the fluentd server using a Windows named pipe, such as
+    /// `\\.\pipe\fluentd`, and create a worker with tokio::spawn.
+    #[cfg(all(windows, feature = "named-pipe"))]
+    pub async fn new_named_pipe<P: AsRef<std::ffi::OsStr>>(
+        path: P,
+        config: &Config,
+    ) -> AnyhowResult<Client> {
+        let stream_config = NamedPipeConfig {
+            path: path.as_ref().to_os_string(),
+            timeout: config.timeout,
+        };
+        Self::spawn(stream_config, config, config.dns_refresh_interval).await
+    }
+
+    async fn spawn<C>(
+        stream_config: C,
+        config: &Config,
+        refresh_interval: Duration,
+    ) -> AnyhowResult<Client>
+    where
+        C: Connectable + Send + Sync + 'static,
+    {
+        Self::spawn_workers(vec![stream_config], config, refresh_interval).await
+    }
+
+    /// Create a worker for each of `stream_configs`, distributing records among them in turn.
+    async fn spawn_workers<C>(
+        stream_configs: Vec<C>,
+        config: &Config,
+        refresh_interval: Duration,
+    ) -> AnyhowResult<Client>
+    where
+        C: Connectable + Send + Sync + 'static,
+    {
+        if config.durable && config.spool_dir.is_none() {
+            return Err(anyhow::anyhow!("durable requires spool_dir"));
+        }
+        let (events, _) = channel(EVENT_CHANNEL_CAPACITY);
+        // the client is closed when every worker has exited
+        let (done_sender, done) = watch::channel(());
+        let done_sender = Arc::new(done_sender);
+        let stats = Arc::new(Recorder::default());
+        let secondary = config.secondary_path.as_ref().map(|path| {
+            let secondary =
+                Secondary::new(path, config.secondary_format, config.secondary_max_bytes);
+            Arc::new(tokio::sync::Mutex::new(secondary))
+        });
+        let budget = (config.retry_budget > 0)
+            .then(|| Arc::new(Mutex::new(RetryBudget::new(config.retry_budget))));
+
+        let mut senders = Vec::with_capacity(stream_configs.len());
+        let mut handles = Vec::with_capacity(stream_configs.len());
+        for (index, stream_config) in stream_configs.into_iter().enumerate() {
+            let (sender, receiver) = queue::channel(config.buffer_capacity, config.overflow);
+            let mut worker_config =
+                Self::worker_config(config, index, refresh_interval, stats.clone());
+            worker_config.retry.budget = budget.clone();
+            worker_config.secondary = secondary.clone();
+            let handle = Self::spawn_worker(
+                stream_config,
+                receiver,
+                events.clone(),
+                done_sender.clone(),
+                worker_config,
+                config,
+            )
+            .await?;
+            senders.push(sender);
+            handles.push(handle);
+        }
+
+        let clock = config.coarse_clock.then(CoarseClock::spawn);
+
+        Ok(Self {
+            workers: senders.into(),
+            events,
+            stats,
+            clock,
+            tags: Default::default(),
+            filter: Arc::new(TagFilter::new(
+                &config.allowed_tags,
+                &config.denied_tags,
+                config.drop_denied_tags,
+            )),
+            strict: config.strict,
+            event_time: config.event_time,
+            event_id_key: config.event_id_key.clone(),
+            bytes_encoding: config.bytes_encoding,
+            datetime_encoding: config.datetime_encoding,
+            done,
+            handles: Arc::new(Mutex::new(handles)),
+            terminate_on_drop: true,
+        })
+    }
+
+    /// Build the options of the TCP sockets of a client.
+    #[cfg(feature = "tcp")]
+    fn socket_options(config: &Config) -> SocketOptions {
+        SocketOptions {
+            nodelay: config.tcp_nodelay,
+            send_buffer_size: config.tcp_send_buffer_size,
+            recv_buffer_size: config.tcp_recv_buffer_size,
+        }
+    }
+
+    /// Build the configuration of the worker at `index` among the workers of a client.
+    fn worker_config(
+        config: &Config,
+        index: usize,
+        refresh_interval: Duration,
+        stats: Arc<Recorder>,
+    ) -> WorkerConfig {
+        WorkerConfig {
+            max_connection_lifetime: config.max_connection_lifetime,
+            ack_timeout: config.ack_timeout,
+            require_ack: config.require_ack_response,
+            retry: RetryConfig {
+                initial_wait: config.retry_wait,
+                max: config.max_retry,
+                max_wait: config.max_retry_wait,
+                jitter: config.retry_jitter,
+                forever: config.retry_forever,
+                budget: None,
+                ack_mismatch: config.ack_mismatch,
+            },
+            slow_ack: SlowAckConfig {
+                threshold: config.slow_ack_threshold,
+                window: config.slow_ack_window,
+            },
+            batch: BatchConfig {
+                mode: config.mode,
+                flush_interval: config.flush_interval,
+                max_size: config.batch_size,
+                budget: config.flush_budget,
+                adaptive: config.adaptive_batching.then_some(AdaptiveConfig {
+                    min_size: config.min_batch_size,
+                    min_flush_interval: config.min_flush_interval,
+                    target_latency: config.target_ack_latency,
+                }),
+            },
+            detect_schema_drift: config.detect_schema_drift,
+            handshake: config
+                .shared_key
+                .as_ref()
+                .map(|shared_key| HandshakeConfig {
+                    shared_key: shared_key.clone(),
+                    self_hostname: config.self_hostname.clone(),
+                    username: config.username.clone(),
+                    password: config.password.clone(),
+                }),
+            tag_rewrite_rules: config.tag_rewrite_rules.clone(),
+            refresh_interval,
+            stats,
+            #[cfg(feature = "tcp")]
+            failover_tag: config.failover_tag.clone(),
+            // each worker replays its own chunks
+            spool_dir: config
+                .spool_dir
+                .as_ref()
+                .map(|dir| dir.join(index.to_string())),
+            spool_limits: Limits {
+                max_bytes: config.spool_max_bytes,
+                max_age: config.spool_max_age,
+            },
+            spool_replay_rate: config.spool_replay_rate,
+            durable: config.durable,
+            chunk_hooks: config.chunk_hooks.clone(),
+            dead_letter: config.dead_letter.clone(),
+            secondary: None,
+        }
+    }
+
+    async fn spawn_worker<C>(
+        stream_config: C,
+        receiver: queue::Receiver,
+        events: Sender<Event>,
+        done_sender: Arc<watch::Sender<()>>,
+        worker_config: WorkerConfig,
+        config: &Config,
+    ) -> AnyhowResult<JoinHandle<()>>
+    where
+        C: Connectable + Send + Sync + 'static,
+    {
+        // create the worker --
+        // new() will try to establish an connection, so it returns error if connection,
+        // so it returns error upon connection error
+        let worker = Worker::new(stream_config, receiver, events, worker_config);
+        // the connection is established on the runtime of the worker, so that
+        // its IO is driven there
+        let mut worker = match config.runtime {
+            Some(ref runtime) => runtime.spawn(worker).await??,
+            None => worker.await?,
+        };
+        let run = async move {
+            worker.run().await;
+            drop(done_sender);
+        };
+        Ok(match config.runtime {
+            Some(ref runtime) => runtime.spawn(run),
+            None => tokio::spawn(run),
+        })
+    }
+
+    /// Return a handle which does not stop the worker when dropped.
+    ///
+    /// By default dropping a client (or any of its clones) terminates the worker.
+    /// Detached handles can be passed into short-lived tasks while a long-lived
+    /// handle keeps the worker running. `stop` still terminates the worker.
+    pub fn detach(mut self) -> Self {
+        self.terminate_on_drop = false;
+        self
+    }
+
+    /// Wait until the worker has exited, e.g. after `stop` was called.
+    pub async fn closed(&self) {
+        let mut done = self.done.clone();
+        while done.changed().await.is_ok() {}
+    }
+
+    /// Wait until the tasks of the workers have completed, e.g. after `stop`
+    /// was called, returning the error of a worker which panicked.
+    ///
+    /// Unlike `closed`, this waits for the tasks to be fully torn down. Only
+    /// the first call among the clones of a client observes the result of the
+    /// tasks, later calls wait like `closed`.
+    pub async fn join(&self) -> Result<(), JoinError> {
+        let handles = std::mem::take(&mut *self.handles.lock().unwrap());
+        if handles.is_empty() {
+            self.closed().await;
+            return Ok(());
+        }
+        let mut result = Ok(());
+        for handle in handles {
+            if let Err(e) = handle.await {
+                result = result.and(Err(e));
+            }
+        }
+        result
+    }
+
+    /// Return true if the worker has exited.
+    #[cfg(any(feature = "tcp", all(unix, feature = "unix")))]
+    pub(crate) fn is_closed(&self) -> bool {
+        self.done.has_changed().is_err()
+    }
+
+    /// Subscribe to the events emitted by the worker.
+    ///
+    /// Only events emitted after the subscription are received.
+    pub fn subscribe_events(&self) -> Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    /// Return a snapshot of the statistics collected by the workers.
+    pub fn stats(&self) -> Stats {
+        let mut stats = self.stats.snapshot();
+        stats.dropped_records = self.workers.dropped();
+        stats
+    }
+
+    /// Force the worker to drop its connection and establish a new one.
+    ///
+    /// This is useful after network changes such as VPN flaps, DNS changes or
+    /// operator-initiated failovers. Records sent before this call are written
+    /// on the old connection.
+    pub fn reconnect(&self) -> Result<(), SendError> {
+        self.workers.broadcast(Message::Reconnect)
+    }
+
+    /// Write out the records queued and batched so far, waiting up to
+    /// `timeout` for the workers to be done with them.
+    ///
+    /// Records which could not be delivered after `max_retry` attempts count
+    /// as written out, like in the normal flow. Records sent concurrently with
+    /// this call may or may not be included.
+    pub async fn flush(&self, timeout: Duration) -> Result<(), SendError> {
+        let receivers = self.workers.flush()?;
+        let flushed = async {
+            for receiver in receivers {
+                if receiver.await.is_err() {
+                    return Err(ClientError::Terminated);
+                }
+            }
+            Ok(())
+        };
+        tokio::time::timeout(timeout, flushed)
+            .await
+            .map_err(|_| ClientError::Timeout)?
+    }
+
+    /// Stop the workers once they have written out the records queued and
+    /// batched so far, waiting up to `timeout` for them to exit.
+    ///
+    /// Unlike `stop`, this waits for the outstanding records to go through
+    /// the usual retries, and returns how many of them were flushed or
+    /// dropped. Records sent concurrently with this call may or may not be
+    /// included.
+    pub async fn stop_graceful(self, timeout: Duration) -> Result<Drained, SendError> {
+        let before = self.stats();
+        self.workers.broadcast(Message::Terminate)?;
+        tokio::time::timeout(timeout, self.closed())
+            .await
+            .map_err(|_| ClientError::Timeout)?;
+        let after = self.stats();
+        Ok(Drained {
+            flushed: after.written_records - before.written_records,
+            dropped: (after.failed_records + after.dropped_records)
+                - (before.failed_records + before.dropped_records),
+            spooled: after.spooled_records - before.spooled_records,
+        })
+    }
+
+    /// Send a fluent record, waiting asynchronously while the buffer of the
+    /// worker is full instead of applying `Config::overflow`.
+    ///
+    /// This propagates backpressure to the caller without blocking a thread
+    /// or losing records, so a producer slows down to the pace of fluentd.
+    pub async fn send_async(&self, tag: &str, record: Map) -> Result<(), SendError> {
+        if !self.admit(tag, record.is_empty())? {
+            return Ok(());
+        }
+        let record = self.record_now(tag, record, Self::options());
+        self.workers.send_async(Message::Record(record)).await
+    }
+
+    /// Send a fluent record like `send_async`, waiting up to `timeout` for
+    /// room in the buffer of the worker.
+    ///
+    /// This sits between `send`, which never waits, and `Overflow::Block` or
+    /// `send_async`, which wait as long as it takes.
+    pub async fn send_timeout(
+        &self,
+        tag: &str,
+        record: Map,
+        timeout: Duration,
+    ) -> Result<(), SendTimeoutError> {
+        if !self.admit(tag, record.is_empty())? {
+            return Ok(());
+        }
+        let record = self.record_now(tag, record, Self::options());
+        match tokio::time::timeout(timeout, self.workers.send_async(Message::Record(record))).await
+        {
+            Ok(result) => result.map_err(|_| SendTimeoutError::Closed),
+            Err(_) => Err(SendTimeoutError::Timeout),
+        }
+    }
+
+    /// Send a fluent record if the buffer of the worker has room, without
+    /// ever blocking or dropping other records.
+    ///
+    /// `TrySendError::QueueFull` lets latency-sensitive callers such as
+    /// request handlers decide to drop the record rather than stall.
+    pub fn try_send(&self, tag: &str, record: Map) -> Result<(), TrySendError> {
+        if !self.admit(tag, record.is_empty())? {
+            return Ok(());
+        }
+        let record = self.record_now(tag, record, Self::options());
+        self.workers.try_send(Message::Record(record))
+    }
+
+    /// Send a fluent record with its own event time instead of the current
+    /// time, e.g. when ingesting historical logs.
+    ///
+    /// `time` may be a `chrono::DateTime` of any time zone or a
+    /// `std::time::SystemTime`, and is sent with a precision of seconds, or
+    /// of nanoseconds if `Config::event_time` is enabled.
+    pub fn send_at(
+        &self,
+        tag: &str,
+        record: Map,
+        time: impl Into<chrono::DateTime<chrono::Utc>>,
+    ) -> Result<(), SendError> {
+        let time = time.into();
+        let nanos = self.event_time.then(|| time.timestamp_subsec_nanos());
+        self.send_with_time(tag, record, time.timestamp(), nanos)
+    }
+
+    /// Send any value implementing `serde::Serialize` as a fluent record,
+    /// instead of building a `Map` by hand. The value must serialize as a
+    /// struct or a map; `None` fields are left out.
+    ///
+    /// To send a `serde_json::Value`, see `send_json` of the `json` feature.
+    ///
+    /// ## Example
+    ///
+    /// ```no_run
+    /// use serde::Serialize;
+    /// use tokio_fluent::{Client, Config};
+    ///
+    /// #[derive(Serialize)]
+    /// struct Login {
+    ///     user: String,
+    ///     success: bool,
+    /// }
+    ///
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     let client = Client::new_tcp(
+    ///         "127.0.0.1:24224".parse().unwrap(),
+    ///         &Config{..Default::default()},
+    ///     )
+    ///     .await
+    ///     .unwrap();
+    ///
+    ///     let login = Login { user: "john".to_string(), success: true };
+    ///     client.send_serde("audit.login", &login).unwrap();
+    /// }
+    /// ```
+    pub fn send_serde<T: Serialize + ?Sized>(
+        &self,
+        tag: &str,
+        record: &T,
+    ) -> Result<(), SendError> {
+        let record = Map::from_serialize(record).map_err(|e| ClientError::Encode(e.to_string()))?;
+        self.send_now(tag, record)
+    }
+
+    /// Send a JSON object as a fluent record. Other JSON types are rejected
+    /// with `ClientError::NotAnObject`. `null` fields are left out, since
+    /// fluent records have no nil value.
+    ///
+    /// ## Example
+    ///
+    /// ```no_run
+    /// use tokio_fluent::{Client, Config};
+    ///
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     let client = Client::new_tcp(
+    ///         "127.0.0.1:24224".parse().unwrap(),
+    ///         &Config{..Default::default()},
+    ///     )
+    ///     .await
+    ///     .unwrap();
+    ///
+    ///     let event = serde_json::json!({"user": "john", "success": true});
+    ///     client.send_json("audit.login", event).unwrap();
+    /// }
+    /// ```
+    #[cfg(feature = "json")]
+    pub fn send_json(&self, tag: &str, record: serde_json::Value) -> Result<(), SendError> {
+        match record {
+            serde_json::Value::Object(object) => self.send_now(tag, object.into()),
+            other => Err(ClientError::NotAnObject(crate::record::json_type(&other))),
+        }
+    }
+
+    /// Send a fluent record with its delivery behavior overridden by `options`.
+    ///
+    /// In `Mode::PackedForward`, such a record is sent on its own instead of
+    /// being batched.
+    ///
+    /// ## Example
+    ///
+    /// ```no_run
+    /// use tokio_fluent::{Client, Config, SendOptions};
+    /// use tokio_fluent::record::Map;
+    ///
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     let client = Client::new_tcp(
+    ///         "127.0.0.1:24224".parse().unwrap(),
+    ///         &Config{..Default::default()},
+    ///     )
+    ///     .await
+    ///     .unwrap();
+    ///
+    ///     let audit = SendOptions { max_retry: Some(100), ..Default::default() };
+    ///     client.send_with_options("audit.login", Map::new(), audit).unwrap();
+    ///     let metric = SendOptions { ack: false, ..Default::default() };
+    ///     client.send_with_options("metrics.cpu", Map::new(), metric).unwrap();
+    /// }
+    /// ```
+    pub fn send_with_options(
+        &self,
+        tag: &str,
+        record: Map,
+        options: SendOptions,
+    ) -> Result<(), SendError> {
+        let options = Options {
+            chunk: if options.ack {
+                Self::options().chunk
+            } else {
+                String::new()
+            },
+            max_retry: options.max_retry,
+        };
+        if !self.admit(tag, record.is_empty())? {
+            return Ok(());
+        }
+        let record = self.record_now(tag, record, options);
+        self.workers.send(Message::Record(record))
+    }
+
+    /// Send a record encoded beforehand as a msgpack map, skipping the `Map`
+    /// representation and its serialization for producers which encode
+    /// upstream.
+    ///
+    /// The bytes are written as they are, so `Config::event_id_key`,
+    /// `Config::bytes_encoding` and `Config::detect_schema_drift` do not apply
+    /// to them. Returns an error if `record` does not start with a msgpack map.
+    ///
+    /// ```no_run
+    /// use bytes::Bytes;
+    /// use tokio_fluent::{Client, Config};
+    ///
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     let client = Client::new_tcp("127.0.0.1:24224".parse().unwrap(), &Config::default())
+    ///         .await
+    ///         .unwrap();
+    ///
+    ///     // {"age": 22}
+    ///     let record = Bytes::from_static(b"\x81\xa3age\x16");
+    ///     client.send_raw("fluent.test", 1700000000, record).unwrap();
+    /// }
+    /// ```
+    pub fn send_raw(&self, tag: &str, timestamp: i64, record: Bytes) -> Result<(), SendError> {
+        let len = rmp::decode::read_map_len(&mut &record[..])
+            .map_err(|e| ClientError::Encode(format!("the record is not a msgpack map: {}", e)))?;
+        if !self.admit(tag, len == 0)? {
+            return Ok(());
+        }
+        let raw = RawRecord {
+            tag: self.tags.get(tag),
+            timestamp,
+            record,
+            options: Self::options(),
+        };
+        self.workers.send(Message::Raw(raw))
+    }
+
+    /// Send a fluent record from synchronous code, such as `spawn_blocking`,
+    /// `Drop` implementations or FFI callbacks, without a runtime handle.
+    ///
+    /// Only `Overflow::Block` waits for room in the buffer, and how it waits
+    /// depends on the caller, as for `send`:
+    /// - outside of a runtime, the thread blocks until the worker makes room;
+    /// - inside a multi-threaded runtime, the wait runs in `block_in_place`,
+    ///   so that the other tasks of the thread, the worker among them, move to
+    ///   another thread;
+    /// - inside a current-thread runtime, waiting would stall the worker, so a
+    ///   full buffer returns `ClientError::QueueFull` instead.
+    pub fn blocking_send(&self, tag: &str, record: Map) -> Result<(), SendError> {
+        self.send_now(tag, record)
+    }
+
+    fn now(&self) -> i64 {
+        match self.clock {
+            Some(ref clock) => clock.now(),
+            None => chrono::Local::now().timestamp(),
+        }
+    }
+
+    /// Send records of a tag together as a single Forward mode request.
+    ///
+    /// This cuts syscall and ack overhead compared to sending the records one by one.
+    /// Every record keeps its own timestamp, so this is also the way to import
+    /// historical data.
+    ///
+    /// ## Params:
+    /// `tag` - Event category of the records to send.
+    ///
+    /// `entries` - Pairs of a unix timestamp in seconds and a Map object.
+    pub fn send_batch(&self, tag: &str, entries: Vec<(i64, Map)>) -> Result<(), SendError> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+        self.forward(tag, entries, None)
+    }
+
+    /// Send records of a tag together like `send_batch`, returning a `Delivery`
+    /// which resolves once fluentd acknowledged the chunk of the records, or
+    /// the worker gave up on it.
+    ///
+    /// ## Example
+    ///
+    /// ```no_run
+    /// use tokio_fluent::{Client, Config};
+    /// use tokio_fluent::record::Map;
+    ///
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     let client = Client::new_tcp(
+    ///         "127.0.0.1:24224".parse().unwrap(),
+    ///         &Config{..Default::default()},
+    ///     )
+    ///     .await
+    ///     .unwrap();
+    ///
+    ///     let entries = vec![(chrono::Local::now().timestamp(), Map::new())];
+    ///     let delivery = client.send_batch_acked("fluent.test", entries).unwrap();
+    ///     if let Err(e) = delivery.wait().await {
+    ///         eprintln!("the batch was not delivered: {}", e);
+    ///     }
+    /// }
+    /// ```
+    pub fn send_batch_acked(
+        &self,
+        tag: &str,
+        entries: Vec<(i64, Map)>,
+    ) -> Result<Delivery, SendError> {
+        let (ack, receiver) = Ack::new();
+        if entries.is_empty() {
+            ack.notify_delivered();
+        } else {
+            self.forward(tag, entries, Some(ack))?;
+        }
+        Ok(Delivery { receiver })
+    }
+
+    /// Send a fluent record and wait until fluentd acknowledged it, or return
+    /// the error the worker gave up with.
+    ///
+    /// This is meant for records which must be confirmed delivered before
+    /// going on, such as audit events. The record is sent as a Forward mode
+    /// request of its own.
+    pub async fn send_confirmed(&self, tag: &str, record: Map) -> Result<(), SendError> {
+        self.send_batch_acked(tag, vec![(self.now(), record)])?
+            .wait()
+            .await
+    }
+
+    fn forward(
+        &self,
+        tag: &str,
+        entries: Vec<(i64, Map)>,
+        ack: Option<Ack>,
+    ) -> Result<(), SendError> {
+        if !self.admit(tag, entries.iter().any(|(_, record)| record.is_empty()))? {
+            // dropped records count as delivered
+            if let Some(ack) = ack {
+                ack.notify_delivered();
+            }
+            return Ok(());
+        }
+        let entries = entries
+            .into_iter()
+            .map(|(timestamp, mut record)| {
+                self.enrich(&mut record);
+                (timestamp, record)
+            })
+            .collect();
+        let forward = Forward {
+            tag: self.tags.get(tag),
+            entries,
+            options: Self::options(),
+            ack,
+        };
+        self.workers.send(Message::Forward(forward))
+    }
+
+    fn enrich(&self, record: &mut Map) {
+        if let Some(ref key) = self.event_id_key {
+            if !record.contains_key(key) {
+                record.insert(key.clone(), Uuid::new_v4().to_string().into());
+            }
+        }
+        if self.bytes_encoding == BytesEncoding::Str {
+            record.bytes_to_str_lossy();
+        }
+        if self.datetime_encoding == DateTimeEncoding::Rfc3339 {
+            record.datetimes_to_rfc3339();
+        }
+    }
+
+    fn options() -> Options {
+        Options {
+            chunk: general_purpose::STANDARD.encode(Uuid::new_v4()),
+            max_retry: None,
+        }
+    }
+
+    /// Return true if records of `tag` may be sent, or false if they are
+    /// dropped by the tag filter. In strict mode, an empty tag is rejected,
+    /// and so are the records if `empty` tells one of them is empty.
+    fn admit(&self, tag: &str, empty: bool) -> Result<bool, Rejected> {
+        if self.strict {
+            if tag.is_empty() {
+                return Err(Rejected::Invalid("the tag is empty"));
+            }
+            if empty {
+                return Err(Rejected::Invalid("the record is empty"));
+            }
+        }
+        let admitted = self.filter.admit(tag)?;
+        if !admitted {
+            self.stats.observe_denied();
+        }
+        Ok(admitted)
+    }
+
+    fn record(&self, tag: &str, mut record: Map, timestamp: i64, options: Options) -> Record {
+        self.enrich(&mut record);
+        Record {
+            tag: self.tags.get(tag),
+            record,
+            timestamp,
+            nanos: None,
+            options,
+        }
+    }
+
+    /// Build a record of the current time, with nanoseconds if
+    /// `Config::event_time` is enabled.
+    fn record_now(&self, tag: &str, record: Map, options: Options) -> Record {
+        if !self.event_time {
+            return self.record(tag, record, self.now(), options);
+        }
+        let now = chrono::Utc::now();
+        let mut record = self.record(tag, record, now.timestamp(), options);
+        record.nanos = Some(now.timestamp_subsec_nanos());
+        record
+    }
+
+    fn send_now(&self, tag: &str, record: Map) -> Result<(), SendError> {
+        if !self.admit(tag, record.is_empty())? {
+            return Ok(());
+        }
+        let record = self.record_now(tag, record, Self::options());
+        self.workers.send(Message::Record(record))
+    }
+
+    fn send_with_time(
+        &self,
+        tag: &str,
+        record: Map,
+        timestamp: i64,
+        nanos: Option<u32>,
+    ) -> Result<(), SendError> {
+        if !self.admit(tag, record.is_empty())? {
+            return Ok(());
+        }
+        let mut record = self.record(tag, record, timestamp, Self::options());
+        record.nanos = nanos;
+        self.workers.send(Message::Record(record))
+    }
+}
+
+impl FluentClient for Client {
+    /// Send a fluent record to the fluentd server.
+    ///
+    /// ## Params:
+    /// `tag` - Event category of a record to send.
+    ///
+    /// `record` - Map object to send as a fluent record.
+    fn send(&self, tag: &str, record: Map) -> Result<(), SendError> {
+        self.send_now(tag, record)
+    }
+
+    /// Stop the worker.
+    fn stop(self) -> Result<(), SendError> {
+        self.workers.broadcast(Message::Terminate)
+    }
+}
+
+/// The worker is terminated when client is dropped, unless it was detached.
+impl Drop for Client {
+    fn drop(&mut self) {
+        if self.terminate_on_drop {
+            let _ = self.workers.broadcast(Message::Terminate);
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+/// NopClient does nothing.
+pub struct NopClient;
+
+impl FluentClient for NopClient {
+    fn send(&self, _tag: &str, _record: Map) -> Result<(), SendError> {
+        Ok(())
+    }
+
+    fn stop(self) -> Result<(), SendError> {
+        Ok(())
+    }
+}
+
+/// Stopping a shared client only stops it when this is the last reference.
+impl<T: FluentClient> FluentClient for Arc<T> {
+    fn send(&self, tag: &str, record: Map) -> Result<(), SendError> {
+        (**self).send(tag, record)
+    }
+
+    fn stop(self) -> Result<(), SendError> {
+        match Arc::try_unwrap(self) {
+            Ok(client) => client.stop(),
+            Err(_) => Ok(()),
+        }
+    }
+
+    fn log(&self, level: Level, tag: &str, record: Map) -> Result<(), SendError> {
+        (**self).log(level, tag, record)
+    }
+}
+
+impl<T: FluentClient> FluentClient for Box<T> {
+    fn send(&self, tag: &str, record: Map) -> Result<(), SendError> {
+        (**self).send(tag, record)
+    }
+
+    fn stop(self) -> Result<(), SendError> {
+        (*self).stop()
+    }
+
+    fn log(&self, level: Level, tag: &str, record: Map) -> Result<(), SendError> {
+        (**self).log(level, tag, record)
+    }
+}
+
+/// A borrowed client can not be stopped, so `stop` does nothing.
+impl<T: FluentClient> FluentClient for &T {
+    fn send(&self, tag: &str, record: Map) -> Result<(), SendError> {
+        (**self).send(tag, record)
+    }
+
+    fn stop(self) -> Result<(), SendError> {
+        Ok(())
+    }
+
+    fn log(&self, level: Level, tag: &str, record: Map) -> Result<(), SendError> {
+        (**self).log(level, tag, record)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_client(sender: queue::Sender) -> Client {
+        Client {
+            workers: vec![sender].into(),
+            events: channel(EVENT_CHANNEL_CAPACITY).0,
+            stats: Default::default(),
+            clock: None,
+            tags: Default::default(),
+            filter: Default::default(),
+            strict: false,
+            event_time: false,
+            event_id_key: None,
+            bytes_encoding: BytesEncoding::Bin,
+            datetime_encoding: DateTimeEncoding::Rfc3339,
+            done: watch::channel(()).1,
+            handles: Default::default(),
+            terminate_on_drop: true,
+        }
+    }
+
+    #[test]
+    fn test_send_with_time() {
+        use std::collections::HashMap;
+
+        use chrono::TimeZone;
+
+        use crate::record::Value;
+        use crate::record_map;
+
+        let (sender, mut receiver) = queue::channel(1024, Overflow::DropOldest);
+        let client = new_client(sender);
+
+        let timestamp = chrono::Utc.timestamp_opt(1234567, 0).unwrap().timestamp();
+        let record = record_map!("age".to_string() => 20.into());
+        assert!(
+            client
+                .send_with_time("test", record, timestamp, None)
+                .is_ok(),
+            "failed to send with time"
+        );
+
+        let got = receiver.try_recv().expect("failed to receive");
+        match got {
+            Message::Record(r) => {
+                assert_eq!(&*r.tag, "test");
+                assert_eq!(r.record, record_map!("age".to_string() => 20.into()));
+                assert_eq!(r.timestamp, 1234567);
+            }
+            Message::Terminate => unreachable!("got terminate message"),
+            _ => unreachable!("got unexpected message"),
+        }
+    }
+
+    #[test]
+    fn test_send_at() {
+        use chrono::TimeZone;
+
+        let (sender, mut receiver) = queue::channel(1024, Overflow::DropOldest);
+        let client = new_client(sender);
+        let time = chrono::Utc.timestamp_opt(1234567, 0).unwrap();
+        client.send_at("test", Map::new(), time).unwrap();
+        client
+            .send_at("test", Map::new(), time.with_timezone(&chrono::Local))
+            .unwrap();
+        let time = std::time::UNIX_EPOCH + Duration::from_millis(7654321500);
+        client.send_at("test", Map::new(), time).unwrap();
+
+        let timestamps: Vec<_> = std::iter::from_fn(|| receiver.try_recv().ok())
+            .map(|message| match message {
+                Message::Record(r) => r.timestamp,
+                _ => unreachable!("got unexpected message"),
+            })
+            .collect();
+        assert_eq!(timestamps, [1234567, 1234567, 7654321]);
+    }
+
+    #[test]
+    fn test_event_time() {
+        use chrono::TimeZone;
+
+        let (sender, mut receiver) = queue::channel(1024, Overflow::DropOldest);
+        let mut client = new_client(sender);
+        let time = chrono::Utc.timestamp_opt(1234567, 500).unwrap();
+        client.send_at("test", Map::new(), time).unwrap();
+        client.event_time = true;
+        client.send_at("test", Map::new(), time).unwrap();
+        client.send("test", Map::new()).unwrap();
+
+        let nanos: Vec<_> = std::iter::from_fn(|| receiver.try_recv().ok())
+            .map(|message| match message {
+                Message::Record(r) => r.nanos,
+                _ => unreachable!("got unexpected message"),
+            })
+            .collect();
+        assert_eq!(nanos[..2], [None, Some(500)]);
+        assert!(nanos[2].is_some());
+    }
+
+    #[test]
+    fn test_send_serde() {
+        use crate::record::Value;
+
+        #[derive(Serialize)]
+        struct Login<'a> {
+            user: &'a str,
+            attempts: u32,
+            source: Option<&'a str>,
+        }
+
+        let (sender, mut receiver) = queue::channel(1024, Overflow::DropOldest);
+        let client = new_client(sender);
+        let login = Login {
+            user: "john",
+            attempts: 3,
+            source: None,
+        };
+        client.send_serde("test", &login).unwrap();
+        match receiver.try_recv().expect("failed to receive") {
+            Message::Record(r) => {
+                assert_eq!(r.record.len(), 2);
+                assert_eq!(r.record["user"], Value::from("john"));
+                assert_eq!(r.record["attempts"], Value::from(3u32));
+            }
+            _ => unreachable!("got unexpected message"),
+        }
+
+        assert!(matches!(
+            client.send_serde("test", &[1, 2, 3]),
+            Err(ClientError::Encode(_))
+        ));
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_send_json() {
+        use crate::record::Value;
+
+        let (sender, mut receiver) = queue::channel(1024, Overflow::DropOldest);
+        let client = new_client(sender);
+        let event = serde_json::json!({"user": "john", "attempts": 3, "source": null});
+        client.send_json("test", event).unwrap();
+        match receiver.try_recv().expect("failed to receive") {
+            Message::Record(r) => {
+                assert_eq!(r.record.len(), 2);
+                assert_eq!(r.record["user"], Value::from("john"));
+                assert_eq!(r.record["attempts"], Value::from(3));
+            }
+            _ => unreachable!("got unexpected message"),
+        }
+
+        assert!(matches!(
+            client.send_json("test", serde_json::json!([1, 2, 3])),
+            Err(ClientError::NotAnObject("an array"))
+        ));
+        assert!(matches!(
+            client.send_json("test", serde_json::Value::Null),
+            Err(ClientError::NotAnObject("null"))
+        ));
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_blocking_send_outside_runtime() {
+        use std::collections::HashMap;
+
+        use crate::record::Value;
+        use crate::record_map;
+
+        let (sender, mut receiver) = queue::channel(1024, Overflow::DropOldest);
+        let client = new_client(sender);
+
+        std::thread::spawn(move || {
+            let record = record_map!("age".to_string() => 20.into());
+            client.blocking_send("test", record)
+        })
+        .join()
+        .expect("thread panicked")
+        .expect("failed to send");
+
+        let got = receiver.try_recv().expect("failed to receive");
+        assert!(matches!(got, Message::Record(r) if &*r.tag == "test"));
+    }
+
+    #[tokio::test]
+    async fn test_blocking_send_on_current_thread() {
+        use std::collections::HashMap;
+
+        use crate::record::Value;
+        use crate::record_map;
+
+        let (sender, mut receiver) = queue::channel(1, Overflow::Block);
+        let client = new_client(sender);
+        let record = || record_map!("age".to_string() => 20.into());
+        client.blocking_send("test", record()).unwrap();
+        // waiting would deadlock, as the receiver runs on this thread
+        assert!(matches!(
+            client.blocking_send("test", record()),
+            Err(ClientError::QueueFull)
+        ));
+        receiver.try_recv().expect("failed to receive");
+        client.blocking_send("test", record()).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_blocking_send_on_multi_thread() {
+        use std::collections::HashMap;
+
+        use crate::record::Value;
+        use crate::record_map;
+
+        let (sender, mut receiver) = queue::channel(1, Overflow::Block);
+        let client = new_client(sender);
+        let record = || record_map!("age".to_string() => 20.into());
+        client.blocking_send("test", record()).unwrap();
+        let received = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let mut n = 0;
+            while let Some(message) = receiver.recv().await {
+                n += matches!(message, Message::Record(_)) as usize;
+            }
+            n
+        });
+        // waits for the spawned task to make room
+        client.blocking_send("test", record()).unwrap();
+        drop(client);
+        assert_eq!(received.await.unwrap(), 2);
+    }
+
+    #[test]
+    fn test_send_with_bytes_encoding() {
+        use std::collections::HashMap;
+
+        use crate::record::Value;
+        use crate::record_map;
+
+        let (sender, mut receiver) = queue::channel(1024, Overflow::DropOldest);
+        let mut client = new_client(sender);
+
+        let record = record_map!("raw".to_string() => b"a\xff\x00".as_slice().into());
+        client
+            .send_with_time("test", record.clone(), 0, None)
+            .unwrap();
+        client.bytes_encoding = BytesEncoding::Str;
+        client
+            .send_with_time("test", record.clone(), 0, None)
+            .unwrap();
+
+        // invalid UTF-8 survives the round trip through msgpack by default
+        match receiver.try_recv().expect("failed to receive") {
+            Message::Record(r) => {
+                let encoded = rmp_serde::to_vec(&r.record).unwrap();
+                let decoded: Map = rmp_serde::from_slice(&encoded).unwrap();
+                assert_eq!(decoded, record);
+            }
+            _ => unreachable!("got unexpected message"),
+        }
+        match receiver.try_recv().expect("failed to receive") {
+            Message::Record(r) => assert_eq!(r.record["raw"], "a\u{fffd}\0".into()),
+            _ => unreachable!("got unexpected message"),
+        }
+    }
+
+    #[test]
+    fn test_send_with_datetime_encoding() {
+        use std::collections::HashMap;
+
+        use chrono::TimeZone;
+
+        use crate::record::Value;
+        use crate::record_map;
+
+        let (sender, mut receiver) = queue::channel(1024, Overflow::DropOldest);
+        let mut client = new_client(sender);
+
+        let time = chrono::Utc.timestamp_opt(1700000000, 5_000_000).unwrap();
+        let record = record_map!("at".to_string() => vec![Value::from(time)].into());
+        client
+            .send_with_time("test", record.clone(), 0, None)
+            .unwrap();
+        client.datetime_encoding = DateTimeEncoding::EventTime;
+        client.send_with_time("test", record, 0, None).unwrap();
+
+        match receiver.try_recv().expect("failed to receive") {
+            Message::Record(r) => assert_eq!(
+                r.record["at"],
+                vec![Value::from("2023-11-14T22:13:20.005Z")].into()
+            ),
+            _ => unreachable!("got unexpected message"),
+        }
+        match receiver.try_recv().expect("failed to receive") {
+            Message::Record(r) => assert_eq!(r.record["at"], vec![Value::from(time)].into()),
+            _ => unreachable!("got unexpected message"),
+        }
+    }
+
+    #[test]
+    fn test_send_with_event_id() {
+        use std::collections::HashMap;
+
+        use crate::record::Value;
+        use crate::record_map;
+
+        let (sender, mut receiver) = queue::channel(1024, Overflow::DropOldest);
+        let mut client = new_client(sender);
+        client.event_id_key = Some("event_id".to_string());
+
+        let record = record_map!("age".to_string() => 20.into());
+        client.send_with_time("test", record, 0, None).unwrap();
+        let record = record_map!("event_id".to_string() => "given".into());
+        client.send_with_time("test", record, 0, None).unwrap();
+
+        match receiver.try_recv().expect("failed to receive") {
+            Message::Record(r) => match r.record["event_id"] {
+                Value::Str(ref id) => assert!(Uuid::parse_str(id).is_ok()),
+                ref v => unreachable!("unexpected event_id: {:?}", v),
+            },
+            _ => unreachable!("got unexpected message"),
+        }
+        match receiver.try_recv().expect("failed to receive") {
+            Message::Record(r) => assert_eq!(r.record["event_id"], "given".into()),
+            _ => unreachable!("got unexpected message"),
+        }
+    }
+
+    #[test]
+    fn test_send_with_level() {
+        let (sender, mut receiver) = queue::channel(1024, Overflow::DropOldest);
+        let client = new_client(sender);
+        client.warn("test", Map::new()).unwrap();
+        client.log(Level::Trace, "test", Map::new()).unwrap();
+
+        for want in ["warn", "trace"] {
+            match receiver.try_recv().expect("failed to receive") {
+                Message::Record(r) => assert_eq!(r.record[LEVEL_KEY], want.into()),
+                _ => unreachable!("got unexpected message"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_closed() {
+        let (sender, _receiver) = queue::channel(1024, Overflow::DropOldest);
+        let (done_sender, done) = watch::channel(());
+        let mut client = new_client(sender);
+        client.done = done;
+
+        let closed = tokio::spawn(async move { client.closed().await });
+        drop(done_sender);
+        tokio::time::timeout(Duration::from_secs(1), closed)
+            .await
+            .expect("closed did not return")
+            .unwrap();
+    }
+
+    #[test]
+    fn test_send_batch() {
+        use std::collections::HashMap;
+
+        use crate::record::Value;
+        use crate::record_map;
+
+        let (sender, mut receiver) = queue::channel(1024, Overflow::DropOldest);
+        let client = new_client(sender);
+        assert!(client.send_batch("test", vec![]).is_ok());
+        assert!(receiver.try_recv().is_err(), "sent an empty batch");
+
+        let entries = vec![
+            (1, record_map!("age".to_string() => 20.into())),
+            (2, record_map!("age".to_string() => 21.into())),
+        ];
+        assert!(client.send_batch("test", entries.clone()).is_ok());
+        match receiver.try_recv().expect("failed to receive") {
+            Message::Forward(f) => {
+                assert_eq!(&*f.tag, "test");
+                assert_eq!(f.entries, entries);
+            }
+            _ => unreachable!("got unexpected message"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_send_batch_acked() {
+        let (sender, mut receiver) = queue::channel(1024, Overflow::DropOldest);
+        let client = new_client(sender);
+        let delivery = client.send_batch_acked("test", vec![]).unwrap();
+        assert!(delivery.wait().await.is_ok());
+        assert!(receiver.try_recv().is_err(), "sent an empty batch");
+
+        let delivery = client
+            .send_batch_acked("test", vec![(1, Map::new())])
+            .unwrap();
+        match receiver.try_recv().expect("failed to receive") {
+            Message::Forward(f) => f.ack.expect("no ack").notify_delivered(),
+            _ => unreachable!("got unexpected message"),
+        }
+        assert!(delivery.wait().await.is_ok());
+
+        // the worker dropped the records
+        let delivery = client
+            .send_batch_acked("test", vec![(1, Map::new())])
+            .unwrap();
+        drop(receiver.try_recv().expect("failed to receive"));
+        assert!(matches!(
+            delivery.wait().await,
+            Err(ClientError::Terminated)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_send_async() {
+        let (sender, mut receiver) = queue::channel(1, Overflow::DropNewest);
+        let client = new_client(sender);
+        client.send_async("test", Map::new()).await.unwrap();
+        let pending = client.send_async("test", Map::new());
+        tokio::pin!(pending);
+        assert!(poll_once(pending.as_mut()).await.is_none());
+
+        assert!(matches!(receiver.recv().await, Some(Message::Record(_))));
+        pending.await.unwrap();
+        assert!(matches!(receiver.try_recv(), Ok(Message::Record(_))));
+        assert_eq!(client.stats().dropped_records, 0);
+    }
+
+    /// Poll a future once, returning its output if it is ready.
+    async fn poll_once<F: std::future::Future + Unpin>(future: F) -> Option<F::Output> {
+        tokio::select! {
+            biased;
+            output = future => Some(output),
+            _ = std::future::ready(()) => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_send_timeout() {
+        let (sender, mut receiver) = queue::channel(1, Overflow::DropOldest);
+        let client = new_client(sender);
+        let timeout = Duration::from_millis(10);
+        client
+            .send_timeout("test", Map::new(), timeout)
+            .await
+            .unwrap();
+        assert_eq!(
+            client
+                .send_timeout("test", Map::new(), timeout)
+                .await
+                .unwrap_err(),
+            SendTimeoutError::Timeout
+        );
+        assert!(matches!(receiver.try_recv(), Ok(Message::Record(_))));
+        assert!(
+            receiver.try_recv().is_err(),
+            "sent a record after the timeout"
+        );
+        assert_eq!(client.stats().dropped_records, 0);
+
+        drop(receiver);
+        assert_eq!(
+            client
+                .send_timeout("test", Map::new(), timeout)
+                .await
+                .unwrap_err(),
+            SendTimeoutError::Closed
+        );
+    }
+
+    #[tokio::test]
+    async fn test_flush() {
+        let (sender, mut receiver) = queue::channel(1024, Overflow::DropOldest);
+        let client = new_client(sender);
+        client.send("test", Map::new()).unwrap();
+        let worker = tokio::spawn(async move {
+            assert!(matches!(receiver.recv().await, Some(Message::Record(_))));
+            match receiver.recv().await {
+                Some(Message::Flush(ack)) => ack.notify_delivered(),
+                _ => unreachable!("got unexpected message"),
+            }
+            receiver
+        });
+        client.flush(Duration::from_secs(1)).await.unwrap();
+
+        // the worker does not answer
+        let _receiver = worker.await.unwrap();
+        assert!(matches!(
+            client.flush(Duration::from_millis(10)).await,
+            Err(ClientError::Timeout)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_stop_graceful() {
+        let (sender, mut receiver) = queue::channel(1024, Overflow::DropOldest);
+        let (done_sender, done) = watch::channel(());
+        let mut client = new_client(sender);
+        client.done = done;
+        let stats = client.stats.clone();
+        client.send("test", Map::new()).unwrap();
+        client.send("test", Map::new()).unwrap();
+        let worker = tokio::spawn(async move {
+            assert!(matches!(receiver.recv().await, Some(Message::Record(_))));
+            assert!(matches!(receiver.recv().await, Some(Message::Record(_))));
+            assert!(matches!(receiver.recv().await, Some(Message::Terminate)));
+            // the worker writes a record and gives up on the other
+            stats.observe_written(1);
+            stats.observe_failed(1);
+            drop(done_sender);
+        });
+        let drained = client.stop_graceful(Duration::from_secs(1)).await.unwrap();
+        assert_eq!(
+            drained,
+            Drained {
+                flushed: 1,
+                dropped: 1,
+                spooled: 0
+            }
+        );
+        worker.await.unwrap();
+
+        // the worker does not exit
+        let (sender, _receiver) = queue::channel(1024, Overflow::DropOldest);
+        let (_done_sender, done) = watch::channel(());
+        let mut client = new_client(sender);
+        client.done = done;
+        assert!(client
+            .stop_graceful(Duration::from_millis(10))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_join() {
+        let (sender, _receiver) = queue::channel(1024, Overflow::DropOldest);
+        let mut client = new_client(sender);
+        let finished = tokio::spawn(async {});
+        let panicked = tokio::spawn(async { panic!("worker panicked") });
+        client.handles = Arc::new(Mutex::new(vec![finished, panicked]));
+        assert!(client.join().await.unwrap_err().is_panic());
+        // the tasks were taken by the first call
+        assert!(client.join().await.is_ok());
+    }
+
+    #[test]
+    fn test_try_send() {
+        let (sender, mut receiver) = queue::channel(1, Overflow::Block);
+        let client = new_client(sender);
+        assert!(client.try_send("test", Map::new()).is_ok());
+        assert_eq!(
+            client.try_send("test", Map::new()).unwrap_err(),
+            TrySendError::QueueFull
+        );
+        assert!(matches!(receiver.try_recv(), Ok(Message::Record(_))));
+        assert!(client.try_send("test", Map::new()).is_ok());
+
+        drop(receiver);
+        assert_eq!(
+            client.try_send("test", Map::new()).unwrap_err(),
+            TrySendError::Closed
+        );
+    }
+
+    #[test]
+    fn test_send_with_options() {
+        let (sender, mut receiver) = queue::channel(1024, Overflow::DropOldest);
+        let client = new_client(sender);
+        let options = SendOptions {
+            max_retry: Some(100),
+            ..Default::default()
+        };
+        client
+            .send_with_options("test", Map::new(), options)
+            .unwrap();
+        match receiver.try_recv().expect("failed to receive") {
+            Message::Record(r) => {
+                assert!(!r.options.chunk.is_empty());
+                assert_eq!(r.options.max_retry, Some(100));
+            }
+            _ => unreachable!("got unexpected message"),
+        }
+
+        let options = SendOptions {
+            ack: false,
+            ..Default::default()
+        };
+        client
+            .send_with_options("test", Map::new(), options)
+            .unwrap();
+        match receiver.try_recv().expect("failed to receive") {
+            Message::Record(r) => {
+                assert!(r.options.chunk.is_empty());
+                assert_eq!(r.options.max_retry, None);
+            }
+            _ => unreachable!("got unexpected message"),
+        }
+    }
+
+    #[test]
+    fn test_tag_filter() {
+        let (sender, mut receiver) = queue::channel(1024, Overflow::DropOldest);
+        let mut client = new_client(sender);
+        client.filter = TagFilter::new(&["app.**".to_string()], &[], false).into();
+        assert!(client.send("app.access", Map::new()).is_ok());
+        assert!(matches!(
+            client.send("system.log", Map::new()),
+            Err(ClientError::Denied(tag)) if tag == "system.log"
+        ));
+        assert_eq!(
+            client.try_send("system.log", Map::new()).unwrap_err(),
+            TrySendError::Denied
+        );
+        assert!(matches!(receiver.try_recv(), Ok(Message::Record(r)) if &*r.tag == "app.access"));
+        assert!(receiver.try_recv().is_err());
+
+        client.filter = TagFilter::new(&[], &["system.**".to_string()], true).into();
+        assert!(client.send("system.log", Map::new()).is_ok());
+        assert!(client
+            .send_batch("system.log", vec![(0, Map::new())])
+            .is_ok());
+        assert!(receiver.try_recv().is_err());
+        assert_eq!(client.stats().denied_records, 2);
+    }
+
+    #[test]
+    fn test_strict() {
+        use std::collections::HashMap;
+
+        use crate::record::Value;
+        use crate::record_map;
+
+        let (sender, mut receiver) = queue::channel(1024, Overflow::DropOldest);
+        let mut client = new_client(sender);
+        let record = record_map!("age".to_string() => 22.into());
+        // lenient by default
+        assert!(client.send("", Map::new()).is_ok());
+        assert!(receiver.try_recv().is_ok());
+
+        client.strict = true;
+        assert!(matches!(
+            client.send("", record.clone()),
+            Err(ClientError::Invalid(_))
+        ));
+        assert!(client.send("test", Map::new()).is_err());
+        assert_eq!(
+            client.try_send("test", Map::new()).unwrap_err(),
+            TrySendError::Invalid
+        );
+        assert!(client
+            .send_batch("test", vec![(0, record.clone()), (1, Map::new())])
+            .is_err());
+        assert!(receiver.try_recv().is_err());
+        assert!(client.send("test", record).is_ok());
+        assert!(receiver.try_recv().is_ok());
+    }
+
+    #[test]
+    fn test_send_raw() {
+        let (sender, mut receiver) = queue::channel(1024, Overflow::DropOldest);
+        let mut client = new_client(sender);
+        // {"age": 22}
+        let record = Bytes::from_static(b"\x81\xa3age\x16");
+        client.send_raw("test", 1234567, record.clone()).unwrap();
+        match receiver.try_recv().expect("failed to receive") {
+            Message::Raw(r) => {
+                assert_eq!(&*r.tag, "test");
+                assert_eq!(r.timestamp, 1234567);
+                assert_eq!(r.record.as_ptr(), record.as_ptr());
+                assert!(!r.options.chunk.is_empty());
+            }
+            _ => unreachable!("got unexpected message"),
+        }
+
+        assert!(client
+            .send_raw("test", 0, Bytes::from_static(b"\xa3age"))
+            .is_err());
+        client.strict = true;
+        assert!(client
+            .send_raw("test", 0, Bytes::from_static(b"\x80"))
+            .is_err());
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_send_confirmed() {
+        let (sender, mut receiver) = queue::channel(1024, Overflow::DropOldest);
+        let client = new_client(sender);
+        let worker = tokio::spawn(async move {
+            match receiver.recv().await {
+                Some(Message::Forward(f)) => {
+                    assert_eq!(f.entries.len(), 1);
+                    f.ack.expect("no ack").notify_delivered();
+                }
+                _ => unreachable!("got unexpected message"),
+            }
+            receiver
+        });
+        client.send_confirmed("test", Map::new()).await.unwrap();
+
+        // the worker exited without delivering the record
+        drop(worker.await.unwrap());
+        assert!(client.send_confirmed("test", Map::new()).await.is_err());
+    }
+
+    #[test]
+    fn test_reconnect() {
+        let (sender, mut receiver) = queue::channel(1024, Overflow::DropOldest);
+        let client = new_client(sender);
+        assert!(client.reconnect().is_ok(), "failed to reconnect");
+
+        let got = receiver.try_recv().expect("failed to receive");
+        assert!(matches!(got, Message::Reconnect), "got unexpected message");
+    }
+
+    #[test]
+    fn test_stop() {
+        let (sender, mut receiver) = queue::channel(1024, Overflow::DropOldest);
+        let client = new_client(sender);
+        assert!(client.stop().is_ok(), "faled to stop");
+
+        let got = receiver.try_recv().expect("failed to receive");
+        match got {
+            Message::Record(_) => unreachable!("got record message"),
+            Message::Terminate => {}
+            _ => unreachable!("got unexpected message"),
+        };
+    }
+
+    #[tokio::test]
+    async fn test_spawn_logging_runtime() {
+        let runtime = spawn_logging_runtime().unwrap();
+        let name = runtime
+            .spawn(async { std::thread::current().name().map(|name| name.to_string()) })
+            .await
+            .unwrap();
+        assert_eq!(name.as_deref(), Some(LOGGING_THREAD_NAME));
+    }
+
+    #[test]
+    fn test_round_robin_workers() {
+        let (sender1, mut receiver1) = queue::channel(1024, Overflow::DropOldest);
+        let (sender2, mut receiver2) = queue::channel(1024, Overflow::DropOldest);
+        let mut client = new_client(sender1);
+        client.workers = vec![client.workers.senders[0].clone(), sender2].into();
+        // several workers are chosen by a selector
+        let balancer = client.workers.balancer.clone().unwrap();
+
+        for _ in 0..4 {
+            client.send("test", Map::new()).unwrap();
+        }
+        client.reconnect().unwrap();
+        for receiver in [&mut receiver1, &mut receiver2] {
+            assert!(matches!(receiver.try_recv(), Ok(Message::Record(_))));
+            assert!(matches!(receiver.try_recv(), Ok(Message::Record(_))));
+            assert!(matches!(receiver.try_recv(), Ok(Message::Reconnect)));
+            assert!(receiver.try_recv().is_err());
+        }
+
+        // the worker of an unhealthy server is skipped
+        balancer.servers[0].lock().unwrap().healthy = false;
+        for _ in 0..2 {
+            client.send("test", Map::new()).unwrap();
+        }
+        assert!(receiver1.try_recv().is_err());
+        assert!(matches!(receiver2.try_recv(), Ok(Message::Record(_))));
+        assert!(matches!(receiver2.try_recv(), Ok(Message::Record(_))));
+    }
+
+    #[test]
+    fn test_selected_workers() {
+        use crate::selector::Weighted;
+
+        let (sender1, mut receiver1) = queue::channel(1024, Overflow::DropOldest);
+        let (sender2, mut receiver2) = queue::channel(1024, Overflow::DropOldest);
+        let mut client = new_client(sender1);
+        client.workers = vec![client.workers.senders[0].clone(), sender2].into();
+        let servers: Vec<_> = [3, 1]
+            .into_iter()
+            .map(|weight| {
+                Arc::new(Mutex::new(ServerStats {
+                    weight,
+                    ..Default::default()
+                }))
+            })
+            .collect();
+        client.workers.balancer = Some(Balancer {
+            selector: Arc::new(Mutex::new(Weighted::new())),
+            servers: servers.clone().into(),
+        });
+
+        for _ in 0..4 {
+            client.send("test", Map::new()).unwrap();
+        }
+        let count = |receiver: &mut queue::Receiver| {
+            std::iter::from_fn(|| receiver.try_recv().ok()).count()
+        };
+        assert_eq!(count(&mut receiver1), 3);
+        assert_eq!(count(&mut receiver2), 1);
+
+        // the worker of an unhealthy server is avoided
+        servers[0].lock().unwrap().healthy = false;
+        for _ in 0..4 {
+            client.send("test", Map::new()).unwrap();
+        }
+        assert_eq!(count(&mut receiver1), 0);
+        assert_eq!(count(&mut receiver2), 4);
+    }
+
+    #[test]
+    fn test_overflow() {
+        let (sender, mut receiver) = queue::channel(1, Overflow::DropNewest);
+        let client = new_client(sender);
+        assert!(client.send("test", Map::new()).is_ok());
+        assert!(client.send("test", Map::new()).is_ok());
+        assert_eq!(client.stats().dropped_records, 1);
+        assert!(receiver.try_recv().is_ok());
+        assert!(receiver.try_recv().is_err());
+
+        let (sender, receiver) = queue::channel(1, Overflow::Error);
+        let client = new_client(sender);
+        assert!(client.send("test", Map::new()).is_ok());
+        assert!(matches!(
+            client.send("test", Map::new()),
+            Err(ClientError::QueueFull)
+        ));
+        // control messages are never refused
+        assert!(client.reconnect().is_ok());
+        drop(receiver);
+        assert!(matches!(
+            client.send("test", Map::new()),
+            Err(ClientError::QueueClosed)
+        ));
+    }
+
+    #[test]
+    fn test_smart_pointers() {
+        fn send(client: impl FluentClient) {
+            client.send("test", Map::new()).unwrap();
+            client.stop().unwrap();
+        }
+
+        let (sender, mut receiver) = queue::channel(1024, Overflow::DropOldest);
+        let client = new_client(sender).detach();
+        send(&client);
+        send(Box::new(client.clone()));
+        assert!(matches!(receiver.try_recv(), Ok(Message::Record(_))));
+        assert!(matches!(receiver.try_recv(), Ok(Message::Record(_))));
+        assert!(matches!(receiver.try_recv(), Ok(Message::Terminate)));
+
+        let shared = Arc::new(client);
+        send(shared.clone());
+        assert!(matches!(receiver.try_recv(), Ok(Message::Record(_))));
+        assert!(receiver.try_recv().is_err());
+        // the last reference stops the client
+        send(shared);
+        assert!(matches!(receiver.try_recv(), Ok(Message::Record(_))));
+        assert!(matches!(receiver.try_recv(), Ok(Message::Terminate)));
+    }
+
+    #[test]
+    fn test_client_drop_sends_terminate() {
+        let (sender, mut receiver) = queue::channel(1024, Overflow::DropOldest);
+        {
+            new_client(sender);
+        }
+        let got = receiver.try_recv().expect("failed to receive");
+        match got {
+            Message::Record(_) => unreachable!("got record message"),
+            Message::Terminate => {}
+            _ => unreachable!("got unexpected message"),
+        };
+    }
+
+    #[test]
+    fn test_detached_client_drop() {
+        let (sender, mut receiver) = queue::channel(1024, Overflow::DropOldest);
+        let client = new_client(sender);
+        {
+            let _detached = client.clone().detach();
+        }
+        assert!(
+            receiver.try_recv().is_err(),
+            "detached client sent a message"
+        );
+
+        drop(client);
+        let got = receiver.try_recv().expect("failed to receive");
+        assert!(matches!(got, Message::Terminate), "got unexpected message");
+    }
+
+    #[test]
+    fn test_default_config() {
+        let config: Config = Default::default();
+        assert_eq!(config.timeout, Duration::new(3, 0));
+        assert!(config.tcp_nodelay);
+        assert_eq!(config.tcp_send_buffer_size, 0);
+        assert_eq!(config.tcp_recv_buffer_size, 0);
+        assert_eq!(config.retry_wait, 500);
+        assert_eq!(config.max_retry, 10);
+        assert_eq!(config.max_retry_wait, 60000);
+        assert_eq!(config.retry_jitter, Jitter::None);
+        assert!(!config.retry_forever);
+        assert_eq!(config.retry_budget, 0);
+        assert_eq!(config.ack_timeout, Duration::from_secs(190));
+        assert!(config.require_ack_response);
+        assert_eq!(config.ack_mismatch, AckMismatch::Reconnect);
+        assert_eq!(config.slow_ack_threshold, Duration::from_secs(0));
+        assert_eq!(config.slow_ack_window, 10);
+        assert!(!config.coarse_clock);
+        assert!(!config.event_time);
+        assert_eq!(config.event_id_key, None);
+        assert!(!config.detect_schema_drift);
+        assert_eq!(config.mode, Mode::Message);
+        assert_eq!(config.flush_interval, Duration::from_secs(1));
+        assert_eq!(config.batch_size, 1024 * 1024);
+        assert!(!config.adaptive_batching);
+        assert_eq!(config.min_batch_size, 64 * 1024);
+        assert_eq!(config.min_flush_interval, Duration::from_millis(100));
+        assert_eq!(config.target_ack_latency, Duration::from_millis(100));
+        assert_eq!(config.flush_budget, 0);
+        assert_eq!(config.bytes_encoding, BytesEncoding::Bin);
+        assert_eq!(config.datetime_encoding, DateTimeEncoding::Rfc3339);
+        assert!(config.tag_rewrite_rules.is_empty());
+        assert!(config.allowed_tags.is_empty());
+        assert!(config.denied_tags.is_empty());
+        assert!(!config.drop_denied_tags);
+        assert!(!config.strict);
+        assert_eq!(config.dns_refresh_interval, Duration::from_secs(0));
+        assert!(config.servers.is_empty());
+        assert_eq!(config.failover_threshold, 3);
+        assert_eq!(config.failback_interval, Duration::from_secs(30));
+        assert!(!config.load_balance);
+        assert!(config.selector.is_none());
+        assert_eq!(config.heartbeat_interval, Duration::from_secs(0));
+        assert_eq!(config.heartbeat_timeout, Duration::from_secs(10));
+        assert_eq!(config.proxy, None);
+        assert_eq!(config.buffer_capacity, 1024);
+        assert_eq!(config.overflow, Overflow::DropOldest);
+        assert_eq!(config.failover_tag, None);
+        assert_eq!(config.spool_dir, None);
+        assert_eq!(config.spool_max_bytes, 0);
+        assert_eq!(config.spool_max_age, Duration::ZERO);
+        assert_eq!(config.spool_replay_rate, 0);
+        assert!(!config.durable);
+        assert!(config.chunk_hooks.is_none());
+        assert!(config.dead_letter.is_none());
+        assert_eq!(config.secondary_path, None);
+        assert_eq!(config.secondary_format, SecondaryFormat::Msgpack);
+        assert_eq!(config.secondary_max_bytes, 0);
+        assert!(config.runtime.is_none());
+    }
+
+    #[cfg(feature = "tcp")]
+    #[tokio::test]
+    async fn test_durable_requires_spool_dir() {
+        let config = Config {
+            durable: true,
+            ..Default::default()
+        };
+        let err = Client::new_tcp("127.0.0.1:1".parse().unwrap(), &config)
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "durable requires spool_dir");
+    }
+
+    #[test]
+    fn test_config_profiles() {
+        let config = Config::low_latency();
+        assert_eq!(config.mode, Mode::Message);
+        assert_eq!(config.max_retry, 3);
+        assert_eq!(config.max_retry_wait, 1000);
+
+        let config = Config::high_throughput();
+        assert_eq!(config.mode, Mode::PackedForward);
+        assert_eq!(config.batch_size, 8 * 1024 * 1024);
+        assert!(config.coarse_clock);
+
+        let config = Config::lossless();
+        assert_eq!(config.mode, Mode::PackedForward);
+        assert!(config.retry_forever);
+        assert_eq!(config.overflow, Overflow::Error);
+        assert_eq!(config.event_id_key, Some("event_id".to_string()));
     }
 }
@@ -0,0 +1,54 @@
+//! A coarse clock for very high event rates.
+
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+const REFRESH_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone)]
+/// Caches the current unix time in seconds so that reading it is a single atomic load.
+pub struct CoarseClock(Arc<AtomicI64>);
+
+impl CoarseClock {
+    /// Create a clock and spawn a task refreshing it until every clone is dropped.
+    pub fn spawn() -> Self {
+        let clock = Self(Arc::new(AtomicI64::new(Self::wall_clock())));
+        tokio::spawn(Self::refresh(Arc::downgrade(&clock.0)));
+        clock
+    }
+
+    pub fn now(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    fn wall_clock() -> i64 {
+        chrono::Utc::now().timestamp()
+    }
+
+    async fn refresh(clock: Weak<AtomicI64>) {
+        let mut interval = tokio::time::interval(REFRESH_INTERVAL);
+        loop {
+            interval.tick().await;
+            match clock.upgrade() {
+                Some(clock) => clock.store(Self::wall_clock(), Ordering::Relaxed),
+                None => break,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_coarse_clock() {
+        let clock = CoarseClock::spawn();
+        assert!((clock.now() - chrono::Utc::now().timestamp()).abs() <= 1);
+
+        clock.0.store(0, Ordering::Relaxed);
+        tokio::time::sleep(REFRESH_INTERVAL * 2).await;
+        assert!((clock.now() - chrono::Utc::now().timestamp()).abs() <= 1);
+    }
+}
@@ -0,0 +1,140 @@
+//! Records which the worker gave up on after `Config::max_retry` attempts,
+//! handed back to the application so that it can persist them itself.
+//!
+//! ## Example
+//!
+//! ```no_run
+//! use tokio::sync::mpsc;
+//! use tokio_fluent::{Client, Config};
+//!
+//! #[tokio::main]
+//! async fn main() {
+//!     let (sender, mut receiver) = mpsc::channel(1024);
+//!     let config = Config {
+//!         dead_letter: Some(sender),
+//!         ..Default::default()
+//!     };
+//!     let client = Client::new_tcp("127.0.0.1:24224".parse().unwrap(), &config)
+//!         .await
+//!         .unwrap();
+//!
+//!     while let Some(letter) = receiver.recv().await {
+//!         eprintln!("failed to deliver a record of {} at {}", letter.tag, letter.time);
+//!     }
+//! }
+//! ```
+
+use chrono::{DateTime, TimeZone, Utc};
+
+use crate::record::{Map, Value};
+
+/// A record which could not be delivered.
+#[derive(Clone, PartialEq)]
+pub struct DeadLetter {
+    /// The tag of the record, after `Config::tag_rewrite_rules`.
+    pub tag: String,
+    /// The timestamp of the record.
+    pub time: DateTime<Utc>,
+    /// The record. Null values of records sent by `Client::send_raw` are lost.
+    pub record: Map,
+}
+
+/// Values are masked so that records never leak into logs.
+impl std::fmt::Debug for DeadLetter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DeadLetter")
+            .field("tag", &self.tag)
+            .field("time", &self.time)
+            .field("record", &self.record.redacted())
+            .finish()
+    }
+}
+
+/// Decode the records of a Message, Forward or PackedForward mode message.
+pub(crate) fn decode(message: &[u8]) -> Result<Vec<DeadLetter>, String> {
+    let values: Vec<Value> = rmp_serde::from_slice(message).map_err(|e| e.to_string())?;
+    let mut values = values.into_iter();
+    let tag = match values.next() {
+        Some(Value::Str(tag)) => tag,
+        _ => return Err("expected a tag".to_string()),
+    };
+    match values.next() {
+        Some(Value::Array(entries)) => entries
+            .into_iter()
+            .map(|entry| match entry {
+                Value::Array(entry) => {
+                    let mut entry = entry.into_iter();
+                    letter(&tag, entry.next(), entry.next())
+                }
+                _ => Err("expected an entry".to_string()),
+            })
+            .collect(),
+        Some(Value::Bytes(entries)) => {
+            let mut entries = entries.as_slice();
+            let mut letters = Vec::new();
+            while !entries.is_empty() {
+                let (time, record): (Value, Value) =
+                    rmp_serde::from_read(&mut entries).map_err(|e| e.to_string())?;
+                letters.push(letter(&tag, Some(time), Some(record))?);
+            }
+            Ok(letters)
+        }
+        time => Ok(vec![letter(&tag, time, values.next())?]),
+    }
+}
+
+fn letter(tag: &str, time: Option<Value>, record: Option<Value>) -> Result<DeadLetter, String> {
+    let time = match time {
+        Some(Value::Int(seconds)) => Utc.timestamp_opt(seconds, 0).single(),
+        Some(Value::DateTime(time)) => Some(time),
+        _ => None,
+    }
+    .ok_or_else(|| "expected a time".to_string())?;
+    let Some(Value::Object(record)) = record else {
+        return Err("expected a record".to_string());
+    };
+    Ok(DeadLetter {
+        tag: tag.to_string(),
+        time,
+        record,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::record_map;
+
+    #[test]
+    fn test_decode() {
+        let record = record_map!("age".to_string() => 22.into());
+        let letter = |seconds| DeadLetter {
+            tag: "test".to_string(),
+            time: Utc.timestamp_opt(seconds, 0).unwrap(),
+            record: record.clone(),
+        };
+
+        // Message mode
+        let message = rmp_serde::to_vec(&("test", 1, &record)).unwrap();
+        assert_eq!(decode(&message).unwrap(), vec![letter(1)]);
+
+        // Forward mode
+        let message = rmp_serde::to_vec(&("test", vec![(1, &record), (2, &record)])).unwrap();
+        assert_eq!(decode(&message).unwrap(), vec![letter(1), letter(2)]);
+
+        // PackedForward mode
+        let mut entries = rmp_serde::to_vec(&(1, &record)).unwrap();
+        entries.extend(rmp_serde::to_vec(&(2, &record)).unwrap());
+        let mut message = Vec::new();
+        rmp::encode::write_array_len(&mut message, 2).unwrap();
+        rmp::encode::write_str(&mut message, "test").unwrap();
+        rmp::encode::write_bin(&mut message, &entries).unwrap();
+        assert_eq!(decode(&message).unwrap(), vec![letter(1), letter(2)]);
+
+        assert!(decode(b"\x01").is_err());
+        let message = rmp_serde::to_vec(&("test", "time", &record)).unwrap();
+        assert!(decode(&message).is_err());
+    }
+}
@@ -0,0 +1,53 @@
+//! Internal diagnostics.
+//!
+//! With the `tracing` feature, diagnostics are emitted as structured `tracing`
+//! events carrying fields such as `chunk_id`, `attempt` and `endpoint`.
+//! Otherwise they fall back to `log` records with the fields appended as
+//! `key=value` pairs.
+
+#[cfg(not(feature = "tracing"))]
+use std::fmt::{Display, Formatter, Result};
+
+/// Emit a diagnostic at the given level, e.g.
+/// `diag!(warn, endpoint = addr, attempt = i; "write failed: {}", e)`.
+macro_rules! diag {
+    ($level:ident $(, $key:ident = $value:expr)* ; $($arg:tt)+) => {{
+        #[cfg(feature = "tracing")]
+        tracing::$level!($($key = %$value,)* $($arg)+);
+        #[cfg(not(feature = "tracing"))]
+        log::$level!(
+            "{}{}",
+            format_args!($($arg)+),
+            $crate::diag::Fields(&[$((stringify!($key), &$value as &dyn std::fmt::Display)),*])
+        );
+    }};
+}
+
+pub(crate) use diag;
+
+/// The fields of a diagnostic formatted for the `log` fallback.
+#[cfg(not(feature = "tracing"))]
+pub struct Fields<'a>(pub &'a [(&'static str, &'a dyn Display)]);
+
+#[cfg(not(feature = "tracing"))]
+impl Display for Fields<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        for (key, value) in self.0 {
+            write!(f, " {}={}", key, value)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, not(feature = "tracing")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fields() {
+        let chunk = "abc";
+        let got = Fields(&[("chunk_id", &chunk), ("attempt", &2)]).to_string();
+        assert_eq!(got, " chunk_id=abc attempt=2");
+        assert_eq!(Fields(&[]).to_string(), "");
+    }
+}
@@ -0,0 +1,200 @@
+//! A poll-based client writing records straight to a stream, for custom event
+//! loops which drive their own IO.
+//!
+//! `DirectClient` bypasses the queue and the worker of `Client`: records are
+//! encoded into a reusable buffer and written when the caller polls, in the
+//! manner of a `Sink`. There are no acks, retries or reconnections, so records
+//! still buffered when the stream fails are lost.
+//!
+//! ## Example
+//!
+//! ```no_run
+//! use std::future::poll_fn;
+//!
+//! use tokio::net::TcpStream;
+//! use tokio_fluent::direct::DirectClient;
+//! use tokio_fluent::record::Map;
+//!
+//! #[tokio::main]
+//! async fn main() {
+//!     let stream = TcpStream::connect("127.0.0.1:24224").await.unwrap();
+//!     let mut client = DirectClient::new(stream);
+//!
+//!     let record = Map::new();
+//!     poll_fn(|cx| client.poll_send(cx, "fluent.test", 1700000000, &record))
+//!         .await
+//!         .unwrap();
+//!     poll_fn(|cx| client.poll_flush(cx)).await.unwrap();
+//! }
+//! ```
+
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use bytes::{Buf, BufMut, BytesMut};
+use rmp_serde::Serializer;
+use serde::Serialize;
+use tokio::io::AsyncWrite;
+
+use crate::record::Map;
+
+/// The default number of buffered bytes beyond which `poll_send` writes out
+/// the buffer before accepting another record.
+const DEFAULT_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Writes Message mode records to a stream without a worker in between.
+#[derive(Debug)]
+pub struct DirectClient<S> {
+    stream: S,
+    buf: BytesMut,
+    buffer_size: usize,
+}
+
+impl<S: AsyncWrite + Unpin> DirectClient<S> {
+    /// Create a client writing to `stream`, which must already be connected
+    /// and authenticated if the server requires a handshake.
+    pub fn new(stream: S) -> Self {
+        Self::with_buffer_size(stream, DEFAULT_BUFFER_SIZE)
+    }
+
+    /// Create a client which buffers up to `buffer_size` bytes of records
+    /// before `poll_send` waits for them to be written.
+    pub fn with_buffer_size(stream: S, buffer_size: usize) -> Self {
+        Self {
+            stream,
+            buf: BytesMut::with_capacity(buffer_size),
+            buffer_size,
+        }
+    }
+
+    /// Encode a record into the buffer, first writing out the buffer if it is
+    /// full. Returns `Poll::Pending` while the stream can not take the
+    /// buffered bytes, in which case the record is not buffered and the call
+    /// must be repeated.
+    ///
+    /// Buffered records are written by later calls or by `poll_flush`.
+    pub fn poll_send(
+        &mut self,
+        cx: &mut Context<'_>,
+        tag: &str,
+        timestamp: i64,
+        record: &Map,
+    ) -> Poll<io::Result<()>> {
+        if self.buf.len() >= self.buffer_size {
+            ready!(self.poll_write_buf(cx))?;
+        }
+        let len = self.buf.len();
+        let mut writer = std::mem::take(&mut self.buf).writer();
+        let result = (tag, timestamp, record).serialize(&mut Serializer::new(&mut writer));
+        self.buf = writer.into_inner();
+        if let Err(e) = result {
+            self.buf.truncate(len);
+            return Poll::Ready(Err(io::Error::new(io::ErrorKind::InvalidData, e)));
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    /// Write out the buffered records and flush the stream.
+    pub fn poll_flush(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_write_buf(cx))?;
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    /// Write out the buffered records and shut down the stream.
+    pub fn poll_close(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_write_buf(cx))?;
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+
+    /// Return the number of bytes waiting to be written.
+    pub fn buffered(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Return the stream, dropping the records which were not written.
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+
+    fn poll_write_buf(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.buf.is_empty() {
+            let n = ready!(Pin::new(&mut self.stream).poll_write(cx, &self.buf))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.buf.advance(n);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::future::poll_fn;
+    use std::time::Duration;
+
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+    use crate::record::Value;
+    use crate::record_map;
+
+    #[tokio::test]
+    async fn test_send_and_flush() {
+        let (stream, mut server) = tokio::io::duplex(1024);
+        let mut client = DirectClient::new(stream);
+        let record = record_map!("age".to_string() => 22.into());
+        for timestamp in [1, 2] {
+            poll_fn(|cx| client.poll_send(cx, "test", timestamp, &record))
+                .await
+                .unwrap();
+        }
+        assert!(client.buffered() > 0);
+        poll_fn(|cx| client.poll_close(cx)).await.unwrap();
+        assert_eq!(client.buffered(), 0);
+
+        let mut got = Vec::new();
+        server.read_to_end(&mut got).await.unwrap();
+        let mut want = rmp_serde::to_vec(&("test", 1, &record)).unwrap();
+        want.extend(rmp_serde::to_vec(&("test", 2, &record)).unwrap());
+        assert_eq!(got, want);
+    }
+
+    #[tokio::test]
+    async fn test_backpressure() {
+        let (stream, mut server) = tokio::io::duplex(16);
+        let mut client = DirectClient::with_buffer_size(stream, 16);
+        let record = record_map!("message".to_string() => "a".repeat(32).into());
+        // the first record is buffered, the second waits for the stream
+        poll_fn(|cx| client.poll_send(cx, "test", 1, &record))
+            .await
+            .unwrap();
+        let send = poll_fn(|cx| client.poll_send(cx, "test", 2, &record));
+        assert!(tokio::time::timeout(Duration::from_millis(10), send)
+            .await
+            .is_err());
+
+        let mut buf = vec![0; 1024];
+        let mut read = 0;
+        let sent = async {
+            poll_fn(|cx| client.poll_send(cx, "test", 2, &record))
+                .await
+                .unwrap();
+            poll_fn(|cx| client.poll_close(cx)).await.unwrap();
+        };
+        let received = async {
+            loop {
+                match server.read(&mut buf[read..]).await.unwrap() {
+                    0 => break,
+                    n => read += n,
+                }
+            }
+        };
+        tokio::join!(sent, received);
+        let mut want = rmp_serde::to_vec(&("test", 1, &record)).unwrap();
+        want.extend(rmp_serde::to_vec(&("test", 2, &record)).unwrap());
+        assert_eq!(&buf[..read], &want[..]);
+    }
+}
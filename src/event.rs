@@ -0,0 +1,121 @@
+//! Events emitted by the worker.
+//!
+//! ## Example
+//!
+//! ```no_run
+//! use tokio_fluent::{Client, Config};
+//! use tokio_fluent::event::Event;
+//!
+//! #[tokio::main]
+//! async fn main() {
+//!     let client = Client::new_tcp(
+//!         "127.0.0.1:24224".parse().unwrap(),
+//!         &Config{..Default::default()},
+//!     )
+//!     .await
+//!     .unwrap();
+//!
+//!     let mut events = client.subscribe_events();
+//!     while let Ok(event) = events.recv().await {
+//!         if let Event::SlowAck { average, .. } = event {
+//!             eprintln!("fluentd is slow to ack: {:?}", average);
+//!         }
+//!     }
+//! }
+//! ```
+
+use std::time::Duration;
+
+#[derive(Debug, Clone, PartialEq)]
+/// An event emitted by the worker.
+pub enum Event {
+    /// The ack latency exceeded `Config::slow_ack_threshold` for `chunks`
+    /// consecutive chunks.
+    SlowAck {
+        /// The number of consecutive slow chunks.
+        chunks: u32,
+        /// The average ack latency of these chunks.
+        average: Duration,
+    },
+    /// A field of a record changed its type, or a new field appeared.
+    /// Emitted only when `Config::detect_schema_drift` is enabled.
+    SchemaDrift {
+        /// The tag of the record.
+        tag: String,
+        /// The name of the top-level field.
+        field: String,
+        /// The previously seen type of the field, or None for a new field.
+        previous: Option<FieldType>,
+        /// The type of the field in this record.
+        current: FieldType,
+    },
+    /// The worker switched to another server of `Config::servers`.
+    Failover {
+        /// The endpoint of the previous server.
+        from: String,
+        /// The endpoint of the new server.
+        to: String,
+        /// Why the server changed: "unreachable", "unhealthy" or "failback".
+        reason: String,
+        /// How long the previous server was used.
+        duration: Duration,
+    },
+    /// The worker connected to the server again.
+    Connected {
+        /// The endpoint of the server.
+        endpoint: String,
+    },
+    /// The worker dropped a broken connection.
+    Disconnected {
+        /// The endpoint of the server.
+        endpoint: String,
+        /// Why the connection was dropped.
+        reason: String,
+    },
+    /// A chunk was dropped after `Config::max_retry` attempts, without being
+    /// spooled.
+    RetryExhausted {
+        /// The chunk id, or an empty string if acks are disabled.
+        chunk: String,
+        /// The tag of the records of the chunk.
+        tag: String,
+    },
+    /// The retry budget of `Config::retry_budget` is spent, so chunks fail
+    /// without being retried until it refills. Emitted once per worker until
+    /// a retry is allowed again.
+    Backoff {
+        /// The endpoint of the server.
+        endpoint: String,
+    },
+    /// A spooled chunk was replayed. Emitted after every chunk of a replay.
+    ReplayProgress {
+        /// The endpoint of the server.
+        endpoint: String,
+        /// The number of chunks replayed so far.
+        replayed: usize,
+        /// The number of chunks spooled when the replay started.
+        total: usize,
+        /// The number of bytes replayed so far.
+        bytes: u64,
+    },
+    /// Records were dropped by the overflow policy because the buffer was
+    /// full. Reported once the worker dequeues the next message.
+    RecordsDropped {
+        /// The number of records dropped since the last report.
+        n: u64,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+/// The type of a record field, as tracked by schema drift detection.
+pub enum FieldType {
+    Bool,
+    /// Both signed and unsigned integers.
+    Integer,
+    Float,
+    String,
+    Bytes,
+    DateTime,
+    Object,
+    Array,
+}
@@ -0,0 +1,133 @@
+//! Tag allowlists and denylists enforced when records are sent.
+//!
+//! Patterns follow fluentd's `<match>` syntax: `*` matches a single tag part,
+//! `**` matches zero or more tag parts, and `*` within a part matches any
+//! characters of that part.
+
+#[derive(Debug, Clone, PartialEq)]
+/// The error returned when a record is sent to a denied tag.
+pub struct TagDenied(pub String);
+
+impl std::error::Error for TagDenied {}
+
+impl std::fmt::Display for TagDenied {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "the tag is not allowed: {}", self.0)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct TagFilter {
+    allowed: Vec<Vec<String>>,
+    denied: Vec<Vec<String>>,
+    /// Drop records of denied tags instead of returning an error.
+    drop: bool,
+}
+
+impl TagFilter {
+    pub fn new(allowed: &[String], denied: &[String], drop: bool) -> Self {
+        let parse = |patterns: &[String]| {
+            patterns
+                .iter()
+                .map(|pattern| pattern.split('.').map(str::to_string).collect())
+                .collect()
+        };
+        Self {
+            allowed: parse(allowed),
+            denied: parse(denied),
+            drop,
+        }
+    }
+
+    /// Return Ok(true) if records of `tag` may be sent, Ok(false) if they are
+    /// to be dropped, or an error if they are to be rejected.
+    pub fn admit(&self, tag: &str) -> Result<bool, TagDenied> {
+        if self.allowed.is_empty() && self.denied.is_empty() {
+            return Ok(true);
+        }
+        let parts: Vec<&str> = tag.split('.').collect();
+        let matches =
+            |patterns: &[Vec<String>]| patterns.iter().any(|pattern| match_parts(pattern, &parts));
+        let allowed = (self.allowed.is_empty() || matches(&self.allowed)) && !matches(&self.denied);
+        match (allowed, self.drop) {
+            (true, _) => Ok(true),
+            (false, true) => Ok(false),
+            (false, false) => Err(TagDenied(tag.to_string())),
+        }
+    }
+}
+
+fn match_parts(pattern: &[String], tag: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => tag.is_empty(),
+        Some((part, rest)) if part == "**" => {
+            (0..=tag.len()).any(|skipped| match_parts(rest, &tag[skipped..]))
+        }
+        Some((part, rest)) => match tag.split_first() {
+            Some((first, tag)) => glob(part.as_bytes(), first.as_bytes()) && match_parts(rest, tag),
+            None => false,
+        },
+    }
+}
+
+/// Match a tag part against a pattern where `*` matches any characters.
+fn glob(pattern: &[u8], part: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => part.is_empty(),
+        Some((b'*', rest)) => (0..=part.len()).any(|skipped| glob(rest, &part[skipped..])),
+        Some((c, rest)) => part.first() == Some(c) && glob(rest, &part[1..]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, tag: &str) -> bool {
+        let pattern: Vec<String> = pattern.split('.').map(str::to_string).collect();
+        let tag: Vec<&str> = tag.split('.').collect();
+        match_parts(&pattern, &tag)
+    }
+
+    #[test]
+    fn test_match() {
+        assert!(matches("app.access", "app.access"));
+        assert!(!matches("app.access", "app.error"));
+        assert!(matches("app.*", "app.access"));
+        assert!(!matches("app.*", "app"));
+        assert!(!matches("app.*", "app.access.v1"));
+        assert!(matches("app.**", "app"));
+        assert!(matches("app.**", "app.access.v1"));
+        assert!(matches("**.audit", "audit"));
+        assert!(matches("**.audit", "team.app.audit"));
+        assert!(matches("a.**.z", "a.z"));
+        assert!(matches("a.**.z", "a.b.c.z"));
+        assert!(!matches("a.**.z", "a.b.c"));
+        assert!(matches("app-*.log", "app-api.log"));
+        assert!(!matches("app-*.log", "svc-api.log"));
+    }
+
+    #[test]
+    fn test_admit() {
+        let patterns =
+            |patterns: &[&str]| -> Vec<String> { patterns.iter().map(|p| p.to_string()).collect() };
+        let filter = TagFilter::default();
+        assert_eq!(filter.admit("anything"), Ok(true));
+
+        let filter = TagFilter::new(
+            &patterns(&["app.**"]),
+            &patterns(&["app.internal.**"]),
+            false,
+        );
+        assert_eq!(filter.admit("app.access"), Ok(true));
+        assert_eq!(
+            filter.admit("app.internal.audit"),
+            Err(TagDenied("app.internal.audit".to_string()))
+        );
+        assert!(filter.admit("system.log").is_err());
+
+        let filter = TagFilter::new(&[], &patterns(&["fluent.**"]), true);
+        assert_eq!(filter.admit("app.access"), Ok(true));
+        assert_eq!(filter.admit("fluent.info"), Ok(false));
+    }
+}
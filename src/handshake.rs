@@ -0,0 +1,226 @@
+//! Shared-key authentication of the forward protocol.
+//!
+//! When fluentd's forward input has `<security>` enabled, the server starts
+//! every connection with a HELO, the client answers with a PING carrying a
+//! digest of the shared key, and the server replies with a PONG which is
+//! verified the same way. If the server also requires user authentication,
+//! the HELO carries an auth salt and the PING adds the username and a digest
+//! of the password.
+
+use anyhow::{anyhow, bail, Result as AnyhowResult};
+use bytes::Bytes;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use sha2::{Digest, Sha512};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use uuid::Uuid;
+
+#[derive(Debug, Clone)]
+pub struct HandshakeConfig {
+    pub shared_key: String,
+    pub self_hostname: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Helo(String, HeloOptions);
+
+#[derive(Debug, Deserialize)]
+struct HeloOptions {
+    nonce: Bytes,
+    #[serde(default)]
+    auth: Bytes,
+}
+
+#[derive(Debug, Deserialize)]
+struct Pong(String, bool, String, String, String);
+
+/// Perform the HELO/PING/PONG handshake on a newly connected stream.
+pub async fn handshake<S>(stream: &mut S, config: &HandshakeConfig) -> AnyhowResult<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let Helo(kind, options) = read_message::<Helo, _>(stream).await?;
+    if kind != "HELO" {
+        bail!("expected HELO but got {}", kind);
+    }
+
+    let salt = Uuid::new_v4().simple().to_string();
+    let digest = hex_digest(&[
+        salt.as_bytes(),
+        config.self_hostname.as_bytes(),
+        &options.nonce,
+        config.shared_key.as_bytes(),
+    ]);
+    let (username, password_digest) = if options.auth.is_empty() {
+        (String::new(), String::new())
+    } else {
+        let (Some(username), Some(password)) = (&config.username, &config.password) else {
+            bail!("server requires username and password authentication");
+        };
+        let password_digest =
+            hex_digest(&[&options.auth, username.as_bytes(), password.as_bytes()]);
+        (username.clone(), password_digest)
+    };
+    let ping = (
+        "PING",
+        &config.self_hostname,
+        &salt,
+        digest,
+        username,
+        password_digest,
+    );
+    let ping = rmp_serde::to_vec(&ping)?;
+    stream.write_all(&ping).await?;
+
+    let Pong(kind, authenticated, reason, server_hostname, server_digest) =
+        read_message::<Pong, _>(stream).await?;
+    if kind != "PONG" {
+        bail!("expected PONG but got {}", kind);
+    }
+    if !authenticated {
+        bail!("authentication failed: {}", reason);
+    }
+    let expected = hex_digest(&[
+        salt.as_bytes(),
+        server_hostname.as_bytes(),
+        &options.nonce,
+        config.shared_key.as_bytes(),
+    ]);
+    if server_digest != expected {
+        bail!("shared key mismatch with server {}", server_hostname);
+    }
+    Ok(())
+}
+
+async fn read_message<T, S>(stream: &mut S) -> AnyhowResult<T>
+where
+    T: DeserializeOwned,
+    S: AsyncRead + Unpin,
+{
+    let mut buf = bytes::BytesMut::with_capacity(256);
+    loop {
+        if let Ok(message) = rmp_serde::from_slice::<T>(&buf) {
+            return Ok(message);
+        }
+        if stream.read_buf(&mut buf).await? == 0 {
+            return Err(anyhow!("connection closed during handshake"));
+        }
+    }
+}
+
+fn hex_digest(parts: &[&[u8]]) -> String {
+    let mut hasher = Sha512::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// fluentd sends the nonce as msgpack bin.
+    struct Bin<'a>(&'a [u8]);
+
+    impl serde::Serialize for Bin<'_> {
+        fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.serialize_bytes(self.0)
+        }
+    }
+
+    #[derive(Deserialize)]
+    struct Ping(String, String, String, String, String, String);
+
+    async fn serve(
+        mut stream: tokio::io::DuplexStream,
+        shared_key: &str,
+        auth: &[u8],
+        accept: bool,
+    ) -> Ping {
+        let nonce = b"\x01nonce";
+        let helo = (
+            "HELO",
+            std::collections::HashMap::from([("nonce", Bin(nonce)), ("auth", Bin(auth))]),
+        );
+        stream
+            .write_all(&rmp_serde::to_vec(&helo).unwrap())
+            .await
+            .unwrap();
+
+        let ping: Ping = read_message(&mut stream).await.unwrap();
+        let digest = hex_digest(&[ping.2.as_bytes(), b"server", nonce, shared_key.as_bytes()]);
+        let pong = ("PONG", accept, "denied", "server", digest);
+        stream
+            .write_all(&rmp_serde::to_vec(&pong).unwrap())
+            .await
+            .unwrap();
+        ping
+    }
+
+    fn config(shared_key: &str) -> HandshakeConfig {
+        HandshakeConfig {
+            shared_key: shared_key.to_string(),
+            self_hostname: "client".to_string(),
+            username: None,
+            password: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handshake() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let server = tokio::spawn(serve(server, "secret", b"", true));
+        handshake(&mut client, &config("secret")).await.unwrap();
+
+        let ping = server.await.unwrap();
+        assert_eq!(ping.0, "PING");
+        assert_eq!(ping.1, "client");
+        let digest = hex_digest(&[ping.2.as_bytes(), b"client", b"\x01nonce", b"secret"]);
+        assert_eq!(ping.3, digest);
+        assert_eq!(ping.4, "");
+        assert_eq!(ping.5, "");
+    }
+
+    #[tokio::test]
+    async fn test_handshake_rejected() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        tokio::spawn(serve(server, "secret", b"", false));
+        let err = handshake(&mut client, &config("secret")).await.unwrap_err();
+        assert_eq!(err.to_string(), "authentication failed: denied");
+
+        let (mut client, server) = tokio::io::duplex(1024);
+        tokio::spawn(serve(server, "other", b"", true));
+        assert!(handshake(&mut client, &config("secret")).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_handshake_user_auth() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let server = tokio::spawn(serve(server, "secret", b"salt", true));
+        let user_config = HandshakeConfig {
+            username: Some("alice".to_string()),
+            password: Some("pass".to_string()),
+            ..config("secret")
+        };
+        handshake(&mut client, &user_config).await.unwrap();
+
+        let ping = server.await.unwrap();
+        assert_eq!(ping.4, "alice");
+        assert_eq!(ping.5, hex_digest(&[b"salt", b"alice", b"pass"]));
+
+        let (mut client, server) = tokio::io::duplex(1024);
+        tokio::spawn(serve(server, "secret", b"salt", true));
+        let err = handshake(&mut client, &config("secret")).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "server requires username and password authentication"
+        );
+    }
+}
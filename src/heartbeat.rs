@@ -0,0 +1,136 @@
+//! UDP heartbeats to fluentd servers, like the `heartbeat_type udp` of
+//! fluentd's out_forward.
+//!
+//! in_forward answers a datagram sent to its port with a single byte, so a
+//! server which stops answering can be considered unavailable before a write
+//! to it fails.
+
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, Weak};
+
+use tokio::net::UdpSocket;
+use tokio::runtime::Handle;
+use tokio::time::{timeout, Duration, Instant, MissedTickBehavior};
+
+/// The health of a server as observed by heartbeats.
+#[derive(Debug)]
+pub(crate) struct Heartbeat {
+    /// The "hostname:port" of the server.
+    host: String,
+    /// The time the server last answered, or the time the heartbeat started.
+    last_seen: Mutex<Instant>,
+    /// The time without an answer after which the server is unavailable.
+    timeout: Duration,
+}
+
+impl Heartbeat {
+    /// Start sending heartbeats to `host` every `interval`, on `runtime` if given.
+    /// The heartbeats stop when the returned Heartbeat is dropped.
+    pub(crate) fn spawn(
+        host: String,
+        interval: Duration,
+        timeout: Duration,
+        runtime: Option<&Handle>,
+    ) -> Arc<Self> {
+        let heartbeat = Arc::new(Self {
+            host,
+            last_seen: Mutex::new(Instant::now()),
+            timeout,
+        });
+        let run = Self::run(Arc::downgrade(&heartbeat), interval);
+        match runtime {
+            Some(runtime) => runtime.spawn(run),
+            None => tokio::spawn(run),
+        };
+        heartbeat
+    }
+
+    /// Return true if the server answered within the timeout.
+    pub(crate) fn is_alive(&self) -> bool {
+        self.last_seen.lock().unwrap().elapsed() < self.timeout
+    }
+
+    async fn run(heartbeat: Weak<Self>, interval: Duration) {
+        let mut ticker = tokio::time::interval(interval.max(Duration::from_millis(1)));
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            let Some(heartbeat) = heartbeat.upgrade() else {
+                return;
+            };
+            if heartbeat.beat(interval).await {
+                *heartbeat.last_seen.lock().unwrap() = Instant::now();
+            }
+        }
+    }
+
+    /// Send a heartbeat, returning true if the server answered within `wait`.
+    async fn beat(&self, wait: Duration) -> bool {
+        let addr = match timeout(wait, tokio::net::lookup_host(&self.host)).await {
+            Ok(Ok(mut addrs)) => addrs.next(),
+            _ => None,
+        };
+        match addr {
+            Some(addr) => timeout(wait, ping(addr)).await.is_ok_and(|r| r.is_ok()),
+            None => false,
+        }
+    }
+}
+
+async fn ping(addr: SocketAddr) -> std::io::Result<()> {
+    let local: SocketAddr = if addr.is_ipv4() {
+        ([0, 0, 0, 0], 0).into()
+    } else {
+        ([0u16; 8], 0).into()
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(addr).await?;
+    socket.send(&[0]).await?;
+    socket.recv(&mut [0; 16]).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_heartbeat() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let host = server.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let mut buf = [0; 16];
+            while let Ok((_, peer)) = server.recv_from(&mut buf).await {
+                let _ = server.send_to(&[0], peer).await;
+            }
+        });
+
+        let heartbeat = Heartbeat::spawn(
+            host,
+            Duration::from_millis(10),
+            Duration::from_millis(100),
+            None,
+        );
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(heartbeat.is_alive());
+    }
+
+    #[tokio::test]
+    async fn test_heartbeat_timeout() {
+        // nothing answers on the address of a dropped socket
+        let host = {
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            socket.local_addr().unwrap().to_string()
+        };
+
+        let heartbeat = Heartbeat::spawn(
+            host,
+            Duration::from_millis(10),
+            Duration::from_millis(100),
+            None,
+        );
+        assert!(heartbeat.is_alive());
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(!heartbeat.is_alive());
+    }
+}
@@ -0,0 +1,102 @@
+//! Callbacks on the lifecycle of the chunks written by the worker, for
+//! integrations with external durability layers.
+//!
+//! ## Example
+//!
+//! ```no_run
+//! use std::sync::Arc;
+//!
+//! use tokio_fluent::hooks::{ChunkHooks, ChunkInfo};
+//! use tokio_fluent::{Client, Config};
+//!
+//! #[derive(Debug)]
+//! struct CommitOnAck;
+//!
+//! impl ChunkHooks for CommitOnAck {
+//!     fn acked(&self, chunk: &ChunkInfo) {
+//!         println!("fluentd acked {} ({} bytes of {})", chunk.id, chunk.size, chunk.tag);
+//!     }
+//! }
+//!
+//! #[tokio::main]
+//! async fn main() {
+//!     let config = Config {
+//!         chunk_hooks: Some(Arc::new(CommitOnAck)),
+//!         ..Default::default()
+//!     };
+//!     let client = Client::new_tcp("127.0.0.1:24224".parse().unwrap(), &config)
+//!         .await
+//!         .unwrap();
+//! }
+//! ```
+
+/// A chunk written by the worker.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChunkInfo {
+    /// The chunk id of the ack, or empty if no ack was requested.
+    pub id: String,
+    /// The tag of the records in the chunk, after `Config::tag_rewrite_rules`.
+    pub tag: String,
+    /// The size of the encoded message in bytes.
+    pub size: usize,
+}
+
+impl ChunkInfo {
+    pub(crate) fn new(id: &str, message: &[u8]) -> Self {
+        Self {
+            id: id.to_string(),
+            tag: tag_of(message).unwrap_or_default(),
+            size: message.len(),
+        }
+    }
+}
+
+/// Callbacks on the chunks of a worker. They are called on the task of the
+/// worker, so they must return quickly and never block.
+///
+/// Every method does nothing by default.
+pub trait ChunkHooks: std::fmt::Debug + Send + Sync {
+    /// A chunk is about to be written for the first time. Chunks split
+    /// because the server rejected them as too large are created anew.
+    fn created(&self, _chunk: &ChunkInfo) {}
+
+    /// fluentd acked a chunk, or the chunk was written if it requested no
+    /// ack. Spooled chunks are acked once they are replayed, possibly by the
+    /// next process.
+    fn acked(&self, _chunk: &ChunkInfo) {}
+
+    /// A chunk was dropped, e.g. after `Config::max_retry` attempts without
+    /// `Config::spool_dir`. Chunks removed by the limits of the spool are not
+    /// reported.
+    fn abandoned(&self, _chunk: &ChunkInfo, _reason: &str) {}
+}
+
+/// Read the tag at the head of a forward protocol message, which is an array
+/// starting with the tag in every mode.
+fn tag_of(mut message: &[u8]) -> Option<String> {
+    rmp::decode::read_array_len(&mut message).ok()?;
+    let len = rmp::decode::read_str_len(&mut message).ok()? as usize;
+    let tag = message.get(..len)?;
+    String::from_utf8(tag.to_vec()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::record::Map;
+
+    #[test]
+    fn test_chunk_info() {
+        let message = rmp_serde::to_vec(&("app.access", 1, Map::new())).unwrap();
+        let chunk = ChunkInfo::new("chunk-id", &message);
+        assert_eq!(
+            chunk,
+            ChunkInfo {
+                id: "chunk-id".to_string(),
+                tag: "app.access".to_string(),
+                size: message.len(),
+            }
+        );
+        assert_eq!(ChunkInfo::new("", b"\x01").tag, "");
+    }
+}
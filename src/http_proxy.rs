@@ -0,0 +1,108 @@
+//! Tunneling through an HTTP proxy with the CONNECT method (RFC 9110).
+
+use anyhow::Result as AnyhowResult;
+use base64::{engine::general_purpose, Engine};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::socks::Target;
+
+/// The maximum size in bytes of the response header of the proxy.
+const MAX_RESPONSE_SIZE: usize = 8 * 1024;
+
+/// Ask the proxy on `stream` to open a tunnel to `target`, authenticating with
+/// `credentials` using the Basic scheme if given.
+pub async fn connect<S>(
+    stream: &mut S,
+    target: Target<'_>,
+    credentials: Option<(&str, &str)>,
+) -> AnyhowResult<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n", target, target);
+    if let Some((username, password)) = credentials {
+        let token = general_purpose::STANDARD.encode(format!("{}:{}", username, password));
+        request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", token));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    let response = read_response_header(stream).await?;
+    let status = response
+        .lines()
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .ok_or_else(|| anyhow::anyhow!("invalid response from the proxy"))?;
+    if !status.starts_with('2') {
+        let line = response.lines().next().unwrap_or_default();
+        return Err(anyhow::anyhow!("the proxy refused to connect: {}", line));
+    }
+    Ok(())
+}
+
+/// Read the response header byte by byte, so that no byte of the tunnel is consumed.
+async fn read_response_header<S>(stream: &mut S) -> AnyhowResult<String>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut header = Vec::new();
+    while !header.ends_with(b"\r\n\r\n") {
+        if header.len() >= MAX_RESPONSE_SIZE {
+            return Err(anyhow::anyhow!(
+                "the response of the proxy exceeds {} bytes",
+                MAX_RESPONSE_SIZE
+            ));
+        }
+        header.push(stream.read_u8().await?);
+    }
+    Ok(String::from_utf8_lossy(&header).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_connect() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let proxy = tokio::spawn(async move {
+            let want = "CONNECT fluentd:24224 HTTP/1.1\r\nHost: fluentd:24224\r\nProxy-Authorization: Basic dXNlcjpwYXNz\r\n\r\n";
+            let mut request = vec![0; want.len()];
+            server.read_exact(&mut request).await.unwrap();
+            assert_eq!(String::from_utf8(request).unwrap(), want);
+            server
+                .write_all(b"HTTP/1.1 200 Connection established\r\n\r\nping")
+                .await
+                .unwrap();
+        });
+
+        connect(
+            &mut client,
+            Target::Host("fluentd", 24224),
+            Some(("user", "pass")),
+        )
+        .await
+        .unwrap();
+        let mut payload = [0; 4];
+        client.read_exact(&mut payload).await.unwrap();
+        assert_eq!(&payload, b"ping");
+        proxy.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_connect_refused() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        tokio::spawn(async move {
+            let mut buf = [0; 256];
+            let _ = server.read(&mut buf).await.unwrap();
+            server
+                .write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n")
+                .await
+                .unwrap();
+        });
+
+        let target = Target::Addr("[::1]:24224".parse().unwrap());
+        let err = connect(&mut client, target, None).await.unwrap_err();
+        assert!(err.to_string().contains("407"));
+    }
+}
@@ -2,7 +2,7 @@
 //!
 //! ## Example
 //!
-//! ```
+//! ```no_run
 //! use std::collections::HashMap;
 //!
 //! use tokio_fluent::record_map;
@@ -31,8 +31,61 @@
 //! }
 //! ```
 
+// Without any transport the worker can not be spawned.
+#![cfg_attr(
+    not(any(
+        feature = "tcp",
+        all(unix, feature = "unix"),
+        all(windows, feature = "named-pipe")
+    )),
+    allow(dead_code)
+)]
+
+#[cfg(all(unix, feature = "unix"))]
+pub mod agent;
 pub mod client;
+mod clock;
+pub mod dead_letter;
+mod diag;
+pub mod direct;
+pub mod event;
+mod filter;
+mod handshake;
+#[cfg(feature = "tcp")]
+mod heartbeat;
+pub mod hooks;
+#[cfg(feature = "tcp")]
+mod http_proxy;
+#[cfg(feature = "loadgen")]
+pub mod loadgen;
+#[cfg(any(feature = "tcp", all(unix, feature = "unix")))]
+pub mod logger;
+pub mod protocol;
+mod queue;
 pub mod record;
+#[cfg(any(feature = "tcp", all(unix, feature = "unix")))]
+mod registry;
+mod rewrite;
+mod schema;
+mod secondary;
+pub mod selector;
+#[cfg(feature = "signal")]
+pub mod shutdown;
+#[cfg(feature = "tcp")]
+mod socks;
+mod spool;
+pub mod stats;
+mod tag;
+#[cfg(any(feature = "rustls", feature = "native-tls"))]
+pub mod tls;
+mod transport;
 mod worker;
 
-pub use client::{Client, Config, FluentClient};
+pub use client::{
+    AckMismatch, BytesEncoding, Client, ClientError, Config, DateTimeEncoding, Delivery, Drained,
+    FluentClient, Jitter, Level, Mode, Overflow, Proxy, SecondaryFormat, SendOptions,
+    SendTimeoutError, Server, TrySendError, WorkerError,
+};
+#[cfg(any(feature = "tcp", all(unix, feature = "unix")))]
+pub use logger::init_from_env;
+pub use rewrite::TagRewriteRule;
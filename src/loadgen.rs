@@ -0,0 +1,256 @@
+//! Synthetic load generation for capacity testing a fluentd pipeline.
+//!
+//! Records are generated at a fixed rate with a configurable size
+//! distribution and tag cardinality, and sent with any [`FluentClient`].
+//!
+//! ## Example
+//!
+//! ```no_run
+//! use std::time::Duration;
+//!
+//! use tokio_fluent::{Client, Config};
+//! use tokio_fluent::loadgen::{self, LoadConfig, SizeDistribution};
+//!
+//! #[tokio::main]
+//! async fn main() {
+//!     let client = Client::new_tcp(
+//!         "127.0.0.1:24224".parse().unwrap(),
+//!         &Config::high_throughput(),
+//!     )
+//!     .await
+//!     .unwrap();
+//!
+//!     let report = loadgen::run(&client, &LoadConfig {
+//!         rate: 50_000,
+//!         duration: Duration::from_secs(60),
+//!         sizes: SizeDistribution::Exponential { mean: 512 },
+//!         tags: 20,
+//!         ..Default::default()
+//!     })
+//!     .await;
+//!     println!("{:?}", report);
+//! }
+//! ```
+
+use std::time::Duration;
+
+use tokio::time::{Instant, MissedTickBehavior};
+
+use crate::client::FluentClient;
+use crate::record::Map;
+
+/// The interval at which records are generated.
+const TICK: Duration = Duration::from_millis(10);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// The distribution of the sizes in bytes of the `message` field of records.
+pub enum SizeDistribution {
+    /// Every record has the same size.
+    Fixed(usize),
+    /// Sizes uniformly distributed in `min..=max`.
+    Uniform { min: usize, max: usize },
+    /// Exponentially distributed sizes: mostly small records with occasional
+    /// large ones, like typical application logs.
+    Exponential { mean: usize },
+}
+
+#[derive(Debug, Clone)]
+/// Config for a load generation run.
+pub struct LoadConfig {
+    /// The number of records per second. The default is 1000.
+    pub rate: u64,
+    /// How long records are generated. The default is 10 seconds.
+    pub duration: Duration,
+    /// The sizes of the records. The default is `SizeDistribution::Fixed(256)`.
+    pub sizes: SizeDistribution,
+    /// The number of distinct tags, used in turn. The default is 1.
+    pub tags: usize,
+    /// The prefix of the tags, followed by a dot and the tag number.
+    /// The default is "loadgen".
+    pub tag_prefix: String,
+    /// The seed of the random sizes, so that runs can be reproduced.
+    /// The default is 1.
+    pub seed: u64,
+}
+
+impl Default for LoadConfig {
+    fn default() -> Self {
+        Self {
+            rate: 1000,
+            duration: Duration::from_secs(10),
+            sizes: SizeDistribution::Fixed(256),
+            tags: 1,
+            tag_prefix: "loadgen".to_string(),
+            seed: 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+/// The result of a load generation run.
+pub struct Report {
+    /// The number of records handed to the client.
+    pub sent: u64,
+    /// The number of records the client refused.
+    pub failed: u64,
+    /// The total size in bytes of the `message` fields of the sent records.
+    pub bytes: u64,
+    /// The duration of the run.
+    pub elapsed: Duration,
+}
+
+/// Generate records as configured and send them with `client`.
+///
+/// Only the rate of handing records to the client is controlled; whether the
+/// pipeline keeps up is observed on the fluentd side or with `Client::stats`.
+pub async fn run<C: FluentClient>(client: &C, config: &LoadConfig) -> Report {
+    let mut generator = Generator::new(config);
+    let mut report = Report::default();
+    let start = Instant::now();
+    let mut ticker = tokio::time::interval(TICK);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    let total = (config.rate as f64 * config.duration.as_secs_f64()) as u64;
+    while report.sent + report.failed < total {
+        ticker.tick().await;
+        // catch up with the records due by now, so that slow sends do not lower the rate
+        let due = ((config.rate as f64 * start.elapsed().as_secs_f64()) as u64).min(total);
+        while report.sent + report.failed < due {
+            let (tag, record, size) = generator.next();
+            match client.send(&tag, record) {
+                Ok(()) => {
+                    report.sent += 1;
+                    report.bytes += size as u64;
+                }
+                Err(_) => report.failed += 1,
+            }
+        }
+    }
+    report.elapsed = start.elapsed();
+    report
+}
+
+/// Generates the records of a run.
+struct Generator<'a> {
+    config: &'a LoadConfig,
+    random: XorShift,
+    seq: u64,
+}
+
+impl<'a> Generator<'a> {
+    fn new(config: &'a LoadConfig) -> Self {
+        Self {
+            config,
+            random: XorShift(config.seed.max(1)),
+            seq: 0,
+        }
+    }
+
+    /// Return the tag, the record and the size of its message.
+    fn next(&mut self) -> (String, Map, usize) {
+        let tag = format!(
+            "{}.{}",
+            self.config.tag_prefix,
+            self.seq % self.config.tags.max(1) as u64
+        );
+        let size = self.size();
+        let mut record = Map::new();
+        record.insert("message".to_string(), "x".repeat(size).into());
+        record.insert("seq".to_string(), self.seq.into());
+        self.seq += 1;
+        (tag, record, size)
+    }
+
+    fn size(&mut self) -> usize {
+        match self.config.sizes {
+            SizeDistribution::Fixed(size) => size,
+            SizeDistribution::Uniform { min, max } if max > min => {
+                min + (self.random.next() % (max - min + 1) as u64) as usize
+            }
+            SizeDistribution::Uniform { min, .. } => min,
+            SizeDistribution::Exponential { mean } => {
+                // inverse transform sampling; 1 - uniform is in (0, 1]
+                let uniform = (self.random.next() >> 11) as f64 / (1u64 << 53) as f64;
+                (-(mean as f64) * (1.0 - uniform).ln()) as usize
+            }
+        }
+    }
+}
+
+/// A small xorshift64 generator. The sizes need not be cryptographically random.
+struct XorShift(u64);
+
+impl XorShift {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::client::SendError;
+
+    /// A client recording the tags of the sent records.
+    #[derive(Default)]
+    struct Recorder {
+        tags: Mutex<Vec<String>>,
+    }
+
+    impl FluentClient for Recorder {
+        fn send(&self, tag: &str, _record: Map) -> Result<(), SendError> {
+            self.tags.lock().unwrap().push(tag.to_string());
+            Ok(())
+        }
+
+        fn stop(self) -> Result<(), SendError> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_run() {
+        let client = Recorder::default();
+        let config = LoadConfig {
+            rate: 1000,
+            duration: Duration::from_millis(100),
+            sizes: SizeDistribution::Fixed(10),
+            tags: 3,
+            ..Default::default()
+        };
+        let report = run(&client, &config).await;
+        assert_eq!(report.sent, 100);
+        assert_eq!(report.failed, 0);
+        assert_eq!(report.bytes, 1000);
+        assert!(report.elapsed >= Duration::from_millis(90));
+
+        let tags = client.tags.lock().unwrap();
+        assert_eq!(
+            tags[..4],
+            ["loadgen.0", "loadgen.1", "loadgen.2", "loadgen.0"]
+        );
+    }
+
+    #[test]
+    fn test_sizes() {
+        let config = LoadConfig {
+            sizes: SizeDistribution::Uniform { min: 10, max: 20 },
+            ..Default::default()
+        };
+        let mut generator = Generator::new(&config);
+        assert!((0..1000).all(|_| (10..=20).contains(&generator.size())));
+
+        let config = LoadConfig {
+            sizes: SizeDistribution::Exponential { mean: 100 },
+            ..Default::default()
+        };
+        let mut generator = Generator::new(&config);
+        let mean = (0..10000).map(|_| generator.size()).sum::<usize>() / 10000;
+        assert!((80..120).contains(&mean), "mean: {}", mean);
+    }
+}
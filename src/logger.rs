@@ -0,0 +1,217 @@
+//! A `log` logger sending records to fluentd, configured from the environment.
+//!
+//! ## Example
+//!
+//! ```no_run
+//! use std::time::Duration;
+//!
+//! #[tokio::main]
+//! async fn main() {
+//!     let guard = tokio_fluent::init_from_env().await.unwrap();
+//!
+//!     log::info!("started");
+//!
+//!     guard.shutdown(Duration::from_secs(5)).await.unwrap();
+//! }
+//! ```
+//!
+//! ## Environment variables
+//!
+//! | Variable            | Default                  | Description |
+//! |---------------------|--------------------------|-------------|
+//! | `FLUENT_ENDPOINT`   | `tcp://127.0.0.1:24224`  | `tcp://<ip>:<port>` or `unix://<path>`, see `Client::get_or_init`. |
+//! | `FLUENT_TAG`        | `app`                    | The tag of the records. |
+//! | `FLUENT_LEVEL`      | `info`                   | The most verbose level sent, e.g. `debug`. |
+//! | `FLUENT_MODE`       | `message`                | `message` or `packed_forward`. |
+//! | `FLUENT_SHARED_KEY` |                          | The shared key of the handshake, if any. |
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result as AnyhowResult;
+
+use crate::client::{Client, Config, FluentClient, Level, Mode};
+use crate::record::Map;
+
+const DEFAULT_ENDPOINT: &str = "tcp://127.0.0.1:24224";
+const DEFAULT_TAG: &str = "app";
+
+/// Forwards `log` records to a client. Records of this crate are not
+/// forwarded, so that failing to send never feeds back into the client.
+#[derive(Debug)]
+pub struct Logger {
+    client: Arc<Client>,
+    tag: String,
+    level: log::LevelFilter,
+}
+
+impl Logger {
+    pub fn new(client: Arc<Client>, tag: &str, level: log::LevelFilter) -> Self {
+        Self {
+            client,
+            tag: tag.to_string(),
+            level,
+        }
+    }
+}
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= self.level && !metadata.target().starts_with("tokio_fluent")
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let mut map = Map::new();
+        map.insert("message".to_string(), record.args().to_string().into());
+        map.insert("target".to_string(), record.target().into());
+        if let Some(module) = record.module_path() {
+            map.insert("module".to_string(), module.into());
+        }
+        if let (Some(file), Some(line)) = (record.file(), record.line()) {
+            map.insert("file".to_string(), format!("{}:{}", file, line).into());
+        }
+        let _ = self.client.log(level(record.level()), &self.tag, map);
+    }
+
+    /// The worker flushes on its own; see `Guard::shutdown` to wait for it.
+    fn flush(&self) {}
+}
+
+fn level(level: log::Level) -> Level {
+    match level {
+        log::Level::Trace => Level::Trace,
+        log::Level::Debug => Level::Debug,
+        log::Level::Info => Level::Info,
+        log::Level::Warn => Level::Warn,
+        log::Level::Error => Level::Error,
+    }
+}
+
+/// Stops the client of the logger when dropped, or when `shutdown` is called
+/// to wait for the queued records to be written.
+#[derive(Debug)]
+#[must_use = "the client is stopped when the guard is dropped"]
+pub struct Guard {
+    client: Option<Arc<Client>>,
+}
+
+impl Guard {
+    /// Stop the client and wait up to `timeout` for the worker to write out
+    /// the queued records.
+    pub async fn shutdown(mut self, timeout: Duration) -> AnyhowResult<()> {
+        let Some(client) = self.client.take() else {
+            return Ok(());
+        };
+        Client::clone(&client).stop_graceful(timeout).await?;
+        Ok(())
+    }
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        if let Some(client) = self.client.take() {
+            let _ = Client::clone(&client).stop();
+        }
+    }
+}
+
+/// The settings read from the environment.
+#[derive(Debug, PartialEq)]
+struct Settings {
+    endpoint: String,
+    tag: String,
+    level: log::LevelFilter,
+    mode: Mode,
+    shared_key: Option<String>,
+}
+
+impl Settings {
+    fn from_env<F>(var: F) -> AnyhowResult<Self>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let level = match var("FLUENT_LEVEL") {
+            Some(level) => level
+                .parse()
+                .map_err(|_| anyhow::anyhow!("invalid FLUENT_LEVEL: {}", level))?,
+            None => log::LevelFilter::Info,
+        };
+        let mode = match var("FLUENT_MODE").as_deref() {
+            None | Some("message") => Mode::Message,
+            Some("packed_forward") => Mode::PackedForward,
+            Some(mode) => return Err(anyhow::anyhow!("invalid FLUENT_MODE: {}", mode)),
+        };
+        Ok(Self {
+            endpoint: var("FLUENT_ENDPOINT").unwrap_or_else(|| DEFAULT_ENDPOINT.to_string()),
+            tag: var("FLUENT_TAG").unwrap_or_else(|| DEFAULT_TAG.to_string()),
+            level,
+            mode,
+            shared_key: var("FLUENT_SHARED_KEY"),
+        })
+    }
+}
+
+/// Create a client from the `FLUENT_*` environment variables and install a
+/// `Logger` forwarding `log` records to it as the global logger.
+///
+/// The returned guard stops the client; call `Guard::shutdown` before the
+/// process exits to flush the queued records.
+pub async fn init_from_env() -> AnyhowResult<Guard> {
+    let settings = Settings::from_env(|key| std::env::var(key).ok())?;
+    let config = Config {
+        mode: settings.mode,
+        shared_key: settings.shared_key,
+        ..Default::default()
+    };
+    let client = Client::get_or_init(&settings.endpoint, &config).await?;
+
+    let logger = Logger::new(client.clone(), &settings.tag, settings.level);
+    log::set_logger(Box::leak(Box::new(logger)))
+        .map_err(|_| anyhow::anyhow!("a global logger is already installed"))?;
+    log::set_max_level(settings.level);
+    Ok(Guard {
+        client: Some(client),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    #[test]
+    fn test_settings_from_env() {
+        let settings = Settings::from_env(|_| None).unwrap();
+        assert_eq!(
+            settings,
+            Settings {
+                endpoint: DEFAULT_ENDPOINT.to_string(),
+                tag: DEFAULT_TAG.to_string(),
+                level: log::LevelFilter::Info,
+                mode: Mode::Message,
+                shared_key: None,
+            }
+        );
+
+        let vars = HashMap::from([
+            ("FLUENT_ENDPOINT", "unix:///var/run/fluentd.sock"),
+            ("FLUENT_TAG", "api"),
+            ("FLUENT_LEVEL", "debug"),
+            ("FLUENT_MODE", "packed_forward"),
+            ("FLUENT_SHARED_KEY", "secret"),
+        ]);
+        let settings = Settings::from_env(|key| vars.get(key).map(|v| v.to_string())).unwrap();
+        assert_eq!(settings.endpoint, "unix:///var/run/fluentd.sock");
+        assert_eq!(settings.tag, "api");
+        assert_eq!(settings.level, log::LevelFilter::Debug);
+        assert_eq!(settings.mode, Mode::PackedForward);
+        assert_eq!(settings.shared_key.as_deref(), Some("secret"));
+
+        assert!(Settings::from_env(|key| (key == "FLUENT_LEVEL").then(|| "loud".into())).is_err());
+        assert!(Settings::from_env(|key| (key == "FLUENT_MODE").then(|| "fast".into())).is_err());
+    }
+}
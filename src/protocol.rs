@@ -0,0 +1,202 @@
+//! Assembly of forward protocol frames around records encoded beforehand.
+//!
+//! Records which arrive msgpack-encoded from upstream systems can be framed
+//! without decoding and re-serializing them: a `Frame` keeps the payload as
+//! is, and only writes the few bytes of the outer array around it.
+//!
+//! ## Example
+//!
+//! ```
+//! use bytes::{Buf, Bytes};
+//! use tokio_fluent::protocol::Frame;
+//!
+//! // {"age": 22}
+//! let record = Bytes::from_static(b"\x81\xa3age\x16");
+//! let frame = Frame::message("fluent.test", 1700000000, record.clone(), Some("chunk-id"));
+//! // the payload is shared, not copied
+//! assert_eq!(frame.payload().as_ptr(), record.as_ptr());
+//!
+//! let len = frame.encoded_len();
+//! let mut buf = frame.into_buf();
+//! assert_eq!(buf.remaining(), len);
+//! ```
+
+use bytes::{Buf, Bytes};
+
+/// A forward protocol message made of a head, a payload encoded beforehand
+/// and a tail.
+///
+/// Write it with `AsyncWriteExt::write_all_buf(&mut frame.into_buf())`, which
+/// uses vectored writes where the stream supports them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    head: Bytes,
+    payload: Bytes,
+    tail: Bytes,
+}
+
+impl Frame {
+    /// Frame a Message mode message `[tag, time, record, option]`, where
+    /// `record` is an encoded msgpack map. The option requests an ack of
+    /// `chunk` if given, and is omitted otherwise.
+    pub fn message(tag: &str, time: i64, record: Bytes, chunk: Option<&str>) -> Self {
+        let mut head = Vec::with_capacity(tag.len() + 16);
+        write(rmp::encode::write_array_len(&mut head, array_len(chunk)));
+        write(rmp::encode::write_str(&mut head, tag));
+        write(rmp::encode::write_sint(&mut head, time));
+        Self {
+            head: head.into(),
+            payload: record,
+            tail: options(chunk, None),
+        }
+    }
+
+    /// Frame a Forward mode message `[tag, entries, option]`, where `entries`
+    /// is an encoded msgpack array of `[time, record]` arrays.
+    pub fn forward(tag: &str, entries: Bytes, chunk: Option<&str>) -> Self {
+        let mut head = Vec::with_capacity(tag.len() + 8);
+        write(rmp::encode::write_array_len(
+            &mut head,
+            array_len(chunk) - 1,
+        ));
+        write(rmp::encode::write_str(&mut head, tag));
+        Self {
+            head: head.into(),
+            payload: entries,
+            tail: options(chunk, None),
+        }
+    }
+
+    /// Frame a PackedForward mode message `[tag, entries, option]`, where
+    /// `entries` is a msgpack stream of `count` encoded `[time, record]`
+    /// arrays, carried as bin.
+    pub fn packed_forward(tag: &str, entries: Bytes, count: usize, chunk: Option<&str>) -> Self {
+        let mut head = Vec::with_capacity(tag.len() + 16);
+        write(rmp::encode::write_array_len(&mut head, 3));
+        write(rmp::encode::write_str(&mut head, tag));
+        write(rmp::encode::write_bin_len(&mut head, entries.len() as u32));
+        Self {
+            head: head.into(),
+            payload: entries,
+            tail: options(chunk, Some(count)),
+        }
+    }
+
+    /// Return the payload given to the constructor.
+    pub fn payload(&self) -> &Bytes {
+        &self.payload
+    }
+
+    /// Return the size of the whole message in bytes.
+    pub fn encoded_len(&self) -> usize {
+        self.head.len() + self.payload.len() + self.tail.len()
+    }
+
+    /// Return the message as a buffer of its parts, without copying them.
+    pub fn into_buf(self) -> impl Buf {
+        self.head.chain(self.payload).chain(self.tail)
+    }
+
+    /// Copy the message into contiguous bytes.
+    pub fn to_bytes(&self) -> Bytes {
+        let mut bytes = Vec::with_capacity(self.encoded_len());
+        bytes.extend_from_slice(&self.head);
+        bytes.extend_from_slice(&self.payload);
+        bytes.extend_from_slice(&self.tail);
+        bytes.into()
+    }
+}
+
+/// Return the length of the outer array of a Message mode message.
+fn array_len(chunk: Option<&str>) -> u32 {
+    if chunk.is_some() {
+        4
+    } else {
+        3
+    }
+}
+
+/// Encode the option map with `chunk` and `size`, or nothing if both are None.
+fn options(chunk: Option<&str>, size: Option<usize>) -> Bytes {
+    let len = chunk.is_some() as u32 + size.is_some() as u32;
+    if len == 0 {
+        return Bytes::new();
+    }
+    let mut tail = Vec::with_capacity(32);
+    write(rmp::encode::write_map_len(&mut tail, len));
+    if let Some(chunk) = chunk {
+        write(rmp::encode::write_str(&mut tail, "chunk"));
+        write(rmp::encode::write_str(&mut tail, chunk));
+    }
+    if let Some(size) = size {
+        write(rmp::encode::write_str(&mut tail, "size"));
+        write(rmp::encode::write_uint(&mut tail, size as u64));
+    }
+    tail.into()
+}
+
+/// Writing to a Vec can not fail.
+fn write<T, E: std::fmt::Debug>(result: Result<T, E>) {
+    result.expect("failed to write to memory");
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, HashMap};
+
+    use serde::Serialize;
+
+    use super::*;
+    use crate::record::{Map, Value};
+    use crate::record_map;
+
+    fn encode<T: Serialize>(value: &T) -> Vec<u8> {
+        rmp_serde::to_vec(value).unwrap()
+    }
+
+    #[test]
+    fn test_message() {
+        let record = record_map!("age".to_string() => 22.into());
+        let payload = Bytes::from(encode(&record));
+
+        let frame = Frame::message("test", 1234567, payload.clone(), Some("chunk-id"));
+        let options = HashMap::from([("chunk", "chunk-id")]);
+        let want = encode(&("test", 1234567, &record, options));
+        assert_eq!(&frame.to_bytes()[..], &want[..]);
+        assert_eq!(frame.encoded_len(), want.len());
+        assert_eq!(frame.payload().as_ptr(), payload.as_ptr());
+
+        let mut buf = frame.into_buf();
+        assert_eq!(&buf.copy_to_bytes(buf.remaining())[..], &want[..]);
+
+        let frame = Frame::message("test", 1234567, payload, None);
+        assert_eq!(
+            &frame.to_bytes()[..],
+            &encode(&("test", 1234567, &record))[..]
+        );
+    }
+
+    #[test]
+    fn test_forward() {
+        let entries = vec![(1, Map::new()), (2, Map::new())];
+        let frame = Frame::forward("test", encode(&entries).into(), Some("chunk-id"));
+        let options = HashMap::from([("chunk", "chunk-id")]);
+        assert_eq!(
+            &frame.to_bytes()[..],
+            &encode(&("test", &entries, options))[..]
+        );
+    }
+
+    #[test]
+    fn test_packed_forward() {
+        let mut entries = encode(&(1, Map::new()));
+        entries.extend(encode(&(2, Map::new())));
+        let frame = Frame::packed_forward("test", entries.clone().into(), 2, Some("chunk-id"));
+        let options =
+            BTreeMap::from([("chunk", Value::from("chunk-id")), ("size", Value::from(2))]);
+        assert_eq!(
+            &frame.to_bytes()[..],
+            &encode(&("test", Bytes::from(entries), options))[..]
+        );
+    }
+}
@@ -0,0 +1,474 @@
+//! The bounded queue of messages from a client to its worker.
+//!
+//! Records are subject to the capacity and the overflow policy of the queue,
+//! while control messages such as `Message::Terminate` are always enqueued so
+//! that they are never lost.
+//!
+//! The capacity counts messages, so a `Message::Forward` takes a single slot
+//! however many entries it holds. The records dropped by the overflow policy
+//! are counted by entry.
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+
+use tokio::runtime::{Handle, RuntimeFlavor};
+use tokio::sync::Notify;
+use tokio::task::block_in_place;
+
+use crate::client::Overflow;
+use crate::worker::Message;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendError {
+    /// The queue is full and the overflow policy is `Overflow::Error`, or
+    /// `Overflow::Block` inside a current-thread runtime.
+    Full,
+    /// The worker has exited.
+    Closed,
+}
+
+impl std::error::Error for SendError {}
+
+impl std::fmt::Display for SendError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SendError::Full => write!(f, "the buffer is full"),
+            SendError::Closed => write!(f, "the worker has exited"),
+        }
+    }
+}
+
+struct State {
+    messages: VecDeque<Message>,
+    /// The number of messages carrying records in `messages`.
+    records: usize,
+    senders: usize,
+    receiving: bool,
+}
+
+impl State {
+    fn pop(&mut self) -> Option<Message> {
+        let message = self.messages.pop_front()?;
+        if is_record(&message) {
+            self.records -= 1;
+        }
+        Some(message)
+    }
+}
+
+struct Shared {
+    state: Mutex<State>,
+    capacity: usize,
+    overflow: Overflow,
+    /// Notified when a message is enqueued or the last sender is dropped.
+    readable: Notify,
+    /// Notified when a message is dequeued or the receiver is dropped.
+    writable: Condvar,
+    /// Wakes the async senders waiting for room, see `Sender::send_async`.
+    space: Notify,
+    dropped: AtomicU64,
+}
+
+/// Create a queue of `capacity` records.
+pub fn channel(capacity: usize, overflow: Overflow) -> (Sender, Receiver) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            messages: VecDeque::new(),
+            records: 0,
+            senders: 1,
+            receiving: true,
+        }),
+        capacity: capacity.max(1),
+        overflow,
+        readable: Notify::new(),
+        writable: Condvar::new(),
+        space: Notify::new(),
+        dropped: AtomicU64::new(0),
+    });
+    (Sender(shared.clone()), Receiver(shared))
+}
+
+pub struct Sender(Arc<Shared>);
+
+impl std::fmt::Debug for Sender {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Sender")
+            .field("capacity", &self.0.capacity)
+            .field("overflow", &self.0.overflow)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Sender {
+    /// Enqueue a record, applying the overflow policy when the queue is full.
+    ///
+    /// With `Overflow::Block`, a sender inside a multi-threaded runtime waits
+    /// in `block_in_place`, so that the other tasks of its thread move to
+    /// another one. Inside a current-thread runtime, waiting would stall every
+    /// task of the runtime, possibly the receiver among them, so it fails with
+    /// `SendError::Full` instead.
+    pub fn send(&self, message: Message) -> Result<(), SendError> {
+        let shared = &self.0;
+        let mut state = shared.state.lock().unwrap();
+        loop {
+            if !state.receiving {
+                return Err(SendError::Closed);
+            }
+            if state.records < shared.capacity {
+                break;
+            }
+            match shared.overflow {
+                Overflow::Block => match Handle::try_current().map(|h| h.runtime_flavor()) {
+                    Err(_) => state = shared.writable.wait(state).unwrap(),
+                    Ok(RuntimeFlavor::CurrentThread) => return Err(SendError::Full),
+                    Ok(_) => {
+                        state = block_in_place(|| shared.writable.wait(state).unwrap());
+                    }
+                },
+                Overflow::DropNewest => {
+                    shared
+                        .dropped
+                        .fetch_add(records(&message), Ordering::Relaxed);
+                    return Ok(());
+                }
+                Overflow::DropOldest => {
+                    let index = state.messages.iter().position(is_record);
+                    if let Some(evicted) = index.and_then(|index| state.messages.remove(index)) {
+                        state.records -= 1;
+                        shared
+                            .dropped
+                            .fetch_add(records(&evicted), Ordering::Relaxed);
+                    }
+                    break;
+                }
+                Overflow::Error => return Err(SendError::Full),
+            }
+        }
+        state.messages.push_back(message);
+        state.records += 1;
+        drop(state);
+        shared.readable.notify_one();
+        Ok(())
+    }
+
+    /// Enqueue a record if there is room, regardless of the overflow policy.
+    pub fn try_send(&self, message: Message) -> Result<(), SendError> {
+        let shared = &self.0;
+        let mut state = shared.state.lock().unwrap();
+        if !state.receiving {
+            return Err(SendError::Closed);
+        }
+        if state.records >= shared.capacity {
+            return Err(SendError::Full);
+        }
+        state.messages.push_back(message);
+        state.records += 1;
+        drop(state);
+        shared.readable.notify_one();
+        Ok(())
+    }
+
+    /// Enqueue a record, waiting asynchronously for room when the queue is
+    /// full regardless of the overflow policy.
+    pub async fn send_async(&self, message: Message) -> Result<(), SendError> {
+        let shared = &self.0;
+        loop {
+            let space = shared.space.notified();
+            tokio::pin!(space);
+            // register before checking, so that a dequeue in between is not missed
+            space.as_mut().enable();
+            {
+                let mut state = shared.state.lock().unwrap();
+                if !state.receiving {
+                    return Err(SendError::Closed);
+                }
+                if state.records < shared.capacity {
+                    state.messages.push_back(message);
+                    state.records += 1;
+                    drop(state);
+                    shared.readable.notify_one();
+                    return Ok(());
+                }
+            }
+            space.await;
+        }
+    }
+
+    /// Enqueue a control message regardless of the capacity.
+    pub fn send_control(&self, message: Message) -> Result<(), SendError> {
+        let mut state = self.0.state.lock().unwrap();
+        if !state.receiving {
+            return Err(SendError::Closed);
+        }
+        state.messages.push_back(message);
+        drop(state);
+        self.0.readable.notify_one();
+        Ok(())
+    }
+
+    /// Return the number of records dropped by the overflow policy.
+    pub fn dropped(&self) -> u64 {
+        self.0.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl Clone for Sender {
+    fn clone(&self) -> Self {
+        self.0.state.lock().unwrap().senders += 1;
+        Self(self.0.clone())
+    }
+}
+
+impl Drop for Sender {
+    fn drop(&mut self) {
+        let mut state = self.0.state.lock().unwrap();
+        state.senders -= 1;
+        if state.senders == 0 {
+            drop(state);
+            self.0.readable.notify_one();
+        }
+    }
+}
+
+pub struct Receiver(Arc<Shared>);
+
+impl Receiver {
+    /// Dequeue the next message, or return None once every sender is dropped
+    /// and the queue is empty.
+    pub async fn recv(&mut self) -> Option<Message> {
+        loop {
+            {
+                let mut state = self.0.state.lock().unwrap();
+                if let Some(message) = state.pop() {
+                    drop(state);
+                    self.0.writable.notify_one();
+                    self.0.space.notify_waiters();
+                    return Some(message);
+                }
+                if state.senders == 0 {
+                    return None;
+                }
+            }
+            // a notification between the check and here is kept as a permit
+            self.0.readable.notified().await;
+        }
+    }
+
+    /// Return the number of records dropped by the overflow policy.
+    pub fn dropped(&self) -> u64 {
+        self.0.dropped.load(Ordering::Relaxed)
+    }
+
+    #[cfg(test)]
+    pub fn try_recv(&mut self) -> Result<Message, SendError> {
+        let mut state = self.0.state.lock().unwrap();
+        state.pop().ok_or(SendError::Closed)
+    }
+}
+
+impl Drop for Receiver {
+    fn drop(&mut self) {
+        let mut state = self.0.state.lock().unwrap();
+        state.receiving = false;
+        state.messages.clear();
+        state.records = 0;
+        drop(state);
+        self.0.writable.notify_all();
+        self.0.space.notify_waiters();
+    }
+}
+
+fn is_record(message: &Message) -> bool {
+    matches!(
+        message,
+        Message::Record(_) | Message::Forward(_) | Message::Raw(_)
+    )
+}
+
+/// Return the number of records carried by `message`.
+fn records(message: &Message) -> u64 {
+    match message {
+        Message::Record(_) | Message::Raw(_) => 1,
+        Message::Forward(forward) => forward.entries.len() as u64,
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::record::Map;
+    use crate::worker::{Forward, Options, RawRecord, Record};
+
+    fn record(timestamp: i64) -> Message {
+        Message::Record(Record {
+            tag: "test".into(),
+            timestamp,
+            nanos: None,
+            record: Map::new(),
+            options: Options::default(),
+        })
+    }
+
+    fn timestamps(receiver: &mut Receiver) -> Vec<i64> {
+        std::iter::from_fn(|| receiver.try_recv().ok())
+            .map(|message| match message {
+                Message::Record(record) => record.timestamp,
+                _ => -1,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_overflow() {
+        let (sender, mut receiver) = channel(2, Overflow::DropNewest);
+        for i in 0..3 {
+            assert!(sender.send(record(i)).is_ok());
+        }
+        assert!(sender.send_control(Message::Terminate).is_ok());
+        assert_eq!(timestamps(&mut receiver), [0, 1, -1]);
+        assert_eq!(sender.dropped(), 1);
+
+        let (sender, mut receiver) = channel(2, Overflow::DropOldest);
+        assert!(sender.send_control(Message::Reconnect).is_ok());
+        for i in 0..3 {
+            assert!(sender.send(record(i)).is_ok());
+        }
+        assert_eq!(timestamps(&mut receiver), [-1, 1, 2]);
+        assert_eq!(sender.dropped(), 1);
+
+        let (sender, mut receiver) = channel(1, Overflow::DropOldest);
+        assert!(sender.try_send(record(0)).is_ok());
+        assert_eq!(sender.try_send(record(1)).unwrap_err(), SendError::Full);
+        assert_eq!(timestamps(&mut receiver), [0]);
+        assert_eq!(sender.dropped(), 0);
+
+        let (sender, mut receiver) = channel(2, Overflow::Error);
+        assert!(sender.send(record(0)).is_ok());
+        assert!(sender.send(record(1)).is_ok());
+        assert_eq!(sender.send(record(2)).unwrap_err(), SendError::Full);
+        assert_eq!(timestamps(&mut receiver), [0, 1]);
+
+        drop(receiver);
+        assert_eq!(sender.send(record(3)).unwrap_err(), SendError::Closed);
+    }
+
+    #[test]
+    fn test_raw_records() {
+        let raw = || {
+            Message::Raw(RawRecord {
+                tag: "test".into(),
+                timestamp: 0,
+                record: bytes::Bytes::from_static(b"\x80"),
+                options: Options::default(),
+            })
+        };
+        let (sender, mut receiver) = channel(2, Overflow::Error);
+        for _ in 0..4 {
+            assert!(sender.send(raw()).is_ok());
+            assert!(sender.send(raw()).is_ok());
+            assert_eq!(sender.send(raw()).unwrap_err(), SendError::Full);
+            // dequeued raw records free their room
+            assert_eq!(timestamps(&mut receiver), [-1, -1]);
+        }
+
+        let (sender, mut receiver) = channel(2, Overflow::DropOldest);
+        assert!(sender.send(raw()).is_ok());
+        assert!(sender.send(record(1)).is_ok());
+        assert!(sender.send(record(2)).is_ok());
+        assert_eq!(timestamps(&mut receiver), [1, 2]);
+        assert_eq!(sender.dropped(), 1);
+    }
+
+    #[test]
+    fn test_forward_records() {
+        let forward = |n| {
+            Message::Forward(Forward {
+                tag: "test".into(),
+                entries: vec![(0, Map::new()); n],
+                options: Options::default(),
+                ack: None,
+            })
+        };
+        // a forward takes one slot, but its entries are dropped one by one
+        let (sender, mut receiver) = channel(2, Overflow::DropOldest);
+        assert!(sender.send(forward(3)).is_ok());
+        assert!(sender.send(record(1)).is_ok());
+        assert!(sender.send(record(2)).is_ok());
+        assert_eq!(timestamps(&mut receiver), [1, 2]);
+        assert_eq!(sender.dropped(), 3);
+
+        let (sender, mut receiver) = channel(1, Overflow::DropNewest);
+        assert!(sender.send(record(0)).is_ok());
+        assert!(sender.send(forward(5)).is_ok());
+        assert_eq!(timestamps(&mut receiver), [0]);
+        assert_eq!(sender.dropped(), 5);
+    }
+
+    #[tokio::test]
+    async fn test_send_async() {
+        let (sender, mut receiver) = channel(1, Overflow::DropNewest);
+        sender.send_async(record(0)).await.unwrap();
+        let waiting = tokio::spawn(async move {
+            sender.send_async(record(1)).await.unwrap();
+            sender
+        });
+        tokio::task::yield_now().await;
+        assert!(!waiting.is_finished());
+
+        assert!(matches!(receiver.recv().await, Some(Message::Record(r)) if r.timestamp == 0));
+        let sender = waiting.await.unwrap();
+        assert_eq!(timestamps(&mut receiver), [1]);
+        assert_eq!(sender.dropped(), 0);
+
+        drop(receiver);
+        assert_eq!(
+            sender.send_async(record(2)).await.unwrap_err(),
+            SendError::Closed
+        );
+    }
+
+    #[tokio::test]
+    async fn test_block() {
+        let (sender, mut receiver) = channel(1, Overflow::Block);
+        assert!(sender.send(record(0)).is_ok());
+        let blocked = std::thread::spawn(move || {
+            sender.send(record(1)).unwrap();
+        });
+        assert!(matches!(receiver.recv().await, Some(Message::Record(_))));
+        blocked.join().unwrap();
+        assert!(matches!(receiver.recv().await, Some(Message::Record(_))));
+        // every sender is dropped
+        assert!(receiver.recv().await.is_none());
+    }
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_block_on_current_thread() {
+        let (sender, mut receiver) = channel(1, Overflow::Block);
+        assert!(sender.send(record(0)).is_ok());
+        // waiting would deadlock, as the receiver runs on this thread
+        assert_eq!(sender.send(record(1)).unwrap_err(), SendError::Full);
+        assert_eq!(timestamps(&mut receiver), [0]);
+        assert!(sender.send(record(2)).is_ok());
+        assert_eq!(timestamps(&mut receiver), [2]);
+        assert_eq!(sender.dropped(), 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_block_on_multi_thread() {
+        let (sender, mut receiver) = channel(1, Overflow::Block);
+        assert!(sender.send(record(0)).is_ok());
+        let received = tokio::spawn(async move {
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            let mut timestamps = Vec::new();
+            while let Some(Message::Record(r)) = receiver.recv().await {
+                timestamps.push(r.timestamp);
+            }
+            timestamps
+        });
+        // waits for the spawned task to make room
+        assert!(sender.send(record(1)).is_ok());
+        drop(sender);
+        assert_eq!(received.await.unwrap(), [0, 1]);
+    }
+}
@@ -3,9 +3,23 @@
 use core::fmt::Debug;
 use std::collections::HashMap;
 
+use bytes::Bytes;
+use chrono::{DateTime, SecondsFormat, TimeZone, Utc};
 use serde::ser::{SerializeMap, SerializeSeq};
 use serde::{Serialize, Serializer};
 
+mod builder;
+mod canonical;
+mod de;
+#[cfg(feature = "json")]
+mod json;
+mod ser;
+
+pub use builder::RecordBuilder;
+#[cfg(feature = "json")]
+pub(crate) use json::json_type;
+pub use ser::Error;
+
 #[derive(Clone, PartialEq)]
 /// HashMap object for fluent record.
 pub struct Map(HashMap<String, Value>);
@@ -29,6 +43,10 @@ impl Map {
 #[macro_export]
 /// Create a Map object from a list of key-value pairs.
 ///
+/// Values in braces are nested maps and values in brackets are arrays, whose
+/// elements follow the same rules. Literal values are converted into Values,
+/// and other expressions must evaluate to a Value.
+///
 /// ## Example
 ///
 /// ```
@@ -45,26 +63,125 @@ impl Map {
 /// assert_eq!(map["name"], Value::from("John"));
 /// assert_eq!(map["age"], Value::from(22));
 /// assert_eq!(map["scores"], Value::from([70, 80].into_iter().map(|e| e.into()).collect::<Vec<_>>()));
+///
+/// let nested = record_map!(
+///     "user" => {"name" => "John", "scores" => [70, 80]},
+///     "tags" => ["a", {"b" => true}],
+/// );
+/// assert_eq!(nested["user"], Value::from(record_map!("name" => "John", "scores" => [70, 80])));
 /// ```
 macro_rules! record_map {
-    ($($key:expr => $field:expr,)+) => { record_map!($($key => $field),+) };
-    ($($key:expr => $field:expr),*) => {
+    (@insert $map:ident) => {};
+    (@insert $map:ident $key:expr => {$($inner:tt)*} $(, $($rest:tt)*)?) => {
+        $map.insert(String::from($key), Value::Object($crate::record_map!($($inner)*)));
+        $crate::record_map!(@insert $map $($($rest)*)?);
+    };
+    (@insert $map:ident $key:expr => [$($inner:tt)*] $(, $($rest:tt)*)?) => {
+        $map.insert(String::from($key), $crate::record_map!(@array [] $($inner)*));
+        $crate::record_map!(@insert $map $($($rest)*)?);
+    };
+    (@insert $map:ident $key:expr => $field:literal $(, $($rest:tt)*)?) => {
+        $map.insert(String::from($key), Value::from($field));
+        $crate::record_map!(@insert $map $($($rest)*)?);
+    };
+    (@insert $map:ident $key:expr => $field:expr $(, $($rest:tt)*)?) => {
+        $map.insert(String::from($key), $field);
+        $crate::record_map!(@insert $map $($($rest)*)?);
+    };
+    (@insert $map:ident $($rest:tt)+) => {
+        compile_error!("expected `key => value` pairs")
+    };
+
+    (@array [$($done:expr),*]) => { Value::Array(vec![$($done),*]) };
+    (@array [$($done:expr),*] {$($inner:tt)*} $(, $($rest:tt)*)?) => {
+        $crate::record_map!(@array [$($done,)* Value::Object($crate::record_map!($($inner)*))] $($($rest)*)?)
+    };
+    (@array [$($done:expr),*] [$($inner:tt)*] $(, $($rest:tt)*)?) => {
+        $crate::record_map!(@array [$($done,)* $crate::record_map!(@array [] $($inner)*)] $($($rest)*)?)
+    };
+    (@array [$($done:expr),*] $element:literal $(, $($rest:tt)*)?) => {
+        $crate::record_map!(@array [$($done,)* Value::from($element)] $($($rest)*)?)
+    };
+    (@array [$($done:expr),*] $element:expr $(, $($rest:tt)*)?) => {
+        $crate::record_map!(@array [$($done,)* $element] $($($rest)*)?)
+    };
+
+    () => { Map::new() };
+    ($($pairs:tt)+) => {
         {
             let mut map: HashMap<String, Value> = HashMap::new();
-            $(
-                map.insert($key, $field);
-            )+
+            $crate::record_map!(@insert map $($pairs)*);
             Map::new_with(map)
         }
     };
 }
 
+impl Map {
+    /// Replace every `Value::Bytes` in this Map with a `Value::Str`,
+    /// substituting invalid UTF-8 sequences with U+FFFD.
+    pub(crate) fn bytes_to_str_lossy(&mut self) {
+        self.0.values_mut().for_each(Value::bytes_to_str_lossy);
+    }
+
+    /// Replace every `Value::DateTime` in this Map with a `Value::Str` of its
+    /// RFC 3339 representation.
+    pub(crate) fn datetimes_to_rfc3339(&mut self) {
+        self.0.values_mut().for_each(Value::datetimes_to_rfc3339);
+    }
+}
+
+impl Value {
+    fn datetimes_to_rfc3339(&mut self) {
+        match self {
+            Value::DateTime(value) => {
+                *self = Value::Str(value.to_rfc3339_opts(SecondsFormat::AutoSi, true));
+            }
+            Value::Object(value) => value.datetimes_to_rfc3339(),
+            Value::Array(value) => value.iter_mut().for_each(Value::datetimes_to_rfc3339),
+            _ => {}
+        }
+    }
+
+    fn bytes_to_str_lossy(&mut self) {
+        match self {
+            Value::Bytes(value) => {
+                *self = Value::Str(String::from_utf8_lossy(value).into_owned());
+            }
+            Value::Object(value) => value.bytes_to_str_lossy(),
+            Value::Array(value) => value.iter_mut().for_each(Value::bytes_to_str_lossy),
+            _ => {}
+        }
+    }
+}
+
 impl Default for Map {
     fn default() -> Self {
         Self::new()
     }
 }
 
+impl<K: Into<String>, V: Into<Value>> FromIterator<(K, V)> for Map {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        let mut map = Map::new();
+        map.extend(iter);
+        map
+    }
+}
+
+/// Inserts the pairs, replacing the values of existing keys.
+impl<K: Into<String>, V: Into<Value>> Extend<(K, V)> for Map {
+    fn extend<I: IntoIterator<Item = (K, V)>>(&mut self, iter: I) {
+        self.0
+            .extend(iter.into_iter().map(|(k, v)| (k.into(), v.into())));
+    }
+}
+
+impl<K: Into<String>, V: Into<Value>, S> From<HashMap<K, V, S>> for Map {
+    fn from(map: HashMap<K, V, S>) -> Self {
+        map.into_iter().collect()
+    }
+}
+
 impl Debug for Map {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         Debug::fmt(&self.0, f)
@@ -98,6 +215,13 @@ pub enum Value {
     Float(f64),
     /// String
     Str(String),
+    /// Byte string which may not be valid UTF-8.
+    /// How it is encoded is controlled by `Config::bytes_encoding`, which
+    /// keeps binary payloads such as protobuf messages intact by default.
+    Bytes(Vec<u8>),
+    /// Point in time.
+    /// How it is encoded is controlled by `Config::datetime_encoding`.
+    DateTime(DateTime<Utc>),
     /// Object
     Object(Map),
     /// Array
@@ -170,6 +294,24 @@ impl From<String> for Value {
     }
 }
 
+impl From<&[u8]> for Value {
+    fn from(value: &[u8]) -> Self {
+        Self::Bytes(value.to_vec())
+    }
+}
+
+impl From<Bytes> for Value {
+    fn from(value: Bytes) -> Self {
+        Self::Bytes(value.into())
+    }
+}
+
+impl<Tz: TimeZone> From<DateTime<Tz>> for Value {
+    fn from(value: DateTime<Tz>) -> Self {
+        Self::DateTime(value.with_timezone(&Utc))
+    }
+}
+
 impl From<Map> for Value {
     fn from(value: Map) -> Self {
         Self::Object(value)
@@ -190,12 +332,70 @@ impl Debug for Value {
             Value::Uint(value) => f.write_fmt(format_args!("{}", value)),
             Value::Float(value) => f.write_fmt(format_args!("{}", value)),
             Value::Str(value) => f.write_fmt(format_args!("{}", value)),
+            Value::Bytes(value) => f.write_fmt(format_args!("{}", String::from_utf8_lossy(value))),
+            Value::DateTime(value) => f.write_fmt(format_args!(
+                "{}",
+                value.to_rfc3339_opts(SecondsFormat::AutoSi, true)
+            )),
             Value::Object(value) => f.write_fmt(format_args!("{:?}", value)),
             Value::Array(value) => f.write_fmt(format_args!("{:?}", value)),
         }
     }
 }
 
+/// Debug wrapper which masks values while keeping keys and structure.
+///
+/// Used by the worker when logging records so that payloads do not leak
+/// into application logs.
+///
+/// ## Example
+///
+/// ```
+/// use std::collections::HashMap;
+///
+/// use tokio_fluent::record_map;
+/// use tokio_fluent::record::{Map, Value};
+///
+/// let map = record_map!("password".to_string() => "secret".into());
+/// assert_eq!(format!("{:?}", map.redacted()), r#"{"password": ***}"#);
+/// ```
+pub struct Redacted<'a, T>(&'a T);
+
+impl Map {
+    /// Return a Debug wrapper which masks the values of this Map.
+    pub fn redacted(&self) -> Redacted<'_, Map> {
+        Redacted(self)
+    }
+}
+
+impl Value {
+    /// Return a Debug wrapper which masks this Value.
+    pub fn redacted(&self) -> Redacted<'_, Value> {
+        Redacted(self)
+    }
+}
+
+impl Debug for Redacted<'_, Map> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_map()
+            .entries(self.0.iter().map(|(k, v)| (k, v.redacted())))
+            .finish()
+    }
+}
+
+impl Debug for Redacted<'_, Value> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.0 {
+            Value::Object(value) => Debug::fmt(&value.redacted(), f),
+            Value::Array(value) => f
+                .debug_list()
+                .entries(value.iter().map(|e| e.redacted()))
+                .finish(),
+            _ => f.write_str("***"),
+        }
+    }
+}
+
 impl Serialize for Map {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -209,6 +409,42 @@ impl Serialize for Map {
     }
 }
 
+/// The time of an event, serialized as an integer of seconds, or as the
+/// EventTime ext type of the forward protocol when it has nanoseconds.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct EventTime {
+    pub(crate) seconds: i64,
+    pub(crate) nanos: Option<u32>,
+}
+
+impl Serialize for EventTime {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let Some(nanos) = self.nanos else {
+            return serializer.serialize_i64(self.seconds);
+        };
+        // the ext type 0 holds the seconds and the nanoseconds as big-endian u32
+        let mut data = [0; 8];
+        data[..4].copy_from_slice(&(self.seconds as u32).to_be_bytes());
+        data[4..].copy_from_slice(&nanos.to_be_bytes());
+        serializer.serialize_newtype_struct(rmp_serde::MSGPACK_EXT_STRUCT_NAME, &(0i8, Bin(&data)))
+    }
+}
+
+/// A byte slice serialized as msgpack bin rather than as an array.
+struct Bin<'a>(&'a [u8]);
+
+impl Serialize for Bin<'_> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_bytes(self.0)
+    }
+}
+
 impl Serialize for Value {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -220,6 +456,12 @@ impl Serialize for Value {
             Value::Uint(value) => serializer.serialize_u64(*value),
             Value::Float(value) => serializer.serialize_f64(*value),
             Value::Str(value) => serializer.serialize_str(value),
+            Value::Bytes(value) => serializer.serialize_bytes(value),
+            Value::DateTime(value) => EventTime {
+                seconds: value.timestamp(),
+                nanos: Some(value.timestamp_subsec_nanos()),
+            }
+            .serialize(serializer),
             Value::Object(value) => {
                 let mut map = serializer.serialize_map(Some(value.len()))?;
                 for (k, v) in value.iter() {
@@ -263,4 +505,114 @@ mod tests {
         );
         assert_eq!(got, want);
     }
+
+    #[test]
+    fn test_collect() {
+        let want = record_map!("a" => 1, "b" => 2);
+        let map: Map = [("a", 1), ("b", 2)].into_iter().collect();
+        assert_eq!(map, want);
+        let map: Map = HashMap::from([("a".to_string(), 1), ("b".to_string(), 2)]).into();
+        assert_eq!(map, want);
+
+        let mut map = record_map!("a" => 0);
+        map.extend([
+            ("a".to_string(), Value::from(1)),
+            ("b".to_string(), Value::from(2)),
+        ]);
+        assert_eq!(map, want);
+    }
+
+    #[test]
+    fn test_record_map_nested() {
+        let name = "John";
+        let got = record_map!(
+            "user" => {"name" => Value::from(name), "age" => 22},
+            "scores" => [70, -1, 0.5],
+            "events" => [{"kind" => "login"}, ["a", true], {}],
+            "empty" => {},
+        );
+
+        let mut user = Map::new();
+        user.insert("name".to_string(), "John".into());
+        user.insert("age".to_string(), 22.into());
+        let mut login = Map::new();
+        login.insert("kind".to_string(), "login".into());
+        let mut want = Map::new();
+        want.insert("user".to_string(), user.into());
+        want.insert(
+            "scores".to_string(),
+            vec![Value::from(70), Value::from(-1), Value::from(0.5)].into(),
+        );
+        want.insert(
+            "events".to_string(),
+            vec![
+                login.into(),
+                vec![Value::from("a"), Value::from(true)].into(),
+                Map::new().into(),
+            ]
+            .into(),
+        );
+        want.insert("empty".to_string(), Map::new().into());
+        assert_eq!(got, want);
+    }
+
+    #[test]
+    fn test_redacted() {
+        let map = record_map!(
+            "user".to_string() => record_map!("name".to_string() => "John".into()).into(),
+            "scores".to_string() => vec![70.into(), 80.into()].into(),
+        );
+        let got = format!("{:?}", map["user"].redacted());
+        assert_eq!(got, r#"{"name": ***}"#);
+        let got = format!("{:?}", map["scores"].redacted());
+        assert_eq!(got, "[***, ***]");
+        assert!(!format!("{:?}", map.redacted()).contains("John"));
+    }
+
+    #[test]
+    fn test_bytes_to_str_lossy() {
+        let mut map = record_map!(
+            "raw".to_string() => b"ok\xff".as_slice().into(),
+            "nested".to_string() => vec![Value::Bytes(b"abc".to_vec())].into(),
+            "name".to_string() => "John".into(),
+        );
+        map.bytes_to_str_lossy();
+
+        let want = record_map!(
+            "raw".to_string() => "ok\u{fffd}".into(),
+            "nested".to_string() => vec![Value::from("abc")].into(),
+            "name".to_string() => "John".into(),
+        );
+        assert_eq!(map, want);
+    }
+
+    #[test]
+    fn test_bytes() {
+        let blob = Bytes::from_static(b"\x08\x96\x01");
+        let value = Value::from(blob.clone());
+        assert_eq!(value, Value::Bytes(blob.to_vec()));
+        // msgpack bin 8
+        assert_eq!(rmp_serde::to_vec(&value).unwrap(), b"\xc4\x03\x08\x96\x01");
+    }
+
+    #[test]
+    fn test_datetime() {
+        use chrono::FixedOffset;
+
+        let time = FixedOffset::east_opt(9 * 3600)
+            .unwrap()
+            .timestamp_opt(1700000000, 2)
+            .unwrap();
+        let value = Value::from(time);
+        assert_eq!(value, Value::DateTime(time.with_timezone(&Utc)));
+        // EventTime ext of 1700000000 seconds and 2 nanoseconds
+        assert_eq!(
+            rmp_serde::to_vec(&value).unwrap(),
+            b"\xd7\x00\x65\x53\xf1\x00\x00\x00\x00\x02"
+        );
+
+        let mut map = record_map!("at".to_string() => value);
+        map.datetimes_to_rfc3339();
+        assert_eq!(map["at"], "2023-11-14T22:13:20.000000002Z".into());
+    }
 }
@@ -0,0 +1,164 @@
+//! A builder of records with keys known at compile time.
+
+use std::collections::HashMap;
+
+use super::{Map, Value};
+
+/// Builds `Map` objects from `&'static str` keys.
+///
+/// The fields are kept in insertion order with their keys unallocated, and
+/// the Map is sized for all of them when it is built. A builder can be kept
+/// and reused across sends, updating only the values which change.
+///
+/// ## Example
+///
+/// ```
+/// use tokio_fluent::record::{RecordBuilder, Value};
+///
+/// let mut builder = RecordBuilder::new()
+///     .field("service", "api")
+///     .field("status", 200);
+/// let map = builder.build();
+/// assert_eq!(map["service"], Value::from("api"));
+///
+/// builder.set("status", 500);
+/// assert_eq!(builder.build()["status"], Value::from(500));
+/// ```
+///
+/// Nested records are built in place:
+///
+/// ```
+/// use tokio_fluent::record::Map;
+///
+/// let map = Map::builder()
+///     .field("user", 1)
+///     .field("path", "/x")
+///     .nested("meta", |meta| meta.field("region", "eu").field("retries", 2))
+///     .build();
+/// ```
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RecordBuilder {
+    fields: Vec<(&'static str, Value)>,
+}
+
+impl RecordBuilder {
+    /// Create an empty builder.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create an empty builder with room for `capacity` fields.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            fields: Vec::with_capacity(capacity),
+        }
+    }
+
+    /// Add a field, replacing the value of an existing field with the same key.
+    pub fn field<V: Into<Value>>(mut self, key: &'static str, value: V) -> Self {
+        self.set(key, value);
+        self
+    }
+
+    /// Add a field holding the nested record built by `build` from an empty
+    /// builder.
+    pub fn nested<F>(self, key: &'static str, build: F) -> Self
+    where
+        F: FnOnce(RecordBuilder) -> RecordBuilder,
+    {
+        self.field(key, build(RecordBuilder::new()))
+    }
+
+    /// Set the value of a field, adding the field if it does not exist.
+    pub fn set<V: Into<Value>>(&mut self, key: &'static str, value: V) {
+        let value = value.into();
+        match self.fields.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, v)) => *v = value,
+            None => self.fields.push((key, value)),
+        }
+    }
+
+    /// Remove a field.
+    pub fn remove(&mut self, key: &str) {
+        self.fields.retain(|(k, _)| *k != key);
+    }
+
+    /// Build a Map of the fields, leaving this builder intact for reuse.
+    pub fn build(&self) -> Map {
+        let mut map = HashMap::with_capacity(self.fields.len());
+        for (key, value) in self.fields.iter() {
+            map.insert(key.to_string(), value.clone());
+        }
+        Map(map)
+    }
+}
+
+impl Map {
+    /// Return an empty `RecordBuilder`.
+    pub fn builder() -> RecordBuilder {
+        RecordBuilder::new()
+    }
+}
+
+impl From<RecordBuilder> for Value {
+    fn from(builder: RecordBuilder) -> Self {
+        Value::Object(builder.into())
+    }
+}
+
+impl From<RecordBuilder> for Map {
+    fn from(builder: RecordBuilder) -> Self {
+        let mut map = HashMap::with_capacity(builder.fields.len());
+        for (key, value) in builder.fields {
+            map.insert(key.to_string(), value);
+        }
+        Map(map)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::record_map;
+
+    #[test]
+    fn test_record_builder() {
+        let mut builder = RecordBuilder::with_capacity(3)
+            .field("name", "John")
+            .field("age", 22)
+            .field("age", 23);
+        let want = record_map!(
+            "name".to_string() => "John".into(),
+            "age".to_string() => 23.into(),
+        );
+        assert_eq!(builder.build(), want);
+        assert_eq!(Map::from(builder.clone()), want);
+
+        builder.remove("name");
+        builder.set("scores", vec![Value::from(70)]);
+        let want = record_map!(
+            "age".to_string() => 23.into(),
+            "scores".to_string() => vec![Value::from(70)].into(),
+        );
+        assert_eq!(builder.build(), want);
+    }
+
+    #[test]
+    fn test_nested() {
+        let map = Map::builder()
+            .field("user", 1)
+            .nested("meta", |meta| {
+                meta.field("region", "eu")
+                    .nested("client", |client| client.field("version", "1.2"))
+            })
+            .build();
+        let want = record_map!(
+            "user".to_string() => 1.into(),
+            "meta".to_string() => record_map!(
+                "region".to_string() => "eu".into(),
+                "client".to_string() => record_map!("version".to_string() => "1.2".into()).into(),
+            ).into(),
+        );
+        assert_eq!(map, want);
+    }
+}
@@ -0,0 +1,230 @@
+//! Canonical form of records.
+//!
+//! The entries of a `Map` have no order, so two equal records may serialize
+//! differently. The canonical form sorts the keys of every map, which gives
+//! a stable serialization, fingerprint, hash and ordering.
+
+use std::cmp::Ordering;
+use std::hash::{Hash, Hasher};
+
+use serde::ser::{SerializeMap, SerializeSeq};
+use serde::{Serialize, Serializer};
+use sha2::{Digest, Sha256};
+
+use super::{Map, Value};
+
+impl Map {
+    /// Serialize this Map into msgpack with the keys of every map sorted.
+    ///
+    /// Equal Maps always produce the same bytes.
+    pub fn to_canonical_bytes(&self) -> Vec<u8> {
+        rmp_serde::to_vec(&Canonical(self)).expect("serializing into a Vec never fails")
+    }
+
+    /// Return the SHA-256 digest of the canonical form of this Map.
+    ///
+    /// Unlike `Hash`, it is stable across processes and versions, so it can
+    /// be used to fingerprint events consistently.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// use std::collections::HashMap;
+    ///
+    /// use tokio_fluent::record_map;
+    /// use tokio_fluent::record::{Map, Value};
+    ///
+    /// let a = record_map!("a".to_string() => 1.into(), "b".to_string() => 2.into());
+    /// let b = record_map!("b".to_string() => 2.into(), "a".to_string() => 1.into());
+    /// assert_eq!(a.fingerprint(), b.fingerprint());
+    /// ```
+    pub fn fingerprint(&self) -> [u8; 32] {
+        Sha256::digest(self.to_canonical_bytes()).into()
+    }
+
+    pub(super) fn sorted(&self) -> Vec<(&String, &Value)> {
+        let mut entries: Vec<_> = self.iter().collect();
+        entries.sort_unstable_by(|a, b| a.0.cmp(b.0));
+        entries
+    }
+}
+
+impl Value {
+    /// Serialize this Value into msgpack with the keys of every map sorted.
+    pub fn to_canonical_bytes(&self) -> Vec<u8> {
+        rmp_serde::to_vec(&Canonical(self)).expect("serializing into a Vec never fails")
+    }
+
+    /// Return the SHA-256 digest of the canonical form of this Value.
+    pub fn fingerprint(&self) -> [u8; 32] {
+        Sha256::digest(self.to_canonical_bytes()).into()
+    }
+
+    /// The order of the variants when comparing Values of different variants.
+    fn rank(&self) -> u8 {
+        match self {
+            Value::Bool(_) => 0,
+            Value::Int(_) => 1,
+            Value::Uint(_) => 2,
+            Value::Float(_) => 3,
+            Value::Str(_) => 4,
+            Value::Bytes(_) => 5,
+            Value::Object(_) => 6,
+            Value::Array(_) => 7,
+            Value::DateTime(_) => 8,
+        }
+    }
+}
+
+/// Hashes the sorted entries, consistently with `PartialEq`.
+impl Hash for Map {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.sorted().hash(state);
+    }
+}
+
+impl Hash for Value {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.rank().hash(state);
+        match self {
+            Value::Bool(value) => value.hash(state),
+            Value::Int(value) => value.hash(state),
+            Value::Uint(value) => value.hash(state),
+            // 0.0 and -0.0 are equal, so they must have the same hash
+            Value::Float(value) if *value == 0.0 => 0.0f64.to_bits().hash(state),
+            Value::Float(value) => value.to_bits().hash(state),
+            Value::Str(value) => value.hash(state),
+            Value::Bytes(value) => value.hash(state),
+            Value::DateTime(value) => value.hash(state),
+            Value::Object(value) => value.hash(state),
+            Value::Array(value) => value.hash(state),
+        }
+    }
+}
+
+/// Compares the sorted entries lexicographically.
+impl PartialOrd for Map {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        self.sorted().partial_cmp(&other.sorted())
+    }
+}
+
+/// Values of different variants are ordered by variant, and values of the
+/// same variant by their content.
+impl PartialOrd for Value {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        match (self, other) {
+            (Value::Bool(a), Value::Bool(b)) => a.partial_cmp(b),
+            (Value::Int(a), Value::Int(b)) => a.partial_cmp(b),
+            (Value::Uint(a), Value::Uint(b)) => a.partial_cmp(b),
+            (Value::Float(a), Value::Float(b)) => a.partial_cmp(b),
+            (Value::Str(a), Value::Str(b)) => a.partial_cmp(b),
+            (Value::Bytes(a), Value::Bytes(b)) => a.partial_cmp(b),
+            (Value::DateTime(a), Value::DateTime(b)) => a.partial_cmp(b),
+            (Value::Object(a), Value::Object(b)) => a.partial_cmp(b),
+            (Value::Array(a), Value::Array(b)) => a.partial_cmp(b),
+            _ => self.rank().partial_cmp(&other.rank()),
+        }
+    }
+}
+
+/// Serializes the wrapped record with sorted keys.
+struct Canonical<'a, T>(&'a T);
+
+impl Serialize for Canonical<'_, Map> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let entries = self.0.sorted();
+        let mut map = serializer.serialize_map(Some(entries.len()))?;
+        for (k, v) in entries {
+            map.serialize_entry(k, &Canonical(v))?;
+        }
+        map.end()
+    }
+}
+
+impl Serialize for Canonical<'_, Value> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match self.0 {
+            Value::Object(value) => Canonical(value).serialize(serializer),
+            Value::Array(value) => {
+                let mut seq = serializer.serialize_seq(Some(value.len()))?;
+                for e in value {
+                    seq.serialize_element(&Canonical(e))?;
+                }
+                seq.end()
+            }
+            value => value.serialize(serializer),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::hash_map::DefaultHasher;
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::record_map;
+
+    fn hash<T: Hash>(value: &T) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        value.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    fn record() -> Map {
+        let mut map = Map::new();
+        for i in 0..32 {
+            map.insert(format!("key{}", i), i.into());
+        }
+        map.insert(
+            "nested".to_string(),
+            record_map!("b".to_string() => 1.into(), "a".to_string() => 2.into()).into(),
+        );
+        map
+    }
+
+    #[test]
+    fn test_canonical_bytes() {
+        let map = record_map!(
+            "b".to_string() => record_map!("d".to_string() => 1.into(), "c".to_string() => 2.into()).into(),
+            "a".to_string() => "x".into(),
+        );
+        let mut want = vec![0x82, 0xa1, b'a', 0xa1, b'x', 0xa1, b'b'];
+        want.extend([0x82, 0xa1, b'c', 0x02, 0xa1, b'd', 0x01]);
+        assert_eq!(map.to_canonical_bytes(), want);
+
+        // the iteration order of equal maps differs, but not the canonical form
+        let (a, b) = (record(), record());
+        assert_eq!(a.to_canonical_bytes(), b.to_canonical_bytes());
+        assert_eq!(a.fingerprint(), b.fingerprint());
+        assert_eq!(hash(&a), hash(&b));
+        assert_ne!(a.fingerprint(), Map::new().fingerprint());
+    }
+
+    #[test]
+    fn test_hash() {
+        assert_eq!(hash(&Value::Float(0.0)), hash(&Value::Float(-0.0)));
+        assert_ne!(hash(&Value::Int(1)), hash(&Value::Uint(1)));
+    }
+
+    #[test]
+    fn test_partial_cmp() {
+        assert!(Value::Int(1) < Value::Int(2));
+        assert!(Value::Bool(true) < Value::Int(0));
+        assert_eq!(Value::Float(f64::NAN).partial_cmp(&Value::Float(0.0)), None);
+
+        let a = record_map!("a".to_string() => 1.into());
+        let b = record_map!("a".to_string() => 2.into());
+        let c = record_map!("b".to_string() => 0.into());
+        assert!(a < b);
+        assert!(b < c);
+        assert_eq!(record().partial_cmp(&record()), Some(Ordering::Equal));
+    }
+}
@@ -0,0 +1,209 @@
+//! Deserialization of Values from self-describing formats such as msgpack
+//! or JSON.
+//!
+//! `Value` has no nil variant, so nulls are skipped when they appear as a map
+//! value and rejected anywhere else, as on serialization. Non-negative
+//! integers which fit in an `i64` become `Value::Int`, since msgpack does not
+//! tell them apart from unsigned ones, and the EventTime ext type becomes
+//! `Value::DateTime`.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use chrono::{TimeZone, Utc};
+use serde::de::{self, Deserialize, Deserializer, MapAccess, SeqAccess, Visitor};
+
+use super::{Map, Value};
+
+/// Decodes a Value from any self-describing format. Nulls within maps are
+/// skipped, non-negative integers become `Value::Int` when they fit, and the
+/// EventTime ext type of msgpack becomes `Value::DateTime`.
+impl<'de> Deserialize<'de> for Value {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer
+            .deserialize_any(ValueVisitor)?
+            .ok_or_else(|| de::Error::custom("null has no representation as a Value"))
+    }
+}
+
+/// Decodes a Map from a map of any self-describing format, such as a
+/// fluentd record.
+impl<'de> Deserialize<'de> for Map {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        match deserializer.deserialize_map(ValueVisitor)? {
+            Some(Value::Object(map)) => Ok(map),
+            _ => Err(de::Error::custom("expected a map")),
+        }
+    }
+}
+
+/// A Value which may be null.
+struct Nullable(Option<Value>);
+
+impl<'de> Deserialize<'de> for Nullable {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(ValueVisitor).map(Nullable)
+    }
+}
+
+/// Visits any value, producing `None` for nulls.
+struct ValueVisitor;
+
+impl<'de> Visitor<'de> for ValueVisitor {
+    type Value = Option<Value>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a fluentd record value")
+    }
+
+    fn visit_bool<E: de::Error>(self, v: bool) -> Result<Self::Value, E> {
+        Ok(Some(Value::Bool(v)))
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<Self::Value, E> {
+        Ok(Some(Value::Int(v)))
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<Self::Value, E> {
+        Ok(Some(match i64::try_from(v) {
+            Ok(v) => Value::Int(v),
+            Err(_) => Value::Uint(v),
+        }))
+    }
+
+    fn visit_f64<E: de::Error>(self, v: f64) -> Result<Self::Value, E> {
+        Ok(Some(Value::Float(v)))
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+        Ok(Some(Value::Str(v.to_string())))
+    }
+
+    fn visit_string<E: de::Error>(self, v: String) -> Result<Self::Value, E> {
+        Ok(Some(Value::Str(v)))
+    }
+
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Self::Value, E> {
+        Ok(Some(Value::Bytes(v.to_vec())))
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<Self::Value, E> {
+        Ok(Some(Value::Bytes(v)))
+    }
+
+    fn visit_none<E: de::Error>(self) -> Result<Self::Value, E> {
+        Ok(None)
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<Self::Value, E> {
+        Ok(None)
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        Nullable::deserialize(deserializer).map(|value| value.0)
+    }
+
+    /// rmp_serde presents ext types as a newtype of their type and data.
+    fn visit_newtype_struct<D: Deserializer<'de>>(
+        self,
+        deserializer: D,
+    ) -> Result<Self::Value, D::Error> {
+        let (kind, Data(data)) = <(i8, Data)>::deserialize(deserializer)?;
+        match (kind, <[u8; 8]>::try_from(data.as_slice())) {
+            (0, Ok(data)) => {
+                let seconds = u32::from_be_bytes(data[..4].try_into().unwrap());
+                let nanos = u32::from_be_bytes(data[4..].try_into().unwrap());
+                Utc.timestamp_opt(seconds as i64, nanos)
+                    .single()
+                    .map(|time| Some(Value::DateTime(time)))
+                    .ok_or_else(|| de::Error::custom("invalid EventTime"))
+            }
+            _ => Err(de::Error::custom(format!("unsupported ext type {}", kind))),
+        }
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let mut values = Vec::with_capacity(seq.size_hint().unwrap_or(0).min(4096));
+        while let Some(value) = seq.next_element::<Value>()? {
+            values.push(value);
+        }
+        Ok(Some(Value::Array(values)))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let mut values = HashMap::with_capacity(map.size_hint().unwrap_or(0).min(4096));
+        while let Some((key, Nullable(value))) = map.next_entry::<String, Nullable>()? {
+            if let Some(value) = value {
+                values.insert(key, value);
+            }
+        }
+        Ok(Some(Value::Object(Map::new_with(values))))
+    }
+}
+
+/// The data of an ext type.
+struct Data(Vec<u8>);
+
+impl<'de> Deserialize<'de> for Data {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct DataVisitor;
+
+        impl<'de> Visitor<'de> for DataVisitor {
+            type Value = Data;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("ext data")
+            }
+
+            fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Data, E> {
+                Ok(Data(v.to_vec()))
+            }
+        }
+
+        deserializer.deserialize_bytes(DataVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::record_map;
+
+    #[test]
+    fn test_round_trip() {
+        let time = Utc.timestamp_opt(1700000000, 2).unwrap();
+        let map = record_map!(
+            "bool".to_string() => true.into(),
+            "int".to_string() => (-3).into(),
+            "positive".to_string() => 22.into(),
+            "uint".to_string() => u64::MAX.into(),
+            "float".to_string() => 0.5.into(),
+            "str".to_string() => "John".into(),
+            "bytes".to_string() => b"a\xff".as_slice().into(),
+            "time".to_string() => time.into(),
+            "nested".to_string() => record_map!(
+                "array".to_string() => vec![Value::from(1), Value::from("a")].into(),
+            ).into(),
+        );
+        let encoded = rmp_serde::to_vec(&map).unwrap();
+        assert_eq!(rmp_serde::from_slice::<Map>(&encoded).unwrap(), map);
+        // small unsigned integers are not told apart from signed ones
+        let encoded = rmp_serde::to_vec(&Value::from(22u32)).unwrap();
+        assert_eq!(rmp_serde::from_slice::<Value>(&encoded).unwrap(), 22.into());
+    }
+
+    #[test]
+    fn test_null() {
+        let encoded = rmp_serde::to_vec(&BTreeMap::from([("a", None), ("b", Some(1))])).unwrap();
+        assert_eq!(
+            rmp_serde::from_slice::<Map>(&encoded).unwrap(),
+            record_map!("b".to_string() => 1.into())
+        );
+        let encoded = rmp_serde::to_vec(&vec![None, Some(1)]).unwrap();
+        assert!(rmp_serde::from_slice::<Value>(&encoded).is_err());
+        let encoded = rmp_serde::to_vec(&vec![1]).unwrap();
+        assert!(rmp_serde::from_slice::<Map>(&encoded).is_err());
+    }
+}
@@ -0,0 +1,181 @@
+//! Conversion between Values and `serde_json::Value`s, for interop with JSON
+//! tooling.
+//!
+//! `Value` has no nil variant, so nulls are left out wherever they appear, in
+//! objects as in arrays, and a null root becomes an empty object. The other
+//! way, byte strings become strings with invalid UTF-8 sequences replaced,
+//! points in time RFC 3339 strings, and non-finite floats `null`.
+
+use chrono::SecondsFormat;
+
+use super::{Map, Value};
+
+/// Numbers become `Value::Int` when they fit in an `i64`, then `Value::Uint`,
+/// then `Value::Float`.
+impl From<serde_json::Value> for Value {
+    fn from(value: serde_json::Value) -> Self {
+        use serde_json::Value as Json;
+
+        match value {
+            Json::Null => Value::Object(Map::new()),
+            Json::Bool(value) => Value::Bool(value),
+            Json::Number(value) => match (value.as_i64(), value.as_u64()) {
+                (Some(value), _) => Value::Int(value),
+                (None, Some(value)) => Value::Uint(value),
+                (None, None) => Value::Float(value.as_f64().unwrap_or(f64::NAN)),
+            },
+            Json::String(value) => Value::Str(value),
+            Json::Array(values) => Value::Array(
+                values
+                    .into_iter()
+                    .filter(|value| !value.is_null())
+                    .map(Value::from)
+                    .collect(),
+            ),
+            Json::Object(object) => Value::Object(object.into()),
+        }
+    }
+}
+
+impl From<serde_json::Map<String, serde_json::Value>> for Map {
+    fn from(object: serde_json::Map<String, serde_json::Value>) -> Self {
+        object
+            .into_iter()
+            .filter(|(_, value)| !value.is_null())
+            .collect()
+    }
+}
+
+impl Map {
+    /// Convert this Map into a JSON object.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// use tokio_fluent::record::RecordBuilder;
+    ///
+    /// let record = RecordBuilder::new().field("name", "John").field("age", 22).build();
+    /// assert_eq!(record.to_json(), serde_json::json!({"name": "John", "age": 22}));
+    /// ```
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::Value::Object(
+            self.iter()
+                .map(|(key, value)| (key.clone(), value.to_json()))
+                .collect(),
+        )
+    }
+}
+
+impl Value {
+    /// Convert this Value into a JSON value.
+    pub fn to_json(&self) -> serde_json::Value {
+        use serde_json::Value as Json;
+
+        match self {
+            Value::Bool(value) => Json::Bool(*value),
+            Value::Int(value) => Json::from(*value),
+            Value::Uint(value) => Json::from(*value),
+            // non-finite floats become null
+            Value::Float(value) => Json::from(*value),
+            Value::Str(value) => Json::String(value.clone()),
+            Value::Bytes(value) => Json::String(String::from_utf8_lossy(value).into_owned()),
+            Value::DateTime(value) => {
+                Json::String(value.to_rfc3339_opts(SecondsFormat::AutoSi, true))
+            }
+            Value::Object(value) => value.to_json(),
+            Value::Array(values) => Json::Array(values.iter().map(Value::to_json).collect()),
+        }
+    }
+}
+
+/// Return the name of the JSON type of `value`, for error messages.
+pub(crate) fn json_type(value: &serde_json::Value) -> &'static str {
+    match value {
+        serde_json::Value::Null => "null",
+        serde_json::Value::Bool(_) => "a boolean",
+        serde_json::Value::Number(_) => "a number",
+        serde_json::Value::String(_) => "a string",
+        serde_json::Value::Array(_) => "an array",
+        serde_json::Value::Object(_) => "an object",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::record_map;
+
+    #[test]
+    fn test_from_json() {
+        let json = serde_json::json!({
+            "name": "John",
+            "age": 22,
+            "big": u64::MAX,
+            "score": 0.5,
+            "ok": true,
+            "missing": null,
+            "tags": ["a", null, {"nested": [1, -2]}],
+        });
+        let want = record_map!(
+            "name".to_string() => "John".into(),
+            "age".to_string() => 22.into(),
+            "big".to_string() => u64::MAX.into(),
+            "score".to_string() => 0.5.into(),
+            "ok".to_string() => true.into(),
+            "tags".to_string() => vec![
+                Value::from("a"),
+                record_map!(
+                    "nested".to_string() => vec![Value::from(1), Value::from(-2)].into(),
+                ).into(),
+            ].into(),
+        );
+        assert_eq!(Value::from(json), Value::Object(want));
+        assert_eq!(
+            Value::from(serde_json::Value::Null),
+            Value::Object(Map::new())
+        );
+        assert_eq!(json_type(&serde_json::json!([1])), "an array");
+    }
+
+    #[test]
+    fn test_to_json() {
+        let time = chrono::Utc.timestamp_opt(1700000000, 0).unwrap();
+        let map = record_map!(
+            "message".to_string() => "say \"hi\"\n\u{1}".into(),
+            "raw".to_string() => b"a\xff".as_slice().into(),
+            "nested".to_string() => record_map!(
+                "scores".to_string() => vec![Value::from(1), Value::from(-2), Value::from(0.5)].into(),
+            ).into(),
+            "flags".to_string() => vec![Value::from(true), Value::from(f64::NAN)].into(),
+            "at".to_string() => time.into(),
+        );
+        assert_eq!(
+            map.to_json(),
+            serde_json::json!({
+                "at": "2023-11-14T22:13:20Z",
+                "flags": [true, null],
+                "message": "say \"hi\"\n\u{1}",
+                "nested": {"scores": [1, -2, 0.5]},
+                "raw": "a\u{fffd}",
+            })
+        );
+        assert_eq!(Value::from(u64::MAX).to_json(), serde_json::json!(u64::MAX));
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+        let json = serde_json::json!({
+            "name": "John",
+            "big": u64::MAX,
+            "matrix": [[1, 2], [3, [4.5, {"deep": [true, "x"]}]], []],
+            "user": {"address": {"city": "Tokyo", "codes": [100, -1]}, "empty": {}},
+        });
+        let value = Value::from(json.clone());
+        assert_eq!(value.to_json(), json);
+        assert_eq!(Value::from(value.to_json()), value);
+    }
+}
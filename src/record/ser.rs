@@ -0,0 +1,483 @@
+//! A serde Serializer which builds a Value tree.
+//!
+//! `Value` has no nil variant, so `None` and unit values are skipped when
+//! they appear as a map or struct field and rejected anywhere else.
+
+use serde::ser::{self, Serialize};
+
+use super::{Map, Value};
+
+#[derive(Debug, Clone, PartialEq)]
+/// Error returned when a value can not be converted into a `Value`.
+pub struct Error {
+    message: String,
+}
+
+impl Error {
+    fn new<T: std::fmt::Display>(message: T) -> Self {
+        Self {
+            message: message.to_string(),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl ser::Error for Error {
+    fn custom<T: std::fmt::Display>(msg: T) -> Self {
+        Self::new(msg)
+    }
+}
+
+impl Value {
+    /// Build a Value from any type implementing `serde::Serialize`.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// use serde::Serialize;
+    /// use tokio_fluent::record::Value;
+    ///
+    /// #[derive(Serialize)]
+    /// struct User {
+    ///     name: String,
+    ///     age: u32,
+    /// }
+    ///
+    /// let value = Value::from_serialize(&User { name: "John".to_string(), age: 22 }).unwrap();
+    /// match value {
+    ///     Value::Object(map) => assert_eq!(map["age"], Value::from(22u32)),
+    ///     _ => unreachable!(),
+    /// }
+    /// ```
+    pub fn from_serialize<T: Serialize + ?Sized>(value: &T) -> Result<Value, Error> {
+        value
+            .serialize(ValueSerializer)?
+            .ok_or_else(|| Error::new("a value can not be none or unit"))
+    }
+}
+
+impl Map {
+    /// Build a Map from any type implementing `serde::Serialize` which
+    /// serializes as a map or a struct.
+    pub fn from_serialize<T: Serialize + ?Sized>(value: &T) -> Result<Map, Error> {
+        match Value::from_serialize(value)? {
+            Value::Object(map) => Ok(map),
+            _ => Err(Error::new("expected a map or a struct")),
+        }
+    }
+}
+
+fn element<T: Serialize + ?Sized>(value: &T) -> Result<Value, Error> {
+    Value::from_serialize(value)
+}
+
+fn variant(name: &'static str, value: Value) -> Value {
+    let mut map = Map::new();
+    map.insert(name.to_string(), value);
+    Value::Object(map)
+}
+
+/// Serializer producing `None` for values which have no representation.
+struct ValueSerializer;
+
+impl ser::Serializer for ValueSerializer {
+    type Ok = Option<Value>;
+    type Error = Error;
+
+    type SerializeSeq = SerializeVec;
+    type SerializeTuple = SerializeVec;
+    type SerializeTupleStruct = SerializeVec;
+    type SerializeTupleVariant = SerializeTupleVariant;
+    type SerializeMap = SerializeMap;
+    type SerializeStruct = SerializeMap;
+    type SerializeStructVariant = SerializeStructVariant;
+
+    fn serialize_bool(self, v: bool) -> Result<Self::Ok, Error> {
+        Ok(Some(Value::Bool(v)))
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<Self::Ok, Error> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<Self::Ok, Error> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<Self::Ok, Error> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<Self::Ok, Error> {
+        Ok(Some(Value::Int(v)))
+    }
+
+    fn serialize_i128(self, v: i128) -> Result<Self::Ok, Error> {
+        match i64::try_from(v) {
+            Ok(v) => self.serialize_i64(v),
+            Err(_) => self
+                .serialize_u128(u128::try_from(v).map_err(|_| Error::new("i128 is out of range"))?),
+        }
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<Self::Ok, Error> {
+        self.serialize_u64(v as u64)
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<Self::Ok, Error> {
+        self.serialize_u64(v as u64)
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<Self::Ok, Error> {
+        self.serialize_u64(v as u64)
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<Self::Ok, Error> {
+        Ok(Some(Value::Uint(v)))
+    }
+
+    fn serialize_u128(self, v: u128) -> Result<Self::Ok, Error> {
+        let v = u64::try_from(v).map_err(|_| Error::new("u128 is out of range"))?;
+        self.serialize_u64(v)
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<Self::Ok, Error> {
+        self.serialize_f64(v as f64)
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<Self::Ok, Error> {
+        Ok(Some(Value::Float(v)))
+    }
+
+    fn serialize_char(self, v: char) -> Result<Self::Ok, Error> {
+        Ok(Some(Value::Str(v.to_string())))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<Self::Ok, Error> {
+        Ok(Some(Value::Str(v.to_string())))
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<Self::Ok, Error> {
+        Ok(Some(Value::Array(
+            v.iter().map(|&b| Value::Uint(b as u64)).collect(),
+        )))
+    }
+
+    fn serialize_none(self) -> Result<Self::Ok, Error> {
+        Ok(None)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Self::Ok, Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Self::Ok, Error> {
+        Ok(None)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Self::Ok, Error> {
+        Ok(None)
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<Self::Ok, Error> {
+        self.serialize_str(variant)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<Self::Ok, Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant_name: &'static str,
+        value: &T,
+    ) -> Result<Self::Ok, Error> {
+        Ok(Some(variant(variant_name, element(value)?)))
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<Self::SerializeSeq, Error> {
+        Ok(SerializeVec {
+            vec: Vec::with_capacity(len.unwrap_or(0)),
+        })
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<Self::SerializeTuple, Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeTupleStruct, Error> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeTupleVariant, Error> {
+        Ok(SerializeTupleVariant {
+            name: variant,
+            vec: Vec::with_capacity(len),
+        })
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap, Error> {
+        Ok(SerializeMap {
+            map: Map::new(),
+            next_key: None,
+        })
+    }
+
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeStruct, Error> {
+        self.serialize_map(Some(len))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStructVariant, Error> {
+        Ok(SerializeStructVariant {
+            name: variant,
+            map: Map::new(),
+        })
+    }
+}
+
+struct SerializeVec {
+    vec: Vec<Value>,
+}
+
+impl ser::SerializeSeq for SerializeVec {
+    type Ok = Option<Value>;
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.vec.push(element(value)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<Self::Ok, Error> {
+        Ok(Some(Value::Array(self.vec)))
+    }
+}
+
+impl ser::SerializeTuple for SerializeVec {
+    type Ok = Option<Value>;
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<Self::Ok, Error> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+impl ser::SerializeTupleStruct for SerializeVec {
+    type Ok = Option<Value>;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<Self::Ok, Error> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+struct SerializeTupleVariant {
+    name: &'static str,
+    vec: Vec<Value>,
+}
+
+impl ser::SerializeTupleVariant for SerializeTupleVariant {
+    type Ok = Option<Value>;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.vec.push(element(value)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<Self::Ok, Error> {
+        Ok(Some(variant(self.name, Value::Array(self.vec))))
+    }
+}
+
+struct SerializeMap {
+    map: Map,
+    next_key: Option<String>,
+}
+
+impl ser::SerializeMap for SerializeMap {
+    type Ok = Option<Value>;
+    type Error = Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), Error> {
+        let key = match element(key)? {
+            Value::Str(key) => key,
+            Value::Bool(key) => key.to_string(),
+            Value::Int(key) => key.to_string(),
+            Value::Uint(key) => key.to_string(),
+            _ => return Err(Error::new("a map key must be a string, a number or a bool")),
+        };
+        self.next_key = Some(key);
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        let key = self
+            .next_key
+            .take()
+            .ok_or_else(|| Error::new("serialize_value called before serialize_key"))?;
+        if let Some(value) = value.serialize(ValueSerializer)? {
+            self.map.insert(key, value);
+        }
+        Ok(())
+    }
+
+    fn end(self) -> Result<Self::Ok, Error> {
+        Ok(Some(Value::Object(self.map)))
+    }
+}
+
+impl ser::SerializeStruct for SerializeMap {
+    type Ok = Option<Value>;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        if let Some(value) = value.serialize(ValueSerializer)? {
+            self.map.insert(key.to_string(), value);
+        }
+        Ok(())
+    }
+
+    fn end(self) -> Result<Self::Ok, Error> {
+        ser::SerializeMap::end(self)
+    }
+}
+
+struct SerializeStructVariant {
+    name: &'static str,
+    map: Map,
+}
+
+impl ser::SerializeStructVariant for SerializeStructVariant {
+    type Ok = Option<Value>;
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        if let Some(value) = value.serialize(ValueSerializer)? {
+            self.map.insert(key.to_string(), value);
+        }
+        Ok(())
+    }
+
+    fn end(self) -> Result<Self::Ok, Error> {
+        Ok(Some(variant(self.name, Value::Object(self.map))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use serde::Serialize;
+
+    use crate::record::{Map, Value};
+    use crate::record_map;
+
+    #[derive(Serialize)]
+    enum Kind {
+        Login,
+        Retry(u32),
+    }
+
+    #[derive(Serialize)]
+    struct Meta {
+        tags: Vec<&'static str>,
+        kind: Kind,
+        retry: Kind,
+    }
+
+    #[derive(Serialize)]
+    struct Event {
+        user: String,
+        score: f64,
+        comment: Option<String>,
+        meta: Meta,
+    }
+
+    #[test]
+    fn test_map_from_serialize() {
+        let event = Event {
+            user: "John".to_string(),
+            score: 1.5,
+            comment: None,
+            meta: Meta {
+                tags: vec!["a", "b"],
+                kind: Kind::Login,
+                retry: Kind::Retry(3),
+            },
+        };
+        let got = Map::from_serialize(&event).expect("failed to serialize");
+
+        let want = record_map!(
+            "user".to_string() => "John".into(),
+            "score".to_string() => 1.5.into(),
+            "meta".to_string() => record_map!(
+                "tags".to_string() => vec!["a".into(), "b".into()].into(),
+                "kind".to_string() => "Login".into(),
+                "retry".to_string() => record_map!("Retry".to_string() => 3u32.into()).into(),
+            ).into(),
+        );
+        assert_eq!(got, want);
+    }
+
+    #[test]
+    fn test_from_serialize_rejects_non_map() {
+        assert!(Map::from_serialize(&vec![1, 2]).is_err());
+        assert!(Value::from_serialize(&()).is_err());
+        assert_eq!(Value::from_serialize(&10u8), Ok(Value::Uint(10)));
+    }
+}
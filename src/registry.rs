@@ -0,0 +1,107 @@
+//! Process-wide registry of clients keyed by endpoint.
+
+use std::collections::HashMap;
+#[cfg(feature = "tcp")]
+use std::net::SocketAddr;
+#[cfg(all(unix, feature = "unix"))]
+use std::path::PathBuf;
+use std::sync::{Arc, OnceLock};
+
+use anyhow::Result as AnyhowResult;
+use tokio::sync::Mutex;
+
+use crate::client::{Client, Config};
+
+static REGISTRY: OnceLock<Mutex<HashMap<String, Arc<Client>>>> = OnceLock::new();
+
+#[derive(Debug, PartialEq)]
+enum Endpoint {
+    #[cfg(feature = "tcp")]
+    Tcp(SocketAddr),
+    #[cfg(all(unix, feature = "unix"))]
+    Unix(PathBuf),
+}
+
+impl std::str::FromStr for Endpoint {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        #[cfg(feature = "tcp")]
+        if let Some(addr) = s.strip_prefix("tcp://") {
+            return Ok(Endpoint::Tcp(addr.parse()?));
+        }
+        #[cfg(all(unix, feature = "unix"))]
+        if let Some(path) = s.strip_prefix("unix://") {
+            return Ok(Endpoint::Unix(PathBuf::from(path)));
+        }
+        Err(anyhow::anyhow!("unsupported endpoint: {}", s))
+    }
+}
+
+impl Client {
+    /// Return the client connected to `endpoint`, creating it on the first call.
+    ///
+    /// Subsystems of a process calling this with the same endpoint share a single
+    /// connection and worker. `config` is only used when the client is created.
+    /// The endpoint is either `tcp://<ip>:<port>` or `unix://<path>`.
+    ///
+    /// ## Example
+    ///
+    /// ```no_run
+    /// use tokio_fluent::{Client, Config, FluentClient};
+    /// use tokio_fluent::record::Map;
+    ///
+    /// #[tokio::main]
+    /// async fn main() {
+    ///     let client = Client::get_or_init("tcp://127.0.0.1:24224", &Config{..Default::default()})
+    ///         .await
+    ///         .unwrap();
+    ///     client.send("fluent.test", Map::new()).unwrap();
+    /// }
+    /// ```
+    pub async fn get_or_init(endpoint: &str, config: &Config) -> AnyhowResult<Arc<Client>> {
+        let mut clients = REGISTRY
+            .get_or_init(|| Mutex::new(HashMap::new()))
+            .lock()
+            .await;
+        if let Some(client) = clients.get(endpoint) {
+            if !client.is_closed() {
+                return Ok(client.clone());
+            }
+        }
+
+        let client = Arc::new(match endpoint.parse()? {
+            #[cfg(feature = "tcp")]
+            Endpoint::Tcp(addr) => Client::new_tcp(addr, config).await?,
+            #[cfg(all(unix, feature = "unix"))]
+            Endpoint::Unix(path) => Client::new_unix(path, config).await?,
+        });
+        clients.insert(endpoint.to_string(), client.clone());
+        Ok(client)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "tcp")]
+    #[test]
+    fn test_parse_tcp_endpoint() {
+        assert_eq!(
+            "tcp://127.0.0.1:24224".parse::<Endpoint>().unwrap(),
+            Endpoint::Tcp("127.0.0.1:24224".parse().unwrap())
+        );
+        assert!("tcp://localhost".parse::<Endpoint>().is_err());
+        assert!("http://127.0.0.1:24224".parse::<Endpoint>().is_err());
+    }
+
+    #[cfg(all(unix, feature = "unix"))]
+    #[test]
+    fn test_parse_unix_endpoint() {
+        assert_eq!(
+            "unix:///var/run/fluentd.sock".parse::<Endpoint>().unwrap(),
+            Endpoint::Unix(PathBuf::from("/var/run/fluentd.sock"))
+        );
+    }
+}
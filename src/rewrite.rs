@@ -0,0 +1,106 @@
+//! Tag rewriting applied by the worker.
+//!
+//! Like fluentd's `rewrite_tag_filter`, the first rule whose pattern matches a
+//! tag replaces it, and tags matching no rule are left untouched.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use regex::Regex;
+
+/// The maximum number of cached rewrites. Tags beyond it are rewritten on every record.
+const MAX_CACHED_TAGS: usize = 1024;
+
+#[derive(Debug, Clone)]
+/// A rule rewriting the tags matching `pattern`.
+///
+/// ## Example
+///
+/// ```
+/// use tokio_fluent::TagRewriteRule;
+///
+/// // "service-a.access" => "app.service-a"
+/// let rule = TagRewriteRule::new(r"^(service-\w+)\.access$", "app.$1").unwrap();
+/// ```
+pub struct TagRewriteRule {
+    pattern: Regex,
+    replacement: String,
+}
+
+impl TagRewriteRule {
+    /// Create a rule replacing the tags matching `pattern` with `replacement`.
+    ///
+    /// `replacement` may refer to the capture groups of `pattern` as `$1` or `${name}`.
+    pub fn new(pattern: &str, replacement: &str) -> Result<Self, regex::Error> {
+        Ok(Self {
+            pattern: Regex::new(pattern)?,
+            replacement: replacement.to_string(),
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct TagRewriter {
+    rules: Vec<TagRewriteRule>,
+    cache: HashMap<Arc<str>, Arc<str>>,
+}
+
+impl TagRewriter {
+    pub fn new(rules: Vec<TagRewriteRule>) -> Self {
+        Self {
+            rules,
+            cache: HashMap::new(),
+        }
+    }
+
+    /// Return the rewritten `tag`.
+    pub fn rewrite(&mut self, tag: &Arc<str>) -> Arc<str> {
+        if self.rules.is_empty() {
+            return tag.clone();
+        }
+        if let Some(rewritten) = self.cache.get(tag) {
+            return rewritten.clone();
+        }
+
+        let rewritten = self
+            .rules
+            .iter()
+            .find(|rule| rule.pattern.is_match(tag))
+            .map(|rule| Arc::from(rule.pattern.replace(tag, &rule.replacement)))
+            .unwrap_or_else(|| tag.clone());
+        if self.cache.len() < MAX_CACHED_TAGS {
+            self.cache.insert(tag.clone(), rewritten.clone());
+        }
+        rewritten
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rewrite() {
+        let mut rewriter = TagRewriter::new(vec![
+            TagRewriteRule::new(r"^(service-\w+)\.access$", "app.$1").unwrap(),
+            TagRewriteRule::new(r"^service-", "app.other.").unwrap(),
+            TagRewriteRule::new(r"^(service-\w+)\.error$", "never.$1").unwrap(),
+        ]);
+
+        let tag: Arc<str> = Arc::from("service-a.access");
+        assert_eq!(&*rewriter.rewrite(&tag), "app.service-a");
+        // cached
+        assert_eq!(&*rewriter.rewrite(&tag), "app.service-a");
+        // the first matching rule wins
+        assert_eq!(
+            &*rewriter.rewrite(&Arc::from("service-b.error")),
+            "app.other.b.error"
+        );
+        assert_eq!(&*rewriter.rewrite(&Arc::from("system.log")), "system.log");
+    }
+
+    #[test]
+    fn test_invalid_pattern() {
+        assert!(TagRewriteRule::new("(", "x").is_err());
+    }
+}
@@ -0,0 +1,111 @@
+//! Schema drift detection.
+//!
+//! Tracks the type of every top-level field per tag and reports when a field
+//! changes its type or a field appears which was not seen in the first record
+//! of the tag.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::event::{Event, FieldType};
+use crate::record::{Map, Value};
+
+/// The maximum number of tracked tags. Records of other tags are not checked.
+const MAX_TAGS: usize = 1024;
+
+#[derive(Debug, Default)]
+pub struct SchemaTracker {
+    schemas: HashMap<Arc<str>, HashMap<String, FieldType>>,
+}
+
+impl SchemaTracker {
+    /// Record the schema of `record` and return the drifts from the known schema of `tag`.
+    pub fn observe(&mut self, tag: &Arc<str>, record: &Map) -> Vec<Event> {
+        let schema = match self.schemas.get_mut(tag) {
+            Some(schema) => schema,
+            None => {
+                if self.schemas.len() < MAX_TAGS {
+                    let schema = record
+                        .iter()
+                        .map(|(k, v)| (k.clone(), field_type(v)))
+                        .collect();
+                    self.schemas.insert(tag.clone(), schema);
+                }
+                return vec![];
+            }
+        };
+
+        let mut drifts = vec![];
+        for (field, value) in record.iter() {
+            let current = field_type(value);
+            let previous = schema.insert(field.clone(), current);
+            if previous != Some(current) {
+                drifts.push(Event::SchemaDrift {
+                    tag: tag.to_string(),
+                    field: field.clone(),
+                    previous,
+                    current,
+                });
+            }
+        }
+        drifts
+    }
+}
+
+fn field_type(value: &Value) -> FieldType {
+    match value {
+        Value::Bool(_) => FieldType::Bool,
+        Value::Int(_) | Value::Uint(_) => FieldType::Integer,
+        Value::Float(_) => FieldType::Float,
+        Value::Str(_) => FieldType::String,
+        Value::Bytes(_) => FieldType::Bytes,
+        Value::DateTime(_) => FieldType::DateTime,
+        Value::Object(_) => FieldType::Object,
+        Value::Array(_) => FieldType::Array,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::record_map;
+
+    #[test]
+    fn test_schema_tracker() {
+        let tag: Arc<str> = Arc::from("test");
+        let mut tracker = SchemaTracker::default();
+
+        let record = record_map!("age".to_string() => 20.into());
+        assert!(tracker.observe(&tag, &record).is_empty());
+        // signed and unsigned integers are the same type
+        let record = record_map!("age".to_string() => 20u32.into());
+        assert!(tracker.observe(&tag, &record).is_empty());
+
+        let record = record_map!(
+            "age".to_string() => "20".into(),
+            "name".to_string() => "John".into(),
+        );
+        let mut got = tracker.observe(&tag, &record);
+        got.sort_by_key(|e| format!("{:?}", e));
+        assert_eq!(
+            got,
+            vec![
+                Event::SchemaDrift {
+                    tag: "test".to_string(),
+                    field: "age".to_string(),
+                    previous: Some(FieldType::Integer),
+                    current: FieldType::String,
+                },
+                Event::SchemaDrift {
+                    tag: "test".to_string(),
+                    field: "name".to_string(),
+                    previous: None,
+                    current: FieldType::String,
+                },
+            ]
+        );
+        assert!(tracker.observe(&tag, &record).is_empty());
+    }
+}
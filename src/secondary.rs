@@ -0,0 +1,156 @@
+//! A local file where chunks dropped after `max_retry` attempts are appended,
+//! like the `<secondary>` output of fluentd.
+//!
+//! In `SecondaryFormat::Msgpack`, chunks are appended as they would have been
+//! sent, so that the file can be replayed with `fluent-cat --format msgpack`.
+//! In `SecondaryFormat::Json`, of the `json` feature, every record is a line
+//! of JSON with its tag, time and record.
+//!
+//! The file is rotated to `<path>.1`, replacing the previous one, before an
+//! append would grow it beyond the size limit.
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+
+use crate::client::SecondaryFormat;
+#[cfg(feature = "json")]
+use crate::dead_letter;
+#[cfg(feature = "json")]
+use crate::record::{Map, Value};
+
+#[derive(Debug)]
+pub struct Secondary {
+    path: PathBuf,
+    format: SecondaryFormat,
+    /// The size beyond which the file is rotated, or 0 for no limit.
+    max_bytes: u64,
+    /// The file, opened upon the first append.
+    file: Option<File>,
+    size: u64,
+}
+
+impl Secondary {
+    pub fn new(path: impl AsRef<Path>, format: SecondaryFormat, max_bytes: u64) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            format,
+            max_bytes,
+            file: None,
+            size: 0,
+        }
+    }
+
+    /// Append the records of a Message, Forward or PackedForward mode message.
+    pub async fn append(&mut self, message: &[u8]) -> io::Result<()> {
+        let contents = match self.format {
+            SecondaryFormat::Msgpack => message.to_vec(),
+            #[cfg(feature = "json")]
+            SecondaryFormat::Json => to_json_lines(message)?,
+        };
+        if self.file.is_none() {
+            self.open().await?;
+        }
+        if self.max_bytes > 0 && self.size > 0 && self.size + contents.len() as u64 > self.max_bytes
+        {
+            self.rotate().await?;
+        }
+        let file = self.file.as_mut().expect("the file is open");
+        file.write_all(&contents).await?;
+        file.flush().await?;
+        self.size += contents.len() as u64;
+        Ok(())
+    }
+
+    async fn open(&mut self) -> io::Result<()> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await?;
+        self.size = file.metadata().await?.len();
+        self.file = Some(file);
+        Ok(())
+    }
+
+    async fn rotate(&mut self) -> io::Result<()> {
+        self.file = None;
+        let mut rotated = self.path.clone().into_os_string();
+        rotated.push(".1");
+        tokio::fs::rename(&self.path, rotated).await?;
+        self.open().await
+    }
+}
+
+#[cfg(feature = "json")]
+fn to_json_lines(message: &[u8]) -> io::Result<Vec<u8>> {
+    let letters =
+        dead_letter::decode(message).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let mut lines = String::new();
+    for letter in letters {
+        let mut line = Map::new();
+        line.insert("tag".to_string(), letter.tag.into());
+        line.insert("time".to_string(), letter.time.into());
+        line.insert("record".to_string(), Value::Object(letter.record));
+        lines.push_str(&line.to_json().to_string());
+        lines.push('\n');
+    }
+    Ok(lines.into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path() -> PathBuf {
+        std::env::temp_dir().join(format!("tokio-fluent-secondary-{}", uuid::Uuid::new_v4()))
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn test_json() {
+        let path = temp_path();
+        let mut secondary = Secondary::new(&path, SecondaryFormat::Json, 0);
+        let mut record = Map::new();
+        record.insert("age".to_string(), 22.into());
+        let message = rmp_serde::to_vec(&("test", vec![(1, &record), (2, &record)])).unwrap();
+        secondary.append(&message).await.unwrap();
+        assert_eq!(
+            tokio::fs::read_to_string(&path).await.unwrap(),
+            concat!(
+                r#"{"record":{"age":22},"tag":"test","time":"1970-01-01T00:00:01Z"}"#,
+                "\n",
+                r#"{"record":{"age":22},"tag":"test","time":"1970-01-01T00:00:02Z"}"#,
+                "\n",
+            )
+        );
+        assert!(secondary.append(b"\x01").await.is_err());
+
+        tokio::fs::remove_file(&path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_rotation() {
+        let path = temp_path();
+        let mut rotated = path.clone().into_os_string();
+        rotated.push(".1");
+        let mut secondary = Secondary::new(&path, SecondaryFormat::Msgpack, 10);
+        secondary.append(b"first").await.unwrap();
+        secondary.append(b"six").await.unwrap();
+        secondary.append(b"second").await.unwrap();
+        assert_eq!(tokio::fs::read(&path).await.unwrap(), b"second");
+        assert_eq!(tokio::fs::read(&rotated).await.unwrap(), b"firstsix");
+
+        // the size of an existing file counts
+        drop(secondary);
+        let mut secondary = Secondary::new(&path, SecondaryFormat::Msgpack, 10);
+        secondary.append(b"third").await.unwrap();
+        assert_eq!(tokio::fs::read(&path).await.unwrap(), b"third");
+        assert_eq!(tokio::fs::read(&rotated).await.unwrap(), b"second");
+
+        tokio::fs::remove_file(&path).await.unwrap();
+        tokio::fs::remove_file(&rotated).await.unwrap();
+    }
+}
@@ -0,0 +1,292 @@
+//! Server selection strategies.
+//!
+//! A [`ServerSelector`] chooses which of the servers of `Config::servers` is
+//! used next, given the health and latency stats of every server. Set it as
+//! `Config::selector` for `Client::new_tcp_servers`.
+//! Built-in strategies are [`RoundRobin`], [`Weighted`] and [`LowestLatency`];
+//! implement the trait to provide your own (e.g. zone-aware) selection.
+//!
+//! ## Example
+//!
+//! ```no_run
+//! use std::sync::{Arc, Mutex};
+//!
+//! use tokio_fluent::selector::Weighted;
+//! use tokio_fluent::{Client, Config, Server};
+//!
+//! #[tokio::main]
+//! async fn main() {
+//!     let config = Config {
+//!         servers: vec![
+//!             Server { host: "fluentd-a:24224".to_string(), standby: false, weight: 3 },
+//!             Server { host: "fluentd-b:24224".to_string(), standby: false, weight: 1 },
+//!         ],
+//!         load_balance: true,
+//!         selector: Some(Arc::new(Mutex::new(Weighted::new()))),
+//!         ..Default::default()
+//!     };
+//!     let client = Client::new_tcp_servers(&config).await.unwrap();
+//! }
+//! ```
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+#[derive(Debug, Clone, PartialEq)]
+/// Stats of a server passed to a selector.
+pub struct ServerStats {
+    /// Whether the server is considered healthy.
+    pub healthy: bool,
+    /// The weight of the server, `Server::weight`. Only used by weighted strategies.
+    pub weight: u32,
+    /// The recently observed latency of the server, if any. Clients record
+    /// the latencies of connections and acks with `observe_latency`.
+    pub latency: Option<Duration>,
+    /// The number of consecutive failed connections to the server.
+    pub failures: u32,
+}
+
+impl Default for ServerStats {
+    fn default() -> Self {
+        Self {
+            healthy: true,
+            weight: 1,
+            latency: None,
+            failures: 0,
+        }
+    }
+}
+
+/// The weight of a new latency sample in the moving average of `ServerStats::observe_latency`.
+const LATENCY_SMOOTHING: f64 = 0.3;
+
+impl ServerStats {
+    /// Add a connect or ack latency sample to the exponentially weighted
+    /// moving average in `latency`, so that a single outlier does not flip
+    /// the preference of latency-based selectors.
+    pub fn observe_latency(&mut self, sample: Duration) {
+        self.latency = Some(match self.latency {
+            Some(latency) => {
+                latency.mul_f64(1.0 - LATENCY_SMOOTHING) + sample.mul_f64(LATENCY_SMOOTHING)
+            }
+            None => sample,
+        });
+    }
+}
+
+/// A selector shared by the workers of a client.
+pub type SharedSelector = Arc<Mutex<dyn ServerSelector>>;
+
+/// Choose the next server to use.
+pub trait ServerSelector: std::fmt::Debug + Send + Sync {
+    /// Return the index of the server to use next, or `None` if `servers` is empty.
+    ///
+    /// `servers` is given in configuration order.
+    fn select(&mut self, servers: &[ServerStats]) -> Option<usize>;
+}
+
+/// Return the indexes of the healthy servers, or of all servers if none is healthy.
+fn candidates(servers: &[ServerStats]) -> Vec<usize> {
+    let healthy: Vec<usize> = (0..servers.len()).filter(|&i| servers[i].healthy).collect();
+    if healthy.is_empty() {
+        (0..servers.len()).collect()
+    } else {
+        healthy
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+/// Use healthy servers in turn.
+pub struct RoundRobin {
+    next: usize,
+}
+
+impl RoundRobin {
+    /// Create a round-robin selector.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl ServerSelector for RoundRobin {
+    fn select(&mut self, servers: &[ServerStats]) -> Option<usize> {
+        let candidates = candidates(servers);
+        if candidates.is_empty() {
+            return None;
+        }
+        let chosen = candidates
+            .iter()
+            .copied()
+            .find(|&i| i >= self.next)
+            .unwrap_or(candidates[0]);
+        self.next = chosen + 1;
+        Some(chosen)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+/// Use healthy servers in proportion to their weights.
+///
+/// This is the smooth weighted round-robin used by nginx, which spreads
+/// the picks of a heavy server instead of choosing it several times in a row.
+pub struct Weighted {
+    current: Vec<i64>,
+}
+
+impl Weighted {
+    /// Create a weighted selector.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl ServerSelector for Weighted {
+    fn select(&mut self, servers: &[ServerStats]) -> Option<usize> {
+        let candidates = candidates(servers);
+        if candidates.is_empty() {
+            return None;
+        }
+        self.current.resize(servers.len(), 0);
+
+        let mut total = 0;
+        let mut chosen = candidates[0];
+        for &i in candidates.iter() {
+            let weight = servers[i].weight as i64;
+            self.current[i] += weight;
+            total += weight;
+            if self.current[i] > self.current[chosen] {
+                chosen = i;
+            }
+        }
+        self.current[chosen] -= total;
+        Some(chosen)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+/// Use the healthy server with the lowest latency.
+///
+/// Servers without observed latency are chosen first so that they get measured.
+/// With hysteresis, the selector keeps using its current server until another
+/// one is faster by the given ratio, to avoid flapping between servers of
+/// similar latency.
+pub struct LowestLatency {
+    hysteresis: f64,
+    current: Option<usize>,
+}
+
+impl LowestLatency {
+    /// Create a lowest-latency selector.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a lowest-latency selector which only switches servers when another
+    /// server is faster by `ratio`, e.g. 0.2 for 20%.
+    pub fn with_hysteresis(ratio: f64) -> Self {
+        Self {
+            hysteresis: ratio.max(0.0),
+            current: None,
+        }
+    }
+}
+
+impl ServerSelector for LowestLatency {
+    fn select(&mut self, servers: &[ServerStats]) -> Option<usize> {
+        let candidates = candidates(servers);
+        let latency = |i: usize| servers[i].latency.unwrap_or(Duration::ZERO);
+        let best = candidates.iter().copied().min_by_key(|&i| latency(i))?;
+
+        let chosen = match self.current {
+            Some(current)
+                if candidates.contains(&current)
+                    && latency(best).mul_f64(1.0 + self.hysteresis) >= latency(current) =>
+            {
+                current
+            }
+            _ => best,
+        };
+        self.current = Some(chosen);
+        Some(chosen)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats(healthy: bool, weight: u32, latency: Option<u64>) -> ServerStats {
+        ServerStats {
+            healthy,
+            weight,
+            latency: latency.map(Duration::from_millis),
+            failures: 0,
+        }
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let servers = vec![
+            stats(true, 1, None),
+            stats(false, 1, None),
+            stats(true, 1, None),
+        ];
+        let mut selector = RoundRobin::new();
+        let got: Vec<_> = (0..4).map(|_| selector.select(&servers).unwrap()).collect();
+        assert_eq!(got, vec![0, 2, 0, 2]);
+        assert_eq!(selector.select(&[]), None);
+    }
+
+    #[test]
+    fn test_weighted() {
+        let servers = vec![stats(true, 3, None), stats(true, 1, None)];
+        let mut selector = Weighted::new();
+        let got: Vec<_> = (0..4).map(|_| selector.select(&servers).unwrap()).collect();
+        assert_eq!(got, vec![0, 0, 1, 0]);
+    }
+
+    #[test]
+    fn test_lowest_latency() {
+        let mut selector = LowestLatency::new();
+        let servers = vec![
+            stats(true, 1, Some(30)),
+            stats(true, 1, Some(10)),
+            stats(false, 1, Some(1)),
+        ];
+        assert_eq!(selector.select(&servers), Some(1));
+
+        let servers = vec![stats(true, 1, Some(30)), stats(true, 1, None)];
+        assert_eq!(selector.select(&servers), Some(1));
+
+        // fall back to unhealthy servers when none is healthy
+        let servers = vec![stats(false, 1, Some(30)), stats(false, 1, Some(10))];
+        assert_eq!(selector.select(&servers), Some(1));
+    }
+
+    #[test]
+    fn test_lowest_latency_with_hysteresis() {
+        let mut selector = LowestLatency::with_hysteresis(0.5);
+        let servers = vec![stats(true, 1, Some(10)), stats(true, 1, Some(12))];
+        assert_eq!(selector.select(&servers), Some(0));
+
+        // 1 is faster, but not by 50%
+        let servers = vec![stats(true, 1, Some(14)), stats(true, 1, Some(12))];
+        assert_eq!(selector.select(&servers), Some(0));
+
+        let servers = vec![stats(true, 1, Some(20)), stats(true, 1, Some(12))];
+        assert_eq!(selector.select(&servers), Some(1));
+
+        // switch away from the current server when it becomes unhealthy
+        let servers = vec![stats(true, 1, Some(10)), stats(false, 1, Some(12))];
+        assert_eq!(selector.select(&servers), Some(0));
+    }
+
+    #[test]
+    fn test_observe_latency() {
+        let mut stats = ServerStats::default();
+        stats.observe_latency(Duration::from_millis(100));
+        assert_eq!(stats.latency, Some(Duration::from_millis(100)));
+        stats.observe_latency(Duration::from_millis(200));
+        assert_eq!(stats.latency, Some(Duration::from_millis(130)));
+    }
+}
@@ -0,0 +1,69 @@
+//! Shutdown integrated with OS signals.
+//!
+//! Kubernetes and most process supervisors send SIGTERM before killing a
+//! process. Records still queued in the client are lost if the process exits
+//! right away, so this helper waits for the worker to write them out.
+//!
+//! ## Example
+//!
+//! ```no_run
+//! use std::time::Duration;
+//!
+//! use tokio_fluent::{Client, Config, FluentClient};
+//!
+//! #[tokio::main]
+//! async fn main() {
+//!     let client = Client::new_tcp(
+//!         "127.0.0.1:24224".parse().unwrap(),
+//!         &Config{..Default::default()},
+//!     )
+//!     .await
+//!     .unwrap();
+//!
+//!     // ... hand clones of the client to the application ...
+//!
+//!     tokio_fluent::shutdown::on_signal(client, Duration::from_secs(5))
+//!         .await
+//!         .unwrap();
+//! }
+//! ```
+
+use std::time::Duration;
+
+use anyhow::Result as AnyhowResult;
+
+use crate::client::{Client, FluentClient};
+
+/// Wait for SIGTERM or SIGINT, then stop the client and wait up to `timeout`
+/// for the worker to write out the records queued before the signal.
+pub async fn on_signal(client: Client, timeout: Duration) -> AnyhowResult<()> {
+    wait_for_signal().await?;
+    close(client, timeout).await
+}
+
+/// Stop the client and wait up to `timeout` for the worker to exit.
+pub async fn close(client: Client, timeout: Duration) -> AnyhowResult<()> {
+    let closer = client.clone();
+    client.stop()?;
+    tokio::time::timeout(timeout, closer.closed())
+        .await
+        .map_err(|_| anyhow::anyhow!("timed out waiting for the worker to exit"))
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() -> AnyhowResult<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        _ = terminate.recv() => {}
+        result = tokio::signal::ctrl_c() => result?,
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() -> AnyhowResult<()> {
+    tokio::signal::ctrl_c().await?;
+    Ok(())
+}
@@ -0,0 +1,251 @@
+//! A minimal SOCKS5 client (RFC 1928) with username/password authentication (RFC 1929).
+
+use std::net::SocketAddr;
+
+use anyhow::Result as AnyhowResult;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+const VERSION: u8 = 5;
+const NO_AUTH: u8 = 0;
+const USERNAME_PASSWORD: u8 = 2;
+const NO_ACCEPTABLE_METHODS: u8 = 0xff;
+const CONNECT: u8 = 1;
+const IPV4: u8 = 1;
+const DOMAIN_NAME: u8 = 3;
+const IPV6: u8 = 4;
+
+/// The destination of a connection through the proxy.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Target<'a> {
+    Addr(SocketAddr),
+    /// A hostname resolved by the proxy, and a port.
+    Host(&'a str, u16),
+}
+
+impl<'a> Target<'a> {
+    /// Parse a "hostname:port".
+    pub fn parse(host: &'a str) -> AnyhowResult<Self> {
+        let (name, port) = host
+            .rsplit_once(':')
+            .ok_or_else(|| anyhow::anyhow!("missing port in {}", host))?;
+        let name = name.trim_start_matches('[').trim_end_matches(']');
+        let port = port.parse()?;
+        Ok(match name.parse() {
+            Ok(ip) => Target::Addr(SocketAddr::new(ip, port)),
+            Err(_) => Target::Host(name, port),
+        })
+    }
+}
+
+impl std::fmt::Display for Target<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            Target::Addr(addr) => write!(f, "{}", addr),
+            Target::Host(name, port) => write!(f, "{}:{}", name, port),
+        }
+    }
+}
+
+/// Ask the proxy on `stream` to connect to `target`, authenticating with
+/// `credentials` if given. The stream is tunneled to the target on success.
+pub async fn connect<S>(
+    stream: &mut S,
+    target: Target<'_>,
+    credentials: Option<(&str, &str)>,
+) -> AnyhowResult<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let method = if credentials.is_some() {
+        USERNAME_PASSWORD
+    } else {
+        NO_AUTH
+    };
+    stream.write_all(&[VERSION, 1, method]).await?;
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != VERSION {
+        return Err(anyhow::anyhow!("unsupported SOCKS version: {}", reply[0]));
+    }
+    match (reply[1], credentials) {
+        (NO_AUTH, _) => {}
+        (USERNAME_PASSWORD, Some((username, password))) => {
+            authenticate(stream, username, password).await?
+        }
+        (NO_ACCEPTABLE_METHODS, _) => {
+            return Err(anyhow::anyhow!(
+                "the proxy accepts no offered authentication method"
+            ))
+        }
+        (method, _) => {
+            return Err(anyhow::anyhow!(
+                "unexpected authentication method: {}",
+                method
+            ))
+        }
+    }
+
+    let mut request = vec![VERSION, CONNECT, 0];
+    let port = match target {
+        Target::Addr(SocketAddr::V4(addr)) => {
+            request.push(IPV4);
+            request.extend_from_slice(&addr.ip().octets());
+            addr.port()
+        }
+        Target::Addr(SocketAddr::V6(addr)) => {
+            request.push(IPV6);
+            request.extend_from_slice(&addr.ip().octets());
+            addr.port()
+        }
+        Target::Host(name, port) => {
+            request.push(DOMAIN_NAME);
+            request.push(field_len(name)?);
+            request.extend_from_slice(name.as_bytes());
+            port
+        }
+    };
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut reply = [0; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[1] != 0 {
+        return Err(anyhow::anyhow!(
+            "the proxy failed to connect: {}",
+            reply_message(reply[1])
+        ));
+    }
+    // skip the bound address and port
+    let len = match reply[3] {
+        IPV4 => 4,
+        IPV6 => 16,
+        DOMAIN_NAME => stream.read_u8().await? as usize,
+        kind => return Err(anyhow::anyhow!("unexpected address type: {}", kind)),
+    };
+    let mut bound = vec![0; len + 2];
+    stream.read_exact(&mut bound).await?;
+    Ok(())
+}
+
+async fn authenticate<S>(stream: &mut S, username: &str, password: &str) -> AnyhowResult<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut request = vec![1, field_len(username)?];
+    request.extend_from_slice(username.as_bytes());
+    request.push(field_len(password)?);
+    request.extend_from_slice(password.as_bytes());
+    stream.write_all(&request).await?;
+
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply).await?;
+    if reply[1] != 0 {
+        return Err(anyhow::anyhow!("the proxy rejected the credentials"));
+    }
+    Ok(())
+}
+
+fn field_len(field: &str) -> AnyhowResult<u8> {
+    u8::try_from(field.len()).map_err(|_| anyhow::anyhow!("too long for SOCKS5: {}", field))
+}
+
+fn reply_message(code: u8) -> &'static str {
+    match code {
+        1 => "general failure",
+        2 => "connection not allowed by ruleset",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_target() {
+        assert_eq!(
+            Target::parse("fluentd:24224").unwrap(),
+            Target::Host("fluentd", 24224)
+        );
+        assert_eq!(
+            Target::parse("127.0.0.1:24224").unwrap(),
+            Target::Addr("127.0.0.1:24224".parse().unwrap())
+        );
+        assert_eq!(
+            Target::parse("[::1]:24224").unwrap(),
+            Target::Addr("[::1]:24224".parse().unwrap())
+        );
+        assert!(Target::parse("fluentd").is_err());
+
+        for host in ["fluentd:24224", "127.0.0.1:24224", "[::1]:24224"] {
+            assert_eq!(Target::parse(host).unwrap().to_string(), host);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connect() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let proxy = tokio::spawn(async move {
+            let mut greeting = [0; 3];
+            server.read_exact(&mut greeting).await.unwrap();
+            assert_eq!(greeting, [VERSION, 1, USERNAME_PASSWORD]);
+            server
+                .write_all(&[VERSION, USERNAME_PASSWORD])
+                .await
+                .unwrap();
+
+            let mut auth = [0; 11];
+            server.read_exact(&mut auth).await.unwrap();
+            assert_eq!(&auth, b"\x01\x04user\x04pass");
+            server.write_all(&[1, 0]).await.unwrap();
+
+            let mut request = [0; 14];
+            server.read_exact(&mut request).await.unwrap();
+            assert_eq!(&request, b"\x05\x01\x00\x03\x07fluentd\x5e\xa0");
+            server
+                .write_all(&[VERSION, 0, 0, IPV4, 10, 0, 0, 1, 0x5e, 0xa0])
+                .await
+                .unwrap();
+
+            let mut payload = [0; 4];
+            server.read_exact(&mut payload).await.unwrap();
+            assert_eq!(&payload, b"ping");
+        });
+
+        connect(
+            &mut client,
+            Target::Host("fluentd", 24224),
+            Some(("user", "pass")),
+        )
+        .await
+        .unwrap();
+        client.write_all(b"ping").await.unwrap();
+        proxy.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_connect_refused() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        tokio::spawn(async move {
+            let mut greeting = [0; 3];
+            server.read_exact(&mut greeting).await.unwrap();
+            server.write_all(&[VERSION, NO_AUTH]).await.unwrap();
+            let mut request = [0; 10];
+            server.read_exact(&mut request).await.unwrap();
+            server
+                .write_all(&[VERSION, 5, 0, IPV4, 0, 0, 0, 0, 0, 0])
+                .await
+                .unwrap();
+        });
+
+        let target = Target::Addr("10.0.0.1:24224".parse().unwrap());
+        let err = connect(&mut client, target, None).await.unwrap_err();
+        assert!(err.to_string().contains("connection refused"));
+    }
+}
@@ -0,0 +1,447 @@
+//! A directory of chunks which could not be delivered, kept on disk until the
+//! server recovers.
+//!
+//! Chunks are grouped into one subdirectory per hour, named `YYYYMMDDHH` in
+//! UTC, so that a long outage does not pile up in a single directory and
+//! operators can tell the age of chunks at a glance. Each chunk is a file
+//! named after a sequence number, so that chunks are replayed in the order
+//! they were spooled. A file holds the chunk id, a space, the CRC-32 of the
+//! message in hex, a newline and the encoded message. Chunks whose checksum
+//! does not match are renamed with a `.corrupt` extension instead of being
+//! replayed, so that a torn or damaged file never reaches the server. Files
+//! written by older versions carry no checksum and are replayed as is.
+//!
+//! The oldest chunks are removed when the spool grows beyond its size limit,
+//! or when their hour is older than the age limit.
+
+use std::collections::VecDeque;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use bytes::Bytes;
+use chrono::{DateTime, NaiveDateTime, Utc};
+
+const EXTENSION: &str = "chunk";
+const CORRUPT_EXTENSION: &str = "corrupt";
+const PARTITION_FORMAT: &str = "%Y%m%d%H";
+
+/// The limits beyond which the oldest chunks are removed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Limits {
+    /// The total size of the chunks in bytes, or 0 for no limit.
+    pub max_bytes: u64,
+    /// The age of the chunks, or zero for no limit.
+    pub max_age: Duration,
+}
+
+#[derive(Debug)]
+struct Chunk {
+    seq: u64,
+    /// The hour the chunk was spooled in, or empty for chunks spooled at the
+    /// top of the directory by older versions.
+    partition: String,
+    size: u64,
+}
+
+#[derive(Debug)]
+pub struct Spool {
+    dir: PathBuf,
+    limits: Limits,
+    /// The spooled chunks, oldest first.
+    pending: VecDeque<Chunk>,
+    /// The total size of the spooled chunks.
+    bytes: u64,
+    next: u64,
+}
+
+impl Spool {
+    /// Open the spool in `dir`, creating the directory if needed. Chunks left
+    /// by a previous process are kept pending.
+    pub async fn open(dir: impl AsRef<Path>, limits: Limits) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        tokio::fs::create_dir_all(&dir).await?;
+
+        let mut pending = Vec::new();
+        scan(&dir, "", &mut pending).await?;
+        let mut entries = tokio::fs::read_dir(&dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name();
+            let Some(partition) = name.to_str() else {
+                continue;
+            };
+            if entry.file_type().await?.is_dir() && partition_start(partition).is_some() {
+                scan(&entry.path(), partition, &mut pending).await?;
+            }
+        }
+        pending.sort_unstable_by_key(|chunk| chunk.seq);
+        let next = pending.last().map_or(0, |chunk| chunk.seq + 1);
+        Ok(Self {
+            dir,
+            limits,
+            bytes: pending.iter().map(|chunk| chunk.size).sum(),
+            pending: pending.into(),
+            next,
+        })
+    }
+
+    /// Return true if no chunk is waiting to be replayed.
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Return the number of chunks waiting to be replayed.
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Append a chunk, then remove the oldest chunks beyond the limits. The
+    /// file is written under a temporary name and renamed, so that a crash
+    /// never leaves a partial chunk behind.
+    ///
+    /// Return the number of chunks removed.
+    pub async fn push(&mut self, chunk: &str, message: &[u8]) -> io::Result<usize> {
+        self.push_at(Utc::now(), chunk, message).await
+    }
+
+    async fn push_at(
+        &mut self,
+        now: DateTime<Utc>,
+        chunk: &str,
+        message: &[u8],
+    ) -> io::Result<usize> {
+        let seq = self.next;
+        let partition = now.format(PARTITION_FORMAT).to_string();
+        tokio::fs::create_dir_all(self.dir.join(&partition)).await?;
+        let header = format!("{} {:08x}\n", chunk, crc32(message));
+        let spooled = Chunk {
+            seq,
+            partition,
+            size: (header.len() + message.len()) as u64,
+        };
+        let path = self.path(&spooled);
+        let tmp = path.with_extension("tmp");
+        let mut contents = Vec::with_capacity(spooled.size as usize);
+        contents.extend_from_slice(header.as_bytes());
+        contents.extend_from_slice(message);
+        tokio::fs::write(&tmp, contents).await?;
+        tokio::fs::rename(&tmp, &path).await?;
+
+        self.next += 1;
+        self.bytes += spooled.size;
+        self.pending.push_back(spooled);
+        self.prune_at(now).await
+    }
+
+    /// Remove the oldest chunks beyond the limits, returning how many were
+    /// removed. The newest chunk is always kept.
+    pub async fn prune(&mut self) -> io::Result<usize> {
+        self.prune_at(Utc::now()).await
+    }
+
+    async fn prune_at(&mut self, now: DateTime<Utc>) -> io::Result<usize> {
+        let mut removed = 0;
+        while self.pending.len() > 1 && self.exceeds_limits(&self.pending[0], now) {
+            self.pop().await?;
+            removed += 1;
+        }
+        Ok(removed)
+    }
+
+    fn exceeds_limits(&self, oldest: &Chunk, now: DateTime<Utc>) -> bool {
+        if self.limits.max_bytes > 0 && self.bytes > self.limits.max_bytes {
+            return true;
+        }
+        if self.limits.max_age.is_zero() {
+            return false;
+        }
+        // a chunk is as old as the end of its hour, to never remove it early
+        match partition_start(&oldest.partition) {
+            Some(start) => start + chrono::Duration::hours(1) + self.limits.max_age <= now,
+            None => false,
+        }
+    }
+
+    /// Read the oldest chunk, returning its id and message. A chunk whose
+    /// checksum does not match fails with [`io::ErrorKind::InvalidData`], and
+    /// should be moved aside with [`Spool::quarantine`].
+    pub async fn front(&self) -> io::Result<Option<(String, Bytes)>> {
+        let Some(chunk) = self.pending.front() else {
+            return Ok(None);
+        };
+        let contents = tokio::fs::read(self.path(chunk)).await?;
+        let newline = contents
+            .iter()
+            .position(|&b| b == b'\n')
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "missing chunk id"))?;
+        let header = std::str::from_utf8(&contents[..newline])
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let message = &contents[newline + 1..];
+        // chunks spooled by older versions have no checksum
+        let chunk = match header.split_once(' ') {
+            Some((chunk, checksum)) => {
+                if u32::from_str_radix(checksum, 16).ok() != Some(crc32(message)) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("checksum mismatch in chunk {}", chunk),
+                    ));
+                }
+                chunk.to_string()
+            }
+            None => header.to_string(),
+        };
+        let message = Bytes::from(contents).slice(newline + 1..);
+        Ok(Some((chunk, message)))
+    }
+
+    /// Remove the oldest chunk, and its hour directory once it is empty. The
+    /// chunk is no longer pending even if its file could not be removed.
+    pub async fn pop(&mut self) -> io::Result<()> {
+        self.remove_front(false).await
+    }
+
+    /// Stop replaying the oldest chunk, keeping its file with a `.corrupt`
+    /// extension for inspection. Return the path it was moved to. The chunk
+    /// is no longer pending even if its file could not be renamed.
+    pub async fn quarantine(&mut self) -> io::Result<Option<PathBuf>> {
+        let Some(chunk) = self.pending.front() else {
+            return Ok(None);
+        };
+        let path = self.path(chunk).with_extension(CORRUPT_EXTENSION);
+        self.remove_front(true).await?;
+        Ok(Some(path))
+    }
+
+    async fn remove_front(&mut self, quarantine: bool) -> io::Result<()> {
+        let Some(chunk) = self.pending.pop_front() else {
+            return Ok(());
+        };
+        self.bytes -= chunk.size;
+        let path = self.path(&chunk);
+        if quarantine {
+            tokio::fs::rename(&path, path.with_extension(CORRUPT_EXTENSION)).await?;
+        } else {
+            tokio::fs::remove_file(&path).await?;
+        }
+        let emptied = !chunk.partition.is_empty()
+            && self
+                .pending
+                .front()
+                .map_or(true, |next| next.partition != chunk.partition);
+        if emptied {
+            // leftover temporary and corrupt files keep the directory, which is
+            // harmless
+            let _ = tokio::fs::remove_dir(self.dir.join(&chunk.partition)).await;
+        }
+        Ok(())
+    }
+
+    fn path(&self, chunk: &Chunk) -> PathBuf {
+        self.dir
+            .join(&chunk.partition)
+            .join(format!("{:016x}.{}", chunk.seq, EXTENSION))
+    }
+}
+
+/// Collect the chunks in `dir`, which holds the chunks of `partition`.
+async fn scan(dir: &Path, partition: &str, chunks: &mut Vec<Chunk>) -> io::Result<()> {
+    let mut entries = tokio::fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if path.extension().and_then(|e| e.to_str()) != Some(EXTENSION) {
+            continue;
+        }
+        let seq = path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .and_then(|s| u64::from_str_radix(s, 16).ok());
+        if let Some(seq) = seq {
+            chunks.push(Chunk {
+                seq,
+                partition: partition.to_string(),
+                size: entry.metadata().await?.len(),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Return the CRC-32 (IEEE) of `data`.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in data {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+/// Return the start of the hour named by `partition`.
+fn partition_start(partition: &str) -> Option<DateTime<Utc>> {
+    if partition.len() != 10 {
+        return None;
+    }
+    NaiveDateTime::parse_from_str(&format!("{}00", partition), "%Y%m%d%H%M")
+        .ok()
+        .map(|start| start.and_utc())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn temp_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("tokio-fluent-spool-{}", uuid::Uuid::new_v4()))
+    }
+
+    #[tokio::test]
+    async fn test_spool() {
+        let dir = temp_dir();
+        let mut spool = Spool::open(&dir, Limits::default()).await.unwrap();
+        assert!(spool.is_empty());
+        assert_eq!(spool.front().await.unwrap(), None);
+
+        spool.push("chunk-1", b"first").await.unwrap();
+        spool.push("chunk-2", b"second\n").await.unwrap();
+        assert_eq!(spool.len(), 2);
+        assert_eq!(
+            spool.front().await.unwrap(),
+            Some(("chunk-1".to_string(), Bytes::from_static(b"first")))
+        );
+        spool.pop().await.unwrap();
+
+        // pending chunks survive reopening
+        drop(spool);
+        let mut spool = Spool::open(&dir, Limits::default()).await.unwrap();
+        assert_eq!(spool.len(), 1);
+        spool.push("chunk-3", b"third").await.unwrap();
+        assert_eq!(
+            spool.front().await.unwrap(),
+            Some(("chunk-2".to_string(), Bytes::from_static(b"second\n")))
+        );
+        spool.pop().await.unwrap();
+        assert_eq!(
+            spool.front().await.unwrap(),
+            Some(("chunk-3".to_string(), Bytes::from_static(b"third")))
+        );
+        spool.pop().await.unwrap();
+        assert!(spool.is_empty());
+
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_partitions() {
+        let dir = temp_dir();
+        // a chunk spooled by an older version at the top of the directory
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        tokio::fs::write(dir.join(format!("{:016x}.chunk", 0)), b"chunk-0\nzero")
+            .await
+            .unwrap();
+
+        let mut spool = Spool::open(&dir, Limits::default()).await.unwrap();
+        let hour = Utc.with_ymd_and_hms(2024, 5, 1, 13, 30, 0).unwrap();
+        spool.push_at(hour, "chunk-1", b"one").await.unwrap();
+        spool
+            .push_at(hour + chrono::Duration::hours(1), "chunk-2", b"two")
+            .await
+            .unwrap();
+        assert!(dir
+            .join("2024050113")
+            .join(format!("{:016x}.chunk", 1))
+            .exists());
+        assert!(dir
+            .join("2024050114")
+            .join(format!("{:016x}.chunk", 2))
+            .exists());
+
+        drop(spool);
+        let mut spool = Spool::open(&dir, Limits::default()).await.unwrap();
+        assert_eq!(spool.len(), 3);
+        for want in ["chunk-0", "chunk-1", "chunk-2"] {
+            assert_eq!(spool.front().await.unwrap().unwrap().0, want);
+            spool.pop().await.unwrap();
+        }
+        // emptied hours are removed
+        assert!(!dir.join("2024050113").exists());
+        assert!(!dir.join("2024050114").exists());
+
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_limits() {
+        let dir = temp_dir();
+        let limits = Limits {
+            max_bytes: 50,
+            ..Default::default()
+        };
+        let mut spool = Spool::open(&dir, limits).await.unwrap();
+        let now = Utc.with_ymd_and_hms(2024, 5, 1, 13, 0, 0).unwrap();
+        // each chunk is 21 bytes
+        for chunk in ["chunk-1", "chunk-2"] {
+            assert_eq!(spool.push_at(now, chunk, b"abcd").await.unwrap(), 0);
+        }
+        assert_eq!(spool.push_at(now, "chunk-3", b"abcd").await.unwrap(), 1);
+        assert_eq!(spool.len(), 2);
+        assert_eq!(spool.front().await.unwrap().unwrap().0, "chunk-2");
+        // the newest chunk is kept even if it is too large on its own
+        assert_eq!(spool.push_at(now, "chunk-4", &[0; 64]).await.unwrap(), 2);
+        assert_eq!(spool.len(), 1);
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+
+        let dir = temp_dir();
+        let limits = Limits {
+            max_age: Duration::from_secs(3600),
+            ..Default::default()
+        };
+        let mut spool = Spool::open(&dir, limits).await.unwrap();
+        spool.push_at(now, "chunk-1", b"old").await.unwrap();
+        let later = now + chrono::Duration::minutes(90);
+        assert_eq!(spool.push_at(later, "chunk-2", b"new").await.unwrap(), 0);
+        // the hour of chunk-1 ended an hour ago
+        let later = now + chrono::Duration::hours(2);
+        assert_eq!(spool.prune_at(later).await.unwrap(), 1);
+        assert_eq!(spool.front().await.unwrap().unwrap().0, "chunk-2");
+        assert!(!dir.join("2024050113").exists());
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_quarantine() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+
+        let dir = temp_dir();
+        let mut spool = Spool::open(&dir, Limits::default()).await.unwrap();
+        let now = Utc.with_ymd_and_hms(2024, 5, 1, 13, 0, 0).unwrap();
+        spool.push_at(now, "chunk-1", b"first").await.unwrap();
+        spool.push_at(now, "chunk-2", b"second").await.unwrap();
+        let path = dir.join("2024050113").join(format!("{:016x}.chunk", 0));
+        let mut contents = tokio::fs::read(&path).await.unwrap();
+        *contents.last_mut().unwrap() ^= 0xff;
+        tokio::fs::write(&path, contents).await.unwrap();
+
+        let err = spool.front().await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            spool.quarantine().await.unwrap(),
+            Some(path.with_extension("corrupt"))
+        );
+        assert!(!path.exists());
+        assert!(path.with_extension("corrupt").exists());
+        assert_eq!(
+            spool.front().await.unwrap(),
+            Some(("chunk-2".to_string(), Bytes::from_static(b"second")))
+        );
+
+        // quarantined chunks are not replayed after reopening
+        drop(spool);
+        let spool = Spool::open(&dir, Limits::default()).await.unwrap();
+        assert_eq!(spool.len(), 1);
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
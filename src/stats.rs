@@ -0,0 +1,257 @@
+//! Statistics collected by the workers.
+//!
+//! ## Example
+//!
+//! ```no_run
+//! use tokio_fluent::{Client, Config};
+//!
+//! #[tokio::main]
+//! async fn main() {
+//!     let client = Client::new_tcp(
+//!         "127.0.0.1:24224".parse().unwrap(),
+//!         &Config{..Default::default()},
+//!     )
+//!     .await
+//!     .unwrap();
+//!
+//!     let sizes = client.stats().record_sizes;
+//!     for (upper, count) in sizes.buckets.iter() {
+//!         println!("< {} bytes: {}", upper, count);
+//!     }
+//!
+//!     let stats = client.stats();
+//!     println!("serializing: {:?}, io: {:?}", stats.serialize_time, stats.io_time);
+//! }
+//! ```
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// The number of buckets of a size histogram. The last bucket has no upper bound.
+const SIZE_BUCKETS: usize = 32;
+
+#[derive(Debug, Clone, PartialEq, Default)]
+/// A snapshot of the statistics of a client.
+pub struct Stats {
+    /// The distribution of the encoded sizes of records.
+    pub record_sizes: SizeHistogram,
+    /// The number of records dropped because the buffer was full.
+    pub dropped_records: u64,
+    /// The number of records dropped because their tag is not allowed.
+    pub denied_records: u64,
+    /// The number of records written to the server.
+    pub written_records: u64,
+    /// The number of records dropped by the workers, because they could not
+    /// be serialized or delivered.
+    pub failed_records: u64,
+    /// The number of records appended to `Config::spool_dir` to be replayed.
+    pub spooled_records: u64,
+    /// The time the workers spent encoding records and framing messages.
+    pub serialize_time: Duration,
+    /// The time the workers spent writing messages and waiting for their acks.
+    pub io_time: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+/// An exponential histogram of sizes in bytes.
+pub struct SizeHistogram {
+    /// The number of observed sizes.
+    pub count: u64,
+    /// The sum of the observed sizes.
+    pub sum: u64,
+    /// The largest observed size.
+    pub max: u64,
+    /// Pairs of an exclusive upper bound and the number of sizes below it and
+    /// at or above the previous bound. Bounds are powers of two, and the last
+    /// bound is `u64::MAX`.
+    pub buckets: Vec<(u64, u64)>,
+}
+
+impl SizeHistogram {
+    /// Return the mean of the observed sizes, or None if nothing was observed.
+    pub fn mean(&self) -> Option<f64> {
+        (self.count > 0).then(|| self.sum as f64 / self.count as f64)
+    }
+}
+
+/// The statistics shared by the workers of a client.
+#[derive(Debug)]
+pub(crate) struct Recorder {
+    count: AtomicU64,
+    sum: AtomicU64,
+    max: AtomicU64,
+    buckets: [AtomicU64; SIZE_BUCKETS],
+    denied: AtomicU64,
+    written: AtomicU64,
+    failed: AtomicU64,
+    spooled: AtomicU64,
+    serialize_nanos: AtomicU64,
+    io_nanos: AtomicU64,
+}
+
+impl Default for Recorder {
+    fn default() -> Self {
+        Self {
+            count: AtomicU64::new(0),
+            sum: AtomicU64::new(0),
+            max: AtomicU64::new(0),
+            buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+            denied: AtomicU64::new(0),
+            written: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+            spooled: AtomicU64::new(0),
+            serialize_nanos: AtomicU64::new(0),
+            io_nanos: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Recorder {
+    /// Record the encoded size of a record.
+    pub(crate) fn observe_record_size(&self, size: usize) {
+        let size = size as u64;
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(size, Ordering::Relaxed);
+        self.max.fetch_max(size, Ordering::Relaxed);
+        self.buckets[bucket(size)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a record dropped because its tag is not allowed.
+    pub(crate) fn observe_denied(&self) {
+        self.denied.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count records written to the server.
+    pub(crate) fn observe_written(&self, records: usize) {
+        self.written.fetch_add(records as u64, Ordering::Relaxed);
+    }
+
+    /// Count records dropped by a worker.
+    pub(crate) fn observe_failed(&self, records: usize) {
+        self.failed.fetch_add(records as u64, Ordering::Relaxed);
+    }
+
+    /// Count records appended to the spool.
+    pub(crate) fn observe_spooled(&self, records: usize) {
+        self.spooled.fetch_add(records as u64, Ordering::Relaxed);
+    }
+
+    /// Add time spent serializing in a worker.
+    pub(crate) fn observe_serialize_time(&self, elapsed: Duration) {
+        self.serialize_nanos
+            .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    /// Add time spent in the IO of a worker.
+    pub(crate) fn observe_io_time(&self, elapsed: Duration) {
+        self.io_nanos
+            .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> Stats {
+        let buckets = self
+            .buckets
+            .iter()
+            .enumerate()
+            .map(|(i, count)| (upper_bound(i), count.load(Ordering::Relaxed)))
+            .collect();
+        Stats {
+            record_sizes: SizeHistogram {
+                count: self.count.load(Ordering::Relaxed),
+                sum: self.sum.load(Ordering::Relaxed),
+                max: self.max.load(Ordering::Relaxed),
+                buckets,
+            },
+            dropped_records: 0,
+            denied_records: self.denied.load(Ordering::Relaxed),
+            written_records: self.written.load(Ordering::Relaxed),
+            failed_records: self.failed.load(Ordering::Relaxed),
+            spooled_records: self.spooled.load(Ordering::Relaxed),
+            serialize_time: Duration::from_nanos(self.serialize_nanos.load(Ordering::Relaxed)),
+            io_time: Duration::from_nanos(self.io_nanos.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// Return the index of the bucket of `size`: 0 for 0, and i for [2^(i-1), 2^i).
+fn bucket(size: u64) -> usize {
+    ((u64::BITS - size.leading_zeros()) as usize).min(SIZE_BUCKETS - 1)
+}
+
+fn upper_bound(bucket: usize) -> u64 {
+    if bucket == SIZE_BUCKETS - 1 {
+        u64::MAX
+    } else {
+        1 << bucket
+    }
+}
+
+/// An io::Write which only counts the written bytes.
+#[derive(Debug, Default)]
+pub(crate) struct ByteCounter(pub(crate) usize);
+
+impl std::io::Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket() {
+        assert_eq!(bucket(0), 0);
+        assert_eq!(bucket(1), 1);
+        assert_eq!(bucket(2), 2);
+        assert_eq!(bucket(3), 2);
+        assert_eq!(bucket(1024), 11);
+        assert_eq!(bucket(u64::MAX), SIZE_BUCKETS - 1);
+        for size in [0, 1, 100, 4096, 1 << 40] {
+            let i = bucket(size);
+            assert!(size < upper_bound(i));
+            assert!(i == 0 || size >= upper_bound(i - 1));
+        }
+    }
+
+    #[test]
+    fn test_recorder() {
+        let recorder = Recorder::default();
+        assert_eq!(recorder.snapshot().record_sizes.mean(), None);
+
+        for size in [10, 20, 300] {
+            recorder.observe_record_size(size);
+        }
+        let sizes = recorder.snapshot().record_sizes;
+        assert_eq!(sizes.count, 3);
+        assert_eq!(sizes.sum, 330);
+        assert_eq!(sizes.max, 300);
+        assert_eq!(sizes.mean(), Some(110.0));
+        assert_eq!(sizes.buckets.len(), SIZE_BUCKETS);
+        assert_eq!(sizes.buckets[4], (16, 1));
+        assert_eq!(sizes.buckets[5], (32, 1));
+        assert_eq!(sizes.buckets[9], (512, 1));
+        assert_eq!(sizes.buckets.iter().map(|(_, n)| n).sum::<u64>(), 3);
+
+        recorder.observe_written(3);
+        recorder.observe_failed(1);
+        recorder.observe_spooled(2);
+        let stats = recorder.snapshot();
+        assert_eq!(stats.written_records, 3);
+        assert_eq!(stats.failed_records, 1);
+        assert_eq!(stats.spooled_records, 2);
+
+        recorder.observe_serialize_time(Duration::from_micros(5));
+        recorder.observe_serialize_time(Duration::from_micros(7));
+        recorder.observe_io_time(Duration::from_millis(3));
+        let stats = recorder.snapshot();
+        assert_eq!(stats.serialize_time, Duration::from_micros(12));
+        assert_eq!(stats.io_time, Duration::from_millis(3));
+    }
+}
@@ -0,0 +1,51 @@
+//! Interning of tags.
+
+use std::collections::HashSet;
+use std::sync::{Arc, RwLock};
+
+/// The maximum number of interned tags.
+/// Tags beyond this are allocated on every send, so that programs generating
+/// tags dynamically do not grow the cache forever.
+const MAX_TAGS: usize = 1024;
+
+#[derive(Debug, Default)]
+/// Caches tags so that repeated sends of the same tag do not allocate.
+pub struct TagCache(RwLock<HashSet<Arc<str>>>);
+
+impl TagCache {
+    pub fn get(&self, tag: &str) -> Arc<str> {
+        let tags = self.0.read().unwrap_or_else(|e| e.into_inner());
+        if let Some(tag) = tags.get(tag) {
+            return tag.clone();
+        }
+        drop(tags);
+
+        let tag: Arc<str> = Arc::from(tag);
+        let mut tags = self.0.write().unwrap_or_else(|e| e.into_inner());
+        if tags.len() < MAX_TAGS {
+            tags.insert(tag.clone());
+        }
+        tag
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tag_cache() {
+        let cache = TagCache::default();
+        let a = cache.get("app.access");
+        let b = cache.get("app.access");
+        assert!(Arc::ptr_eq(&a, &b));
+        assert_eq!(&*a, "app.access");
+
+        for i in 0..MAX_TAGS {
+            cache.get(&i.to_string());
+        }
+        let a = cache.get("app.error");
+        let b = cache.get("app.error");
+        assert!(!Arc::ptr_eq(&a, &b));
+    }
+}
@@ -0,0 +1,470 @@
+//! TLS configuration of `Client::new_tls`.
+//!
+//! The connection is established with rustls by the `rustls` feature, or with
+//! the TLS stack of the system by the `native-tls` feature. rustls is used when
+//! both are enabled.
+//!
+//! The certificates are read from disk on every connection, so that rotated
+//! certificates are picked up when the worker reconnects. They are read on the
+//! blocking thread pool, so that a slow disk does not stall the runtime.
+
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use anyhow::Result as AnyhowResult;
+use tokio::net::TcpStream;
+#[cfg(feature = "rustls")]
+use tokio_rustls::rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    client::WebPkiServerVerifier,
+    crypto::{verify_tls12_signature, verify_tls13_signature, WebPkiSupportedAlgorithms},
+    pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime},
+    DigitallySignedStruct, Error as RustlsError, SignatureScheme,
+};
+
+#[cfg(feature = "rustls")]
+pub(crate) type TlsStream = tokio_rustls::client::TlsStream<TcpStream>;
+#[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+pub(crate) type TlsStream = tokio_native_tls::TlsStream<TcpStream>;
+
+#[cfg(feature = "rustls")]
+type Connector = tokio_rustls::TlsConnector;
+#[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+type Connector = tokio_native_tls::TlsConnector;
+
+#[derive(Debug, Clone, Default)]
+/// TLS config for a client.
+pub struct TlsConfig {
+    /// The name used for SNI and to verify the server certificate.
+    /// The default is None (the IP address of the server).
+    pub server_name: Option<String>,
+    /// A PEM file of the CA certificates used to verify the server.
+    /// The default is None (the root certificates of the system).
+    pub ca_file: Option<PathBuf>,
+    /// A PEM file of the client certificate chain presented to the server,
+    /// for fluentd's `transport tls` with `client_cert_auth true`.
+    /// The default is None (no client authentication).
+    pub cert_file: Option<PathBuf>,
+    /// A PEM file of the private key of `cert_file`. With the `native-tls`
+    /// feature the key must be in PKCS #8 format.
+    /// The default is None.
+    pub key_file: Option<PathBuf>,
+    /// The SHA-256 fingerprint of the server certificate in hex, optionally
+    /// separated by colons. The server certificate must match it in addition
+    /// to the CA chain. The default is None (no pinning).
+    pub fingerprint: Option<String>,
+    /// Skip the verification of the CA chain and the server name, and rely on
+    /// `fingerprint` only. This is useful for deployments without a PKI.
+    /// The default is false.
+    pub skip_ca_verification: bool,
+}
+
+impl TlsConfig {
+    /// Return the client certificate and key files, if client authentication is configured.
+    fn identity_files(&self) -> AnyhowResult<Option<(&PathBuf, &PathBuf)>> {
+        match (&self.cert_file, &self.key_file) {
+            (Some(cert), Some(key)) => Ok(Some((cert, key))),
+            (None, None) => Ok(None),
+            _ => Err(anyhow::anyhow!(
+                "both cert_file and key_file are required for client authentication"
+            )),
+        }
+    }
+
+    /// Return the decoded fingerprint of the server certificate, if pinned.
+    fn fingerprint(&self) -> AnyhowResult<Option<[u8; 32]>> {
+        let Some(ref fingerprint) = self.fingerprint else {
+            if self.skip_ca_verification {
+                return Err(anyhow::anyhow!(
+                    "skip_ca_verification requires a fingerprint"
+                ));
+            }
+            return Ok(None);
+        };
+        let hex: String = fingerprint.chars().filter(|&c| c != ':').collect();
+        let mut decoded = [0; 32];
+        if hex.len() != decoded.len() * 2 || !hex.is_ascii() {
+            return Err(anyhow::anyhow!(
+                "invalid SHA-256 fingerprint: {}",
+                fingerprint
+            ));
+        }
+        for (i, byte) in decoded.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+                .map_err(|_| anyhow::anyhow!("invalid SHA-256 fingerprint: {}", fingerprint))?;
+        }
+        Ok(Some(decoded))
+    }
+}
+
+impl TlsConfig {
+    /// Build the connector on the blocking thread pool, since it reads the
+    /// certificates from disk.
+    async fn connector_blocking(&self) -> AnyhowResult<Connector> {
+        let config = self.clone();
+        tokio::task::spawn_blocking(move || config.connector()).await?
+    }
+}
+
+fn sha256(der: &[u8]) -> [u8; 32] {
+    use sha2::{Digest, Sha256};
+
+    Sha256::digest(der).into()
+}
+
+#[cfg(feature = "rustls")]
+impl TlsConfig {
+    pub(crate) async fn connect(
+        &self,
+        addr: SocketAddr,
+        stream: TcpStream,
+    ) -> AnyhowResult<TlsStream> {
+        let server_name = self.server_name(addr)?;
+        let connector = self.connector_blocking().await?;
+        Ok(connector.connect(server_name, stream).await?)
+    }
+
+    fn server_name(&self, addr: SocketAddr) -> AnyhowResult<ServerName<'static>> {
+        match self.server_name {
+            Some(ref name) => Ok(ServerName::try_from(name.clone())?),
+            None => Ok(ServerName::IpAddress(addr.ip().into())),
+        }
+    }
+
+    fn connector(&self) -> AnyhowResult<Connector> {
+        use std::sync::Arc;
+
+        use tokio_rustls::rustls::crypto::ring;
+        use tokio_rustls::rustls::ClientConfig;
+
+        // the process-wide default provider is ambiguous, and makes rustls
+        // panic, when the application enables both ring and aws-lc-rs
+        let provider = Arc::new(ring::default_provider());
+        let algorithms = provider.signature_verification_algorithms;
+        let builder =
+            ClientConfig::builder_with_provider(provider).with_safe_default_protocol_versions()?;
+        let builder = match self.fingerprint()? {
+            None => builder.with_root_certificates(self.roots()?),
+            Some(fingerprint) => {
+                let chain = match self.skip_ca_verification {
+                    true => None,
+                    false => Some(
+                        WebPkiServerVerifier::builder_with_provider(
+                            Arc::new(self.roots()?),
+                            Arc::new(ring::default_provider()),
+                        )
+                        .build()?,
+                    ),
+                };
+                builder
+                    .dangerous()
+                    .with_custom_certificate_verifier(Arc::new(PinnedVerifier {
+                        fingerprint,
+                        chain,
+                        algorithms,
+                    }))
+            }
+        };
+        let config = match self.identity_files()? {
+            Some((cert, key)) => {
+                let (certs, key) = load_identity(cert, key)?;
+                builder.with_client_auth_cert(certs, key)?
+            }
+            None => builder.with_no_client_auth(),
+        };
+        Ok(tokio_rustls::TlsConnector::from(Arc::new(config)))
+    }
+
+    fn roots(&self) -> AnyhowResult<tokio_rustls::rustls::RootCertStore> {
+        use anyhow::anyhow;
+        use tokio_rustls::rustls::pki_types::pem::PemObject;
+        use tokio_rustls::rustls::RootCertStore;
+
+        let mut roots = RootCertStore::empty();
+        match self.ca_file {
+            Some(ref path) => {
+                for cert in CertificateDer::pem_file_iter(path)? {
+                    roots.add(cert?)?;
+                }
+            }
+            None => {
+                let native = rustls_native_certs::load_native_certs();
+                roots.add_parsable_certificates(native.certs);
+            }
+        }
+        if roots.is_empty() {
+            return Err(anyhow!("no CA certificates to verify the server"));
+        }
+        Ok(roots)
+    }
+}
+
+/// Verifies the server certificate against a pinned fingerprint, and the CA
+/// chain unless `skip_ca_verification` is set.
+#[cfg(feature = "rustls")]
+#[derive(Debug)]
+struct PinnedVerifier {
+    fingerprint: [u8; 32],
+    chain: Option<std::sync::Arc<WebPkiServerVerifier>>,
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+#[cfg(feature = "rustls")]
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, RustlsError> {
+        if let Some(ref chain) = self.chain {
+            chain.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)?;
+        }
+        if sha256(end_entity) != self.fingerprint {
+            return Err(RustlsError::General(
+                "certificate fingerprint mismatch".to_string(),
+            ));
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, RustlsError> {
+        verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, RustlsError> {
+        verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+/// Load a client certificate chain and its private key from PEM files.
+#[cfg(feature = "rustls")]
+fn load_identity(
+    cert: &PathBuf,
+    key: &PathBuf,
+) -> AnyhowResult<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
+    use tokio_rustls::rustls::pki_types::pem::PemObject;
+
+    let certs = CertificateDer::pem_file_iter(cert)?.collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(anyhow::anyhow!("no certificates in {}", cert.display()));
+    }
+    let key = PrivateKeyDer::from_pem_file(key)?;
+    Ok((certs, key))
+}
+
+#[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+impl TlsConfig {
+    pub(crate) async fn connect(
+        &self,
+        addr: SocketAddr,
+        stream: TcpStream,
+    ) -> AnyhowResult<TlsStream> {
+        let domain = self.domain(addr);
+        let connector = self.connector_blocking().await?;
+        let stream = connector.connect(&domain, stream).await?;
+        if let Some(fingerprint) = self.fingerprint()? {
+            let cert = stream
+                .get_ref()
+                .peer_certificate()?
+                .ok_or_else(|| anyhow::anyhow!("server presented no certificate"))?;
+            if sha256(&cert.to_der()?) != fingerprint {
+                return Err(anyhow::anyhow!("certificate fingerprint mismatch"));
+            }
+        }
+        Ok(stream)
+    }
+
+    fn domain(&self, addr: SocketAddr) -> String {
+        match self.server_name {
+            Some(ref name) => name.clone(),
+            None => addr.ip().to_string(),
+        }
+    }
+
+    fn connector(&self) -> AnyhowResult<Connector> {
+        use tokio_native_tls::native_tls::{Certificate, TlsConnector};
+
+        let mut builder = TlsConnector::builder();
+        if let Some(ref path) = self.ca_file {
+            let pem = std::fs::read_to_string(path)?;
+            builder.disable_built_in_roots(true);
+            for cert in pem_certificates(&pem) {
+                builder.add_root_certificate(Certificate::from_pem(cert.as_bytes())?);
+            }
+        }
+        if let Some((cert, key)) = self.identity_files()? {
+            builder.identity(load_identity(cert, key)?);
+        }
+        // the fingerprint is verified after the handshake
+        if self.fingerprint()?.is_some() && self.skip_ca_verification {
+            builder
+                .danger_accept_invalid_certs(true)
+                .danger_accept_invalid_hostnames(true);
+        }
+        Ok(builder.build()?.into())
+    }
+}
+
+/// Load a client certificate chain and its PKCS #8 private key from PEM files.
+#[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+fn load_identity(
+    cert: &PathBuf,
+    key: &PathBuf,
+) -> AnyhowResult<tokio_native_tls::native_tls::Identity> {
+    let cert = std::fs::read(cert)?;
+    let key = std::fs::read(key)?;
+    Ok(tokio_native_tls::native_tls::Identity::from_pkcs8(
+        &cert, &key,
+    )?)
+}
+
+/// Split a PEM bundle into its certificates, since native-tls parses only one
+/// certificate at a time.
+#[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+fn pem_certificates(pem: &str) -> Vec<String> {
+    const END: &str = "-----END CERTIFICATE-----";
+
+    pem.split_inclusive(END)
+        .filter_map(|block| {
+            let start = block.find("-----BEGIN CERTIFICATE-----")?;
+            block.ends_with(END).then(|| block[start..].to_string())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "rustls")]
+    #[test]
+    fn test_server_name() {
+        use tokio_rustls::rustls::pki_types::ServerName;
+
+        let addr = "127.0.0.1:24224".parse().unwrap();
+        let config = TlsConfig::default();
+        assert_eq!(
+            config.server_name(addr).unwrap(),
+            ServerName::IpAddress("127.0.0.1".parse::<std::net::IpAddr>().unwrap().into())
+        );
+
+        let config = TlsConfig {
+            server_name: Some("fluentd.example.com".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            config.server_name(addr).unwrap(),
+            ServerName::try_from("fluentd.example.com").unwrap()
+        );
+    }
+
+    #[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+    #[test]
+    fn test_pem_certificates() {
+        let pem = "comment\n-----BEGIN CERTIFICATE-----\nAAA\n-----END CERTIFICATE-----\n\
+                   -----BEGIN CERTIFICATE-----\nBBB\n-----END CERTIFICATE-----\n";
+        assert_eq!(
+            pem_certificates(pem),
+            vec![
+                "-----BEGIN CERTIFICATE-----\nAAA\n-----END CERTIFICATE-----",
+                "-----BEGIN CERTIFICATE-----\nBBB\n-----END CERTIFICATE-----",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_connector_missing_ca_file() {
+        let config = TlsConfig {
+            ca_file: Some(PathBuf::from("/nonexistent/ca.pem")),
+            ..Default::default()
+        };
+        assert!(config.connector().is_err());
+        assert!(config.connector_blocking().await.is_err());
+    }
+
+    #[cfg(feature = "rustls")]
+    #[test]
+    fn test_connector_with_ring_provider() {
+        // builds without a process-wide default provider being installed
+        let config = TlsConfig {
+            fingerprint: Some("ab".repeat(32)),
+            skip_ca_verification: true,
+            ..Default::default()
+        };
+        assert!(config.connector().is_ok());
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let hex = "AB".repeat(32);
+        let config = TlsConfig {
+            fingerprint: Some(hex.clone()),
+            ..Default::default()
+        };
+        assert_eq!(config.fingerprint().unwrap(), Some([0xab; 32]));
+
+        let colons = vec!["ab"; 32].join(":");
+        let config = TlsConfig {
+            fingerprint: Some(colons),
+            skip_ca_verification: true,
+            ..Default::default()
+        };
+        assert_eq!(config.fingerprint().unwrap(), Some([0xab; 32]));
+
+        for invalid in ["ab", &"zz".repeat(32), &"é".repeat(32)] {
+            let config = TlsConfig {
+                fingerprint: Some(invalid.to_string()),
+                ..Default::default()
+            };
+            assert!(config.fingerprint().is_err(), "{}", invalid);
+        }
+
+        // skipping the CA chain without pinning would accept any server
+        let config = TlsConfig {
+            skip_ca_verification: true,
+            ..Default::default()
+        };
+        assert!(config.fingerprint().is_err());
+        assert_eq!(TlsConfig::default().fingerprint().unwrap(), None);
+    }
+
+    #[test]
+    fn test_identity_files() {
+        let config = TlsConfig::default();
+        assert_eq!(config.identity_files().unwrap(), None);
+
+        let config = TlsConfig {
+            cert_file: Some(PathBuf::from("client.pem")),
+            key_file: Some(PathBuf::from("client.key")),
+            ..Default::default()
+        };
+        assert_eq!(
+            config.identity_files().unwrap(),
+            Some((&PathBuf::from("client.pem"), &PathBuf::from("client.key")))
+        );
+
+        let config = TlsConfig {
+            cert_file: Some(PathBuf::from("client.pem")),
+            ..Default::default()
+        };
+        assert!(config.identity_files().is_err());
+        assert!(config.connector().is_err());
+    }
+}
@@ -0,0 +1,1062 @@
+//! Transports used by the worker to connect to the fluentd server.
+//!
+//! Each transport is gated behind its own cargo feature.
+
+#[cfg(all(windows, feature = "named-pipe"))]
+use std::ffi::OsString;
+#[cfg(all(unix, feature = "unix"))]
+use std::path::PathBuf;
+
+use std::future::Future;
+#[cfg(feature = "tcp")]
+use std::sync::{Arc, Mutex};
+#[cfg(feature = "tcp")]
+use std::time::Instant;
+
+use anyhow::Result as AnyhowResult;
+use tokio::io::{AsyncRead, AsyncWrite};
+#[cfg(all(windows, feature = "named-pipe"))]
+use tokio::net::windows::named_pipe::{ClientOptions, NamedPipeClient};
+#[cfg(all(unix, feature = "unix"))]
+use tokio::net::UnixStream;
+#[cfg(feature = "tcp")]
+use tokio::net::{TcpSocket, TcpStream};
+#[cfg(any(
+    feature = "tcp",
+    all(unix, feature = "unix"),
+    all(windows, feature = "named-pipe")
+))]
+use tokio::time::{timeout, Duration};
+
+#[cfg(feature = "tcp")]
+use crate::client::{Proxy, ProxyKind};
+#[cfg(feature = "tcp")]
+use crate::heartbeat::Heartbeat;
+#[cfg(feature = "tcp")]
+use crate::http_proxy;
+#[cfg(feature = "tcp")]
+use crate::selector::{ServerStats, SharedSelector};
+#[cfg(feature = "tcp")]
+use crate::socks;
+#[cfg(any(feature = "rustls", feature = "native-tls"))]
+use crate::tls::TlsStream;
+
+pub trait Connectable {
+    type Stream: AsyncRead + AsyncWrite + Unpin + Send;
+
+    fn connect(&self) -> impl Future<Output = AnyhowResult<Self::Stream>> + Send;
+
+    /// A human readable description of the server, used in diagnostics.
+    fn endpoint(&self) -> String;
+
+    /// Refresh the address of the server, returning true if it changed.
+    fn refresh(&self) -> impl Future<Output = bool> + Send {
+        async { false }
+    }
+
+    /// Return false if the server is known to be unavailable, e.g. by heartbeats.
+    #[cfg(feature = "tcp")]
+    fn is_healthy(&self) -> bool {
+        true
+    }
+
+    /// Take the changes of the server used since the last call.
+    #[cfg(feature = "tcp")]
+    fn transitions(&self) -> Vec<Transition> {
+        Vec::new()
+    }
+
+    /// Return the stats of the server used, if they are tracked.
+    #[cfg(feature = "tcp")]
+    fn stats(&self) -> Option<Arc<Mutex<ServerStats>>> {
+        None
+    }
+}
+
+/// Options of the TCP sockets connecting to fluentd.
+#[cfg(feature = "tcp")]
+#[derive(Debug, Clone, Copy)]
+pub struct SocketOptions {
+    /// Disable Nagle's algorithm on the connections.
+    pub nodelay: bool,
+    /// The size of the kernel send buffer (SO_SNDBUF), or 0 for the system default.
+    pub send_buffer_size: u32,
+    /// The size of the kernel receive buffer (SO_RCVBUF), or 0 for the system default.
+    pub recv_buffer_size: u32,
+}
+
+#[cfg(feature = "tcp")]
+impl SocketOptions {
+    /// Connect to `addr`, sizing the buffers before connecting so that the
+    /// window scale negotiated by the handshake accounts for them.
+    async fn connect(&self, addr: std::net::SocketAddr) -> std::io::Result<TcpStream> {
+        let socket = if addr.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
+        };
+        if self.send_buffer_size > 0 {
+            socket.set_send_buffer_size(self.send_buffer_size)?;
+        }
+        if self.recv_buffer_size > 0 {
+            socket.set_recv_buffer_size(self.recv_buffer_size)?;
+        }
+        let stream = socket.connect(addr).await?;
+        stream.set_nodelay(self.nodelay)?;
+        Ok(stream)
+    }
+
+    /// Connect to the first reachable address of "hostname:port".
+    async fn connect_host(&self, host: &str) -> std::io::Result<TcpStream> {
+        let mut last_err =
+            std::io::Error::new(std::io::ErrorKind::NotFound, "no addresses to connect to");
+        for addr in tokio::net::lookup_host(host).await? {
+            match self.connect(addr).await {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_err = e,
+            }
+        }
+        Err(last_err)
+    }
+}
+
+#[cfg(feature = "tcp")]
+#[derive(Debug)]
+pub struct TCPConnectionConfig {
+    /// The "hostname:port" re-resolved on every connection, if any.
+    pub host: Option<String>,
+    /// The addresses tried in order until a connection is established.
+    pub addrs: Mutex<Vec<std::net::SocketAddr>>,
+    pub timeout: Duration,
+    /// The proxy to connect through, if any.
+    pub proxy: Option<Proxy>,
+    pub socket: SocketOptions,
+}
+
+#[cfg(feature = "tcp")]
+impl TCPConnectionConfig {
+    /// Resolve `host` again, returning true if the addresses changed.
+    /// The previous addresses are kept if the resolution fails.
+    async fn resolve(&self) -> bool {
+        let Some(ref host) = self.host else {
+            return false;
+        };
+        let addrs = match timeout(self.timeout, tokio::net::lookup_host(host)).await {
+            Ok(Ok(addrs)) => addrs.collect::<Vec<_>>(),
+            _ => return false,
+        };
+        if addrs.is_empty() {
+            return false;
+        }
+        let mut current = self.addrs.lock().unwrap();
+        if *current == addrs {
+            return false;
+        }
+        *current = addrs;
+        true
+    }
+
+    /// Connect through the proxy. `host` is resolved by the proxy.
+    async fn connect_proxy(&self, proxy: &Proxy) -> AnyhowResult<TcpStream> {
+        let targets = match self.host {
+            Some(ref host) => vec![socks::Target::parse(host)?],
+            None => self
+                .addrs
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|addr| socks::Target::Addr(*addr))
+                .collect(),
+        };
+        let credentials = proxy.username.as_deref().map(|username| {
+            let password = proxy.password.as_deref().unwrap_or_default();
+            (username, password)
+        });
+        let mut last_err = anyhow::anyhow!("no addresses to connect to");
+        for target in targets {
+            let mut stream = self.socket.connect_host(&proxy.addr).await?;
+            let result = match proxy.kind {
+                ProxyKind::Socks5 => socks::connect(&mut stream, target, credentials).await,
+                ProxyKind::Http => http_proxy::connect(&mut stream, target, credentials).await,
+            };
+            match result {
+                Ok(()) => return Ok(stream),
+                Err(e) => last_err = e,
+            }
+        }
+        Err(last_err)
+    }
+}
+
+#[cfg(feature = "tcp")]
+impl Connectable for TCPConnectionConfig {
+    type Stream = TcpStream;
+
+    async fn connect(&self) -> AnyhowResult<TcpStream> {
+        if let Some(ref proxy) = self.proxy {
+            return timeout(self.timeout, self.connect_proxy(proxy)).await?;
+        }
+        self.resolve().await;
+        let addrs = self.addrs.lock().unwrap().clone();
+        let mut last_err = anyhow::anyhow!("no addresses to connect to");
+        for addr in addrs {
+            match timeout(self.timeout, self.socket.connect(addr)).await {
+                Ok(Ok(stream)) => return Ok(stream),
+                Ok(Err(e)) => last_err = e.into(),
+                Err(e) => last_err = e.into(),
+            }
+        }
+        Err(last_err)
+    }
+
+    fn endpoint(&self) -> String {
+        if let Some(ref host) = self.host {
+            return host.clone();
+        }
+        self.addrs
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|addr| addr.to_string())
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    async fn refresh(&self) -> bool {
+        self.resolve().await
+    }
+}
+
+#[cfg(all(unix, feature = "unix"))]
+#[derive(Debug)]
+pub struct UnixSocketConfig {
+    /// The path of the socket. On Linux, a path starting with '@' or a NUL
+    /// byte names a socket in the abstract namespace.
+    pub path: PathBuf,
+    pub timeout: Duration,
+}
+
+#[cfg(all(target_os = "linux", feature = "unix"))]
+impl UnixSocketConfig {
+    /// Return the name of the socket in the abstract namespace if the path
+    /// starts with '@' or a NUL byte.
+    fn abstract_name(&self) -> Option<&[u8]> {
+        use std::os::unix::ffi::OsStrExt;
+
+        match self.path.as_os_str().as_bytes() {
+            [b'@' | 0, name @ ..] => Some(name),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(all(unix, feature = "unix"))]
+impl Connectable for UnixSocketConfig {
+    type Stream = UnixStream;
+
+    async fn connect(&self) -> AnyhowResult<UnixStream> {
+        #[cfg(target_os = "linux")]
+        if let Some(name) = self.abstract_name() {
+            use std::os::linux::net::SocketAddrExt;
+
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            let connect = tokio::task::spawn_blocking(move || {
+                std::os::unix::net::UnixStream::connect_addr(&addr)
+            });
+            let stream = timeout(self.timeout, connect).await???;
+            stream.set_nonblocking(true)?;
+            return Ok(UnixStream::from_std(stream)?);
+        }
+        let stream = timeout(self.timeout, UnixStream::connect(self.path.as_path())).await??;
+        Ok(stream)
+    }
+
+    fn endpoint(&self) -> String {
+        self.path.display().to_string()
+    }
+}
+
+/// The error returned while every instance of a named pipe is busy.
+#[cfg(all(windows, feature = "named-pipe"))]
+const ERROR_PIPE_BUSY: i32 = 231;
+
+#[cfg(all(windows, feature = "named-pipe"))]
+#[derive(Debug)]
+pub struct NamedPipeConfig {
+    /// The name of the pipe, e.g. `\\.\pipe\fluentd`.
+    pub path: OsString,
+    pub timeout: Duration,
+}
+
+#[cfg(all(windows, feature = "named-pipe"))]
+impl Connectable for NamedPipeConfig {
+    type Stream = NamedPipeClient;
+
+    async fn connect(&self) -> AnyhowResult<NamedPipeClient> {
+        let client = timeout(self.timeout, async {
+            loop {
+                match ClientOptions::new().open(&self.path) {
+                    Err(e) if e.raw_os_error() == Some(ERROR_PIPE_BUSY) => {}
+                    result => return result,
+                }
+                // wait for the server to create another instance of the pipe
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await??;
+        Ok(client)
+    }
+
+    fn endpoint(&self) -> String {
+        self.path.to_string_lossy().into_owned()
+    }
+}
+
+#[cfg(any(feature = "rustls", feature = "native-tls"))]
+#[derive(Debug)]
+pub struct TLSConnectionConfig {
+    pub addr: std::net::SocketAddr,
+    pub timeout: Duration,
+    pub tls: crate::tls::TlsConfig,
+    pub socket: SocketOptions,
+}
+
+#[cfg(any(feature = "rustls", feature = "native-tls"))]
+impl Connectable for TLSConnectionConfig {
+    type Stream = TlsStream;
+
+    async fn connect(&self) -> AnyhowResult<TlsStream> {
+        let stream = timeout(self.timeout, async {
+            let stream = self.socket.connect(self.addr).await?;
+            self.tls.connect(self.addr, stream).await
+        })
+        .await??;
+        Ok(stream)
+    }
+
+    fn endpoint(&self) -> String {
+        self.addr.to_string()
+    }
+}
+
+/// A server whose health is monitored by UDP heartbeats, if enabled.
+#[cfg(feature = "tcp")]
+#[derive(Debug)]
+pub struct Monitored<C> {
+    pub server: C,
+    pub heartbeat: Option<Arc<Heartbeat>>,
+    /// Shared by the workers using the server.
+    pub stats: Arc<Mutex<ServerStats>>,
+}
+
+#[cfg(feature = "tcp")]
+impl<C> Connectable for Monitored<C>
+where
+    C: Connectable + Send + Sync,
+{
+    type Stream = C::Stream;
+
+    async fn connect(&self) -> AnyhowResult<C::Stream> {
+        self.server.connect().await
+    }
+
+    fn endpoint(&self) -> String {
+        self.server.endpoint()
+    }
+
+    async fn refresh(&self) -> bool {
+        self.server.refresh().await
+    }
+
+    fn is_healthy(&self) -> bool {
+        self.heartbeat
+            .as_ref()
+            .map_or(true, |heartbeat| heartbeat.is_alive())
+    }
+
+    fn stats(&self) -> Option<Arc<Mutex<ServerStats>>> {
+        Some(self.stats.clone())
+    }
+}
+
+/// Connects to one of several servers, failing over to the next server when
+/// the active one is unreachable, like the `<server>` standby semantics of
+/// fluentd's out_forward.
+///
+/// Non-standby servers are used before standby servers. After failing over,
+/// `refresh` probes the first server and fails back when it has recovered.
+/// Servers which are not healthy are skipped while a healthy server remains.
+///
+/// With a selector, the server is chosen by the selector on every connection
+/// and every `refresh` instead, among the servers which are healthy and have
+/// not failed `threshold` times in a row. Standby servers are only offered to
+/// the selector while no other server is available, and `refresh` probes the
+/// failed servers so that they are offered again once they have recovered.
+#[cfg(feature = "tcp")]
+#[derive(Debug)]
+pub struct FailoverConfig<C> {
+    /// The servers, ordered with the non-standby servers first.
+    servers: Vec<C>,
+    /// The number of non-standby servers.
+    primaries: usize,
+    /// The stats of `servers`, shared with the servers which track them.
+    stats: Vec<Arc<Mutex<ServerStats>>>,
+    /// The number of consecutive failed connections after which the next server is used.
+    threshold: u32,
+    selector: Option<SharedSelector>,
+    state: Mutex<FailoverState>,
+}
+
+#[cfg(feature = "tcp")]
+#[derive(Debug)]
+struct FailoverState {
+    active: usize,
+    failures: u32,
+    connected: bool,
+    /// When the active server became active.
+    since: Instant,
+    /// The transitions not taken by `transitions` yet.
+    transitions: Vec<Transition>,
+}
+
+/// A change of the server used by a `FailoverConfig`.
+#[cfg(feature = "tcp")]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Transition {
+    pub from: String,
+    pub to: String,
+    /// Why the server changed: "unreachable", "unhealthy", "failback" or
+    /// "selected" by the selector.
+    pub reason: &'static str,
+    /// How long the previous server was used.
+    pub duration: Duration,
+}
+
+#[cfg(feature = "tcp")]
+impl<C: Connectable> FailoverConfig<C> {
+    /// `servers` are pairs of a server and whether it is a standby.
+    pub fn new(servers: Vec<(C, bool)>, threshold: u32) -> Self {
+        let (mut servers, standbys): (Vec<_>, Vec<_>) =
+            servers.into_iter().partition(|(_, standby)| !standby);
+        let primaries = servers.len();
+        servers.extend(standbys);
+        let servers: Vec<C> = servers.into_iter().map(|(server, _)| server).collect();
+        Self {
+            stats: servers
+                .iter()
+                .map(|server| server.stats().unwrap_or_default())
+                .collect(),
+            servers,
+            primaries,
+            threshold: threshold.max(1),
+            selector: None,
+            state: Mutex::new(FailoverState {
+                active: 0,
+                failures: 0,
+                connected: false,
+                since: Instant::now(),
+                transitions: Vec::new(),
+            }),
+        }
+    }
+
+    /// Choose the server with `selector` instead of using the servers in order.
+    pub fn with_selector(mut self, selector: SharedSelector) -> Self {
+        self.selector = Some(selector);
+        self
+    }
+
+    /// Record the result of a connection to `index` started at `started` in its stats.
+    fn observe<T>(&self, index: usize, result: &AnyhowResult<T>, started: Instant) {
+        let mut stats = self.stats[index].lock().unwrap();
+        match result {
+            Ok(_) => {
+                stats.failures = 0;
+                stats.observe_latency(started.elapsed());
+            }
+            Err(_) => stats.failures = stats.failures.saturating_add(1),
+        }
+        stats.healthy = stats.failures < self.threshold;
+    }
+
+    /// Return the stats offered to the selector. Standby servers are only
+    /// healthy while no other server is.
+    fn snapshot(&self) -> Vec<ServerStats> {
+        let mut snapshot: Vec<ServerStats> = self
+            .servers
+            .iter()
+            .zip(self.stats.iter())
+            .map(|(server, stats)| {
+                let mut stats = stats.lock().unwrap().clone();
+                stats.healthy = server.is_healthy() && stats.failures < self.threshold;
+                stats
+            })
+            .collect();
+        if snapshot[..self.primaries].iter().any(|stats| stats.healthy) {
+            for stats in snapshot[self.primaries..].iter_mut() {
+                stats.healthy = false;
+            }
+        }
+        snapshot
+    }
+
+    /// Return the server chosen by the selector, if any.
+    fn select(&self) -> Option<usize> {
+        let selector = self.selector.as_ref()?;
+        let snapshot = self.snapshot();
+        let index = selector.lock().unwrap().select(&snapshot)?;
+        (index < self.servers.len()).then_some(index)
+    }
+
+    /// Switch to the server chosen by the selector, returning true if the
+    /// active server changed.
+    fn reselect(&self) -> bool {
+        let Some(index) = self.select() else {
+            return false;
+        };
+        let mut state = self.state.lock().unwrap();
+        if state.active == index {
+            return false;
+        }
+        let reason = match self.servers[state.active].is_healthy() {
+            true => "selected",
+            false => "unhealthy",
+        };
+        self.switch(&mut state, index, reason);
+        true
+    }
+
+    /// Connect to the servers which failed `threshold` times in a row, so
+    /// that they are offered to the selector again once they have recovered.
+    async fn probe(&self) {
+        let active = self.active();
+        for (index, server) in self.servers.iter().enumerate() {
+            let failed = self.stats[index].lock().unwrap().failures >= self.threshold;
+            if index == active || !failed || !server.is_healthy() {
+                continue;
+            }
+            let started = Instant::now();
+            let result = server.connect().await;
+            self.observe(index, &result, started);
+        }
+    }
+
+    /// Make `to` the active server, recording the transition.
+    fn switch(&self, state: &mut FailoverState, to: usize, reason: &'static str) {
+        state.failures = 0;
+        if state.active == to {
+            return;
+        }
+        state.transitions.push(Transition {
+            from: self.servers[state.active].endpoint(),
+            to: self.servers[to].endpoint(),
+            reason,
+            duration: state.since.elapsed(),
+        });
+        state.active = to;
+        state.since = Instant::now();
+    }
+
+    fn active(&self) -> usize {
+        self.state.lock().unwrap().active
+    }
+
+    /// Record a failed connection to `index`, returning true if the next server should be tried.
+    fn fail(&self, index: usize) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.active != index {
+            return false;
+        }
+        state.failures += 1;
+        // until the first connection succeeds, every server is tried right away
+        if state.failures < self.threshold && state.connected {
+            return false;
+        }
+        let next = self
+            .select()
+            .filter(|&next| next != index)
+            .unwrap_or((index + 1) % self.servers.len());
+        self.switch(&mut state, next, "unreachable");
+        true
+    }
+
+    /// Switch to the first healthy server when the active one is not healthy,
+    /// returning true if the active server changed.
+    fn avoid_unhealthy(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if self.servers[state.active].is_healthy() {
+            return false;
+        }
+        match self.servers.iter().position(|server| server.is_healthy()) {
+            Some(index) => {
+                self.switch(&mut state, index, "unhealthy");
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn succeed(&self, index: usize) {
+        let mut state = self.state.lock().unwrap();
+        self.switch(&mut state, index, "unreachable");
+        state.connected = true;
+    }
+}
+
+#[cfg(feature = "tcp")]
+impl<C> Connectable for FailoverConfig<C>
+where
+    C: Connectable + Send + Sync,
+{
+    type Stream = C::Stream;
+
+    async fn connect(&self) -> AnyhowResult<C::Stream> {
+        if self.selector.is_some() {
+            self.reselect();
+        } else {
+            self.avoid_unhealthy();
+        }
+        let mut index = self.active();
+        // try every server at most once
+        for _ in 0..self.servers.len() {
+            let started = Instant::now();
+            let result = self.servers[index].connect().await;
+            self.observe(index, &result, started);
+            match result {
+                Ok(stream) => {
+                    self.succeed(index);
+                    return Ok(stream);
+                }
+                Err(e) if !self.fail(index) => return Err(e),
+                Err(_) => index = self.active(),
+            }
+        }
+        Err(anyhow::anyhow!("all servers are unreachable"))
+    }
+
+    fn endpoint(&self) -> String {
+        self.servers[self.active()].endpoint()
+    }
+
+    async fn refresh(&self) -> bool {
+        if self.selector.is_some() {
+            self.probe().await;
+            if self.reselect() {
+                return true;
+            }
+            return self.servers[self.active()].refresh().await;
+        }
+        if self.avoid_unhealthy() {
+            return true;
+        }
+        let active = self.active();
+        if self.servers[active].refresh().await {
+            return true;
+        }
+        if active == 0 || !self.servers[0].is_healthy() {
+            return false;
+        }
+        // fail back when the first server has recovered
+        let started = Instant::now();
+        let result = self.servers[0].connect().await;
+        self.observe(0, &result, started);
+        if result.is_err() {
+            return false;
+        }
+        let mut state = self.state.lock().unwrap();
+        self.switch(&mut state, 0, "failback");
+        true
+    }
+
+    fn transitions(&self) -> Vec<Transition> {
+        std::mem::take(&mut self.state.lock().unwrap().transitions)
+    }
+
+    fn stats(&self) -> Option<Arc<Mutex<ServerStats>>> {
+        Some(self.stats[self.active()].clone())
+    }
+}
+
+#[cfg(all(test, feature = "tcp"))]
+mod tests {
+    use super::*;
+
+    fn socket() -> SocketOptions {
+        SocketOptions {
+            nodelay: true,
+            send_buffer_size: 0,
+            recv_buffer_size: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tcp_connect_tries_each_address() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        // nothing listens on the address of a dropped listener
+        let closed = {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            listener.local_addr().unwrap()
+        };
+
+        let config = TCPConnectionConfig {
+            host: None,
+            addrs: Mutex::new(vec![closed, listener.local_addr().unwrap()]),
+            timeout: Duration::from_secs(1),
+            proxy: None,
+            socket: socket(),
+        };
+        let stream = config.connect().await.unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), listener.local_addr().unwrap());
+        assert!(stream.nodelay().unwrap());
+
+        let config = TCPConnectionConfig {
+            host: None,
+            addrs: Mutex::new(vec![listener.local_addr().unwrap()]),
+            timeout: Duration::from_secs(1),
+            proxy: None,
+            socket: SocketOptions {
+                nodelay: false,
+                ..socket()
+            },
+        };
+        assert!(!config.connect().await.unwrap().nodelay().unwrap());
+
+        let config = TCPConnectionConfig {
+            host: None,
+            addrs: Mutex::new(vec![closed]),
+            timeout: Duration::from_secs(1),
+            proxy: None,
+            socket: socket(),
+        };
+        assert!(config.connect().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_tcp_buffer_sizes() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let connect = |size| {
+            let config = TCPConnectionConfig {
+                host: None,
+                addrs: Mutex::new(vec![listener.local_addr().unwrap()]),
+                timeout: Duration::from_secs(1),
+                proxy: None,
+                socket: SocketOptions {
+                    send_buffer_size: size,
+                    recv_buffer_size: size,
+                    ..socket()
+                },
+            };
+            async move {
+                let stream = config.connect().await.unwrap();
+                TcpSocket::from_std_stream(stream.into_std().unwrap())
+            }
+        };
+        let small = connect(8 * 1024).await;
+        let large = connect(1024 * 1024).await;
+        // the kernel may round or cap the sizes, but they follow the settings
+        assert!(small.send_buffer_size().unwrap() < large.send_buffer_size().unwrap());
+        assert!(small.recv_buffer_size().unwrap() < large.recv_buffer_size().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_tcp_connect_resolves_host() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let stale = {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            listener.local_addr().unwrap()
+        };
+
+        let config = TCPConnectionConfig {
+            host: Some(format!("127.0.0.1:{}", port)),
+            addrs: Mutex::new(vec![stale]),
+            timeout: Duration::from_secs(1),
+            proxy: None,
+            socket: socket(),
+        };
+        assert!(config.connect().await.is_ok());
+        assert_eq!(
+            *config.addrs.lock().unwrap(),
+            vec![listener.local_addr().unwrap()]
+        );
+        assert!(!config.refresh().await);
+
+        // the previous addresses are kept when the resolution fails
+        let config = TCPConnectionConfig {
+            host: Some("invalid host".to_string()),
+            addrs: Mutex::new(vec![listener.local_addr().unwrap()]),
+            timeout: Duration::from_secs(1),
+            proxy: None,
+            socket: socket(),
+        };
+        assert!(!config.refresh().await);
+        assert!(config.connect().await.is_ok());
+    }
+
+    /// A server which is reachable when `up` is set, and healthy when `healthy` is set.
+    #[derive(Debug)]
+    struct Mock {
+        name: &'static str,
+        up: std::sync::atomic::AtomicBool,
+        healthy: std::sync::atomic::AtomicBool,
+        /// How long connecting takes.
+        delay: Duration,
+    }
+
+    impl Mock {
+        fn new(name: &'static str, up: bool) -> Self {
+            Self {
+                name,
+                up: std::sync::atomic::AtomicBool::new(up),
+                healthy: std::sync::atomic::AtomicBool::new(true),
+                delay: Duration::ZERO,
+            }
+        }
+
+        fn slow(name: &'static str, delay: Duration) -> Self {
+            Self {
+                delay,
+                ..Self::new(name, true)
+            }
+        }
+
+        fn set_up(&self, up: bool) {
+            self.up.store(up, std::sync::atomic::Ordering::SeqCst);
+        }
+
+        fn set_healthy(&self, healthy: bool) {
+            self.healthy
+                .store(healthy, std::sync::atomic::Ordering::SeqCst);
+        }
+    }
+
+    impl Connectable for Mock {
+        type Stream = tokio::io::DuplexStream;
+
+        async fn connect(&self) -> AnyhowResult<tokio::io::DuplexStream> {
+            if !self.up.load(std::sync::atomic::Ordering::SeqCst) {
+                return Err(anyhow::anyhow!("{} is down", self.name));
+            }
+            tokio::time::sleep(self.delay).await;
+            Ok(tokio::io::duplex(1).0)
+        }
+
+        fn endpoint(&self) -> String {
+            self.name.to_string()
+        }
+
+        fn is_healthy(&self) -> bool {
+            self.healthy.load(std::sync::atomic::Ordering::SeqCst)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failover() {
+        let config = FailoverConfig::new(
+            vec![
+                (Mock::new("standby", true), true),
+                (Mock::new("primary", true), false),
+            ],
+            2,
+        );
+        assert!(config.connect().await.is_ok());
+        assert_eq!(config.endpoint(), "primary");
+
+        // fail over after the threshold
+        config.servers[0].set_up(false);
+        assert!(config.connect().await.is_err());
+        assert_eq!(config.endpoint(), "primary");
+        assert!(config.connect().await.is_ok());
+        assert_eq!(config.endpoint(), "standby");
+
+        // fail back when the primary has recovered
+        assert!(!config.refresh().await);
+        config.servers[0].set_up(true);
+        assert!(config.refresh().await);
+        assert_eq!(config.endpoint(), "primary");
+        assert!(!config.refresh().await);
+
+        let transitions = config.transitions();
+        let got: Vec<_> = transitions
+            .iter()
+            .map(|t| (t.from.as_str(), t.to.as_str(), t.reason))
+            .collect();
+        assert_eq!(
+            got,
+            [
+                ("primary", "standby", "unreachable"),
+                ("standby", "primary", "failback")
+            ]
+        );
+        assert!(config.transitions().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_failover_first_connection() {
+        let config = FailoverConfig::new(
+            vec![
+                (Mock::new("primary", false), false),
+                (Mock::new("standby", true), true),
+            ],
+            3,
+        );
+        assert!(config.connect().await.is_ok());
+        assert_eq!(config.endpoint(), "standby");
+
+        config.servers[1].set_up(false);
+        for _ in 0..3 {
+            assert!(config.connect().await.is_err());
+        }
+        assert_eq!(config.endpoint(), "primary");
+    }
+
+    #[tokio::test]
+    async fn test_failover_unhealthy() {
+        let config = FailoverConfig::new(
+            vec![
+                (Mock::new("primary", true), false),
+                (Mock::new("standby", true), true),
+            ],
+            3,
+        );
+        assert!(config.connect().await.is_ok());
+        assert_eq!(config.endpoint(), "primary");
+
+        // fail over as soon as the heartbeat of the active server stops
+        config.servers[0].set_healthy(false);
+        assert!(config.refresh().await);
+        assert_eq!(config.endpoint(), "standby");
+        assert!(!config.refresh().await);
+
+        // unhealthy servers are still used when no server is healthy
+        config.servers[1].set_healthy(false);
+        assert!(config.connect().await.is_ok());
+        assert_eq!(config.endpoint(), "standby");
+
+        config.servers[0].set_healthy(true);
+        assert!(config.refresh().await);
+        assert_eq!(config.endpoint(), "primary");
+    }
+
+    /// Choose the first healthy server.
+    #[derive(Debug)]
+    struct First;
+
+    impl crate::selector::ServerSelector for First {
+        fn select(&mut self, servers: &[ServerStats]) -> Option<usize> {
+            servers.iter().position(|stats| stats.healthy)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failover_selector() {
+        let config = FailoverConfig::new(
+            vec![
+                (Mock::new("a", true), false),
+                (Mock::new("b", true), false),
+                (Mock::new("standby", true), true),
+            ],
+            1,
+        )
+        .with_selector(Arc::new(Mutex::new(First)));
+        assert!(config.connect().await.is_ok());
+        assert_eq!(config.endpoint(), "a");
+
+        // the selector chooses among the servers which did not fail
+        config.servers[0].set_up(false);
+        assert!(config.connect().await.is_ok());
+        assert_eq!(config.endpoint(), "b");
+        assert_eq!(config.stats[0].lock().unwrap().failures, 1);
+
+        // standby servers are offered once no other server is available
+        config.servers[1].set_up(false);
+        assert!(config.connect().await.is_ok());
+        assert_eq!(config.endpoint(), "standby");
+
+        // failed servers are probed, and chosen again once they recovered
+        assert!(!config.refresh().await);
+        config.servers[0].set_up(true);
+        assert!(config.refresh().await);
+        assert_eq!(config.endpoint(), "a");
+        assert_eq!(config.stats[0].lock().unwrap().failures, 0);
+        assert_eq!(config.stats[1].lock().unwrap().failures, 3);
+
+        let got: Vec<_> = config
+            .transitions()
+            .iter()
+            .map(|t| (t.from.clone(), t.to.clone(), t.reason))
+            .collect();
+        assert_eq!(
+            got,
+            [
+                ("a".to_string(), "b".to_string(), "unreachable"),
+                ("b".to_string(), "standby".to_string(), "unreachable"),
+                ("standby".to_string(), "a".to_string(), "selected"),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_failover_lowest_latency() {
+        use crate::selector::LowestLatency;
+
+        let config = FailoverConfig::new(
+            vec![
+                (Mock::slow("slow", Duration::from_millis(50)), false),
+                (Mock::new("fast", true), false),
+            ],
+            3,
+        )
+        .with_selector(Arc::new(Mutex::new(LowestLatency::new())));
+        assert!(config.connect().await.is_ok());
+        assert_eq!(config.endpoint(), "slow");
+        assert!(config.stats[0].lock().unwrap().latency.unwrap() >= Duration::from_millis(50));
+
+        // the server without latency is measured, then kept as the fastest
+        assert!(config.refresh().await);
+        assert_eq!(config.endpoint(), "fast");
+        assert!(config.connect().await.is_ok());
+        assert!(!config.refresh().await);
+        assert_eq!(config.endpoint(), "fast");
+
+        // ack latencies are recorded for the active server
+        let stats = config.stats().unwrap();
+        for _ in 0..10 {
+            stats
+                .lock()
+                .unwrap()
+                .observe_latency(Duration::from_millis(500));
+        }
+        assert!(config.refresh().await);
+        assert_eq!(config.endpoint(), "slow");
+    }
+
+    #[cfg(all(target_os = "linux", feature = "unix"))]
+    #[tokio::test]
+    async fn test_unix_connect_abstract() {
+        use std::os::linux::net::SocketAddrExt;
+
+        let name = format!("tokio-fluent-test-{}", std::process::id());
+        let addr = std::os::unix::net::SocketAddr::from_abstract_name(&name).unwrap();
+        let _listener = std::os::unix::net::UnixListener::bind_addr(&addr).unwrap();
+
+        let config = UnixSocketConfig {
+            path: PathBuf::from(format!("@{}", name)),
+            timeout: Duration::from_secs(1),
+        };
+        assert_eq!(config.abstract_name(), Some(name.as_bytes()));
+        assert!(config.connect().await.is_ok());
+
+        let config = UnixSocketConfig {
+            path: PathBuf::from(format!("@{}-missing", name)),
+            timeout: Duration::from_secs(1),
+        };
+        assert!(config.connect().await.is_err());
+
+        let config = UnixSocketConfig {
+            path: PathBuf::from("/tmp/fluentd.sock"),
+            timeout: Duration::from_secs(1),
+        };
+        assert_eq!(config.abstract_name(), None);
+    }
+}
@@ -1,60 +1,262 @@
 use std::cell::Cell;
+use std::collections::{HashMap, VecDeque};
 use std::path::PathBuf;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::time::Instant;
 
 use anyhow::Result as AnyhowResult;
-use async_trait::async_trait;
+use base64::{engine::general_purpose, Engine};
 use bytes::{Buf, BufMut};
-use log::{debug, warn};
+use rmp::encode::ValueWriteError;
 use rmp_serde::Serializer;
 use serde::{ser::SerializeMap, Deserialize, Serialize};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
-    net::{TcpStream, UnixStream},
-    sync::broadcast::{error::RecvError, Receiver},
-    time::{timeout, Duration},
+    sync::broadcast::Sender,
+    sync::{mpsc, oneshot},
+    time::{Duration, MissedTickBehavior},
 };
+use uuid::Uuid;
 
-use crate::record::Map;
+use crate::client::{AckMismatch, Jitter, Mode};
+use crate::dead_letter::{self, DeadLetter};
+use crate::diag::diag;
+use crate::event::Event;
+use crate::handshake::{handshake, HandshakeConfig};
+use crate::hooks::{ChunkHooks, ChunkInfo};
+use crate::protocol::Frame;
+use crate::queue;
+use crate::record::{EventTime, Map};
+use crate::rewrite::{TagRewriteRule, TagRewriter};
+use crate::schema::SchemaTracker;
+use crate::secondary::Secondary;
+use crate::spool::{Limits, Spool};
+use crate::stats::{ByteCounter, Recorder};
+use crate::transport::Connectable;
 
 const RETRY_INCREMENT_RATE: f64 = 1.5;
 
+/// The number of consecutive times the server closes the connection right
+/// after a chunk is written, without an ack, before the chunk is considered
+/// too large for the server.
+const CHUNK_REJECTED_CLOSES: u32 = 2;
+
+/// How soon after a chunk is written the server must close the connection
+/// for the close to hint at the chunk, rather than at a restart or an idle
+/// timeout.
+const CHUNK_REJECTED_WINDOW: Duration = Duration::from_secs(1);
+
+/// The maximum size in bytes of an ack response. Acks are tiny maps, so a
+/// larger frame means the server is misbehaving or the stream is out of sync.
+const MAX_ACK_FRAME_SIZE: usize = 1024;
+
 #[derive(Debug, Clone)]
+/// Why the worker failed to deliver records, as reported by
+/// `Delivery::wait`.
 pub enum Error {
+    /// Writing to the connection failed.
     WriteFailed(String),
+    /// Reading the ack from the connection failed.
     ReadFailed(String),
+    /// The ack of the server did not match the chunk of the request.
     AckUnmatched(String, String),
+    /// The records could not be delivered within `Config::max_retry` attempts.
     MaxRetriesExceeded,
+    /// The connection was closed.
     ConnectionClosed,
+    /// The connection was closed right after a chunk was written, before any
+    /// byte of its ack.
+    ClosedAfterWrite,
+    /// The ack frame exceeded the maximum size.
+    AckFrameTooLarge(usize),
+    /// The ack frame could not be decoded.
+    InvalidAckFrame(String),
+    /// The records could not be serialized.
+    SerializeFailed(String),
+    /// The server was unreachable, so the records were spooled to disk.
+    Spooled,
+    /// The server closed the connection without an ack.
+    ChunkTooLarge,
+    /// The server did not ack within `Config::ack_timeout`.
+    AckTimeout(Duration),
 }
 
 impl std::error::Error for Error {}
 
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let s = match *self {
-            Error::WriteFailed(ref e) => e,
-            Error::ReadFailed(ref e) => e,
-            Error::AckUnmatched(_, _) => "request chunk and response ack did not match",
-            Error::MaxRetriesExceeded => "max retries exceeded",
-            Error::ConnectionClosed => "connection closed",
-        };
-        write!(f, "{}", s)
+        match *self {
+            Error::WriteFailed(ref e) => write!(f, "{}", e),
+            Error::ReadFailed(ref e) => write!(f, "{}", e),
+            Error::AckUnmatched(ref ack, ref chunk) => write!(
+                f,
+                "request chunk and response ack did not match. ack: {}, chunk: {}",
+                ack, chunk
+            ),
+            Error::MaxRetriesExceeded => write!(f, "max retries exceeded"),
+            Error::ConnectionClosed => write!(f, "connection closed"),
+            Error::ClosedAfterWrite => write!(f, "connection closed right after a chunk"),
+            Error::AckFrameTooLarge(size) => write!(
+                f,
+                "ack frame exceeds {} bytes: {} bytes",
+                MAX_ACK_FRAME_SIZE, size
+            ),
+            Error::InvalidAckFrame(ref e) => write!(f, "invalid ack frame: {}", e),
+            Error::SerializeFailed(ref e) => write!(f, "failed to serialize a message: {}", e),
+            Error::ChunkTooLarge => write!(
+                f,
+                "the server closed the connection without an ack, the chunk is likely too large"
+            ),
+            Error::Spooled => write!(f, "the server is unreachable, spooled to be sent later"),
+            Error::AckTimeout(timeout) => write!(f, "no ack within {:?}", timeout),
+        }
     }
 }
 
-#[derive(Clone, Debug, Serialize)]
+#[derive(Clone)]
 pub struct Record {
-    pub tag: String,
+    pub tag: Arc<str>,
     pub timestamp: i64,
+    /// The nanoseconds of the timestamp, sent as an EventTime if set.
+    pub nanos: Option<u32>,
     pub record: Map,
     pub options: Options,
 }
 
+impl Record {
+    fn time(&self) -> EventTime {
+        EventTime {
+            seconds: self.timestamp,
+            nanos: self.nanos,
+        }
+    }
+}
+
+/// A Message mode message: `[tag, time, record, options]`.
+impl Serialize for Record {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        (&self.tag, self.time(), &self.record, &self.options).serialize(serializer)
+    }
+}
+
+/// Values are masked so that records never leak into logs.
+impl std::fmt::Debug for Record {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Record")
+            .field("tag", &self.tag)
+            .field("timestamp", &self.timestamp)
+            .field("record", &self.record.redacted())
+            .field("options", &self.options)
+            .finish()
+    }
+}
+
+/// A record encoded as a msgpack map beforehand, sent as a Message mode
+/// message without decoding it.
+#[derive(Clone)]
+pub struct RawRecord {
+    pub tag: Arc<str>,
+    pub timestamp: i64,
+    pub record: bytes::Bytes,
+    pub options: Options,
+}
+
+impl RawRecord {
+    /// Write the `[time, record]` entry of the record.
+    fn write_entry<W: std::io::Write>(&self, writer: &mut W) -> Result<(), ValueWriteError> {
+        rmp::encode::write_array_len(writer, 2)?;
+        rmp::encode::write_sint(writer, self.timestamp)?;
+        writer
+            .write_all(&self.record)
+            .map_err(ValueWriteError::InvalidDataWrite)
+    }
+}
+
+/// The record is opaque, so only its size is shown.
+impl std::fmt::Debug for RawRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RawRecord")
+            .field("tag", &self.tag)
+            .field("timestamp", &self.timestamp)
+            .field("record", &format_args!("<{} bytes>", self.record.len()))
+            .field("options", &self.options)
+            .finish()
+    }
+}
+
+/// Records of a tag sent together as a Forward mode message:
+/// `[tag, [[time, record], ...], options]`.
+#[derive(Clone, Serialize)]
+pub struct Forward {
+    pub tag: Arc<str>,
+    pub entries: Vec<(i64, Map)>,
+    pub options: Options,
+    /// Notified of the delivery result of the message, if any.
+    #[serde(skip)]
+    pub ack: Option<Ack>,
+}
+
+/// The delivery result of a message.
+pub type AckReceiver = oneshot::Receiver<Result<(), Error>>;
+
+/// Notifies the sender of a message of its delivery result.
 #[derive(Clone, Debug)]
+pub struct Ack(Arc<Mutex<Option<AckSender>>>);
+
+type AckSender = oneshot::Sender<Result<(), Error>>;
+
+impl Ack {
+    pub fn new() -> (Self, AckReceiver) {
+        let (sender, receiver) = oneshot::channel();
+        (Self(Arc::new(Mutex::new(Some(sender)))), receiver)
+    }
+
+    /// Notify that the message was delivered without sending it.
+    pub fn notify_delivered(&self) {
+        self.notify(Ok(()));
+    }
+
+    fn notify(&self, result: Result<(), Error>) {
+        if let Some(sender) = self.0.lock().unwrap().take() {
+            let _ = sender.send(result);
+        }
+    }
+}
+
+/// Values are masked so that records never leak into logs.
+impl std::fmt::Debug for Forward {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Forward")
+            .field("tag", &self.tag)
+            .field(
+                "entries",
+                &self
+                    .entries
+                    .iter()
+                    .map(|(timestamp, record)| (timestamp, record.redacted()))
+                    .collect::<Vec<_>>(),
+            )
+            .field("options", &self.options)
+            .finish()
+    }
+}
+
+#[derive(Clone, Debug, Default)]
 pub struct Options {
+    /// The chunk id for which fluentd returns an ack, or empty to request no ack.
     pub chunk: String,
+    /// Overrides `RetryConfig::max` for this message.
+    pub max_retry: Option<u32>,
+}
+
+impl Options {
+    /// Return true if the message can be batched with others of its tag.
+    fn is_batchable(&self) -> bool {
+        !self.chunk.is_empty() && self.max_retry.is_none()
+    }
 }
 
 impl Serialize for Options {
@@ -62,6 +264,9 @@ impl Serialize for Options {
     where
         S: serde::Serializer,
     {
+        if self.chunk.is_empty() {
+            return serializer.serialize_map(Some(0))?.end();
+        }
         let mut map = serializer.serialize_map(Some(1))?;
         map.serialize_entry("chunk", &self.chunk)?;
         map.end()
@@ -71,13 +276,74 @@ impl Serialize for Options {
 #[derive(Clone)]
 pub enum Message {
     Record(Record),
+    Forward(Forward),
+    Raw(RawRecord),
+    Reconnect,
+    /// Flush the batches and notify the ack once the messages enqueued
+    /// before are written.
+    Flush(Ack),
     Terminate,
 }
 
 #[derive(Debug)]
 struct SerializedRecord {
     record: bytes::Bytes,
+    tag: Arc<str>,
     chunk: String,
+    max_retry: Option<u32>,
+}
+
+fn encode<T: Serialize>(
+    tag: &Arc<str>,
+    message: &T,
+    options: &Options,
+) -> Result<SerializedRecord, rmp_serde::encode::Error> {
+    let mut writer = bytes::BytesMut::new().writer();
+    message.serialize(&mut Serializer::new(&mut writer))?;
+    Ok(SerializedRecord {
+        record: writer.into_inner().freeze(),
+        tag: tag.clone(),
+        chunk: options.chunk.clone(),
+        max_retry: options.max_retry,
+    })
+}
+
+/// Return the result of `f` and the time it took.
+fn timed<T>(f: impl FnOnce() -> T) -> (T, Duration) {
+    let started = Instant::now();
+    let result = f();
+    (result, started.elapsed())
+}
+
+fn report_pruned(pruned: usize) {
+    if pruned > 0 {
+        diag!(warn; "removed {} spooled chunks beyond the spool limits", pruned);
+    }
+}
+
+/// Encode a PackedForward mode message: `[tag, entries, {"chunk": chunk, "size": count}]`
+/// where `entries` is a msgpack stream of `[time, record]` carried as bin.
+/// The chunk option is left out if `chunk` is empty.
+fn encode_packed_forward(
+    tag: &str,
+    entries: &[u8],
+    count: usize,
+    chunk: &str,
+) -> Result<bytes::Bytes, rmp::encode::ValueWriteError> {
+    let mut writer = bytes::BytesMut::with_capacity(entries.len() + 64).writer();
+    rmp::encode::write_array_len(&mut writer, 3)?;
+    rmp::encode::write_str(&mut writer, tag)?;
+    rmp::encode::write_bin(&mut writer, entries)?;
+    if chunk.is_empty() {
+        rmp::encode::write_map_len(&mut writer, 1)?;
+    } else {
+        rmp::encode::write_map_len(&mut writer, 2)?;
+        rmp::encode::write_str(&mut writer, "chunk")?;
+        rmp::encode::write_str(&mut writer, chunk)?;
+    }
+    rmp::encode::write_str(&mut writer, "size")?;
+    rmp::encode::write_uint(&mut writer, count as u64)?;
+    Ok(writer.into_inner().freeze())
 }
 
 #[derive(Debug, Deserialize)]
@@ -85,104 +351,1148 @@ struct AckResponse {
     ack: String,
 }
 
+pub struct WorkerConfig {
+    pub max_connection_lifetime: Duration,
+    /// How long to wait for an ack, or zero to wait forever.
+    pub ack_timeout: Duration,
+    /// Request acks for the chunks whose options ask for them.
+    pub require_ack: bool,
+    pub retry: RetryConfig,
+    pub slow_ack: SlowAckConfig,
+    pub batch: BatchConfig,
+    pub detect_schema_drift: bool,
+    pub handshake: Option<HandshakeConfig>,
+    pub tag_rewrite_rules: Vec<TagRewriteRule>,
+    /// The interval at which the address of the server is refreshed.
+    pub refresh_interval: Duration,
+    pub stats: Arc<Recorder>,
+    #[cfg(feature = "tcp")]
+    pub failover_tag: Option<String>,
+    pub spool_dir: Option<PathBuf>,
+    pub spool_limits: Limits,
+    /// The number of spooled chunks replayed per second, or 0 for no limit.
+    pub spool_replay_rate: u32,
+    pub durable: bool,
+    pub chunk_hooks: Option<Arc<dyn ChunkHooks>>,
+    pub dead_letter: Option<mpsc::Sender<DeadLetter>>,
+    /// Shared by the workers of a client.
+    pub secondary: Option<Arc<tokio::sync::Mutex<Secondary>>>,
+}
+
+pub struct BatchConfig {
+    pub mode: Mode,
+    pub flush_interval: Duration,
+    pub max_size: usize,
+    /// The number of bytes flushed per `flush_interval`, or 0 for no limit.
+    pub budget: usize,
+    /// Adapt `max_size` and `flush_interval` to the ack latency, using them
+    /// as upper bounds.
+    pub adaptive: Option<AdaptiveConfig>,
+}
+
+pub struct AdaptiveConfig {
+    pub min_size: usize,
+    pub min_flush_interval: Duration,
+    pub target_latency: Duration,
+}
+
+/// Grows the batch size and the flush interval while acks are fast, and
+/// shrinks them when the server slows down, within bounds.
+struct BatchTuner {
+    min_size: usize,
+    max_size: usize,
+    min_interval: Duration,
+    max_interval: Duration,
+    target: Duration,
+    size: usize,
+    interval: Duration,
+    /// Set when `interval` changed since the last call to `take_interval`.
+    interval_changed: bool,
+}
+
+impl BatchTuner {
+    /// Start from the lower bounds, like TCP slow start.
+    fn new(config: &AdaptiveConfig, max_size: usize, max_interval: Duration) -> Self {
+        let min_size = config.min_size.clamp(1, max_size.max(1));
+        let min_interval = config.min_flush_interval.min(max_interval);
+        Self {
+            min_size,
+            max_size,
+            min_interval,
+            max_interval,
+            target: config.target_latency,
+            size: min_size,
+            interval: min_interval,
+            interval_changed: false,
+        }
+    }
+
+    /// Adjust the batch to the latency of an ack. Latencies between half the
+    /// target and the target leave it unchanged, so that it settles.
+    fn observe(&mut self, latency: Duration) {
+        let interval = self.interval;
+        if latency > self.target {
+            self.size = (self.size / 2).max(self.min_size);
+            self.interval = (self.interval / 2).max(self.min_interval);
+        } else if latency < self.target / 2 {
+            self.size = self.size.saturating_mul(2).min(self.max_size);
+            self.interval = (self.interval * 2).min(self.max_interval);
+        }
+        self.interval_changed |= self.interval != interval;
+    }
+
+    /// Return the new flush interval, if it changed since the last call.
+    fn take_interval(&mut self) -> Option<Duration> {
+        std::mem::take(&mut self.interval_changed).then_some(self.interval)
+    }
+}
+
+/// Entries of a tag waiting to be flushed in PackedForward mode.
+#[derive(Default)]
+struct Batch {
+    entries: bytes::BytesMut,
+    /// The end offset of each entry in `entries`.
+    ends: Vec<usize>,
+}
+
+/// Return the tags of `pending` to flush in order, the oldest first, until
+/// `budget` bytes are reached. The batch reaching the budget is included so
+/// that every cycle makes progress.
+fn within_budget(
+    pending: &VecDeque<Arc<str>>,
+    batches: &HashMap<Arc<str>, Batch>,
+    budget: usize,
+) -> Vec<Arc<str>> {
+    let mut spent = 0;
+    let mut tags = Vec::new();
+    for tag in pending.iter() {
+        if budget > 0 && spent >= budget {
+            break;
+        }
+        spent += batches.get(tag).map_or(0, |batch| batch.entries.len());
+        tags.push(tag.clone());
+    }
+    tags
+}
+
+/// Return the byte range of the entries `first..last` given their end offsets.
+fn entry_range(ends: &[usize], first: usize, last: usize) -> std::ops::Range<usize> {
+    let start = if first == 0 { 0 } else { ends[first - 1] };
+    start..ends[last - 1]
+}
+
 pub struct RetryConfig {
     pub initial_wait: u64,
     pub max: u32,
     pub max_wait: u64,
+    pub jitter: Jitter,
+    /// Ignore `max` and retry until the chunk is delivered.
+    pub forever: bool,
+    /// Shared by the workers of a client, or None for no limit.
+    pub budget: Option<Arc<Mutex<RetryBudget>>>,
+    pub ack_mismatch: AckMismatch,
+}
+
+/// A token bucket of retries, refilled continuously.
+#[derive(Debug)]
+pub struct RetryBudget {
+    /// The number of retries per second, which is also the capacity.
+    rate: f64,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl RetryBudget {
+    pub fn new(per_second: u32) -> Self {
+        Self {
+            rate: per_second as f64,
+            tokens: per_second as f64,
+            refilled: Instant::now(),
+        }
+    }
+
+    /// Take a retry from the budget, returning false if it is spent.
+    fn take(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.refilled = now;
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+/// Randomize a backoff of `wait` milliseconds with `random` bits.
+fn jittered(wait: u64, jitter: Jitter, random: u64) -> u64 {
+    match jitter {
+        Jitter::None => wait,
+        Jitter::Full => random % (wait + 1),
+        Jitter::Equal => wait / 2 + random % (wait - wait / 2 + 1),
+    }
+}
+
+pub struct SlowAckConfig {
+    pub threshold: Duration,
+    pub window: u32,
+}
+
+/// Tracks consecutive slow acks and reports when a whole window was slow.
+struct SlowAckTracker {
+    config: SlowAckConfig,
+    count: u32,
+    total: Duration,
 }
 
-pub struct Worker<StreamType> {
-    stream_config: Arc<dyn Connectable<StreamType> + Send + Sync>,
+impl SlowAckTracker {
+    fn new(config: SlowAckConfig) -> Self {
+        Self {
+            config,
+            count: 0,
+            total: Duration::ZERO,
+        }
+    }
+
+    fn observe(&mut self, latency: Duration) -> Option<Event> {
+        if self.config.threshold.is_zero() {
+            return None;
+        }
+        if latency < self.config.threshold {
+            self.count = 0;
+            self.total = Duration::ZERO;
+            return None;
+        }
+
+        self.count += 1;
+        self.total += latency;
+        if self.count < self.config.window.max(1) {
+            return None;
+        }
+        let event = Event::SlowAck {
+            chunks: self.count,
+            average: self.total / self.count,
+        };
+        self.count = 0;
+        self.total = Duration::ZERO;
+        Some(event)
+    }
+}
+
+pub struct Worker<C: Connectable> {
+    stream_config: C,
     max_connection_lifetime: Duration,
-    stream: Cell<StreamType>,
+    ack_timeout: Duration,
+    require_ack: bool,
+    stream: Cell<C::Stream>,
     last_connection_time: Cell<Instant>,
-    receiver: Receiver<Message>,
+    receiver: queue::Receiver,
+    /// The number of records dropped by the overflow policy as of the last
+    /// `Event::RecordsDropped`.
+    dropped: u64,
     retry_config: RetryConfig,
+    /// Whether an `Event::Backoff` was emitted since the last allowed retry.
+    backing_off: bool,
+    batch_config: BatchConfig,
+    batches: HashMap<Arc<str>, Batch>,
+    /// The tags of `batches` in the order they started batching.
+    pending: VecDeque<Arc<str>>,
+    slow_acks: SlowAckTracker,
+    tuner: Option<BatchTuner>,
+    schema: Option<SchemaTracker>,
+    tags: TagRewriter,
+    events: Sender<Event>,
+    handshake: Option<HandshakeConfig>,
+    refresh_interval: Duration,
+    stats: Arc<Recorder>,
+    #[cfg(feature = "tcp")]
+    failover_tag: Option<String>,
+    spool: Option<Spool>,
+    /// The interval between replayed chunks, if replays are throttled.
+    replay_interval: Option<Duration>,
+    durable: bool,
+    hooks: Option<Arc<dyn ChunkHooks>>,
+    dead_letter: Option<mpsc::Sender<DeadLetter>>,
+    secondary: Option<Arc<tokio::sync::Mutex<Secondary>>>,
 }
 
-impl<StreamType> Worker<StreamType>
-where
-    StreamType: AsyncReadExt + AsyncWriteExt + Unpin,
-{
+impl<C: Connectable> Worker<C> {
     pub async fn new(
-        stream_config: Arc<dyn Connectable<StreamType> + Send + Sync>,
-        max_connection_lifetime: Duration,
-        receiver: Receiver<Message>,
-        retry_config: RetryConfig,
+        stream_config: C,
+        receiver: queue::Receiver,
+        events: Sender<Event>,
+        config: WorkerConfig,
     ) -> AnyhowResult<Self> {
-        let stream = stream_config.connect().await?;
+        let stream = Self::connect(&stream_config, config.handshake.as_ref()).await?;
+        let spool = match config.spool_dir {
+            Some(ref dir) => Some(Spool::open(dir, config.spool_limits).await?),
+            None => None,
+        };
+        let tuner = config.batch.adaptive.as_ref().map(|adaptive| {
+            BatchTuner::new(adaptive, config.batch.max_size, config.batch.flush_interval)
+        });
         Ok(Self {
             stream_config,
-            max_connection_lifetime,
+            max_connection_lifetime: config.max_connection_lifetime,
+            ack_timeout: config.ack_timeout,
+            require_ack: config.require_ack,
             stream: Cell::new(stream),
             last_connection_time: Cell::new(Instant::now()),
             receiver,
-            retry_config,
+            dropped: 0,
+            backing_off: false,
+            retry_config: config.retry,
+            batch_config: config.batch,
+            batches: HashMap::new(),
+            pending: VecDeque::new(),
+            slow_acks: SlowAckTracker::new(config.slow_ack),
+            tuner,
+            schema: config.detect_schema_drift.then(SchemaTracker::default),
+            tags: TagRewriter::new(config.tag_rewrite_rules),
+            events,
+            handshake: config.handshake,
+            refresh_interval: config.refresh_interval,
+            stats: config.stats,
+            #[cfg(feature = "tcp")]
+            failover_tag: config.failover_tag,
+            spool,
+            replay_interval: (config.spool_replay_rate > 0)
+                .then(|| Duration::from_secs(1) / config.spool_replay_rate),
+            durable: config.durable,
+            hooks: config.chunk_hooks,
+            dead_letter: config.dead_letter,
+            secondary: config.secondary,
         })
     }
 
+    /// Connect to the server, authenticating with the shared key if configured.
+    async fn connect(
+        stream_config: &C,
+        handshake_config: Option<&HandshakeConfig>,
+    ) -> AnyhowResult<C::Stream> {
+        let mut stream = stream_config.connect().await?;
+        if let Some(config) = handshake_config {
+            handshake(&mut stream, config).await?;
+        }
+        Ok(stream)
+    }
+
     pub async fn run(&mut self) {
+        let batching = self.batch_config.mode == Mode::PackedForward;
+        let period = self
+            .tuner
+            .as_ref()
+            .map_or(self.batch_config.flush_interval, |tuner| tuner.interval)
+            .max(Duration::from_millis(1));
+        let mut flush = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+        flush.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let refreshing = !self.refresh_interval.is_zero();
+        let period = self.refresh_interval.max(Duration::from_millis(1));
+        let mut refresh = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+        refresh.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        // the first tick is immediate to replay chunks left by a previous process
+        let mut replay =
+            tokio::time::interval(Duration::from_millis(self.retry_config.max_wait.max(1)));
+        replay.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
         loop {
-            match self.receiver.recv().await {
-                Ok(Message::Record(record)) => {
-                    let record = match self.encode(record) {
+            self.report_dropped();
+            #[cfg(feature = "tcp")]
+            self.report_transitions().await;
+
+            if let Some(interval) = self.tuner.as_mut().and_then(BatchTuner::take_interval) {
+                diag!(debug; "adapted the flush interval to {:?}", interval);
+                let period = interval.max(Duration::from_millis(1));
+                flush = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+                flush.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            }
+
+            let message = tokio::select! {
+                message = self.receiver.recv() => Some(message),
+                _ = flush.tick(), if batching => None,
+                _ = refresh.tick(), if refreshing => {
+                    if self.stream_config.refresh().await {
+                        diag!(debug, endpoint = self.stream_config.endpoint(); "the address of the server changed");
+                        self.reconnect().await;
+                    }
+                    continue;
+                }
+                _ = replay.tick(), if self.spool.as_ref().is_some_and(|spool| !spool.is_empty()) => {
+                    self.replay().await;
+                    continue;
+                }
+            };
+            let message = match message {
+                Some(message) => message,
+                None => {
+                    self.flush_scheduled().await;
+                    continue;
+                }
+            };
+
+            match message {
+                Some(Message::Record(mut record)) => {
+                    record.tag = self.tags.rewrite(&record.tag);
+                    // batches get a chunk id of their own, empty without acks
+                    let batchable = record.options.is_batchable();
+                    if !self.require_ack {
+                        record.options.chunk.clear();
+                    }
+                    if let Some(ref mut schema) = self.schema {
+                        for event in schema.observe(&record.tag, &record.record) {
+                            diag!(warn, tag = record.tag; "schema drift detected: {:?}", event);
+                            let _ = self.events.send(event);
+                        }
+                    }
+
+                    if batching {
+                        if batchable {
+                            self.push(&record).await;
+                            continue;
+                        }
+                        // keep the order of records batched before
+                        self.flush(&record.tag).await;
+                    }
+                    self.observe_record_size(record.time(), &record.record);
+
+                    let (encoded, elapsed) =
+                        timed(|| encode(&record.tag, &record, &record.options));
+                    self.stats.observe_serialize_time(elapsed);
+                    let record = match encoded {
                         Ok(record) => record,
                         Err(e) => {
-                            warn!("failed to serialize a message: {}", e);
+                            diag!(
+                                warn,
+                                tag = record.tag,
+                                chunk_id = record.options.chunk;
+                                "failed to serialize a message: {}, record: {:?}",
+                                e,
+                                record
+                            );
+                            self.stats.observe_failed(1);
                             continue;
                         }
                     };
 
-                    match self.write_with_retry(&record).await {
-                        Ok(_) => {}
-                        Err(_) => continue,
+                    let result = self.deliver(&record).await;
+                    self.observe_result(&result, 1);
+                }
+                Some(Message::Forward(mut forward)) => {
+                    forward.tag = self.tags.rewrite(&forward.tag);
+                    if !self.require_ack {
+                        forward.options.chunk.clear();
+                    }
+                    if let Some(ref mut schema) = self.schema {
+                        for (_, record) in forward.entries.iter() {
+                            for event in schema.observe(&forward.tag, record) {
+                                diag!(warn, tag = forward.tag; "schema drift detected: {:?}", event);
+                                let _ = self.events.send(event);
+                            }
+                        }
+                    }
+                    for (timestamp, record) in forward.entries.iter() {
+                        self.observe_record_size(*timestamp, record);
+                    }
+                    // keep the order of records batched before
+                    self.flush(&forward.tag).await;
+
+                    let (encoded, elapsed) =
+                        timed(|| encode(&forward.tag, &forward, &forward.options));
+                    self.stats.observe_serialize_time(elapsed);
+                    let result = match encoded {
+                        Ok(record) => {
+                            match self.deliver_with(&record, forward.entries.len() > 1).await {
+                                // the parts are counted as they are delivered
+                                Err(Error::ChunkTooLarge) if forward.entries.len() > 1 => {
+                                    self.split_forward(&forward).await
+                                }
+                                result => {
+                                    self.observe_result(&result, forward.entries.len());
+                                    result
+                                }
+                            }
+                        }
+                        Err(e) => {
+                            diag!(
+                                warn,
+                                tag = forward.tag,
+                                chunk_id = forward.options.chunk;
+                                "failed to serialize a message: {}, record: {:?}",
+                                e,
+                                forward
+                            );
+                            self.stats.observe_failed(forward.entries.len());
+                            Err(Error::SerializeFailed(e.to_string()))
+                        }
+                    };
+                    if let Some(ref ack) = forward.ack {
+                        ack.notify(result);
+                    }
+                }
+                Some(Message::Raw(mut raw)) => {
+                    raw.tag = self.tags.rewrite(&raw.tag);
+                    // batches get a chunk id of their own, empty without acks
+                    let batchable = raw.options.is_batchable();
+                    if !self.require_ack {
+                        raw.options.chunk.clear();
+                    }
+                    if batching {
+                        if batchable {
+                            self.push_raw(&raw).await;
+                            continue;
+                        }
+                        // keep the order of records batched before
+                        self.flush(&raw.tag).await;
+                    }
+                    let mut counter = ByteCounter::default();
+                    if raw.write_entry(&mut counter).is_ok() {
+                        self.stats.observe_record_size(counter.0);
+                    }
+
+                    let chunk =
+                        (!raw.options.chunk.is_empty()).then_some(raw.options.chunk.as_str());
+                    let (record, elapsed) = timed(|| {
+                        Frame::message(&raw.tag, raw.timestamp, raw.record.clone(), chunk)
+                            .to_bytes()
+                    });
+                    self.stats.observe_serialize_time(elapsed);
+                    let record = SerializedRecord {
+                        record,
+                        tag: raw.tag.clone(),
+                        chunk: raw.options.chunk.clone(),
+                        max_retry: raw.options.max_retry,
                     };
+                    let result = self.deliver(&record).await;
+                    self.observe_result(&result, 1);
+                }
+                Some(Message::Reconnect) => {
+                    self.reconnect().await;
                 }
-                Err(RecvError::Closed) | Ok(Message::Terminate) => {
+                Some(Message::Flush(ack)) => {
+                    self.flush_all().await;
+                    ack.notify_delivered();
+                }
+                None | Some(Message::Terminate) => {
+                    self.flush_all().await;
                     break;
                 }
-                Err(RecvError::Lagged(_)) => continue,
             }
         }
     }
 
-    fn encode(&self, record: Record) -> Result<SerializedRecord, rmp_serde::encode::Error> {
-        let mut writer = bytes::BytesMut::new().writer();
-        record.serialize(&mut Serializer::new(&mut writer))?;
-        Ok(SerializedRecord {
-            record: writer.into_inner().freeze(),
-            chunk: record.options.chunk,
-        })
+    /// Emit an event for the records dropped by the overflow policy since the
+    /// last call.
+    fn report_dropped(&mut self) {
+        let dropped = self.receiver.dropped();
+        if dropped > self.dropped {
+            let _ = self.events.send(Event::RecordsDropped {
+                n: dropped - self.dropped,
+            });
+            self.dropped = dropped;
+        }
+    }
+
+    /// Emit an event, and a record to the failover tag if configured, for
+    /// every change of the server since the last call.
+    #[cfg(feature = "tcp")]
+    async fn report_transitions(&mut self) {
+        for transition in self.stream_config.transitions() {
+            diag!(
+                warn,
+                endpoint = transition.to;
+                "switched from {} to {}: {}",
+                transition.from,
+                transition.to,
+                transition.reason
+            );
+            let _ = self.events.send(Event::Failover {
+                from: transition.from.clone(),
+                to: transition.to.clone(),
+                reason: transition.reason.to_string(),
+                duration: transition.duration,
+            });
+
+            let Some(ref tag) = self.failover_tag else {
+                continue;
+            };
+            let mut record = Map::new();
+            record.insert("from".to_string(), transition.from.into());
+            record.insert("to".to_string(), transition.to.into());
+            record.insert("reason".to_string(), transition.reason.into());
+            record.insert(
+                "duration".to_string(),
+                transition.duration.as_secs_f64().into(),
+            );
+            let record = Record {
+                tag: tag.as_str().into(),
+                timestamp: chrono::Local::now().timestamp(),
+                nanos: None,
+                record,
+                options: Options {
+                    chunk: self.chunk_id(),
+                    ..Default::default()
+                },
+            };
+            let (encoded, elapsed) = timed(|| encode(&record.tag, &record, &record.options));
+            self.stats.observe_serialize_time(elapsed);
+            match encoded {
+                Ok(record) => {
+                    let _ = self.deliver(&record).await;
+                }
+                Err(e) => {
+                    diag!(warn, tag = record.tag; "failed to serialize a failover record: {}", e);
+                }
+            }
+        }
+    }
+
+    /// Return a new chunk id, or an empty one if acks are not requested.
+    fn chunk_id(&self) -> String {
+        if self.require_ack {
+            general_purpose::STANDARD.encode(Uuid::new_v4())
+        } else {
+            String::new()
+        }
+    }
+
+    /// Append a record to the batch of its tag, flushing the batch when it is full.
+    async fn push(&mut self, record: &Record) {
+        let result = self
+            .push_entry(&record.tag, |writer| {
+                (record.time(), &record.record)
+                    .serialize(&mut Serializer::new(writer))
+                    .map_err(|e| e.to_string())
+            })
+            .await;
+        if let Err(e) = result {
+            diag!(warn, tag = record.tag; "failed to serialize a message: {}, record: {:?}", e, record);
+        }
+    }
+
+    /// Append a record encoded beforehand to the batch of its tag, flushing
+    /// the batch when it is full.
+    async fn push_raw(&mut self, raw: &RawRecord) {
+        let result = self
+            .push_entry(&raw.tag, |writer| {
+                raw.write_entry(writer).map_err(|e| e.to_string())
+            })
+            .await;
+        if let Err(e) = result {
+            diag!(warn, tag = raw.tag; "failed to serialize a message: {}, record: {:?}", e, raw);
+        }
+    }
+
+    /// Append the `[time, record]` entry written by `write` to the batch of
+    /// `tag`. The entry is discarded and counted as failed on error.
+    async fn push_entry<F>(&mut self, tag: &Arc<str>, write: F) -> Result<(), String>
+    where
+        F: FnOnce(&mut bytes::buf::Writer<bytes::BytesMut>) -> Result<(), String>,
+    {
+        if !self.batches.contains_key(tag) {
+            self.pending.push_back(tag.clone());
+        }
+        let batch = self.batches.entry(tag.clone()).or_default();
+        let len = batch.entries.len();
+        let mut writer = std::mem::take(&mut batch.entries).writer();
+        let (result, elapsed) = timed(|| write(&mut writer));
+        batch.entries = writer.into_inner();
+        self.stats.observe_serialize_time(elapsed);
+        if let Err(e) = result {
+            batch.entries.truncate(len);
+            self.stats.observe_failed(1);
+            return Err(e);
+        }
+        batch.ends.push(batch.entries.len());
+        self.stats.observe_record_size(batch.entries.len() - len);
+
+        let max_size = self
+            .tuner
+            .as_ref()
+            .map_or(self.batch_config.max_size, |tuner| tuner.size);
+        if batch.entries.len() >= max_size {
+            self.flush(tag).await;
+        }
+        Ok(())
+    }
+
+    /// Count `records` as written, spooled or dropped after their delivery.
+    fn observe_result(&self, result: &Result<(), Error>, records: usize) {
+        match result {
+            Ok(_) => self.stats.observe_written(records),
+            Err(Error::Spooled) => self.stats.observe_spooled(records),
+            Err(_) => self.stats.observe_failed(records),
+        }
+    }
+
+    /// Record the size of the msgpack encoding of a `[time, record]` entry.
+    fn observe_record_size<T: Serialize>(&self, timestamp: T, record: &Map) {
+        let mut counter = ByteCounter::default();
+        if (timestamp, record)
+            .serialize(&mut Serializer::new(&mut counter))
+            .is_ok()
+        {
+            self.stats.observe_record_size(counter.0);
+        }
+    }
+
+    async fn flush_all(&mut self) {
+        while let Some(tag) = self.pending.front().cloned() {
+            self.flush(&tag).await;
+        }
+    }
+
+    /// Flush the batches in turn within the byte budget of a flush interval,
+    /// so that a hot tag can not delay the others. Batches left over are the
+    /// first to be flushed on the next interval.
+    async fn flush_scheduled(&mut self) {
+        for tag in within_budget(&self.pending, &self.batches, self.batch_config.budget) {
+            self.flush(&tag).await;
+        }
+    }
+
+    async fn flush(&mut self, tag: &Arc<str>) {
+        self.pending.retain(|pending| pending != tag);
+        let batch = match self.batches.remove(tag) {
+            Some(batch) if !batch.ends.is_empty() => batch,
+            _ => return,
+        };
+        let _ = self.deliver_entries(tag, &batch.entries, &batch.ends).await;
+    }
+
+    /// Resend the entries of a Forward message rejected as too large in smaller chunks.
+    async fn split_forward(&mut self, forward: &Forward) -> Result<(), Error> {
+        let mut entries = Vec::new();
+        let mut ends = Vec::with_capacity(forward.entries.len());
+        let (result, elapsed) = timed(|| {
+            for entry in forward.entries.iter() {
+                entry.serialize(&mut Serializer::new(&mut entries))?;
+                ends.push(entries.len());
+            }
+            Ok::<_, rmp_serde::encode::Error>(())
+        });
+        self.stats.observe_serialize_time(elapsed);
+        if let Err(e) = result {
+            self.stats.observe_failed(forward.entries.len());
+            return Err(Error::SerializeFailed(e.to_string()));
+        }
+        self.deliver_entries(&forward.tag, &entries, &ends).await
+    }
+
+    /// Write encoded `[time, record]` entries as PackedForward chunks. A chunk
+    /// which the server rejects as too large is split in halves, until every
+    /// part is accepted or a single entry is left to be given up.
+    async fn deliver_entries(
+        &mut self,
+        tag: &Arc<str>,
+        entries: &[u8],
+        ends: &[usize],
+    ) -> Result<(), Error> {
+        let mut result = Ok(());
+        // ranges of entries to send, the next one last
+        let mut parts = vec![(0, ends.len())];
+        while let Some((first, last)) = parts.pop() {
+            let chunk = self.chunk_id();
+            let range = entry_range(ends, first, last);
+            let (encoded, elapsed) =
+                timed(|| encode_packed_forward(tag, &entries[range], last - first, &chunk));
+            self.stats.observe_serialize_time(elapsed);
+            let record = match encoded {
+                Ok(record) => record,
+                Err(e) => {
+                    diag!(warn, tag = tag, chunk_id = chunk; "failed to serialize a chunk: {}", e);
+                    self.stats.observe_failed(last - first);
+                    result = Err(Error::SerializeFailed(e.to_string()));
+                    continue;
+                }
+            };
+            let record = SerializedRecord {
+                record,
+                tag: tag.clone(),
+                chunk,
+                max_retry: None,
+            };
+            let delivered = self.deliver_with(&record, last - first > 1).await;
+            if !matches!(delivered, Err(Error::ChunkTooLarge) if last - first > 1) {
+                self.observe_result(&delivered, last - first);
+            }
+            match delivered {
+                Ok(_) => {}
+                Err(Error::ChunkTooLarge) if last - first > 1 => {
+                    let middle = (first + last) / 2;
+                    diag!(
+                        warn,
+                        tag = tag;
+                        "splitting a chunk of {} entries rejected by the server",
+                        last - first
+                    );
+                    parts.push((middle, last));
+                    parts.push((first, middle));
+                }
+                Err(e) => {
+                    if let Error::ChunkTooLarge = e {
+                        diag!(warn, tag = tag; "giving up an entry rejected by the server");
+                    }
+                    result = Err(e);
+                }
+            }
+        }
+        result
+    }
+
+    /// Write a chunk, spooling it if the spool is enabled and the server can
+    /// not be reached. While older chunks are spooled, new chunks are appended
+    /// to the spool so that they are delivered in order.
+    async fn deliver(&mut self, record: &SerializedRecord) -> Result<(), Error> {
+        self.deliver_with(record, false).await
+    }
+
+    /// Write a chunk like `deliver`. A `splittable` chunk rejected as too
+    /// large is left to the caller to resend in parts, rather than given up.
+    async fn deliver_with(
+        &mut self,
+        record: &SerializedRecord,
+        splittable: bool,
+    ) -> Result<(), Error> {
+        let result = match self.hooks.clone() {
+            Some(hooks) => {
+                let chunk = ChunkInfo::new(&record.chunk, &record.record);
+                hooks.created(&chunk);
+                let result = self.deliver_chunk(record).await;
+                match result {
+                    Ok(_) => hooks.acked(&chunk),
+                    // acked once replayed
+                    Err(Error::Spooled) => {}
+                    Err(ref e) => hooks.abandoned(&chunk, &e.to_string()),
+                }
+                result
+            }
+            None => self.deliver_chunk(record).await,
+        };
+        if let Err(Error::MaxRetriesExceeded) = result {
+            let _ = self.events.send(Event::RetryExhausted {
+                chunk: record.chunk.clone(),
+                tag: record.tag.to_string(),
+            });
+        }
+        // given up by `Config::ack_mismatch`, or too large to ever be accepted;
+        // the latter is not spooled, since its replay would be rejected again
+        let given_up = match result {
+            Err(Error::MaxRetriesExceeded | Error::AckUnmatched(_, _)) => true,
+            Err(Error::ChunkTooLarge) => !splittable,
+            _ => false,
+        };
+        if given_up {
+            self.send_dead_letters(record);
+            if let Some(ref secondary) = self.secondary {
+                if let Err(e) = secondary.lock().await.append(&record.record).await {
+                    diag!(warn, chunk_id = record.chunk; "failed to write a chunk to the secondary: {}", e);
+                }
+            }
+        }
+        result
+    }
+
+    /// Hand the records of a dropped chunk to `Config::dead_letter`.
+    fn send_dead_letters(&self, record: &SerializedRecord) {
+        let Some(ref sender) = self.dead_letter else {
+            return;
+        };
+        let letters = match dead_letter::decode(&record.record) {
+            Ok(letters) => letters,
+            Err(e) => {
+                diag!(warn, chunk_id = record.chunk; "failed to decode a dropped chunk: {}", e);
+                return;
+            }
+        };
+        for letter in letters {
+            if let Err(e) = sender.try_send(letter) {
+                diag!(warn, chunk_id = record.chunk; "failed to send a dead letter: {}", e);
+            }
+        }
+    }
+
+    async fn deliver_chunk(&mut self, record: &SerializedRecord) -> Result<(), Error> {
+        if self.durable {
+            if let Some(result) = self.deliver_logged(record).await {
+                return result;
+            }
+        }
+        let result = match self.spool {
+            Some(ref spool) if !spool.is_empty() => Err(Error::MaxRetriesExceeded),
+            _ => self.write_with_retry(record).await,
+        };
+        let spool = match (result, self.spool.as_mut()) {
+            (Err(Error::MaxRetriesExceeded), Some(spool)) => spool,
+            (result, _) => return result,
+        };
+        match spool.push(&record.chunk, &record.record).await {
+            Ok(pruned) => {
+                report_pruned(pruned);
+                Err(Error::Spooled)
+            }
+            Err(e) => {
+                diag!(warn, chunk_id = record.chunk; "failed to spool a chunk: {}", e);
+                Err(Error::MaxRetriesExceeded)
+            }
+        }
+    }
+
+    /// Append a chunk to the spool before writing it, and remove it once it
+    /// is acked, so that it is replayed if the process exits in between.
+    /// Return None if the chunk could not be logged.
+    async fn deliver_logged(&mut self, record: &SerializedRecord) -> Option<Result<(), Error>> {
+        let spool = self.spool.as_mut()?;
+        match spool.push(&record.chunk, &record.record).await {
+            Ok(pruned) => report_pruned(pruned),
+            Err(e) => {
+                diag!(warn, chunk_id = record.chunk; "failed to log a chunk: {}", e);
+                return None;
+            }
+        }
+        // older chunks are replayed first
+        if spool.len() > 1 {
+            return Some(Err(Error::Spooled));
+        }
+        let result = match self.write_with_retry(record).await {
+            Err(Error::MaxRetriesExceeded) => return Some(Err(Error::Spooled)),
+            result => result,
+        };
+        if let Some(spool) = self.spool.as_mut() {
+            if let Err(e) = spool.pop().await {
+                diag!(warn, chunk_id = record.chunk; "failed to remove a logged chunk: {}", e);
+            }
+        }
+        Some(result)
+    }
+
+    /// Send the spooled chunks in order, stopping at the first failure to
+    /// write a chunk or to remove it from the spool. At most
+    /// one chunk is sent per `replay_interval`, and an `Event::ReplayProgress`
+    /// is emitted after every chunk.
+    async fn replay(&mut self) {
+        let Some(spool) = self.spool.as_mut() else {
+            return;
+        };
+        match spool.prune().await {
+            Ok(pruned) => report_pruned(pruned),
+            Err(e) => diag!(warn; "failed to prune the spool: {}", e),
+        }
+        diag!(debug, endpoint = self.stream_config.endpoint(); "replaying {} spooled chunks", spool.len());
+        let total = spool.len();
+        let (mut replayed, mut bytes) = (0, 0);
+        let mut failed = false;
+        let mut next = tokio::time::Instant::now();
+        loop {
+            let (chunk, record) = match spool.front().await {
+                Ok(Some(chunk)) => chunk,
+                Ok(None) => break,
+                Err(e) => {
+                    let removed = if e.kind() == std::io::ErrorKind::InvalidData {
+                        spool.quarantine().await.map(|path| {
+                            let path = path.unwrap_or_default();
+                            diag!(warn; "quarantined a corrupt spooled chunk to {}: {}", path.display(), e);
+                        })
+                    } else {
+                        diag!(warn; "dropping an unreadable spooled chunk: {}", e);
+                        spool.pop().await
+                    };
+                    // a spool which can not be changed is retried at the next
+                    // replay rather than in a loop
+                    if let Err(e) = removed {
+                        diag!(warn; "failed to remove a spooled chunk, pausing the replay: {}", e);
+                        break;
+                    }
+                    continue;
+                }
+            };
+            if let Some(interval) = self.replay_interval {
+                tokio::time::sleep_until(next).await;
+                next = tokio::time::Instant::now() + interval;
+            }
+            // spooled chunks do not keep their tag, which only matters to retries
+            let record = SerializedRecord {
+                record,
+                tag: "".into(),
+                chunk,
+                max_retry: None,
+            };
+            let started = Instant::now();
+            let written = Self::write(self.stream.get_mut(), &record, self.ack_timeout).await;
+            self.stats.observe_io_time(started.elapsed());
+            if let Err(e) = written {
+                diag!(
+                    debug,
+                    endpoint = self.stream_config.endpoint(),
+                    chunk_id = record.chunk;
+                    "failed to replay a spooled chunk: {}",
+                    e
+                );
+                failed = true;
+                break;
+            }
+            if let Some(ref hooks) = self.hooks {
+                hooks.acked(&ChunkInfo::new(&record.chunk, &record.record));
+            }
+            let removed = spool.pop().await;
+            replayed += 1;
+            bytes += record.record.len() as u64;
+            let _ = self.events.send(Event::ReplayProgress {
+                endpoint: self.stream_config.endpoint(),
+                replayed,
+                total,
+                bytes,
+            });
+            if let Err(e) = removed {
+                diag!(warn, chunk_id = record.chunk; "failed to remove a spooled chunk, pausing the replay: {}", e);
+                break;
+            }
+        }
+        if failed {
+            self.reconnect().await;
+        }
     }
 
     async fn write_with_retry(&mut self, record: &SerializedRecord) -> Result<(), Error> {
         let mut wait_time = Duration::from_millis(0);
-        for i in 0..self.retry_config.max as i32 {
+        let mut closed = 0;
+        let mut mismatches = 0;
+        // None to retry forever
+        let max = match record.max_retry {
+            Some(max) => Some(max),
+            None => (!self.retry_config.forever).then_some(self.retry_config.max),
+        };
+        let mut i: i32 = 0;
+        while max.map_or(true, |max| (i as u32) < max) {
+            if i > 0 && !self.take_retry() {
+                diag!(
+                    debug,
+                    endpoint = self.stream_config.endpoint(),
+                    chunk_id = record.chunk,
+                    attempt = i;
+                    "the retry budget is spent"
+                );
+                return Err(Error::MaxRetriesExceeded);
+            }
             tokio::time::sleep(wait_time).await;
 
             // reconnect when the lifetime is reached
             if !self.max_connection_lifetime.is_zero()
                 && self.last_connection_time.get().elapsed() >= self.max_connection_lifetime
             {
-                debug!("attempting to re-establish connection");
-                match self.stream_config.connect().await {
-                    Ok(new_stream) => {
-                        self.stream.replace(new_stream);
-                        self.last_connection_time.replace(Instant::now());
+                self.reconnect().await;
+            }
+
+            let started = Instant::now();
+            match self.write_timed(record).await {
+                Ok(_) if record.chunk.is_empty() => return Ok(()),
+                Ok(_) => {
+                    if let Some(ref mut tuner) = self.tuner {
+                        tuner.observe(started.elapsed());
                     }
-                    Err(err) => {
-                        warn!(
-                            "failed to reconnect. Will try again upon the next try-write: {}",
-                            err
+                    #[cfg(feature = "tcp")]
+                    if let Some(stats) = self.stream_config.stats() {
+                        stats.lock().unwrap().observe_latency(started.elapsed());
+                    }
+                    if let Some(event) = self.slow_acks.observe(started.elapsed()) {
+                        diag!(
+                            warn,
+                            endpoint = self.stream_config.endpoint(),
+                            chunk_id = record.chunk;
+                            "upstream is slow to ack: {:?}",
+                            event
                         );
+                        let _ = self.events.send(event);
                     }
+                    return Ok(());
+                }
+                // fluentd drops the connection upon a chunk exceeding its limit
+                Err(Error::ClosedAfterWrite) if closed + 1 >= CHUNK_REJECTED_CLOSES => {
+                    diag!(
+                        warn,
+                        endpoint = self.stream_config.endpoint(),
+                        chunk_id = record.chunk,
+                        size = record.record.len();
+                        "the server repeatedly closed the connection after a chunk"
+                    );
+                    self.reconnect().await;
+                    return Err(Error::ChunkTooLarge);
+                }
+                Err(e @ Error::AckUnmatched(_, _))
+                    if self.retry_config.ack_mismatch != AckMismatch::Reconnect =>
+                {
+                    closed = 0;
+                    match self.retry_config.ack_mismatch {
+                        AckMismatch::Resend(n) if mismatches < n => {
+                            mismatches += 1;
+                            diag!(
+                                warn,
+                                endpoint = self.stream_config.endpoint(),
+                                chunk_id = record.chunk,
+                                attempt = i + 1;
+                                "resending a chunk on the same connection: {}",
+                                e
+                            );
+                        }
+                        _ => {
+                            diag!(
+                                warn,
+                                endpoint = self.stream_config.endpoint(),
+                                chunk_id = record.chunk,
+                                attempt = i + 1;
+                                "giving up a chunk: {}",
+                                e
+                            );
+                            let _ = self.events.send(Event::Disconnected {
+                                endpoint: self.stream_config.endpoint(),
+                                reason: e.to_string(),
+                            });
+                            self.reconnect().await;
+                            return Err(e);
+                        }
+                    }
+                }
+                Err(
+                    e @ (Error::ConnectionClosed
+                    | Error::ClosedAfterWrite
+                    | Error::WriteFailed(_)
+                    | Error::ReadFailed(_)
+                    | Error::AckUnmatched(_, _)
+                    | Error::AckFrameTooLarge(_)
+                    | Error::InvalidAckFrame(_)
+                    | Error::AckTimeout(_)),
+                ) => {
+                    closed = match e {
+                        Error::ClosedAfterWrite => closed + 1,
+                        _ => 0,
+                    };
+                    // the connection is broken or out of sync, so resend on a new connection
+                    diag!(
+                        warn,
+                        endpoint = self.stream_config.endpoint(),
+                        chunk_id = record.chunk,
+                        attempt = i + 1;
+                        "dropping the connection: {}",
+                        e
+                    );
+                    let _ = self.events.send(Event::Disconnected {
+                        endpoint: self.stream_config.endpoint(),
+                        reason: e.to_string(),
+                    });
+                    // only closes of working connections hint at the chunk
+                    if !self.reconnect().await {
+                        closed = 0;
+                    }
+                }
+                Err(e) => {
+                    diag!(
+                        debug,
+                        endpoint = self.stream_config.endpoint(),
+                        chunk_id = record.chunk,
+                        attempt = i + 1;
+                        "failed to write a chunk: {}",
+                        e
+                    );
                 }
-            }
-
-            match Self::write(&mut self.stream.get_mut(), record).await {
-                Ok(_) => return Ok(()),
-                Err(Error::ConnectionClosed) => return Err(Error::ConnectionClosed),
-                Err(_) => {}
             }
 
             let mut t =
@@ -190,34 +1500,113 @@ where
             if t > self.retry_config.max_wait {
                 t = self.retry_config.max_wait;
             }
-            wait_time = Duration::from_millis(t);
+            let random = Uuid::new_v4().as_u128() as u64;
+            wait_time = Duration::from_millis(jittered(t, self.retry_config.jitter, random));
+            i = i.saturating_add(1);
         }
-        warn!("write's max retries exceeded.");
+        diag!(
+            warn,
+            endpoint = self.stream_config.endpoint(),
+            chunk_id = record.chunk,
+            attempt = i;
+            "write's max retries exceeded."
+        );
         Err(Error::MaxRetriesExceeded)
     }
 
-    async fn write(stream: &mut StreamType, record: &SerializedRecord) -> Result<(), Error> {
+    /// Take a retry from the budget if there is one, emitting an
+    /// `Event::Backoff` when it is first found spent.
+    fn take_retry(&mut self) -> bool {
+        let Some(ref budget) = self.retry_config.budget else {
+            return true;
+        };
+        let allowed = budget.lock().unwrap().take(Instant::now());
+        if !allowed && !self.backing_off {
+            diag!(warn, endpoint = self.stream_config.endpoint(); "the retry budget is spent, failing chunks without retries");
+            let _ = self.events.send(Event::Backoff {
+                endpoint: self.stream_config.endpoint(),
+            });
+        }
+        self.backing_off = !allowed;
+        allowed
+    }
+
+    /// Replace the connection with a new one, returning false if it failed.
+    async fn reconnect(&mut self) -> bool {
+        diag!(debug, endpoint = self.stream_config.endpoint(); "attempting to re-establish connection");
+        match Self::connect(&self.stream_config, self.handshake.as_ref()).await {
+            Ok(new_stream) => {
+                self.stream.replace(new_stream);
+                self.last_connection_time.replace(Instant::now());
+                let _ = self.events.send(Event::Connected {
+                    endpoint: self.stream_config.endpoint(),
+                });
+                true
+            }
+            Err(err) => {
+                diag!(
+                    warn,
+                    endpoint = self.stream_config.endpoint();
+                    "failed to reconnect. Will try again upon the next try-write: {}",
+                    err
+                );
+                false
+            }
+        }
+    }
+
+    /// Write a message, counting the time until it is written and acked.
+    async fn write_timed(&mut self, record: &SerializedRecord) -> Result<(), Error> {
+        let started = Instant::now();
+        let result = Self::write(self.stream.get_mut(), record, self.ack_timeout).await;
+        self.stats.observe_io_time(started.elapsed());
+        result
+    }
+
+    async fn write(
+        stream: &mut C::Stream,
+        record: &SerializedRecord,
+        ack_timeout: Duration,
+    ) -> Result<(), Error> {
         stream
             .write_all(record.record.chunk())
             .await
             .map_err(|e| Error::WriteFailed(e.to_string()))?;
+        if record.chunk.is_empty() {
+            return Ok(());
+        }
 
-        let received_ack = Self::read_ack(stream).await?;
+        let written = Instant::now();
+        let received = if ack_timeout.is_zero() {
+            Self::read_ack(stream).await
+        } else {
+            tokio::time::timeout(ack_timeout, Self::read_ack(stream))
+                .await
+                .map_err(|_| Error::AckTimeout(ack_timeout))?
+        };
+        let received_ack = match received {
+            Err(Error::ClosedAfterWrite) if written.elapsed() > CHUNK_REJECTED_WINDOW => {
+                return Err(Error::ConnectionClosed);
+            }
+            received => received?,
+        };
 
         if received_ack.ack != record.chunk {
-            warn!(
-                "ack and chunk did not match. ack: {}, chunk: {}",
-                received_ack.ack, record.chunk
+            diag!(
+                warn,
+                chunk_id = record.chunk,
+                ack = received_ack.ack;
+                "ack and chunk did not match."
             );
             return Err(Error::AckUnmatched(received_ack.ack, record.chunk.clone()));
         }
         Ok(())
     }
 
-    async fn read_ack(stream: &mut StreamType) -> Result<AckResponse, Error> {
+    async fn read_ack(stream: &mut C::Stream) -> Result<AckResponse, Error> {
         let mut buf = bytes::BytesMut::with_capacity(64);
         loop {
-            if let Ok(ack) = rmp_serde::from_slice::<AckResponse>(&buf) {
+            if let Some(ack) = decode_ack(&buf)? {
                 return Ok(ack);
             }
 
@@ -227,41 +1616,959 @@ where
                 .map_err(|e| Error::ReadFailed(e.to_string()))?
                 == 0
             {
-                return Err(Error::ConnectionClosed);
+                return Err(match buf.is_empty() {
+                    true => Error::ClosedAfterWrite,
+                    false => Error::ConnectionClosed,
+                });
             }
         }
     }
 }
 
-#[async_trait]
-pub trait Connectable<T> {
-    async fn connect(&self) -> AnyhowResult<T>;
-}
+/// Decode an ack response, returning None if more bytes are needed.
+fn decode_ack(buf: &[u8]) -> Result<Option<AckResponse>, Error> {
+    use rmp_serde::decode::Error as DecodeError;
 
-#[derive(Debug)]
-pub struct TCPConnectionConfig {
-    pub addr: std::net::SocketAddr,
-    pub timeout: Duration,
+    match rmp_serde::from_slice::<AckResponse>(buf) {
+        Ok(ack) => Ok(Some(ack)),
+        Err(DecodeError::InvalidMarkerRead(ref e) | DecodeError::InvalidDataRead(ref e))
+            if e.kind() == std::io::ErrorKind::UnexpectedEof =>
+        {
+            if buf.len() > MAX_ACK_FRAME_SIZE {
+                return Err(Error::AckFrameTooLarge(buf.len()));
+            }
+            Ok(None)
+        }
+        Err(e) => Err(Error::InvalidAckFrame(e.to_string())),
+    }
 }
 
-#[async_trait]
-impl Connectable<TcpStream> for TCPConnectionConfig {
-    async fn connect(&self) -> AnyhowResult<TcpStream> {
-        let stream = timeout(self.timeout, TcpStream::connect(self.addr)).await??;
-        Ok(stream)
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_ack() {
+        let ack = rmp_serde::to_vec_named(&HashMap::from([("ack", "chunk")])).unwrap();
+        assert_eq!(decode_ack(&ack).unwrap().unwrap().ack, "chunk");
+        // incomplete frames wait for more bytes
+        assert!(decode_ack(&[]).unwrap().is_none());
+        assert!(decode_ack(&ack[..ack.len() - 1]).unwrap().is_none());
+
+        // a huge string where an ack is expected
+        let mut huge = vec![0x81, 0xa3, b'a', b'c', b'k', 0xdb, 0xff, 0xff, 0xff, 0xff];
+        huge.resize(MAX_ACK_FRAME_SIZE + 1, b'x');
+        assert!(matches!(
+            decode_ack(&huge),
+            Err(Error::AckFrameTooLarge(size)) if size == MAX_ACK_FRAME_SIZE + 1
+        ));
+        // garbage which is not an ack
+        assert!(matches!(
+            decode_ack(&[0xc1, 0x00]),
+            Err(Error::InvalidAckFrame(_))
+        ));
+        let not_ack = rmp_serde::to_vec(&42).unwrap();
+        assert!(matches!(
+            decode_ack(&not_ack),
+            Err(Error::InvalidAckFrame(_))
+        ));
     }
-}
 
-#[derive(Debug)]
-pub struct UnixSocketConfig {
-    pub path: PathBuf,
-    pub timeout: Duration,
-}
+    #[test]
+    fn test_slow_ack_tracker() {
+        let mut tracker = SlowAckTracker::new(SlowAckConfig {
+            threshold: Duration::from_millis(100),
+            window: 2,
+        });
+        assert_eq!(tracker.observe(Duration::from_millis(200)), None);
+        // a fast ack resets the window
+        assert_eq!(tracker.observe(Duration::from_millis(10)), None);
+        assert_eq!(tracker.observe(Duration::from_millis(200)), None);
+        assert_eq!(
+            tracker.observe(Duration::from_millis(400)),
+            Some(Event::SlowAck {
+                chunks: 2,
+                average: Duration::from_millis(300),
+            })
+        );
+        assert_eq!(tracker.observe(Duration::from_millis(200)), None);
+    }
 
-#[async_trait]
-impl Connectable<UnixStream> for UnixSocketConfig {
-    async fn connect(&self) -> AnyhowResult<UnixStream> {
-        let stream = timeout(self.timeout, UnixStream::connect(self.path.as_path())).await??;
-        Ok(stream)
+    #[test]
+    fn test_serialize_options() {
+        let options = Options {
+            chunk: "chunk-id".to_string(),
+            max_retry: Some(3),
+        };
+        let got = rmp_serde::to_vec(&options).unwrap();
+        assert_eq!(
+            got,
+            rmp_serde::to_vec(
+                &[("chunk", "chunk-id")]
+                    .into_iter()
+                    .collect::<HashMap<_, _>>()
+            )
+            .unwrap()
+        );
+
+        // no chunk requests no ack
+        let got = rmp_serde::to_vec(&Options::default()).unwrap();
+        assert_eq!(got, [0x80]);
+    }
+
+    #[test]
+    fn test_serialize_event_time() {
+        let mut record = Record {
+            tag: "test".into(),
+            timestamp: 1234567,
+            nanos: None,
+            record: Map::new(),
+            options: Options::default(),
+        };
+        let got = rmp_serde::to_vec(&record).unwrap();
+        assert_eq!(
+            got,
+            rmp_serde::to_vec(&("test", 1234567, Map::new(), Map::new())).unwrap()
+        );
+
+        record.nanos = Some(500);
+        let got = rmp_serde::to_vec(&record).unwrap();
+        // fixext 8 of type 0 holding the seconds and the nanoseconds
+        let mut time = vec![0xd7, 0x00];
+        time.extend(1234567u32.to_be_bytes());
+        time.extend(500u32.to_be_bytes());
+        let mut want = vec![0x94, 0xa4];
+        want.extend(b"test");
+        want.extend(time);
+        want.extend([0x80, 0x80]);
+        assert_eq!(got, want);
+    }
+
+    #[test]
+    fn test_raw_entry() {
+        use std::collections::HashMap;
+
+        use crate::record::Value;
+        use crate::record_map;
+
+        let record = record_map!("age".to_string() => 22.into());
+        let raw = RawRecord {
+            tag: "test".into(),
+            timestamp: 1234567,
+            record: rmp_serde::to_vec(&record).unwrap().into(),
+            options: Options::default(),
+        };
+        let mut entry = Vec::new();
+        raw.write_entry(&mut entry).unwrap();
+        assert_eq!(entry, rmp_serde::to_vec(&(1234567, &record)).unwrap());
+    }
+
+    #[test]
+    fn test_batch_tuner() {
+        let config = AdaptiveConfig {
+            min_size: 100,
+            min_flush_interval: Duration::from_millis(10),
+            target_latency: Duration::from_millis(100),
+        };
+        let mut tuner = BatchTuner::new(&config, 350, Duration::from_millis(50));
+        assert_eq!(
+            (tuner.size, tuner.interval),
+            (100, Duration::from_millis(10))
+        );
+        assert_eq!(tuner.take_interval(), None);
+
+        // fast acks grow the batch up to the upper bounds
+        for _ in 0..3 {
+            tuner.observe(Duration::from_millis(10));
+        }
+        assert_eq!(
+            (tuner.size, tuner.interval),
+            (350, Duration::from_millis(50))
+        );
+        assert_eq!(tuner.take_interval(), Some(Duration::from_millis(50)));
+        assert_eq!(tuner.take_interval(), None);
+
+        // latencies close to the target leave it unchanged
+        tuner.observe(Duration::from_millis(80));
+        assert_eq!(
+            (tuner.size, tuner.interval),
+            (350, Duration::from_millis(50))
+        );
+        assert_eq!(tuner.take_interval(), None);
+
+        // slow acks shrink it down to the lower bounds
+        for _ in 0..3 {
+            tuner.observe(Duration::from_millis(200));
+        }
+        assert_eq!(
+            (tuner.size, tuner.interval),
+            (100, Duration::from_millis(10))
+        );
+        assert_eq!(tuner.take_interval(), Some(Duration::from_millis(10)));
+
+        // lower bounds above the upper bounds are clamped
+        let tuner = BatchTuner::new(&config, 50, Duration::from_millis(5));
+        assert_eq!((tuner.size, tuner.interval), (50, Duration::from_millis(5)));
+    }
+
+    #[test]
+    fn test_jittered() {
+        assert_eq!(jittered(1000, Jitter::None, 123), 1000);
+        assert_eq!(jittered(1000, Jitter::Full, 123), 123);
+        assert_eq!(jittered(1000, Jitter::Full, 1000), 1000);
+        assert_eq!(jittered(1000, Jitter::Full, 1001), 0);
+        assert_eq!(jittered(1000, Jitter::Equal, 123), 623);
+        assert_eq!(jittered(1000, Jitter::Equal, 500), 1000);
+        assert_eq!(jittered(1001, Jitter::Equal, 501), 1001);
+        assert_eq!(jittered(0, Jitter::Full, u64::MAX), 0);
+        assert_eq!(jittered(0, Jitter::Equal, u64::MAX), 0);
+    }
+
+    #[test]
+    fn test_retry_budget() {
+        let mut budget = RetryBudget::new(2);
+        let now = budget.refilled;
+        assert!(budget.take(now));
+        assert!(budget.take(now));
+        assert!(!budget.take(now));
+        assert!(!budget.take(now + Duration::from_millis(400)));
+        assert!(budget.take(now + Duration::from_millis(500)));
+        // the bucket holds at most a second of retries
+        let later = now + Duration::from_secs(10);
+        assert!(budget.take(later));
+        assert!(budget.take(later));
+        assert!(!budget.take(later));
+    }
+
+    #[test]
+    fn test_within_budget() {
+        let batch = |size: usize| Batch {
+            entries: bytes::BytesMut::from(&vec![0u8; size][..]),
+            ends: vec![size],
+        };
+        let pending: VecDeque<Arc<str>> = ["hot", "cold", "warm"].map(Arc::from).into();
+        let batches = HashMap::from([
+            (Arc::from("hot"), batch(100)),
+            (Arc::from("cold"), batch(1)),
+            (Arc::from("warm"), batch(10)),
+        ]);
+        let tags = |budget| -> Vec<String> {
+            within_budget(&pending, &batches, budget)
+                .iter()
+                .map(|tag| tag.to_string())
+                .collect()
+        };
+        assert_eq!(tags(0), ["hot", "cold", "warm"]);
+        assert_eq!(tags(50), ["hot"]);
+        assert_eq!(tags(101), ["hot", "cold"]);
+        assert_eq!(tags(1000), ["hot", "cold", "warm"]);
+    }
+
+    #[test]
+    fn test_entry_range() {
+        let ends = [3, 5, 9, 10];
+        assert_eq!(entry_range(&ends, 0, 4), 0..10);
+        assert_eq!(entry_range(&ends, 0, 2), 0..5);
+        assert_eq!(entry_range(&ends, 2, 4), 5..10);
+        assert_eq!(entry_range(&ends, 3, 4), 9..10);
+    }
+
+    #[test]
+    fn test_encode_packed_forward() {
+        use crate::record::Value;
+        use crate::record_map;
+
+        let mut entries = Vec::new();
+        let record = record_map!("age".to_string() => 20.into());
+        (1234567, &record)
+            .serialize(&mut Serializer::new(&mut entries))
+            .unwrap();
+        (1234568, &record)
+            .serialize(&mut Serializer::new(&mut entries))
+            .unwrap();
+
+        let got = encode_packed_forward("test", &entries, 2, "chunk-id").unwrap();
+        let mut rd = &got[..];
+        assert_eq!(rmp::decode::read_array_len(&mut rd).unwrap(), 3);
+        let mut buf = [0u8; 16];
+        assert_eq!(rmp::decode::read_str(&mut rd, &mut buf).unwrap(), "test");
+        let len = rmp::decode::read_bin_len(&mut rd).unwrap() as usize;
+        assert_eq!(&rd[..len], &entries[..]);
+        rd = &rd[len..];
+        assert_eq!(rmp::decode::read_map_len(&mut rd).unwrap(), 2);
+        assert_eq!(rmp::decode::read_str(&mut rd, &mut buf).unwrap(), "chunk");
+        assert_eq!(
+            rmp::decode::read_str(&mut rd, &mut buf).unwrap(),
+            "chunk-id"
+        );
+        assert_eq!(rmp::decode::read_str(&mut rd, &mut buf).unwrap(), "size");
+        assert_eq!(rmp::decode::read_int::<u64, _>(&mut rd).unwrap(), 2);
+        assert!(rd.is_empty());
+
+        // without acks
+        let got = encode_packed_forward("test", &entries, 2, "").unwrap();
+        let mut rd = &got[got.len() - 7..];
+        assert_eq!(rmp::decode::read_map_len(&mut rd).unwrap(), 1);
+        assert_eq!(rmp::decode::read_str(&mut rd, &mut buf).unwrap(), "size");
+        assert_eq!(rmp::decode::read_int::<u64, _>(&mut rd).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_slow_ack_tracker_disabled() {
+        let mut tracker = SlowAckTracker::new(SlowAckConfig {
+            threshold: Duration::ZERO,
+            window: 1,
+        });
+        assert_eq!(tracker.observe(Duration::from_secs(10)), None);
+    }
+
+    /// Connects to servers which hang up right away.
+    struct HangingUp;
+
+    impl Connectable for HangingUp {
+        type Stream = tokio::io::DuplexStream;
+
+        async fn connect(&self) -> AnyhowResult<Self::Stream> {
+            let (stream, _server) = tokio::io::duplex(64);
+            Ok(stream)
+        }
+
+        fn endpoint(&self) -> String {
+            "hanging-up".to_string()
+        }
+    }
+
+    /// Connects to servers which hang up right away `failures` times, then
+    /// to a server which reads forever.
+    struct Flaky {
+        failures: std::sync::atomic::AtomicUsize,
+        servers: Mutex<Vec<tokio::io::DuplexStream>>,
+    }
+
+    impl Connectable for Flaky {
+        type Stream = tokio::io::DuplexStream;
+
+        async fn connect(&self) -> AnyhowResult<Self::Stream> {
+            let (stream, server) = tokio::io::duplex(1024);
+            let failures = &self.failures;
+            if failures.load(std::sync::atomic::Ordering::Relaxed) == 0 {
+                self.servers.lock().unwrap().push(server);
+            } else {
+                failures.fetch_sub(1, std::sync::atomic::Ordering::Relaxed);
+            }
+            Ok(stream)
+        }
+
+        fn endpoint(&self) -> String {
+            "flaky".to_string()
+        }
+    }
+
+    /// A server which hangs up right after reading a chunk, as fluentd does
+    /// with chunks above its `chunk_limit_size`.
+    struct Rejecting;
+
+    impl Connectable for Rejecting {
+        type Stream = tokio::io::DuplexStream;
+
+        async fn connect(&self) -> AnyhowResult<Self::Stream> {
+            let (stream, mut server) = tokio::io::duplex(1024);
+            tokio::spawn(async move {
+                let mut buf = [0; 1024];
+                let _ = server.read(&mut buf).await;
+            });
+            Ok(stream)
+        }
+
+        fn endpoint(&self) -> String {
+            "rejecting".to_string()
+        }
+    }
+
+    /// A server which acks every chunk with the chunk id `ack`.
+    #[derive(Default)]
+    struct Acking {
+        ack: &'static str,
+        connections: Arc<std::sync::atomic::AtomicUsize>,
+        writes: Arc<std::sync::atomic::AtomicUsize>,
+        #[cfg(feature = "tcp")]
+        stats: Arc<Mutex<crate::selector::ServerStats>>,
+    }
+
+    impl Connectable for Acking {
+        type Stream = tokio::io::DuplexStream;
+
+        async fn connect(&self) -> AnyhowResult<Self::Stream> {
+            let (stream, mut server) = tokio::io::duplex(1024);
+            self.connections
+                .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            let writes = self.writes.clone();
+            let ack = rmp_serde::to_vec_named(&HashMap::from([("ack", self.ack)])).unwrap();
+            tokio::spawn(async move {
+                let mut buf = [0; 1024];
+                while let Ok(1..) = server.read(&mut buf).await {
+                    writes.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                    if server.write_all(&ack).await.is_err() {
+                        break;
+                    }
+                }
+            });
+            Ok(stream)
+        }
+
+        fn endpoint(&self) -> String {
+            "acking".to_string()
+        }
+
+        #[cfg(feature = "tcp")]
+        fn stats(&self) -> Option<Arc<Mutex<crate::selector::ServerStats>>> {
+            Some(self.stats.clone())
+        }
+    }
+
+    fn config() -> WorkerConfig {
+        WorkerConfig {
+            max_connection_lifetime: Duration::ZERO,
+            ack_timeout: Duration::ZERO,
+            require_ack: true,
+            retry: RetryConfig {
+                initial_wait: 1,
+                max: 2,
+                max_wait: 1,
+                jitter: Jitter::None,
+                forever: false,
+                budget: None,
+                ack_mismatch: AckMismatch::Reconnect,
+            },
+            slow_ack: SlowAckConfig {
+                threshold: Duration::ZERO,
+                window: 1,
+            },
+            batch: BatchConfig {
+                mode: Mode::Message,
+                flush_interval: Duration::from_secs(1),
+                max_size: 1,
+                budget: 0,
+                adaptive: None,
+            },
+            detect_schema_drift: false,
+            handshake: None,
+            tag_rewrite_rules: Vec::new(),
+            refresh_interval: Duration::ZERO,
+            stats: Arc::new(Recorder::default()),
+            #[cfg(feature = "tcp")]
+            failover_tag: None,
+            spool_dir: None,
+            spool_limits: Limits::default(),
+            spool_replay_rate: 0,
+            durable: false,
+            chunk_hooks: None,
+            dead_letter: None,
+            secondary: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_events() {
+        let (sender, receiver) = queue::channel(1, crate::client::Overflow::DropNewest);
+        let (events, mut subscriber) = tokio::sync::broadcast::channel(128);
+        let mut worker = Worker::new(HangingUp, receiver, events, config())
+            .await
+            .unwrap();
+        for _ in 0..2 {
+            let record = Record {
+                tag: "test".into(),
+                timestamp: 0,
+                nanos: None,
+                record: Map::new(),
+                options: Options::default(),
+            };
+            sender.send(Message::Record(record)).unwrap();
+        }
+        drop(sender);
+        worker.run().await;
+
+        assert_eq!(
+            subscriber.recv().await.unwrap(),
+            Event::RecordsDropped { n: 1 }
+        );
+        for _ in 0..2 {
+            assert!(matches!(
+                subscriber.recv().await.unwrap(),
+                Event::Disconnected { endpoint, .. } if endpoint == "hanging-up"
+            ));
+            assert!(matches!(
+                subscriber.recv().await.unwrap(),
+                Event::Connected { endpoint } if endpoint == "hanging-up"
+            ));
+        }
+        assert_eq!(
+            subscriber.recv().await.unwrap(),
+            Event::RetryExhausted {
+                chunk: String::new(),
+                tag: "test".to_string(),
+            }
+        );
+        assert!(subscriber.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_dead_letter() {
+        let (sender, receiver) = queue::channel(1024, crate::client::Overflow::DropNewest);
+        let (letters, mut dead_letters) = mpsc::channel(1);
+        let config = WorkerConfig {
+            dead_letter: Some(letters),
+            ..config()
+        };
+        let mut worker = Worker::new(
+            HangingUp,
+            receiver,
+            tokio::sync::broadcast::channel(1).0,
+            config,
+        )
+        .await
+        .unwrap();
+        for timestamp in 0..2 {
+            let record = Record {
+                tag: "test".into(),
+                timestamp,
+                nanos: None,
+                record: Map::new(),
+                options: Options::default(),
+            };
+            sender.send(Message::Record(record)).unwrap();
+        }
+        drop(sender);
+        worker.run().await;
+
+        // the second record is discarded, since the channel is full
+        assert_eq!(
+            dead_letters.recv().await.unwrap(),
+            DeadLetter {
+                tag: "test".to_string(),
+                time: chrono::TimeZone::timestamp_opt(&chrono::Utc, 0, 0).unwrap(),
+                record: Map::new(),
+            }
+        );
+        assert!(dead_letters.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_retry_forever() {
+        let (sender, receiver) = queue::channel(1024, crate::client::Overflow::DropNewest);
+        let stats = Arc::new(Recorder::default());
+        let mut config = WorkerConfig {
+            stats: stats.clone(),
+            ..config()
+        };
+        config.retry.forever = true;
+        let connectable = Flaky {
+            failures: 5.into(),
+            servers: Mutex::new(Vec::new()),
+        };
+        let (events, _) = tokio::sync::broadcast::channel(1);
+        let mut worker = Worker::new(connectable, receiver, events, config)
+            .await
+            .unwrap();
+        let record = Record {
+            tag: "test".into(),
+            timestamp: 0,
+            nanos: None,
+            record: Map::new(),
+            options: Options::default(),
+        };
+        sender.send(Message::Record(record)).unwrap();
+        drop(sender);
+        worker.run().await;
+
+        let stats = stats.snapshot();
+        assert_eq!(stats.written_records, 1);
+        assert_eq!(stats.failed_records, 0);
+    }
+
+    #[tokio::test]
+    async fn test_retry_budget_spent() {
+        let (sender, receiver) = queue::channel(1024, crate::client::Overflow::DropNewest);
+        let stats = Arc::new(Recorder::default());
+        let mut config = WorkerConfig {
+            stats: stats.clone(),
+            ..config()
+        };
+        config.retry.max = 5;
+        config.retry.budget = Some(Arc::new(Mutex::new(RetryBudget::new(1))));
+        let (events, mut subscriber) = tokio::sync::broadcast::channel(128);
+        let mut worker = Worker::new(HangingUp, receiver, events, config)
+            .await
+            .unwrap();
+        for _ in 0..2 {
+            let record = Record {
+                tag: "test".into(),
+                timestamp: 0,
+                nanos: None,
+                record: Map::new(),
+                options: Options::default(),
+            };
+            sender.send(Message::Record(record)).unwrap();
+        }
+        drop(sender);
+        worker.run().await;
+
+        assert_eq!(stats.snapshot().failed_records, 2);
+        let mut backoffs = 0;
+        while let Ok(event) = subscriber.try_recv() {
+            if let Event::Backoff { endpoint } = event {
+                assert_eq!(endpoint, "hanging-up");
+                backoffs += 1;
+            }
+        }
+        assert_eq!(backoffs, 1);
+    }
+
+    #[tokio::test]
+    async fn test_ack_timeout() {
+        let (sender, receiver) = queue::channel(1024, crate::client::Overflow::DropNewest);
+        let stats = Arc::new(Recorder::default());
+        let config = WorkerConfig {
+            ack_timeout: Duration::from_millis(10),
+            stats: stats.clone(),
+            ..config()
+        };
+        // a server which reads but never acks
+        let connectable = Flaky {
+            failures: 0.into(),
+            servers: Mutex::new(Vec::new()),
+        };
+        let (events, mut subscriber) = tokio::sync::broadcast::channel(128);
+        let mut worker = Worker::new(connectable, receiver, events, config)
+            .await
+            .unwrap();
+        let record = Record {
+            tag: "test".into(),
+            timestamp: 0,
+            nanos: None,
+            record: Map::new(),
+            options: Options {
+                chunk: "chunk-id".to_string(),
+                ..Default::default()
+            },
+        };
+        sender.send(Message::Record(record)).unwrap();
+        drop(sender);
+        worker.run().await;
+
+        assert_eq!(stats.snapshot().failed_records, 1);
+        assert!(matches!(
+            subscriber.recv().await.unwrap(),
+            Event::Disconnected { reason, .. } if reason == "no ack within 10ms"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_ack_mismatch() {
+        use std::sync::atomic::Ordering;
+
+        // (policy, writes, connections)
+        let cases = [
+            (AckMismatch::Reconnect, 3, 4),
+            (AckMismatch::Resend(1), 2, 2),
+            (AckMismatch::DeadLetter, 1, 2),
+        ];
+        for (policy, writes, connections) in cases {
+            let (sender, receiver) = queue::channel(1024, crate::client::Overflow::DropNewest);
+            let (letters, mut dead_letters) = mpsc::channel(1);
+            let mut config = WorkerConfig {
+                dead_letter: Some(letters),
+                ..config()
+            };
+            config.retry.max = 3;
+            config.retry.ack_mismatch = policy;
+            let connectable = Acking {
+                ack: "wrong",
+                ..Default::default()
+            };
+            let (counts, written) = (connectable.connections.clone(), connectable.writes.clone());
+            let mut worker = Worker::new(
+                connectable,
+                receiver,
+                tokio::sync::broadcast::channel(16).0,
+                config,
+            )
+            .await
+            .unwrap();
+            let record = Record {
+                tag: "test".into(),
+                timestamp: 0,
+                nanos: None,
+                record: Map::new(),
+                options: Options {
+                    chunk: "chunk-id".to_string(),
+                    ..Default::default()
+                },
+            };
+            sender.send(Message::Record(record)).unwrap();
+            drop(sender);
+            worker.run().await;
+
+            assert_eq!(written.load(Ordering::Relaxed), writes, "{:?}", policy);
+            assert_eq!(counts.load(Ordering::Relaxed), connections, "{:?}", policy);
+            assert_eq!(dead_letters.recv().await.unwrap().tag, "test");
+        }
+    }
+
+    #[cfg(feature = "tcp")]
+    #[tokio::test]
+    async fn test_ack_latency() {
+        let (sender, receiver) = queue::channel(1024, crate::client::Overflow::DropNewest);
+        let connectable = Acking {
+            ack: "chunk-id",
+            ..Default::default()
+        };
+        let stats = connectable.stats.clone();
+        let (events, _) = tokio::sync::broadcast::channel(1);
+        let mut worker = Worker::new(connectable, receiver, events, config())
+            .await
+            .unwrap();
+        let record = Record {
+            tag: "test".into(),
+            timestamp: 0,
+            nanos: None,
+            record: Map::new(),
+            options: Options {
+                chunk: "chunk-id".to_string(),
+                ..Default::default()
+            },
+        };
+        sender.send(Message::Record(record)).unwrap();
+        drop(sender);
+        worker.run().await;
+
+        assert!(stats.lock().unwrap().latency.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_read_ack_closed() {
+        let (mut stream, server) = tokio::io::duplex(64);
+        drop(server);
+        assert!(matches!(
+            Worker::<Rejecting>::read_ack(&mut stream).await,
+            Err(Error::ClosedAfterWrite)
+        ));
+
+        // a close in the middle of an ack is not a rejection of the chunk
+        let (mut stream, mut server) = tokio::io::duplex(64);
+        let ack = rmp_serde::to_vec_named(&HashMap::from([("ack", "chunk-id")])).unwrap();
+        server.write_all(&ack[..ack.len() - 1]).await.unwrap();
+        drop(server);
+        assert!(matches!(
+            Worker::<Rejecting>::read_ack(&mut stream).await,
+            Err(Error::ConnectionClosed)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_chunk_too_large_dead_letter() {
+        let (sender, receiver) = queue::channel(1024, crate::client::Overflow::DropNewest);
+        let (letters, mut dead_letters) = mpsc::channel(1);
+        let (events, mut subscriber) = tokio::sync::broadcast::channel(128);
+        let config = WorkerConfig {
+            dead_letter: Some(letters),
+            ..config()
+        };
+        let mut worker = Worker::new(Rejecting, receiver, events, config)
+            .await
+            .unwrap();
+        let record = Record {
+            tag: "test".into(),
+            timestamp: 0,
+            nanos: None,
+            record: Map::new(),
+            options: Options {
+                chunk: "chunk-id".to_string(),
+                ..Default::default()
+            },
+        };
+        sender.send(Message::Record(record)).unwrap();
+        drop(sender);
+        worker.run().await;
+
+        // a single record can not be split, so it is given up
+        assert_eq!(
+            dead_letters.try_recv().unwrap(),
+            DeadLetter {
+                tag: "test".to_string(),
+                time: chrono::TimeZone::timestamp_opt(&chrono::Utc, 0, 0).unwrap(),
+                record: Map::new(),
+            }
+        );
+        let mut reasons = Vec::new();
+        while let Ok(event) = subscriber.try_recv() {
+            if let Event::Disconnected { reason, .. } = event {
+                reasons.push(reason);
+            }
+        }
+        assert_eq!(reasons, ["connection closed right after a chunk"]);
+    }
+
+    #[tokio::test]
+    async fn test_replay_progress() {
+        let dir = std::env::temp_dir().join(format!("tokio-fluent-replay-{}", Uuid::new_v4()));
+        let mut spool = Spool::open(&dir, Limits::default()).await.unwrap();
+        for message in [&b"first"[..], b"second", b"third"] {
+            // chunks without an id are not acked
+            spool.push("", message).await.unwrap();
+        }
+        drop(spool);
+
+        let (_sender, receiver) = queue::channel(1, crate::client::Overflow::DropNewest);
+        let (events, mut subscriber) = tokio::sync::broadcast::channel(128);
+        let config = WorkerConfig {
+            spool_dir: Some(dir.clone()),
+            spool_replay_rate: 20,
+            ..config()
+        };
+        let mut worker = Worker::new(Acking::default(), receiver, events, config)
+            .await
+            .unwrap();
+        let started = Instant::now();
+        worker.replay().await;
+        // two intervals of 50ms between three chunks
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        assert!(worker.spool.as_ref().unwrap().is_empty());
+
+        let mut progress = Vec::new();
+        while let Ok(event) = subscriber.try_recv() {
+            if let Event::ReplayProgress {
+                replayed,
+                total,
+                bytes,
+                ..
+            } = event
+            {
+                progress.push((replayed, total, bytes));
+            }
+        }
+        assert_eq!(progress, vec![(1, 3, 5), (2, 3, 11), (3, 3, 16)]);
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_replay_pauses_on_spool_errors() {
+        let dir = std::env::temp_dir().join(format!("tokio-fluent-replay-{}", Uuid::new_v4()));
+        let mut spool = Spool::open(&dir, Limits::default()).await.unwrap();
+        for message in [&b"first"[..], b"second"] {
+            spool.push("", message).await.unwrap();
+        }
+        drop(spool);
+
+        let (_sender, receiver) = queue::channel(1, crate::client::Overflow::DropNewest);
+        let (events, _) = tokio::sync::broadcast::channel(1);
+        let config = WorkerConfig {
+            spool_dir: Some(dir.clone()),
+            ..config()
+        };
+        let connectable = Acking::default();
+        let writes = connectable.writes.clone();
+        let mut worker = Worker::new(connectable, receiver, events, config)
+            .await
+            .unwrap();
+        // the first chunk can neither be read nor removed
+        let mut partitions = std::fs::read_dir(&dir).unwrap();
+        let partition = partitions.next().unwrap().unwrap().path();
+        std::fs::remove_file(partition.join(format!("{:016x}.chunk", 0))).unwrap();
+
+        worker.replay().await;
+        assert_eq!(worker.spool.as_ref().unwrap().len(), 1);
+        assert_eq!(writes.load(std::sync::atomic::Ordering::Relaxed), 0);
+
+        // the next replay goes on
+        worker.replay().await;
+        assert!(worker.spool.as_ref().unwrap().is_empty());
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_without_acks() {
+        let (sender, receiver) = queue::channel(1024, crate::client::Overflow::DropNewest);
+        let stats = Arc::new(Recorder::default());
+        let config = WorkerConfig {
+            ack_timeout: Duration::from_millis(10),
+            require_ack: false,
+            stats: stats.clone(),
+            ..config()
+        };
+        // a server which reads but never acks
+        let connectable = Flaky {
+            failures: 0.into(),
+            servers: Mutex::new(Vec::new()),
+        };
+        let (events, _) = tokio::sync::broadcast::channel(1);
+        let mut worker = Worker::new(connectable, receiver, events, config)
+            .await
+            .unwrap();
+        let record = Record {
+            tag: "test".into(),
+            timestamp: 0,
+            nanos: None,
+            record: Map::new(),
+            options: Options {
+                chunk: "chunk-id".to_string(),
+                ..Default::default()
+            },
+        };
+        sender.send(Message::Record(record)).unwrap();
+        drop(sender);
+        worker.run().await;
+
+        let stats = stats.snapshot();
+        assert_eq!(stats.written_records, 1);
+        assert_eq!(stats.failed_records, 0);
+    }
+
+    #[tokio::test]
+    async fn test_batching_without_acks() {
+        let (sender, receiver) = queue::channel(1024, crate::client::Overflow::DropNewest);
+        let mut config = WorkerConfig {
+            require_ack: false,
+            ..config()
+        };
+        config.batch.mode = Mode::PackedForward;
+        config.batch.max_size = 1024 * 1024;
+        let connectable = Flaky {
+            failures: 0.into(),
+            servers: Mutex::new(Vec::new()),
+        };
+        let (events, _) = tokio::sync::broadcast::channel(1);
+        let mut worker = Worker::new(connectable, receiver, events, config)
+            .await
+            .unwrap();
+        for timestamp in 0..2 {
+            let record = Record {
+                tag: "test".into(),
+                timestamp,
+                nanos: None,
+                record: Map::new(),
+                options: Options {
+                    chunk: "chunk-id".to_string(),
+                    ..Default::default()
+                },
+            };
+            sender.send(Message::Record(record)).unwrap();
+        }
+        drop(sender);
+        worker.run().await;
+
+        let mut server = worker.stream_config.servers.lock().unwrap().remove(0);
+        drop(worker);
+        let mut written = Vec::new();
+        server.read_to_end(&mut written).await.unwrap();
+        // a single PackedForward message without the chunk option
+        let mut reader = written.as_slice();
+        let message: Vec<crate::record::Value> = rmp_serde::from_read(&mut reader).unwrap();
+        assert!(reader.is_empty());
+        assert!(matches!(
+            message.last(),
+            Some(crate::record::Value::Object(options)) if !options.contains_key("chunk")
+        ));
+        assert_eq!(dead_letter::decode(&written).unwrap().len(), 2);
     }
 }